@@ -0,0 +1,176 @@
+//! Memory shared with devices for DMA.
+
+#[cfg(feature = "alloc")]
+use alloc::boxed::Box;
+use core::marker::PhantomData;
+use core::ptr::NonNull;
+
+use crate::{DevError, DevResult};
+
+/// Allocation of physically-contiguous memory for DMA, implemented by the
+/// platform.
+pub trait DmaAllocator {
+    /// Allocates `size` zeroed bytes of physically-contiguous memory aligned
+    /// to `align` bytes, which is a power of two.
+    ///
+    /// Returns the virtual address of the memory and its physical address as
+    /// seen by devices, or `None` if there is not enough memory.
+    fn alloc_dma(size: usize, align: usize) -> Option<(NonNull<u8>, u64)>;
+
+    /// Deallocates memory returned by [`DmaAllocator::alloc_dma`].
+    ///
+    /// # Safety
+    ///
+    /// `vaddr`, `paddr`, `size` and `align` must be those of a previous
+    /// allocation, which must not be used any more.
+    unsafe fn dealloc_dma(vaddr: NonNull<u8>, paddr: u64, size: usize, align: usize);
+}
+
+/// A buffer of physically-contiguous memory for DMA, allocated by `A` and
+/// deallocated when dropped.
+pub struct DmaBuffer<A: DmaAllocator> {
+    vaddr: NonNull<u8>,
+    paddr: u64,
+    size: usize,
+    align: usize,
+    _alloc: PhantomData<A>,
+}
+
+// The buffer owns its memory, like a `Box<[u8]>`.
+unsafe impl<A: DmaAllocator> Send for DmaBuffer<A> {}
+unsafe impl<A: DmaAllocator> Sync for DmaBuffer<A> {}
+
+impl<A: DmaAllocator> DmaBuffer<A> {
+    /// Allocates a zeroed buffer of `size` bytes aligned to `align` bytes.
+    ///
+    /// Returns [`DevError::InvalidParam`] if `size` is 0 or `align` is not a
+    /// power of two, or [`DevError::NoMemory`] if the allocation fails.
+    pub fn new(size: usize, align: usize) -> DevResult<Self> {
+        if size == 0 || !align.is_power_of_two() {
+            return Err(DevError::InvalidParam);
+        }
+        let (vaddr, paddr) = A::alloc_dma(size, align).ok_or(DevError::NoMemory)?;
+        Ok(Self {
+            vaddr,
+            paddr,
+            size,
+            align,
+            _alloc: PhantomData,
+        })
+    }
+
+    /// The virtual address of the buffer.
+    pub const fn as_ptr(&self) -> *mut u8 {
+        self.vaddr.as_ptr()
+    }
+
+    /// The physical address of the buffer, as seen by devices.
+    pub const fn phys_addr(&self) -> u64 {
+        self.paddr
+    }
+
+    /// The size of the buffer in bytes.
+    pub const fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The content of the buffer.
+    ///
+    /// It must not be accessed while a device is writing to it.
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.vaddr.as_ptr(), self.size) }
+    }
+
+    /// The mutable content of the buffer.
+    ///
+    /// It must not be accessed while a device is accessing it.
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.vaddr.as_ptr(), self.size) }
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<A: DmaAllocator + 'static> DmaBuffer<A> {
+    /// Allocates a buffer like [`DmaBuffer::new`], as a [`DmaMemory`].
+    ///
+    /// It is a [`DmaAllocFn`], for drivers that are not generic over the
+    /// allocator.
+    pub fn new_boxed(size: usize, align: usize) -> DevResult<Box<dyn DmaMemory>> {
+        Ok(Box::new(Self::new(size, align)?))
+    }
+}
+
+/// Memory for DMA whatever its allocator, e.g. a [`DmaBuffer`].
+pub trait DmaMemory: Send + Sync {
+    /// The virtual address of the memory.
+    fn as_ptr(&self) -> *mut u8;
+
+    /// The physical address of the memory, as seen by devices.
+    fn phys_addr(&self) -> u64;
+
+    /// The size of the memory in bytes.
+    fn size(&self) -> usize;
+}
+
+impl<A: DmaAllocator> DmaMemory for DmaBuffer<A> {
+    fn as_ptr(&self) -> *mut u8 {
+        DmaBuffer::as_ptr(self)
+    }
+
+    fn phys_addr(&self) -> u64 {
+        DmaBuffer::phys_addr(self)
+    }
+
+    fn size(&self) -> usize {
+        DmaBuffer::size(self)
+    }
+}
+
+/// Allocates `size` bytes of memory for DMA aligned to `align` bytes, see
+/// [`DmaBuffer::new_boxed`].
+#[cfg(feature = "alloc")]
+pub type DmaAllocFn = fn(size: usize, align: usize) -> DevResult<Box<dyn DmaMemory>>;
+
+impl<A: DmaAllocator> Drop for DmaBuffer<A> {
+    fn drop(&mut self) {
+        unsafe { A::dealloc_dma(self.vaddr, self.paddr, self.size, self.align) }
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+
+    /// Allocates from the heap, with physical addresses offset from the
+    /// virtual ones.
+    struct HeapDma;
+
+    const PHYS_OFFSET: u64 = 1 << 40;
+
+    impl DmaAllocator for HeapDma {
+        fn alloc_dma(size: usize, align: usize) -> Option<(NonNull<u8>, u64)> {
+            let ptr =
+                NonNull::new(unsafe { alloc_zeroed(Layout::from_size_align(size, align).ok()?) })?;
+            Some((ptr, ptr.as_ptr() as u64 + PHYS_OFFSET))
+        }
+
+        unsafe fn dealloc_dma(vaddr: NonNull<u8>, _paddr: u64, size: usize, align: usize) {
+            dealloc(
+                vaddr.as_ptr(),
+                Layout::from_size_align(size, align).unwrap(),
+            );
+        }
+    }
+
+    #[test]
+    fn boxed_buffer_keeps_the_addresses_of_the_allocation() {
+        let alloc: DmaAllocFn = DmaBuffer::<HeapDma>::new_boxed;
+        let mem = alloc(4096, 1024).unwrap();
+        assert_eq!(mem.size(), 4096);
+        assert_eq!(mem.as_ptr() as usize % 1024, 0);
+        assert_eq!(mem.phys_addr(), mem.as_ptr() as u64 + PHYS_OFFSET);
+        assert!(matches!(alloc(4096, 3), Err(DevError::InvalidParam)));
+        assert!(matches!(alloc(0, 8), Err(DevError::InvalidParam)));
+    }
+}
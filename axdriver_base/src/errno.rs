@@ -0,0 +1,26 @@
+//! The `errno` values that [`DevError::as_errno`] maps errors to.
+//!
+//! They are the values of the generic Linux ABI, used on x86, Arm, RISC-V and
+//! LoongArch. Some architectures, e.g. MIPS, number `ENOTSUP` and `ETIMEDOUT`
+//! differently.
+//!
+//! [`DevError::as_errno`]: crate::DevError::as_errno
+
+/// I/O error.
+pub const EIO: i32 = 5;
+/// Try again.
+pub const EAGAIN: i32 = 11;
+/// Out of memory.
+pub const ENOMEM: i32 = 12;
+/// Device or resource busy.
+pub const EBUSY: i32 = 16;
+/// File exists.
+pub const EEXIST: i32 = 17;
+/// No such device.
+pub const ENODEV: i32 = 19;
+/// Invalid argument.
+pub const EINVAL: i32 = 22;
+/// Operation not supported.
+pub const ENOTSUP: i32 = 95;
+/// Connection timed out.
+pub const ETIMEDOUT: i32 = 110;
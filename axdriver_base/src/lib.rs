@@ -14,6 +14,19 @@
 //! [4]: ../axdriver_net/index.html
 
 #![no_std]
+#![cfg_attr(doc, feature(doc_auto_cfg))]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+pub mod dma;
+pub mod errno;
+#[cfg(feature = "alloc")]
+mod manager;
+pub mod trace;
+
+#[cfg(feature = "alloc")]
+pub use self::manager::{DeviceId, DeviceManager};
 
 /// All supported device types.
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
@@ -28,30 +41,263 @@ pub enum DeviceType {
     Display,
 }
 
+impl DeviceType {
+    /// All device types.
+    pub const fn all() -> &'static [DeviceType] {
+        &[Self::Block, Self::Char, Self::Net, Self::Display]
+    }
+
+    /// The name of the device type, as accepted by [`str::parse`].
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Block => "block",
+            Self::Char => "char",
+            Self::Net => "net",
+            Self::Display => "display",
+        }
+    }
+}
+
+impl core::fmt::Display for DeviceType {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl core::str::FromStr for DeviceType {
+    type Err = DevError;
+
+    /// Parses a device type from its name, returns
+    /// [`DevError::InvalidParam`] if it is unknown.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::all()
+            .iter()
+            .find(|ty| ty.as_str() == s)
+            .copied()
+            .ok_or(DevError::InvalidParam)
+    }
+}
+
+bitflags::bitflags! {
+    /// Optional features supported by a device driver.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct DeviceCapabilities: u32 {
+        /// Pending writes can be flushed to the storage.
+        const FLUSH = 1 << 0;
+        /// Unused blocks can be discarded (e.g., TRIM for SSDs).
+        const DISCARD = 1 << 1;
+        /// The media is read-only.
+        const READ_ONLY = 1 << 2;
+        /// Asynchronous operations are supported.
+        const ASYNC = 1 << 3;
+        /// Single writes can go directly to the storage, bypassing the write
+        /// cache (Force Unit Access).
+        const FUA = 1 << 4;
+    }
+}
+
 /// The error type for device operation failures.
+///
+/// New variants may be added in future releases, so matches on it need a
+/// wildcard arm.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum DevError {
     /// An entity already exists.
     AlreadyExists,
     /// Try again, for non-blocking APIs.
+    ///
+    /// This is also the "device busy" error of non-blocking block I/O: the
+    /// request was not started, e.g. because all the command slots of the
+    /// device are used, and can be submitted again as is once the device made
+    /// progress. It is expected backpressure, unlike
+    /// [`ResourceBusy`](Self::ResourceBusy).
     Again,
+    /// The data of a block is corrupted.
+    BadBlock,
     /// Bad internal state.
     BadState,
+    /// A redundant device lost a copy of the data. The operation succeeded
+    /// on the remaining copy.
+    Degraded,
     /// Invalid parameter/argument.
     InvalidParam,
     /// Input/output error.
     Io,
     /// Not enough space/cannot allocate memory (DMA).
     NoMemory,
+    /// There is no device, e.g. no drive attached to a controller.
+    NotPresent,
+    /// Any other error, with a short description of its reason.
+    Other(&'static str),
     /// Device or resource is busy.
+    ///
+    /// Returned by blocking operations that cannot run while the device is in
+    /// use, e.g. while queued commands are outstanding.
     ResourceBusy,
+    /// The operation did not complete in time, it may succeed if retried.
+    Timeout,
     /// This operation is unsupported or unimplemented.
     Unsupported,
 }
 
+impl DevError {
+    /// The positive `errno` value of this error, from [`errno`], for
+    /// POSIX-like system call layers.
+    ///
+    /// | Error | `errno` |
+    /// |-------|---------|
+    /// | [`AlreadyExists`](Self::AlreadyExists) | `EEXIST` |
+    /// | [`Again`](Self::Again) | `EAGAIN` |
+    /// | [`InvalidParam`](Self::InvalidParam) | `EINVAL` |
+    /// | [`NoMemory`](Self::NoMemory) | `ENOMEM` |
+    /// | [`NotPresent`](Self::NotPresent) | `ENODEV` |
+    /// | [`ResourceBusy`](Self::ResourceBusy) | `EBUSY` |
+    /// | [`Timeout`](Self::Timeout) | `ETIMEDOUT` |
+    /// | [`Unsupported`](Self::Unsupported) | `ENOTSUP` |
+    /// | [`Io`](Self::Io), [`BadBlock`](Self::BadBlock), [`BadState`](Self::BadState), [`Degraded`](Self::Degraded), [`Other`](Self::Other) | `EIO` |
+    pub const fn as_errno(&self) -> i32 {
+        match self {
+            Self::AlreadyExists => errno::EEXIST,
+            Self::Again => errno::EAGAIN,
+            Self::InvalidParam => errno::EINVAL,
+            Self::NoMemory => errno::ENOMEM,
+            Self::NotPresent => errno::ENODEV,
+            Self::ResourceBusy => errno::EBUSY,
+            Self::Timeout => errno::ETIMEDOUT,
+            Self::Unsupported => errno::ENOTSUP,
+            Self::Io | Self::BadBlock | Self::BadState | Self::Degraded | Self::Other(_) => {
+                errno::EIO
+            }
+        }
+    }
+}
+
+impl core::fmt::Display for DevError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let msg = match self {
+            Self::AlreadyExists => "entity already exists",
+            Self::Again => "try again",
+            Self::BadBlock => "bad block",
+            Self::BadState => "bad internal state",
+            Self::Degraded => "redundancy lost",
+            Self::InvalidParam => "invalid parameter",
+            Self::Io => "I/O error",
+            Self::NoMemory => "not enough memory",
+            Self::NotPresent => "device not present",
+            Self::Other(reason) => reason,
+            Self::ResourceBusy => "resource busy",
+            Self::Timeout => "operation timed out",
+            Self::Unsupported => "unsupported operation",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl core::error::Error for DevError {}
+
 /// A specialized `Result` type for device operations.
 pub type DevResult<T = ()> = Result<T, DevError>;
 
+/// Translation between the virtual addresses of the driver and the physical
+/// addresses seen by devices, implemented by the platform.
+///
+/// Drivers that are given one stop assuming that the memory they pass to
+/// devices is mapped linearly, which is required with an IOMMU or with
+/// non-linear kernel mappings.
+pub trait AddrTranslator: Send + Sync {
+    /// Returns the address at which devices access the memory mapped at
+    /// virtual address `va`.
+    fn virt_to_phys(&self, va: usize) -> u64;
+
+    /// Returns the virtual address of the memory that devices access at
+    /// address `pa`.
+    fn phys_to_virt(&self, pa: u64) -> usize;
+}
+
+/// Calls `cond` until it returns `true`, at most `max_iters` times.
+///
+/// Returns [`DevError::Timeout`] if `cond` never returned `true`, so that a
+/// device that never responds is reported instead of hanging the caller.
+pub fn poll_until<F: FnMut() -> bool>(mut cond: F, max_iters: usize) -> DevResult {
+    for _ in 0..max_iters {
+        if cond() {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(DevError::Timeout)
+}
+
+/// A monotonic clock, implemented by the platform, for drivers that measure
+/// durations.
+pub trait Clock {
+    /// The current time in nanoseconds, since an arbitrary origin.
+    fn now_nanos(&self) -> u64;
+}
+
+/// Counters of the operations performed by a device.
+///
+/// For block devices, reads and writes are the read and write requests. For
+/// network devices, they are the received and transmitted packets.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeviceStats {
+    /// Number of successful reads.
+    pub reads: u64,
+    /// Number of successful writes.
+    pub writes: u64,
+    /// Number of bytes read.
+    pub bytes_read: u64,
+    /// Number of bytes written.
+    pub bytes_written: u64,
+    /// Number of failed reads.
+    pub read_errors: u64,
+    /// Number of failed writes.
+    pub write_errors: u64,
+}
+
+impl DeviceStats {
+    /// Accounts for a read or write of `bytes` bytes that returned `result`.
+    ///
+    /// Reads that fail with [`DevError::Again`] are not counted, as nothing
+    /// was available.
+    pub fn record<T>(&mut self, write: bool, bytes: usize, result: &DevResult<T>) {
+        let (ops, total, errors) = if write {
+            (
+                &mut self.writes,
+                &mut self.bytes_written,
+                &mut self.write_errors,
+            )
+        } else {
+            (&mut self.reads, &mut self.bytes_read, &mut self.read_errors)
+        };
+        match result {
+            Ok(_) => {
+                *ops += 1;
+                *total += bytes as u64;
+            }
+            Err(DevError::Again) if !write => {}
+            Err(_) => *errors += 1,
+        }
+    }
+}
+
+/// Sums the counters of two devices, e.g. of the devices under a RAID.
+impl core::ops::Add for DeviceStats {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            reads: self.reads + other.reads,
+            writes: self.writes + other.writes,
+            bytes_read: self.bytes_read + other.bytes_read,
+            bytes_written: self.bytes_written + other.bytes_written,
+            read_errors: self.read_errors + other.read_errors,
+            write_errors: self.write_errors + other.write_errors,
+        }
+    }
+}
+
 /// Common operations that require all device drivers to implement.
 pub trait BaseDriverOps: Send + Sync {
     /// The name of the device.
@@ -59,4 +305,60 @@ pub trait BaseDriverOps: Send + Sync {
 
     /// The type of the device.
     fn device_type(&self) -> DeviceType;
+
+    /// The optional features supported by the device.
+    fn capabilities(&self) -> DeviceCapabilities {
+        DeviceCapabilities::empty()
+    }
+
+    /// Re-initializes the device, e.g. to recover it after a fatal error.
+    ///
+    /// Returns [`DevError::Unsupported`] if the driver cannot do it.
+    fn reset(&mut self) -> DevResult {
+        Err(DevError::Unsupported)
+    }
+
+    /// The counters of the operations performed by the device.
+    ///
+    /// The default implementation returns all zeros, for drivers that do not
+    /// keep them.
+    fn stats(&self) -> DeviceStats {
+        DeviceStats::default()
+    }
+}
+
+/// Operations of device drivers whose device can signal events with
+/// interrupts.
+///
+/// Drivers keep working by polling if the interrupt is never registered.
+pub trait IrqDriver: BaseDriverOps {
+    /// The interrupt number of the device, or `None` if it is unknown.
+    fn irq_number(&self) -> Option<u32>;
+
+    /// Handles an interrupt of the device.
+    ///
+    /// Processes the completed operations and acknowledges the interrupt, so
+    /// that the device can raise it again.
+    fn handle_irq(&mut self) -> DevResult;
+}
+
+/// A change of the presence of a hot-pluggable device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HotplugEvent {
+    /// A device was attached.
+    Attached,
+    /// The device was removed.
+    Detached,
+}
+
+/// Operations of device drivers whose device can be attached and removed
+/// while the system is running.
+pub trait HotplugDriver: BaseDriverOps {
+    /// Whether the device is currently present.
+    fn is_present(&self) -> bool;
+
+    /// Checks whether the device was attached or removed since the last call.
+    ///
+    /// Returns `None` if nothing changed.
+    fn poll_hotplug(&mut self) -> Option<HotplugEvent>;
 }
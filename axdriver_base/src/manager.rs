@@ -0,0 +1,89 @@
+//! A registry of the device drivers of a system.
+
+use alloc::{boxed::Box, collections::BTreeMap};
+
+use crate::{BaseDriverOps, DeviceType};
+
+/// The identifier of a device in a [`DeviceManager`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DeviceId(u32);
+
+impl DeviceId {
+    /// The value of the identifier.
+    pub const fn as_u32(self) -> u32 {
+        self.0
+    }
+}
+
+impl core::fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A table of device drivers, each identified by a [`DeviceId`].
+///
+/// Identifiers are assigned in the order of registration and are never
+/// reused, so a stale identifier of a removed device finds nothing.
+#[derive(Default)]
+pub struct DeviceManager {
+    devices: BTreeMap<DeviceId, Box<dyn BaseDriverOps>>,
+    next_id: u32,
+}
+
+impl DeviceManager {
+    /// Creates an empty manager.
+    pub const fn new() -> Self {
+        Self {
+            devices: BTreeMap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Adds a driver and returns its identifier.
+    pub fn register(&mut self, driver: Box<dyn BaseDriverOps>) -> DeviceId {
+        let id = DeviceId(self.next_id);
+        self.next_id += 1;
+        self.devices.insert(id, driver);
+        id
+    }
+
+    /// Returns the driver with the given identifier.
+    pub fn get(&self, id: DeviceId) -> Option<&dyn BaseDriverOps> {
+        self.devices.get(&id).map(|dev| dev.as_ref())
+    }
+
+    /// Returns the driver with the given identifier, mutably.
+    pub fn get_mut(&mut self, id: DeviceId) -> Option<&mut (dyn BaseDriverOps + 'static)> {
+        self.devices.get_mut(&id).map(|dev| dev.as_mut())
+    }
+
+    /// Removes the driver with the given identifier and returns it.
+    pub fn remove(&mut self, id: DeviceId) -> Option<Box<dyn BaseDriverOps>> {
+        self.devices.remove(&id)
+    }
+
+    /// Iterates over all the drivers, in the order of registration.
+    pub fn iter(&self) -> impl Iterator<Item = (DeviceId, &dyn BaseDriverOps)> {
+        self.devices.iter().map(|(&id, dev)| (id, dev.as_ref()))
+    }
+
+    /// Iterates over the drivers of devices of type `ty`, in the order of
+    /// registration.
+    pub fn iter_by_type(
+        &self,
+        ty: DeviceType,
+    ) -> impl Iterator<Item = (DeviceId, &dyn BaseDriverOps)> {
+        self.iter().filter(move |(_, dev)| dev.device_type() == ty)
+    }
+
+    /// The number of registered drivers.
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    /// Whether no driver is registered.
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+}
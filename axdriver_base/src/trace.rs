@@ -0,0 +1,159 @@
+//! Diagnostics of the drivers, routed to a sink chosen by the platform.
+//!
+//! Drivers emit messages with the [`error!`], [`warn!`], [`info!`],
+//! [`debug!`] and [`trace!`] macros of this module, which take the same
+//! arguments as [`format_args!`]. The messages go to the sink installed with
+//! [`set_sink`], or if there is none, to the `log` crate when the `log`
+//! feature is enabled (the default). Otherwise they are dropped.
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+/// The importance of a message, from the most to the least important.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    /// A failure of the device or of an operation.
+    Error,
+    /// An unexpected condition that the driver worked around.
+    Warn,
+    /// A notable event, e.g. a device found.
+    Info,
+    /// Details useful to debug a driver.
+    Debug,
+    /// Very verbose details, e.g. each command issued.
+    Trace,
+}
+
+#[cfg(feature = "log")]
+impl From<Level> for log::Level {
+    fn from(level: Level) -> Self {
+        match level {
+            Level::Error => Self::Error,
+            Level::Warn => Self::Warn,
+            Level::Info => Self::Info,
+            Level::Debug => Self::Debug,
+            Level::Trace => Self::Trace,
+        }
+    }
+}
+
+/// A destination of the messages of the drivers.
+pub trait TraceSink: Sync {
+    /// Handles a message of `level`, emitted by the module `target` (e.g.
+    /// `axdriver_block::ahci`).
+    fn emit(&self, level: Level, target: &str, args: fmt::Arguments<'_>);
+}
+
+/// The installed sink, written once before [`STATE`] becomes [`SET`].
+struct SinkCell(UnsafeCell<Option<&'static dyn TraceSink>>);
+
+// SAFETY: The cell is only written by the `set_sink` call that moved `STATE`
+// out of `UNSET`, and only read once `STATE` is `SET`.
+unsafe impl Sync for SinkCell {}
+
+const UNSET: u8 = 0;
+const SETTING: u8 = 1;
+const SET: u8 = 2;
+
+static SINK: SinkCell = SinkCell(UnsafeCell::new(None));
+static STATE: AtomicU8 = AtomicU8::new(UNSET);
+
+/// Sends the messages of all drivers to `sink` from now on.
+///
+/// A sink can only be installed once. Returns `false` and ignores `sink` if
+/// one already is, or is being installed by another CPU.
+pub fn set_sink(sink: &'static dyn TraceSink) -> bool {
+    if STATE
+        .compare_exchange(UNSET, SETTING, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        return false;
+    }
+    // SAFETY: Only this call got `STATE` out of `UNSET`, and nobody reads the
+    // cell until it is `SET`.
+    unsafe { *SINK.0.get() = Some(sink) };
+    STATE.store(SET, Ordering::Release);
+    true
+}
+
+/// Sends a message to the installed sink, or to `log`. Called by the macros.
+#[doc(hidden)]
+pub fn emit(level: Level, target: &str, args: fmt::Arguments<'_>) {
+    if STATE.load(Ordering::Acquire) == SET {
+        // SAFETY: The cell is no longer written once `STATE` is `SET`.
+        if let Some(sink) = unsafe { *SINK.0.get() } {
+            sink.emit(level, target, args);
+            return;
+        }
+    }
+    #[cfg(feature = "log")]
+    {
+        let level: log::Level = level.into();
+        log::log!(target: target, level, "{}", args);
+    }
+    #[cfg(not(feature = "log"))]
+    let _ = (level, target, args);
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __trace_emit {
+    ($level:ident, $($arg:tt)+) => {
+        $crate::trace::emit(
+            $crate::trace::Level::$level,
+            ::core::module_path!(),
+            ::core::format_args!($($arg)+),
+        )
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __trace_error {
+    ($($arg:tt)+) => { $crate::__trace_emit!(Error, $($arg)+) };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __trace_warn {
+    ($($arg:tt)+) => { $crate::__trace_emit!(Warn, $($arg)+) };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __trace_info {
+    ($($arg:tt)+) => { $crate::__trace_emit!(Info, $($arg)+) };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __trace_debug {
+    ($($arg:tt)+) => { $crate::__trace_emit!(Debug, $($arg)+) };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __trace_trace {
+    ($($arg:tt)+) => { $crate::__trace_emit!(Trace, $($arg)+) };
+}
+
+/// Emits a message of [`Level::Error`].
+#[doc(inline)]
+pub use crate::__trace_error as error;
+
+/// Emits a message of [`Level::Warn`].
+#[doc(inline)]
+pub use crate::__trace_warn as warn;
+
+/// Emits a message of [`Level::Info`].
+#[doc(inline)]
+pub use crate::__trace_info as info;
+
+/// Emits a message of [`Level::Debug`].
+#[doc(inline)]
+pub use crate::__trace_debug as debug;
+
+/// Emits a message of [`Level::Trace`].
+#[doc(inline)]
+pub use crate::__trace_trace as trace;
@@ -1,161 +1,1754 @@
 //! AHCI (Advanced Host Controller Interface) driver for SATA devices
 
 extern crate alloc;
-use crate::BlockDriverOps;
-use axdriver_base::{BaseDriverOps, DevError, DevResult, DeviceType};
+use crate::{BlockDriverOps, IoHints};
+use alloc::{boxed::Box, vec, vec::Vec};
+use axdriver_base::dma::{DmaAllocFn, DmaMemory};
+use axdriver_base::{
+    trace, AddrTranslator, BaseDriverOps, DevError, DevResult, DeviceCapabilities, DeviceStats,
+    DeviceType, HotplugDriver, HotplugEvent, IrqDriver,
+};
 
 use ahci_driver::drv_ahci::{ahci_init, ahci_sata_read_common, ahci_sata_write_common};
-use ahci_driver::libahci::{ahci_blk_dev, ahci_cmd_hdr, ahci_device, ahci_ioport, ahci_sg};
+use ahci_driver::libahci::{ahci_blk_dev, ahci_device, ahci_ioport};
+
+use self::ata::IdentifyData;
+use self::cmd::{Fis, Segment};
+
+pub use self::bench::ThroughputReport;
+pub use self::builder::AhciDriverBuilder;
+pub use self::caps::{AhciCapabilities, AhciInitReport, PhyErrors, PortInfo, SataSpeed, SgLimits};
+pub use self::ncq::CommandToken;
+pub use self::smart::SmartStatus;
+
+mod ata;
+mod bench;
+mod builder;
+mod caps;
+mod cmd;
+mod irq;
+mod ncq;
+mod smart;
+
+/// Default number of retries of a failed read/write.
+const DEFAULT_MAX_RETRIES: u32 = 1;
 
 /// AHCI driver implementation
+///
+/// Commands that do not complete in a bounded number of polls fail with
+/// [`DevError::Timeout`], after which they can be retried. The port is reset
+/// before, which aborts the command, so the buffers of the caller are no
+/// longer accessed by the device once the method returns. This applies to
+/// [`AhciDriver::reset_port`], [`AhciDriver::smart_status`], the vectored
+/// reads/writes, `flush` and `discard`. Plain `read_block`/`write_block` are
+/// executed by `ahci_driver`, which reports every failure as
+/// [`DevError::Io`], except on ATAPI devices and with an [`AddrTranslator`].
+///
+/// ATAPI devices (e.g., optical drives) are read-only, and use the block size
+/// of their media, usually 2048 bytes.
+///
+/// # Thread safety
+///
+/// Every method that issues a command or changes the port state takes
+/// `&mut self`, so a driver shared between CPUs must be behind a lock. The
+/// drivers returned by [`AhciDriver::probe_all`] drive disjoint ports of the
+/// same controller and can be used concurrently.
 pub struct AhciDriver {
     /// AHCI device structure containing all the necessary hardware information
     device: ahci_device,
+    /// IDENTIFY DEVICE data of the enabled port
+    id: IdentifyData,
+    /// Whether GET MEDIA STATUS reported the media as write-protected
+    write_protected: bool,
+    /// Number of times a failed read/write is retried after a port reset
+    max_retries: u32,
+    /// Number of polls of a command before it times out
+    poll_iters: usize,
+    /// Limit of the number of blocks transferred by a single command
+    max_blocks: Option<u32>,
+    /// Translation of the addresses of buffers, `None` for the linear mapping
+    /// of the memory allocated by `ahci_init`
+    translator: Option<&'static dyn AddrTranslator>,
+    /// Allocator of the memory shared with the controller, `None` to use the
+    /// memory allocated by `ahci_init`
+    dma_alloc: Option<DmaAllocFn>,
+    /// Command list, received FIS area and command table of the enabled port,
+    /// if they were allocated with `dma_alloc`
+    port_mem: Option<Box<dyn DmaMemory>>,
+    /// Interrupt number of the controller, if interrupts are enabled
+    irq: Option<u32>,
+    /// Queued commands, allocated on the first submission
+    queue: Option<ncq::Queue>,
+    /// Counters of `read_block` and `write_block`
+    stats: DeviceStats,
+    /// Whether the drive was present at the last [`HotplugDriver::poll_hotplug`]
+    present: bool,
 }
 
+// SAFETY: The raw pointers in `ahci_device` point to the command lists,
+// command tables and received FIS areas that `ahci_init` allocated for the
+// ports, and to their MMIO registers. The driver only accesses those of its
+// own port, and only through `&mut self` when issuing commands, so they are
+// never accessed from two CPUs at once. The accesses through `&self` are
+// reads of registers without side effects (e.g. PxSIG), which may race. The
+// global registers are shared with the drivers of the other ports: they are
+// either written with bits of this port only (IS), or updated under a lock
+// (GHC, see `irq::enable`).
+unsafe impl Send for AhciDriver {}
+unsafe impl Sync for AhciDriver {}
+
 impl AhciDriver {
     /// Initialize the AHCI driver, returns `Ok` if successful.
+    ///
+    /// The controller is discovered by `ahci_init`. Only the port enabled by
+    /// `ahci_init` is exposed, use [`AhciDriver::probe_all`] to get a driver
+    /// for every attached drive.
+    ///
+    /// Each controller must be initialized only once, by either this function
+    /// or [`AhciDriver::new_at`].
+    ///
+    /// Returns [`DevError::NotPresent`] if no drive is attached to the
+    /// controller. Use [`AhciDriverBuilder`] to change the default settings.
     pub fn try_new() -> DevResult<AhciDriver> {
-        log::info!("AHCI: initializing");
-        // Create an uninitialized AHCI device structure
-        let mut device = ahci_device {
-            mmio_base: 0,
-            // Initialize other fields as needed
-            flags: 0,
-            cap: 0,
-            cap2: 0,
-            version: 0,
-            port_map: 0,
-            pio_mask: 0,
-            udma_mask: 0,
-            n_ports: 0,
-            port_map_linkup: 0,
-            port: [ahci_ioport {
-                port_mmio: 0,
-                cmd_slot: core::ptr::null_mut(),
-                cmd_slot_dma: 0,
-                rx_fis: 0,
-                rx_fis_dma: 0,
-                cmd_tbl: 0,
-                cmd_tbl_dma: 0,
-                cmd_tbl_sg: core::ptr::null_mut(),
-            }; 32],
-            port_idx: 0, // the enabled port
-
-            blk_dev: ahci_blk_dev {
-                lba48: false,
-                _pad1: [0; 7], // 对齐到8字节边界
-                lba: 0,
-                blksz: 0,
-                queue_depth: 0,
-                _pad2: [0; 4],    // 对齐到8字节边界
-                product: [0; 41], // 41字节
-                _pad3: [0; 7],    // 填充到8字节对齐 (41 + 7 = 48, 48 % 8 = 0)
-                serial: [0; 21],  // 21字节
-                _pad4: [0; 3],    // 填充到8字节对齐 (21 + 3 = 24, 24 % 8 = 0)
-                revision: [0; 9], // 9字节
-                _pad5: [0; 7],    // 填充到8字节对齐 (9 + 7 = 16, 16 % 8 = 0)
-            },
-        };
+        AhciDriverBuilder::new().build()
+    }
+
+    /// Initialize the AHCI driver for the controller whose registers (ABAR,
+    /// i.e. BAR5 of a PCI AHCI controller) are mapped at `mmio_base`.
+    ///
+    /// This is for platforms where the controller has already been found,
+    /// e.g. by PCI enumeration, and skips the discovery of `ahci_init`. A PCI
+    /// controller must have memory space decoding and bus mastering enabled
+    /// beforehand, see `axdriver_pci::ahci_probe`.
+    ///
+    /// Each controller must be initialized only once, by either this function
+    /// or [`AhciDriver::try_new`].
+    pub fn new_at(mmio_base: u64) -> DevResult<AhciDriver> {
+        if mmio_base == 0 {
+            return Err(DevError::InvalidParam);
+        }
+        AhciDriverBuilder::new().mmio_base(mmio_base).build()
+    }
+
+    /// Like [`AhciDriver::new_at`], but translates the addresses of the
+    /// memory given to the controller with `translator` instead of assuming
+    /// a linear mapping.
+    ///
+    /// The command list and received FIS area allocated by `ahci_init` are
+    /// moved to the addresses given by `translator`, and reads and writes are
+    /// issued by this driver rather than by `ahci_driver`.
+    pub fn new_at_with_translator(
+        mmio_base: u64,
+        translator: &'static dyn AddrTranslator,
+    ) -> DevResult<AhciDriver> {
+        if mmio_base == 0 {
+            return Err(DevError::InvalidParam);
+        }
+        AhciDriverBuilder::new()
+            .mmio_base(mmio_base)
+            .translator(translator)
+            .build()
+    }
+
+    fn new_with_config(config: &AhciDriverBuilder) -> DevResult<AhciDriver> {
+        let device = Self::init_device(config.mmio_base)?;
+        if device.port_map_linkup == 0 {
+            trace::info!("AHCI: no drive attached");
+            return Err(DevError::NotPresent);
+        }
+        let mut driver = Self::from_device(device);
+        driver.configure(config);
+        driver.rebase()?;
+        match driver.identify() {
+            Ok(()) if driver.is_atapi() => {
+                if let Err(e) = driver.read_capacity() {
+                    trace::warn!("AHCI: READ CAPACITY failed: {:?}", e);
+                }
+                copy_id_strings(&driver.id, &mut driver.device.blk_dev);
+            }
+            Ok(()) => {
+                driver.check_block_size();
+                copy_id_strings(&driver.id, &mut driver.device.blk_dev);
+            }
+            Err(e) => trace::warn!("AHCI: IDENTIFY DEVICE failed: {:?}", e),
+        }
+        Ok(driver)
+    }
+
+    /// Initialize the AHCI controller and returns one driver for each port
+    /// with a linked-up drive.
+    ///
+    /// The capacity, block size and identity of each driver are those of the
+    /// drive attached to its own port. Ports that fail to be identified are
+    /// skipped. Same as `AhciDriverBuilder::new().probe_all()`, see
+    /// [`AhciDriverBuilder::probe_all`].
+    pub fn probe_all() -> DevResult<Vec<AhciDriver>> {
+        AhciDriverBuilder::new().probe_all()
+    }
+
+    /// Spins up the drives of the implemented ports that are not yet, one at
+    /// a time, calling `delay(idx)` before spinning up that of port `idx`.
+    ///
+    /// The ports whose link comes up are then reported as linked up.
+    fn spin_up_staggered(device: &mut ahci_device, mut delay: impl FnMut(u8)) {
+        for idx in 0..device.port.len() {
+            if device.port_map & (1 << idx) == 0 {
+                continue;
+            }
+            let port = &mut device.port[idx];
+            if port.port_mmio == 0 {
+                port.port_mmio = cmd::port_mmio(device.mmio_base, idx);
+            }
+            if cmd::spun_up(port) {
+                continue;
+            }
+            delay(idx as u8);
+            match cmd::spin_up(port) {
+                Ok(()) => device.port_map_linkup |= 1 << idx,
+                Err(e) => trace::debug!("AHCI: port {}: no drive after spin-up: {:?}", idx, e),
+            }
+        }
+    }
+
+    /// Creates a driver with the settings of `config` for each port of
+    /// `device` with a linked-up drive.
+    ///
+    /// Ports that `ahci_init` did not start are started on memory of the DMA
+    /// allocator of `config`, and skipped without one.
+    fn probe_ports(
+        mut device: ahci_device,
+        config: &AhciDriverBuilder,
+    ) -> DevResult<Vec<AhciDriver>> {
+        let mut drivers = Vec::new();
+        for idx in 0..device.port.len() {
+            if device.port_map_linkup & (1 << idx) == 0 {
+                continue;
+            }
+            let port = &mut device.port[idx];
+            if port.cmd_slot.is_null() {
+                if config.dma_alloc.is_none() {
+                    trace::warn!(
+                        "AHCI: port {} is linked up but not started, it needs a DMA allocator",
+                        idx
+                    );
+                    continue;
+                }
+                if port.port_mmio == 0 {
+                    port.port_mmio = cmd::port_mmio(device.mmio_base, idx);
+                }
+            }
+            let mut driver = Self::from_device(copy_device(&device, idx));
+            driver.configure(config);
+            if let Err(e) = driver.rebase() {
+                trace::warn!("AHCI: failed to start port {}: {:?}", idx, e);
+                continue;
+            }
+            if let Err(e) = driver.identify() {
+                trace::warn!("AHCI: IDENTIFY DEVICE failed on port {}: {:?}", idx, e);
+                continue;
+            }
+            // `ahci_init` only fills in `blk_dev` for the port it enabled
+            if idx != device.port_idx as usize {
+                driver.device.blk_dev = blk_dev_from_id(&driver.id);
+            } else {
+                if !driver.is_atapi() {
+                    driver.check_block_size();
+                }
+                copy_id_strings(&driver.id, &mut driver.device.blk_dev);
+            }
+            if driver.is_atapi() {
+                if let Err(e) = driver.read_capacity() {
+                    trace::warn!("AHCI: READ CAPACITY failed on port {}: {:?}", idx, e);
+                }
+            }
+            trace::info!(
+                "AHCI: port {}: {} device, {} blocks of {} bytes",
+                idx,
+                if driver.is_atapi() { "ATAPI" } else { "ATA" },
+                driver.num_blocks(),
+                driver.block_size()
+            );
+            drivers.push(driver);
+        }
+        Ok(drivers)
+    }
+
+    /// Applies the settings of `config` that do not depend on the drive.
+    fn configure(&mut self, config: &AhciDriverBuilder) {
+        self.translator = config.translator;
+        self.dma_alloc = config.dma_alloc;
+        self.poll_iters = config.poll_iters;
+        self.max_retries = config.max_retries;
+        self.max_blocks = config.max_blocks;
+    }
+
+    fn from_device(device: ahci_device) -> Self {
+        Self {
+            device,
+            id: IdentifyData::empty(),
+            write_protected: false,
+            max_retries: DEFAULT_MAX_RETRIES,
+            poll_iters: cmd::CMD_POLL_ITERS,
+            max_blocks: None,
+            translator: None,
+            dma_alloc: None,
+            port_mem: None,
+            irq: None,
+            queue: None,
+            stats: DeviceStats::default(),
+            present: true,
+        }
+    }
+
+    /// Creates the AHCI device structure and initializes the controller.
+    ///
+    /// `ahci_init` discovers the controller if `mmio_base` is 0, and uses the
+    /// given registers otherwise.
+    fn init_device(mmio_base: u64) -> DevResult<ahci_device> {
+        trace::info!("AHCI: initializing");
+        let mut device = empty_device(mmio_base);
 
         // Call the C-style initialization function
         let result = unsafe { ahci_init(&mut device) };
 
-        if result == 0 {
-            log::info!("AHCI: successfully initialized");
-            Ok(AhciDriver { device })
+        if result == 0 && mmio_base != 0 && device.mmio_base != mmio_base {
+            trace::warn!(
+                "AHCI: controller initialized at {:#x} instead of {:#x}",
+                device.mmio_base,
+                mmio_base
+            );
+            Err(DevError::BadState)
+        } else if result == 0 {
+            trace::info!("AHCI: successfully initialized");
+            Ok(device)
         } else {
-            log::warn!("AHCI: init failed with error code {}", result);
-            Err(DevError::Io)
+            trace::warn!("AHCI: init failed with error code {}", result);
+            Err(DevError::Other("controller initialization failed"))
         }
     }
 
+    /// Sets the number of times a failed read/write is retried, after
+    /// resetting the port with [`AhciDriver::reset_port`].
+    ///
+    /// The default is 1, set it to 0 to disable retrying.
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Sets the number of times the completion of a command is polled before
+    /// it fails with [`DevError::Timeout`].
+    ///
+    /// The default is 10,000,000. The commands issued while the driver is
+    /// created use the default, unless it is set with
+    /// [`AhciDriverBuilder::command_timeout`].
+    pub fn with_poll_iters(mut self, poll_iters: usize) -> Self {
+        self.poll_iters = poll_iters;
+        self
+    }
+
+    /// Enables the interrupt of the enabled port, which the platform has
+    /// wired to `irq`.
+    ///
+    /// The interrupt must then be handled with [`IrqDriver::handle_irq`].
+    pub fn with_irq(mut self, irq: u32) -> Self {
+        irq::enable(&self.device);
+        self.irq = Some(irq);
+        self
+    }
+
     /// Get a reference to the underlying AHCI device
     pub fn device(&self) -> &ahci_device {
         &self.device
     }
-}
 
-impl BaseDriverOps for AhciDriver {
-    fn device_type(&self) -> DeviceType {
-        DeviceType::Block
+    /// Recovers the enabled port from an error state.
+    ///
+    /// Stops the command engine, clears the error bits, issues a COMRESET if
+    /// the device is still busy, then restarts FIS reception and the command
+    /// engine. Any outstanding command is aborted. The error interrupts,
+    /// masked by [`IrqDriver::handle_irq`] when the error occurred, are
+    /// unmasked again.
+    ///
+    /// Returns [`DevError::Timeout`] if the port does not respond.
+    pub fn reset_port(&mut self) -> DevResult {
+        if let Some(queue) = &mut self.queue {
+            queue.abort_all();
+        }
+        cmd::reset_port(self.port()).inspect_err(|_| {
+            trace::error!("AHCI: failed to reset port {}", self.device.port_idx);
+        })?;
+        if self.irq.is_some() {
+            irq::enable(&self.device);
+        }
+        Ok(())
     }
 
-    fn device_name(&self) -> &str {
-        "ahci"
+    /// The number of commands that can be queued with
+    /// [`AhciDriver::submit_read`] and [`AhciDriver::submit_write`].
+    ///
+    /// Returns 1 if Native Command Queuing is not supported by the controller
+    /// or the drive.
+    pub fn queue_depth(&self) -> u32 {
+        let caps = self.capabilities();
+        if caps.ncq && self.id.has_ncq() && !self.is_atapi() {
+            self.id.queue_depth().min(caps.num_command_slots)
+        } else {
+            1
+        }
     }
-}
 
-impl BlockDriverOps for AhciDriver {
-    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
-        let block_size = self.block_size();
-        let block_count = (buf.len() + block_size - 1) / block_size;
+    /// Submits a queued read of blocks starting from `block_id` into `buf`,
+    /// without waiting for it to complete.
+    ///
+    /// The completion is reported by [`AhciDriver::poll_completions`] with the
+    /// returned token. Returns [`DevError::Unsupported`] if Native Command
+    /// Queuing is not supported, or [`DevError::Again`] if all
+    /// [`AhciDriver::queue_depth`] slots are in use or a non-queued command is
+    /// running.
+    ///
+    /// # Safety
+    ///
+    /// `buf` must stay valid, and must not be accessed, until the command is
+    /// reported complete.
+    pub unsafe fn submit_read(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult<CommandToken> {
+        self.check_buf(buf)?;
+        let seg = Segment {
+            addr: buf.as_mut_ptr() as usize,
+            len: buf.len(),
+        };
+        self.submit(block_id, seg, false)
+    }
 
-        if buf.len() % block_size != 0 {
-            log::warn!(
-                "Buffer size {} is not aligned to block size {}",
-                buf.len(),
-                block_size
-            );
+    /// Submits a queued write of `buf` to blocks starting from `block_id`,
+    /// without waiting for it to complete.
+    ///
+    /// See [`AhciDriver::submit_read`].
+    ///
+    /// # Safety
+    ///
+    /// `buf` must stay valid, and must not be modified, until the command is
+    /// reported complete.
+    pub unsafe fn submit_write(&mut self, block_id: u64, buf: &[u8]) -> DevResult<CommandToken> {
+        if self.is_read_only() {
+            return Err(DevError::Unsupported);
         }
+        self.check_buf(buf)?;
+        let seg = Segment {
+            addr: buf.as_ptr() as usize,
+            len: buf.len(),
+        };
+        self.submit(block_id, seg, true)
+    }
 
-        // Call the underlying AHCI read function
+    /// Reports the queued commands that have completed to `f`, and returns
+    /// how many there were.
+    ///
+    /// If the drive reports an error, every outstanding command fails with
+    /// [`DevError::Io`] and the port is reset. Commands aborted by a reset of
+    /// the port fail the same way.
+    pub fn poll_completions(&mut self, f: impl FnMut(CommandToken, DevResult)) -> usize {
+        let Some(queue) = &mut self.queue else {
+            return 0;
+        };
+        let (n, failed) = queue.poll(&self.device.port[self.device.port_idx as usize], f);
+        if failed {
+            let _ = self.reset_port();
+        }
+        n
+    }
+
+    /// Capabilities of the AHCI controller.
+    pub fn capabilities(&self) -> AhciCapabilities {
+        AhciCapabilities::from_regs(self.device.cap, self.device.version)
+    }
+
+    /// The speed negotiated by the link of the enabled port.
+    ///
+    /// Returns `None` if no drive is present, or if its speed is not known.
+    pub fn link_speed(&self) -> Option<SataSpeed> {
+        SataSpeed::from_generation(cmd::link_speed(self.port())?)
+    }
+
+    /// The current state of the enabled port, read from its registers.
+    pub fn active_port(&self) -> PortInfo {
+        let port = self.port();
+        PortInfo::from_regs(
+            self.device.port_idx,
+            cmd::read_reg(port, cmd::PORT_SCR_STAT),
+            cmd::read_reg(port, cmd::PORT_SCR_ERR),
+            cmd::read_reg(port, cmd::PORT_TFDATA),
+            cmd::read_reg(port, cmd::PORT_CMD),
+            cmd::read_reg(port, cmd::PORT_CMD_ISSUE),
+            cmd::read_reg(port, cmd::PORT_SCR_ACT),
+        )
+    }
+
+    /// The errors recorded by the link of the enabled port since they were
+    /// last cleared.
+    pub fn phy_errors(&self) -> PhyErrors {
+        PhyErrors::from_reg(cmd::read_reg(self.port(), cmd::PORT_SCR_ERR))
+    }
+
+    /// Clears the errors recorded by the link of the enabled port, so that
+    /// [`AhciDriver::phy_errors`] only reports the next ones.
+    ///
+    /// The connection changes, [`PhyErrors::phy_ready_change`] and
+    /// [`PhyErrors::exchanged`], are kept for [`HotplugDriver::poll_hotplug`].
+    pub fn clear_phy_errors(&mut self) {
+        cmd::clear_phy_errors(self.port());
+    }
+
+    /// Spins up the drive of the enabled port (PxCMD.SUD), if the controller
+    /// supports staggered spin-up and it is not spun up yet.
+    ///
+    /// Without staggered spin-up (see
+    /// [`AhciCapabilities::staggered_spin_up`]), drives spin up at power-on
+    /// and this does nothing. Returns [`DevError::NotPresent`] if the link
+    /// does not come up after the spin-up.
+    pub fn spin_up_port(&mut self) -> DevResult {
+        if !self.capabilities().staggered_spin_up || cmd::spun_up(self.port()) {
+            return Ok(());
+        }
+        cmd::spin_up(self.port())
+    }
+
+    /// What the initialization of the controller found, for diagnostics.
+    pub fn init_report(&self) -> AhciInitReport {
+        AhciInitReport {
+            mmio_base: self.device.mmio_base,
+            ports_implemented: self.device.port_map,
+            ports_linked_up: self.device.port_map_linkup,
+            port_index: self.device.port_idx,
+            cap: self.device.cap,
+            cap2: self.device.cap2,
+            version: self.device.version,
+            capabilities: self.capabilities(),
+        }
+    }
+
+    /// The enabled port.
+    fn port(&self) -> &ahci_ioport {
+        &self.device.port[self.device.port_idx as usize]
+    }
+
+    /// Whether a non-queued command can be issued on the enabled port now,
+    /// i.e. no queued command is in flight and slot 0 is free.
+    fn can_issue(&self) -> bool {
+        let port = self.port();
+        cmd::read_reg(port, cmd::PORT_SCR_ACT) == 0
+            && cmd::read_reg(port, cmd::PORT_CMD_ISSUE) & 1 == 0
+    }
+
+    /// Moves the command list, received FIS area and command table of the
+    /// enabled port to memory of the DMA allocator, if any and not done yet.
+    /// Otherwise points the port to the physical addresses given by the
+    /// translator, if any.
+    fn rebase(&mut self) -> DevResult {
+        if self.port_mem.is_some() {
+            return Ok(());
+        }
+        if let Some(alloc) = self.dma_alloc {
+            let mem = alloc(cmd::PORT_MEM_SIZE, cmd::PORT_MEM_ALIGN)?;
+            let (va, pa) = (mem.as_ptr() as u64, mem.phys_addr());
+            let port = &mut self.device.port[self.device.port_idx as usize];
+            port.cmd_slot = (va + cmd::PORT_MEM_CMD_LIST as u64) as *mut _;
+            port.cmd_slot_dma = pa + cmd::PORT_MEM_CMD_LIST as u64;
+            port.rx_fis = va + cmd::PORT_MEM_RX_FIS as u64;
+            port.rx_fis_dma = pa + cmd::PORT_MEM_RX_FIS as u64;
+            port.cmd_tbl = va + cmd::PORT_MEM_CMD_TBL as u64;
+            port.cmd_tbl_dma = pa + cmd::PORT_MEM_CMD_TBL as u64;
+            port.cmd_tbl_sg = (port.cmd_tbl + cmd::AHCI_CMD_TBL_HDR_SZ as u64) as *mut _;
+            // Kept even if the port fails to start, as it may still use it
+            self.port_mem = Some(mem);
+            return cmd::start_port(self.port());
+        }
+        match self.translator {
+            Some(tr) => cmd::rebase_port(&mut self.device.port[self.device.port_idx as usize], tr),
+            None => Ok(()),
+        }
+    }
+
+    /// Issues IDENTIFY DEVICE (or IDENTIFY PACKET DEVICE for ATAPI devices)
+    /// on the enabled port and caches the result.
+    fn identify(&mut self) -> DevResult {
+        let mut id = IdentifyData::empty();
+        let buf = Segment {
+            addr: id.0.as_mut_ptr() as usize,
+            len: core::mem::size_of_val(&id.0),
+        };
+        let fis = if cmd::read_reg(self.port(), cmd::PORT_SIG) == cmd::SATA_SIG_ATAPI {
+            Fis::new(ata::ATA_CMD_ID_ATAPI)
+        } else {
+            Fis::new(ata::ATA_CMD_IDENTIFY)
+        };
+        unsafe {
+            cmd::exec(
+                self.port(),
+                self.translator,
+                &fis,
+                &[buf],
+                false,
+                self.poll_iters,
+            )?
+        };
+        self.id = id;
+        self.read_media_status();
+        Ok(())
+    }
+
+    /// Reads whether the media is write-protected with GET MEDIA STATUS, for
+    /// ATA drives with removable media that support it. Drives that do not
+    /// are taken as writable.
+    fn read_media_status(&mut self) {
+        self.write_protected = false;
+        if self.is_atapi() || !self.id.has_media_status() {
+            return;
+        }
+        let fis = Fis::new(ata::ATA_CMD_GET_MEDIA_STATUS);
         let result = unsafe {
-            ahci_sata_read_common(&self.device, block_id, block_count as u32, buf.as_mut_ptr())
+            cmd::exec(
+                self.port(),
+                self.translator,
+                &fis,
+                &[],
+                false,
+                self.poll_iters,
+            )
+        };
+        let tfdata = cmd::read_reg(self.port(), cmd::PORT_TFDATA);
+        self.write_protected = media_write_protected(&result, tfdata);
+        if let Err(DevError::Timeout) = result {
+            trace::warn!("AHCI: GET MEDIA STATUS timed out");
+        }
+    }
+
+    /// Takes the block size and count from the IDENTIFY DEVICE data if those
+    /// computed by `ahci_init` differ.
+    ///
+    /// Block counts and IDs are in units of the logical sector size, which
+    /// must come from the device itself.
+    fn check_block_size(&mut self) {
+        let blksz = self.id.logical_sector_size();
+        if self.block_size() != blksz {
+            trace::warn!(
+                "AHCI: block size {} differs from logical sector size {}",
+                self.block_size(),
+                blksz
+            );
+            self.device.blk_dev.blksz = blksz as _;
+            self.device.blk_dev.lba = self.id.n_sectors();
+        }
+    }
+
+    /// Issues IDENTIFY DEVICE again and updates the capacity, block size and
+    /// identity of the drive, e.g. after it was replaced or, for ATAPI
+    /// devices, after the media was changed.
+    ///
+    /// It is meant to be called after [`AhciDriver::reset_port`]. Returns
+    /// [`DevError::ResourceBusy`] if queued commands are outstanding,
+    /// [`DevError::Io`] if the drive rejects the command and
+    /// [`DevError::Timeout`] if it does not respond. The previous data is
+    /// kept on failure.
+    pub fn reidentify(&mut self) -> DevResult {
+        if cmd::read_reg(self.port(), cmd::PORT_SCR_ACT) != 0 {
+            return Err(DevError::ResourceBusy);
+        }
+        self.identify().inspect_err(|e| {
+            trace::warn!("AHCI: IDENTIFY DEVICE failed: {:?}", e);
+        })?;
+        self.device.blk_dev = blk_dev_from_id(&self.id);
+        if self.is_atapi() {
+            self.read_capacity()?;
+        }
+        trace::info!(
+            "AHCI: port {}: {} blocks of {} bytes",
+            self.device.port_idx,
+            self.num_blocks(),
+            self.block_size()
+        );
+        Ok(())
+    }
+
+    /// Reads the capacity and the block size of the media of an ATAPI device
+    /// with READ CAPACITY.
+    ///
+    /// The device has 0 blocks of 2048 bytes if it fails, e.g. when there is
+    /// no media.
+    fn read_capacity(&mut self) -> DevResult {
+        let blk_dev = &mut self.device.blk_dev;
+        blk_dev.lba48 = false;
+        blk_dev.lba = 0;
+        blk_dev.blksz = ata::ATAPI_SECT_SIZE as _;
+
+        let mut data = [0u16; 4];
+        let buf = Segment {
+            addr: data.as_mut_ptr() as usize,
+            len: core::mem::size_of_val(&data),
+        };
+        let mut cdb = [0; cmd::ATAPI_CDB_LEN];
+        cdb[0] = ata::ATAPI_CMD_READ_CAPACITY;
+        let fis = Fis::new(ata::ATA_CMD_PACKET)
+            .features(ata::ATAPI_FEAT_DMA)
+            .packet(cdb);
+        unsafe {
+            cmd::exec(
+                self.port(),
+                self.translator,
+                &fis,
+                &[buf],
+                false,
+                self.poll_iters,
+            )?
         };
+        let mut bytes = [0; 8];
+        for (b, w) in bytes.chunks_exact_mut(2).zip(data) {
+            b.copy_from_slice(&w.to_ne_bytes());
+        }
+        // The last LBA and the block length, big-endian
+        let last_lba = u32::from_be_bytes(bytes[0..4].try_into().unwrap());
+        let blksz = u32::from_be_bytes(bytes[4..8].try_into().unwrap());
+        if blksz != 0 {
+            self.device.blk_dev.blksz = blksz as _;
+        }
+        self.device.blk_dev.lba = last_lba as u64 + 1;
+        Ok(())
+    }
+
+    /// Whether the drive is an ATAPI device (e.g., an optical drive).
+    ///
+    /// ATAPI devices are read with SCSI READ(10) commands, usually with
+    /// 2048-byte blocks.
+    pub fn is_atapi(&self) -> bool {
+        cmd::read_reg(self.port(), cmd::PORT_SIG) == cmd::SATA_SIG_ATAPI || self.id.is_atapi()
+    }
 
-        if result == block_count as u64 {
-            Ok(())
+    /// Whether the drive can only be read.
+    ///
+    /// This is the case for ATAPI devices (e.g., optical drives), which do
+    /// not accept ATA write commands, and for ATA drives whose removable
+    /// media was reported as write-protected by GET MEDIA STATUS when the
+    /// drive was last identified, e.g. by [`AhciDriver::reidentify`].
+    pub fn is_read_only(&self) -> bool {
+        self.is_atapi() || self.write_protected
+    }
+
+    /// Whether the drive is addressed with 48-bit LBAs.
+    ///
+    /// Otherwise only the first 2^28 blocks can be accessed, accesses beyond
+    /// them fail with [`DevError::InvalidParam`]. Always `false` for ATAPI
+    /// devices, which are addressed by SCSI commands.
+    pub fn supports_48bit_lba(&self) -> bool {
+        self.device.blk_dev.lba48
+    }
+
+    /// The size of a logical block in bytes, the unit of block IDs and
+    /// counts. Same as [`BlockDriverOps::block_size`].
+    pub fn logical_block_size(&self) -> usize {
+        self.block_size()
+    }
+
+    /// The size of a physical block in bytes.
+    ///
+    /// It is larger than the logical block size on Advanced Format drives
+    /// that emulate 512-byte sectors (512e). Accesses aligned to the physical
+    /// block size avoid read-modify-write cycles inside the drive.
+    pub fn physical_block_size(&self) -> usize {
+        if self.id.is_empty() || self.is_atapi() {
+            self.block_size()
         } else {
-            log::error!(
-                "AHCI read failed: expected {} blocks, got {}",
+            self.id.physical_sector_size()
+        }
+    }
+
+    /// The maximum number of blocks transferred by a single command.
+    ///
+    /// It is limited by the capacity of the PRD table and by the sector count
+    /// field of the ATA command, and by
+    /// [`AhciDriverBuilder::max_blocks_per_command`]. Larger requests are
+    /// split into several commands by [`BlockDriverOps::read_block`] and
+    /// [`BlockDriverOps::write_block`].
+    pub fn max_blocks_per_command(&self) -> u32 {
+        let by_prdt = (cmd::AHCI_MAX_SG * cmd::AHCI_MAX_BYTES_PER_SG)
+            .checked_div(self.block_size())
+            .unwrap_or(0);
+        let by_ata = if self.is_atapi() {
+            ata::ATAPI_MAX_SECTORS
+        } else if self.device.blk_dev.lba48 {
+            ata::ATA_MAX_SECTORS_LBA48
+        } else {
+            ata::ATA_MAX_SECTORS
+        };
+        (by_prdt as u32)
+            .min(by_ata)
+            .min(self.max_blocks.unwrap_or(u32::MAX))
+    }
+
+    /// The limits of the buffers of a single DMA command, for callers of the
+    /// vectored reads and writes.
+    ///
+    /// Vectored requests with more or longer segments are split into several
+    /// commands, but segments that are misaligned or above
+    /// [`SgLimits::dma_mask`] are rejected with [`DevError::InvalidParam`].
+    pub fn sg_limits(&self) -> SgLimits {
+        SgLimits {
+            max_segments: cmd::AHCI_MAX_SG,
+            max_segment_len: cmd::AHCI_MAX_BYTES_PER_SG,
+            alignment: cmd::AHCI_DMA_ALIGN,
+            dma_mask: if self.capabilities().addr64 {
+                u64::MAX
+            } else {
+                u32::MAX as u64
+            },
+        }
+    }
+
+    /// Whether the volatile write cache of the drive is enabled, as reported
+    /// by IDENTIFY DEVICE.
+    ///
+    /// Completed writes may be lost on power failure until they are flushed
+    /// if it is.
+    pub fn write_cache_enabled(&self) -> bool {
+        self.id.write_cache_enabled()
+    }
+
+    /// Enables or disables the volatile write cache of the drive with SET
+    /// FEATURES.
+    ///
+    /// The cache is flushed before it is disabled. Returns
+    /// [`DevError::Unsupported`] if the drive has no write cache.
+    pub fn set_write_cache(&mut self, on: bool) -> DevResult {
+        if self.is_read_only() || !self.id.has_write_cache() {
+            return Err(DevError::Unsupported);
+        }
+        if !on {
+            BlockDriverOps::flush(self)?;
+        }
+        let features = if on {
+            ata::ATA_SETFEATURES_WC_ON
+        } else {
+            ata::ATA_SETFEATURES_WC_OFF
+        };
+        let fis = Fis::new(ata::ATA_CMD_SET_FEATURES).features(features);
+        unsafe {
+            cmd::exec(
+                self.port(),
+                self.translator,
+                &fis,
+                &[],
+                false,
+                self.poll_iters,
+            )
+        }
+        .inspect_err(|e| trace::error!("AHCI: SET FEATURES failed: {:?}", e))?;
+        // Refresh the enabled features
+        self.identify()
+    }
+
+    /// Reads the S.M.A.R.T. health information of the drive.
+    ///
+    /// Returns [`DevError::Unsupported`] if the drive does not support
+    /// S.M.A.R.T.
+    pub fn smart_status(&mut self) -> DevResult<SmartStatus> {
+        if !self.id.has_smart() || self.is_atapi() {
+            return Err(DevError::Unsupported);
+        }
+        let fis = Fis::new(ata::ATA_CMD_SMART)
+            .features(ata::ATA_SMART_RETURN_STATUS)
+            .lba(ata::ATA_SMART_LBA);
+        unsafe {
+            cmd::exec(
+                self.port(),
+                self.translator,
+                &fis,
+                &[],
+                false,
+                self.poll_iters,
+            )?
+        };
+        let healthy = cmd::d2h_fis_lba(self.port()) & 0xff_ff00 != ata::ATA_SMART_LBA_BAD;
+
+        let mut data = [0u16; smart::SMART_DATA_LEN / 2];
+        let buf = Segment {
+            addr: data.as_mut_ptr() as usize,
+            len: smart::SMART_DATA_LEN,
+        };
+        let fis = Fis::new(ata::ATA_CMD_SMART)
+            .features(ata::ATA_SMART_READ_DATA)
+            .count(1)
+            .lba(ata::ATA_SMART_LBA);
+        unsafe {
+            cmd::exec(
+                self.port(),
+                self.translator,
+                &fis,
+                &[buf],
+                false,
+                self.poll_iters,
+            )?
+        };
+        let mut bytes = [0; smart::SMART_DATA_LEN];
+        for (b, w) in bytes.chunks_exact_mut(2).zip(data) {
+            b.copy_from_slice(&w.to_ne_bytes());
+        }
+        Ok(SmartStatus::from_data(&bytes, healthy))
+    }
+
+    /// Transfers up to `block_count` blocks with a single command, retrying
+    /// after a port reset if nothing is transferred.
+    ///
+    /// Returns the number of blocks transferred.
+    fn transfer(
+        &mut self,
+        block_id: u64,
+        block_count: usize,
+        buf: *mut u8,
+        write: bool,
+    ) -> DevResult<usize> {
+        // `ahci_driver` uses slot 0 regardless of queued commands or of a
+        // command of `cmd` that is still in flight
+        if !self.can_issue() {
+            return Err(DevError::ResourceBusy);
+        }
+        if self.translator.is_some() || self.is_atapi() {
+            // `ahci_driver` would assume the buffer to be linearly mapped,
+            // and only issues ATA commands
+            let seg = Segment {
+                addr: buf as usize,
+                len: block_count * self.block_size(),
+            };
+            return self.exec_rw(block_id, &[seg], write).map(|()| block_count);
+        }
+        let mut retries = 0;
+        loop {
+            // Call the underlying AHCI read/write function
+            let result = unsafe {
+                if write {
+                    ahci_sata_write_common(&self.device, block_id, block_count as u32, buf)
+                } else {
+                    ahci_sata_read_common(&self.device, block_id, block_count as u32, buf)
+                }
+            };
+            if result == block_count as u64 {
+                return Ok(block_count);
+            }
+
+            trace::error!(
+                "AHCI {} failed: expected {} blocks, got {}",
+                if write { "write" } else { "read" },
                 block_count,
                 result
             );
-            Err(DevError::Io)
+            if result != 0 && result < block_count as u64 {
+                return Ok(result as usize);
+            }
+            if retries == self.max_retries {
+                return Err(DevError::Io);
+            }
+            retries += 1;
+            self.reset_port()?;
         }
     }
 
-    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+    /// Transfers `len` bytes at `buf` from `block_id`, splitting it into as
+    /// many commands as needed, until a command comes up short.
+    ///
+    /// Returns the number of bytes transferred.
+    fn transfer_partial(
+        &mut self,
+        block_id: u64,
+        buf: *mut u8,
+        len: usize,
+        write: bool,
+    ) -> DevResult<usize> {
+        let block_size = self.block_size();
+        let mut max_blocks = self.max_blocks_per_command() as usize;
+        if self.translator.is_some() {
+            // The buffer is described by the PRD table of a single command
+            max_blocks = max_blocks.min(cmd::AHCI_MAX_SG * cmd::AHCI_MAX_BYTES_PER_SG / block_size);
+        }
+        let mut done = 0;
+        while done < len {
+            let block_count = ((len - done) / block_size).min(max_blocks);
+            let block_id = block_id + (done / block_size) as u64;
+            let buf = buf.wrapping_add(done);
+            match self.transfer(block_id, block_count, buf, write) {
+                Ok(n) => {
+                    done += n * block_size;
+                    if n < block_count {
+                        break;
+                    }
+                }
+                Err(e) if done == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
+        Ok(done)
+    }
+
+    /// Whether writes can be issued as WRITE DMA FUA EXT.
+    fn fua_supported(&self) -> bool {
+        self.device.blk_dev.lba48 && !self.is_atapi() && self.id.has_fua()
+    }
+
+    /// The ATA command that flushes the write cache.
+    fn flush_command(&self) -> u8 {
+        if self.device.blk_dev.lba48 && self.id.has_flush_ext() {
+            ata::ATA_CMD_FLUSH_EXT
+        } else {
+            ata::ATA_CMD_FLUSH
+        }
+    }
+
+    unsafe fn submit(
+        &mut self,
+        block_id: u64,
+        seg: Segment,
+        write: bool,
+    ) -> DevResult<CommandToken> {
+        let depth = self.queue_depth();
+        if !self.capabilities().ncq || !self.id.has_ncq() || self.is_atapi() {
+            return Err(DevError::Unsupported);
+        }
+        self.check_range(block_id, seg.len)?;
+        let block_count = (seg.len / self.block_size()) as u64;
+        if block_count == 0 || block_count > ata::ATA_MAX_SECTORS_FPDMA as u64 {
+            return Err(DevError::InvalidParam);
+        }
+
+        if self.queue.is_none() {
+            self.queue = Some(ncq::Queue::new(depth, self.dma_alloc)?);
+        }
+        let port = &self.device.port[self.device.port_idx as usize];
+        let queue = self.queue.as_mut().unwrap();
+        queue.submit(
+            port,
+            self.translator,
+            block_id,
+            block_count as u32,
+            &[seg],
+            write,
+        )
+    }
+
+    /// Builds the DMA read/write command of `len` bytes starting from
+    /// `block_id`.
+    fn rw_fis(&self, block_id: u64, len: usize, write: bool) -> Fis {
+        if self.is_atapi() {
+            // Block IDs and counts fit, see `read_capacity` and
+            // `max_blocks_per_command`
+            let block_count = (len / BlockDriverOps::block_size(self)) as u16;
+            let mut cdb = [0; cmd::ATAPI_CDB_LEN];
+            cdb[0] = ata::ATAPI_CMD_READ_10;
+            cdb[2..6].copy_from_slice(&(block_id as u32).to_be_bytes());
+            cdb[7..9].copy_from_slice(&block_count.to_be_bytes());
+            return Fis::new(ata::ATA_CMD_PACKET)
+                .features(ata::ATAPI_FEAT_DMA)
+                .packet(cdb);
+        }
+        // A count of 0 means 256 sectors for 28-bit commands
+        let block_count = len / BlockDriverOps::block_size(self);
+        let fis = match (write, self.device.blk_dev.lba48) {
+            (false, true) => Fis::new(ata::ATA_CMD_READ_EXT).lba(block_id),
+            (false, false) => Fis::new(ata::ATA_CMD_READ).lba28(block_id),
+            (true, true) => Fis::new(ata::ATA_CMD_WRITE_EXT).lba(block_id),
+            (true, false) => Fis::new(ata::ATA_CMD_WRITE).lba28(block_id),
+        };
+        fis.count(block_count as u16)
+    }
+
+    /// Reads or writes `segments` as contiguous blocks starting from
+    /// `block_id`, building as few DMA commands as the PRDT allows.
+    ///
+    /// Writes are issued as WRITE DMA FUA EXT if `fua` is set.
+    fn transfer_vectored(
+        &mut self,
+        mut block_id: u64,
+        segments: impl DoubleEndedIterator<Item = Segment>,
+        write: bool,
+        fua: bool,
+    ) -> DevResult {
         let block_size = self.block_size();
-        let block_count = (buf.len() + block_size - 1) / block_size;
+        let max_bytes = self.max_blocks_per_command() as usize * block_size;
 
-        if buf.len() % block_size != 0 {
-            log::warn!(
+        // Segments left to transfer, in reverse order
+        let mut pending: Vec<Segment> = segments.filter(|seg| seg.len != 0).rev().collect();
+        let total = pending.iter().map(|seg| seg.len).sum::<usize>();
+        if !total.is_multiple_of(block_size) {
+            trace::warn!(
+                "Total buffer size {} is not aligned to block size {}",
+                total,
+                block_size
+            );
+            return Err(DevError::InvalidParam);
+        }
+        if pending.iter().any(|seg| {
+            !seg.addr.is_multiple_of(cmd::AHCI_DMA_ALIGN)
+                || !seg.len.is_multiple_of(cmd::AHCI_DMA_ALIGN)
+        }) {
+            trace::warn!("Buffers are not aligned to {} bytes", cmd::AHCI_DMA_ALIGN);
+            return Err(DevError::InvalidParam);
+        }
+        let dma_mask = self.sg_limits().dma_mask;
+        if pending.iter().any(|seg| {
+            let start = cmd::virt_to_phys(self.port(), self.translator, seg.addr);
+            start
+                .checked_add(seg.len as u64 - 1)
+                .is_none_or(|end| end > dma_mask)
+        }) {
+            trace::warn!("Buffers are above the DMA mask {:#x}", dma_mask);
+            return Err(DevError::InvalidParam);
+        }
+        self.check_range(block_id, total)?;
+
+        let mut command: Vec<Segment> = Vec::with_capacity(cmd::AHCI_MAX_SG);
+        let mut bytes = 0;
+        while let Some(mut seg) = pending.pop() {
+            let len = seg
+                .len
+                .min(cmd::AHCI_MAX_BYTES_PER_SG)
+                .min(max_bytes - bytes);
+            if seg.len > len {
+                pending.push(Segment {
+                    addr: seg.addr + len,
+                    len: seg.len - len,
+                });
+            }
+            seg.len = len;
+            command.push(seg);
+            bytes += len;
+
+            if command.len() < cmd::AHCI_MAX_SG && bytes < max_bytes && !pending.is_empty() {
+                continue;
+            }
+            // End the command on a block boundary, the cut-off part goes to
+            // the next one
+            let mut excess = bytes % block_size;
+            while excess > 0 {
+                let last = command.last_mut().unwrap();
+                let cut = excess.min(last.len);
+                last.len -= cut;
+                pending.push(Segment {
+                    addr: last.addr + last.len,
+                    len: cut,
+                });
+                if last.len == 0 {
+                    command.pop();
+                }
+                excess -= cut;
+                bytes -= cut;
+            }
+            if bytes == 0 {
+                trace::warn!("Buffers are too fragmented to transfer a single block");
+                return Err(DevError::InvalidParam);
+            }
+
+            if fua {
+                let block_count = (bytes / block_size) as u16;
+                let fis = Fis::new(ata::ATA_CMD_WRITE_FUA_EXT)
+                    .lba(block_id)
+                    .count(block_count);
+                self.exec_fis(&fis, &command, true)?;
+            } else {
+                self.exec_rw(block_id, &command, write)?;
+            }
+            block_id += (bytes / block_size) as u64;
+            command.clear();
+            bytes = 0;
+        }
+        Ok(())
+    }
+
+    /// Executes a single DMA read/write command, retrying it after a port
+    /// reset if it fails.
+    fn exec_rw(&mut self, block_id: u64, segments: &[Segment], write: bool) -> DevResult {
+        let len = segments.iter().map(|seg| seg.len).sum();
+        let fis = self.rw_fis(block_id, len, write);
+        self.exec_fis(&fis, segments, write)
+    }
+
+    /// Executes a single DMA command, retrying it after a port reset if it
+    /// fails.
+    fn exec_fis(&mut self, fis: &Fis, segments: &[Segment], write: bool) -> DevResult {
+        let mut retries = 0;
+        loop {
+            match unsafe {
+                cmd::exec(
+                    self.port(),
+                    self.translator,
+                    fis,
+                    segments,
+                    write,
+                    self.poll_iters,
+                )
+            } {
+                Ok(()) => return Ok(()),
+                Err(e) if retries == self.max_retries => return Err(e),
+                Err(_) => {
+                    retries += 1;
+                    self.reset_port()?;
+                }
+            }
+        }
+    }
+
+    /// Checks that `len` bytes from `block_id` are within the device, and
+    /// can be addressed by its commands.
+    fn check_range(&self, block_id: u64, len: usize) -> DevResult {
+        if self.block_size() == 0 {
+            trace::warn!("AHCI: the block size is unknown, the drive is not identified");
+            return Err(DevError::Io);
+        }
+        let block_count = (len / self.block_size()) as u64;
+        if !self.supports_48bit_lba()
+            && !self.is_atapi()
+            && block_id.saturating_add(block_count) > ata::ATA_MAX_LBA28_SECTORS
+        {
+            trace::warn!(
+                "Access of {} blocks from block {} needs 48-bit addressing, which the device does not support",
+                block_count,
+                block_id
+            );
+            return Err(DevError::InvalidParam);
+        }
+        if block_id
+            .checked_add(block_count)
+            .is_none_or(|end| end > self.num_blocks())
+        {
+            trace::warn!(
+                "Access of {} blocks from block {} is beyond the end of the device ({} blocks)",
+                block_count,
+                block_id,
+                self.num_blocks()
+            );
+            return Err(DevError::InvalidParam);
+        }
+        Ok(())
+    }
+
+    /// Checks that `buf` can be used for a DMA transfer.
+    fn check_buf(&self, buf: &[u8]) -> DevResult {
+        let block_size = self.block_size();
+        if block_size == 0 {
+            return Err(DevError::Io);
+        }
+        if !buf.len().is_multiple_of(block_size) {
+            trace::warn!(
                 "Buffer size {} is not aligned to block size {}",
                 buf.len(),
                 block_size
             );
+            return Err(DevError::InvalidParam);
+        }
+        if !(buf.as_ptr() as usize).is_multiple_of(cmd::AHCI_DMA_ALIGN) {
+            trace::warn!(
+                "Buffer address {:p} is not aligned to {} bytes",
+                buf.as_ptr(),
+                cmd::AHCI_DMA_ALIGN
+            );
+            return Err(DevError::InvalidParam);
         }
+        Ok(())
+    }
 
-        // Call the underlying AHCI write function
-        let result = unsafe {
-            ahci_sata_write_common(
-                &self.device,
-                block_id,
-                block_count as u32,
-                buf.as_ptr() as *mut u8, // Cast away const for C interface
-            )
-        };
+    /// The model number reported by ATA IDENTIFY.
+    ///
+    /// Returns an empty string if the device has not been identified.
+    pub fn model(&self) -> &str {
+        ata_str(&self.device.blk_dev.product)
+    }
 
-        if result == block_count as u64 {
-            Ok(())
+    /// The serial number reported by ATA IDENTIFY.
+    ///
+    /// Returns an empty string if the device has not been identified.
+    pub fn serial(&self) -> &str {
+        ata_str(&self.device.blk_dev.serial)
+    }
+
+    /// The firmware revision reported by ATA IDENTIFY.
+    ///
+    /// Returns an empty string if the device has not been identified.
+    pub fn firmware_revision(&self) -> &str {
+        ata_str(&self.device.blk_dev.revision)
+    }
+}
+
+/// An uninitialized AHCI device structure, for `ahci_init` to fill in.
+fn empty_device(mmio_base: u64) -> ahci_device {
+    ahci_device {
+        mmio_base,
+        flags: 0,
+        cap: 0,
+        cap2: 0,
+        version: 0,
+        port_map: 0,
+        pio_mask: 0,
+        udma_mask: 0,
+        n_ports: 0,
+        port_map_linkup: 0,
+        port: [ahci_ioport {
+            port_mmio: 0,
+            cmd_slot: core::ptr::null_mut(),
+            cmd_slot_dma: 0,
+            rx_fis: 0,
+            rx_fis_dma: 0,
+            cmd_tbl: 0,
+            cmd_tbl_dma: 0,
+            cmd_tbl_sg: core::ptr::null_mut(),
+        }; 32],
+        port_idx: 0, // the enabled port
+        blk_dev: empty_blk_dev(),
+    }
+}
+
+/// Copies the controller state, with `port_idx` as the enabled port.
+fn copy_device(device: &ahci_device, port_idx: usize) -> ahci_device {
+    ahci_device {
+        mmio_base: device.mmio_base,
+        flags: device.flags,
+        cap: device.cap,
+        cap2: device.cap2,
+        version: device.version,
+        port_map: device.port_map,
+        pio_mask: device.pio_mask,
+        udma_mask: device.udma_mask,
+        n_ports: device.n_ports,
+        port_map_linkup: device.port_map_linkup,
+        port: device.port,
+        port_idx: port_idx as _,
+        blk_dev: empty_blk_dev(),
+    }
+}
+
+// The layout of `ahci_blk_dev` that `ahci_driver` shares with its C code, as
+// given by the padding fields. A mismatch would corrupt the fields written by
+// `ahci_init`, so it is checked at build time.
+const _: () = {
+    use core::mem::{align_of, offset_of, size_of};
+    assert!(size_of::<ahci_blk_dev>() == 120);
+    assert!(align_of::<ahci_blk_dev>() == 8);
+    assert!(offset_of!(ahci_blk_dev, lba48) == 0);
+    assert!(offset_of!(ahci_blk_dev, lba) == 8);
+    assert!(offset_of!(ahci_blk_dev, blksz) == 16);
+    assert!(offset_of!(ahci_blk_dev, queue_depth) == 24);
+    assert!(offset_of!(ahci_blk_dev, product) == 32);
+    assert!(offset_of!(ahci_blk_dev, serial) == 80);
+    assert!(offset_of!(ahci_blk_dev, revision) == 104);
+};
+
+const fn empty_blk_dev() -> ahci_blk_dev {
+    ahci_blk_dev {
+        lba48: false,
+        _pad1: [0; 7], // 对齐到8字节边界
+        lba: 0,
+        blksz: 0,
+        queue_depth: 0,
+        _pad2: [0; 4],    // 对齐到8字节边界
+        product: [0; 41], // 41字节
+        _pad3: [0; 7],    // 填充到8字节对齐 (41 + 7 = 48, 48 % 8 = 0)
+        serial: [0; 21],  // 21字节
+        _pad4: [0; 3],    // 填充到8字节对齐 (21 + 3 = 24, 24 % 8 = 0)
+        revision: [0; 9], // 9字节
+        _pad5: [0; 7],    // 填充到8字节对齐 (9 + 7 = 16, 16 % 8 = 0)
+    }
+}
+
+/// Fills in the block device information from IDENTIFY DEVICE data.
+fn blk_dev_from_id(id: &IdentifyData) -> ahci_blk_dev {
+    let mut blk_dev = empty_blk_dev();
+    blk_dev.lba48 = id.has_lba48();
+    blk_dev.lba = id.n_sectors();
+    blk_dev.blksz = id.logical_sector_size() as _;
+    blk_dev.queue_depth = id.queue_depth() as _;
+    copy_id_strings(id, &mut blk_dev);
+    blk_dev
+}
+
+/// Whether GET MEDIA STATUS reported write-protected media: it then fails
+/// with the WP bit set in the error register, bits 15:8 of PxTFD.
+fn media_write_protected(result: &DevResult, tfdata: u32) -> bool {
+    matches!(result, Err(DevError::Io)) && (tfdata >> 8) as u8 & ata::ATA_ERR_WP != 0
+}
+
+/// Decodes the model, serial number and firmware revision of `id` into
+/// `blk_dev`.
+///
+/// The strings filled in by `ahci_init` are replaced, as they may still have
+/// the bytes of each word swapped (e.g. "eGenui cStsii"), depending on the
+/// endianness it was built for.
+fn copy_id_strings(id: &IdentifyData, blk_dev: &mut ahci_blk_dev) {
+    id.c_string(ata::ATA_ID_PROD, &mut blk_dev.product);
+    id.c_string(ata::ATA_ID_SERNO, &mut blk_dev.serial);
+    id.c_string(ata::ATA_ID_FW_REV, &mut blk_dev.revision);
+}
+
+/// Converts a NUL-terminated, space-padded ATA string into a `&str`.
+///
+/// Only the leading valid UTF-8 part is kept, so a non-ASCII byte ends the
+/// string instead of producing garbage.
+fn ata_str(bytes: &[u8]) -> &str {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    let bytes = &bytes[..len];
+    let s = match core::str::from_utf8(bytes) {
+        Ok(s) => s,
+        Err(e) => core::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or_default(),
+    };
+    s.trim_matches(' ')
+}
+
+impl BaseDriverOps for AhciDriver {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+
+    fn device_name(&self) -> &str {
+        "ahci"
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::empty();
+        if self.is_read_only() {
+            caps |= DeviceCapabilities::READ_ONLY;
         } else {
-            log::error!(
-                "AHCI write failed: expected {} blocks, got {}",
-                block_count,
-                result
+            caps.set(DeviceCapabilities::FLUSH, self.id.has_write_cache());
+            caps.set(DeviceCapabilities::DISCARD, self.id.has_trim());
+            caps.set(DeviceCapabilities::FUA, self.fua_supported());
+        }
+        if cfg!(feature = "async") {
+            caps |= DeviceCapabilities::ASYNC;
+        }
+        caps
+    }
+
+    fn stats(&self) -> DeviceStats {
+        self.stats
+    }
+
+    /// Resets the enabled port, see [`AhciDriver::reset_port`], and
+    /// identifies its drive again.
+    ///
+    /// The controller is not re-initialized, so the other ports and their
+    /// drivers are left alone, and no memory is allocated. Outstanding
+    /// commands are aborted.
+    fn reset(&mut self) -> DevResult {
+        let idx = self.device.port_idx;
+        self.reset_port()?;
+        self.rebase()?;
+
+        let old_id = self.id.clone();
+        self.identify()?;
+        let serial = ata::ATA_ID_SERNO..ata::ATA_ID_FW_REV;
+        if self.id.n_sectors() != old_id.n_sectors()
+            || self.id.0[serial.clone()] != old_id.0[serial]
+        {
+            trace::warn!(
+                "AHCI: a different drive is attached to port {} after reset",
+                idx
+            );
+            self.device.blk_dev = blk_dev_from_id(&self.id);
+        }
+        Ok(())
+    }
+}
+
+impl IrqDriver for AhciDriver {
+    fn irq_number(&self) -> Option<u32> {
+        self.irq
+    }
+
+    /// Acknowledges the interrupt of the enabled port.
+    ///
+    /// Commands are still completed by their waiter, which reads the result
+    /// from the port registers. Returns an error if the port has reported
+    /// one, it is then recovered by the waiter. The error interrupts stay
+    /// masked until the port is recovered with [`AhciDriver::reset_port`].
+    fn handle_irq(&mut self) -> DevResult {
+        if irq::ack(&self.device) & cmd::PORT_IRQ_ERROR != 0 {
+            return Err(DevError::Other("port in error state"));
+        }
+        Ok(())
+    }
+}
+
+/// Removal and attachment of the drive on the enabled port.
+///
+/// A drive attached again is not usable until it is identified with
+/// [`AhciDriver::reidentify`], after [`AhciDriver::reset_port`].
+impl HotplugDriver for AhciDriver {
+    fn is_present(&self) -> bool {
+        cmd::device_present(self.port())
+    }
+
+    fn poll_hotplug(&mut self) -> Option<HotplugEvent> {
+        // The change bits are also cleared by port resets, so the presence is
+        // checked even if they are not set
+        cmd::ack_presence_change(self.port());
+        let present = self.is_present();
+        if present == self.present {
+            return None;
+        }
+        self.present = present;
+        trace::info!(
+            "AHCI: drive {} on port {}",
+            if present { "attached" } else { "removed" },
+            self.device.port_idx
+        );
+        Some(if present {
+            HotplugEvent::Attached
+        } else {
+            HotplugEvent::Detached
+        })
+    }
+}
+
+/// Stops the enabled port, so that the controller no longer accesses the
+/// memory of the driver, e.g. the tables of queued commands freed with it.
+/// If the port does not stop, that memory is leaked instead.
+///
+/// The command list, command table and received FIS area allocated by
+/// `ahci_init` are not freed, since `ahci_driver` has no way to free them.
+/// The other ports of the controller, e.g. those of the other drivers
+/// returned by [`AhciDriver::probe_all`], are left running.
+impl Drop for AhciDriver {
+    fn drop(&mut self) {
+        if self.port().port_mmio == 0 {
+            return;
+        }
+        if self.irq.is_some() {
+            irq::disable(&self.device);
+        }
+        if let Err(e) = cmd::stop_port(self.port()) {
+            trace::warn!(
+                "AHCI: failed to stop port {}: {:?}",
+                self.device.port_idx,
+                e
             );
-            Err(DevError::Io)
+            core::mem::forget(self.port_mem.take());
+            core::mem::forget(self.queue.take());
+        }
+    }
+}
+
+impl BlockDriverOps for AhciDriver {
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        // Resume short transfers from where they stopped
+        let block_size = self.block_size();
+        if block_size == 0 {
+            return Err(DevError::Io);
+        }
+        let mut done = 0;
+        let result = loop {
+            if done == buf.len() {
+                break Ok(());
+            }
+            let block_id = block_id + (done / block_size) as u64;
+            match self.read_block_partial(block_id, &mut buf[done..]) {
+                Ok(0) => break Err(DevError::Io),
+                Ok(n) => done += n,
+                Err(e) => break Err(e),
+            }
+        };
+        self.stats.record(false, buf.len(), &result);
+        result
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        let block_size = self.block_size();
+        if block_size == 0 {
+            return Err(DevError::Io);
+        }
+        let mut done = 0;
+        let result = loop {
+            if done == buf.len() {
+                break Ok(());
+            }
+            let block_id = block_id + (done / block_size) as u64;
+            match self.write_block_partial(block_id, &buf[done..]) {
+                Ok(0) => break Err(DevError::Io),
+                Ok(n) => done += n,
+                Err(e) => break Err(e),
+            }
+        };
+        self.stats.record(true, buf.len(), &result);
+        result
+    }
+
+    fn try_read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        if !self.can_issue() {
+            return Err(DevError::Again);
+        }
+        self.read_block(block_id, buf)
+    }
+
+    fn try_write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        if !self.can_issue() {
+            return Err(DevError::Again);
+        }
+        self.write_block(block_id, buf)
+    }
+
+    fn read_block_partial(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult<usize> {
+        self.check_buf(buf)?;
+        self.check_range(block_id, buf.len())?;
+        self.transfer_partial(block_id, buf.as_mut_ptr(), buf.len(), false)
+    }
+
+    fn write_block_partial(&mut self, block_id: u64, buf: &[u8]) -> DevResult<usize> {
+        if self.is_read_only() {
+            return Err(DevError::Unsupported);
+        }
+        self.check_buf(buf)?;
+        self.check_range(block_id, buf.len())?;
+        // Cast away const for C interface
+        self.transfer_partial(block_id, buf.as_ptr() as *mut u8, buf.len(), true)
+    }
+
+    fn read_blocks_vectored(&mut self, block_id: u64, bufs: &mut [&mut [u8]]) -> DevResult {
+        let segments = bufs.iter_mut().map(|buf| Segment {
+            addr: buf.as_mut_ptr() as usize,
+            len: buf.len(),
+        });
+        self.transfer_vectored(block_id, segments, false, false)
+    }
+
+    fn write_blocks_vectored(&mut self, block_id: u64, bufs: &[&[u8]]) -> DevResult {
+        if self.is_read_only() {
+            return Err(DevError::Unsupported);
         }
+        let segments = bufs.iter().map(|buf| Segment {
+            addr: buf.as_ptr() as usize,
+            len: buf.len(),
+        });
+        self.transfer_vectored(block_id, segments, true, false)
     }
 
     fn flush(&mut self) -> DevResult {
-        // The AHCI write function already handles cache flushing based on device flags
-        // No additional flush operation is needed as it's handled internally
+        if self.is_read_only() {
+            return Err(DevError::Unsupported);
+        }
+        // Nothing to flush if the device has no volatile write cache
+        if !self.id.has_write_cache() {
+            return Ok(());
+        }
+        let fis = Fis::new(self.flush_command());
+        unsafe {
+            cmd::exec(
+                self.port(),
+                self.translator,
+                &fis,
+                &[],
+                false,
+                self.poll_iters,
+            )
+        }
+        .inspect_err(|e| {
+            trace::error!("AHCI flush failed: {:?}", e);
+        })
+    }
+
+    /// Issues WRITE DMA FUA EXT if the device supports it, otherwise writes
+    /// then flushes.
+    fn write_block_fua(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        if self.is_read_only() {
+            return Err(DevError::Unsupported);
+        }
+        if !self.fua_supported() {
+            self.write_block(block_id, buf)?;
+            return self.flush();
+        }
+        let seg = Segment {
+            addr: buf.as_ptr() as usize,
+            len: buf.len(),
+        };
+        let result = self.transfer_vectored(block_id, core::iter::once(seg), true, true);
+        self.stats.record(true, buf.len(), &result);
+        result
+    }
+
+    fn discard(&mut self, block_id: u64, count: u64) -> DevResult {
+        if block_id
+            .checked_add(count)
+            .is_none_or(|end| end > self.num_blocks())
+        {
+            return Err(DevError::InvalidParam);
+        }
+        if count == 0 {
+            return Ok(());
+        }
+        if self.is_read_only() || !self.discard_supported() {
+            return Err(DevError::Unsupported);
+        }
+
+        // Each range entry holds a 48-bit LBA and a 16-bit sector count
+        let max_entries = self.id.max_dsm_blocks() * ata::ATA_DSM_ENTRIES_PER_BLOCK;
+        let mut entries = vec![0u64; max_entries];
+        let (mut lba, end) = (block_id, block_id + count);
+        while lba < end {
+            let mut n = 0;
+            while n < max_entries && lba < end {
+                let len = (end - lba).min(ata::ATA_DSM_MAX_RANGE);
+                entries[n] = (lba | len << 48).to_le();
+                lba += len;
+                n += 1;
+            }
+            let dsm_blocks = n.div_ceil(ata::ATA_DSM_ENTRIES_PER_BLOCK);
+            let len = dsm_blocks * ata::ATA_DSM_ENTRIES_PER_BLOCK;
+            entries[n..len].fill(0);
+            let buf = Segment {
+                addr: entries.as_ptr() as usize,
+                len: len * core::mem::size_of::<u64>(),
+            };
+            let fis = Fis::new(ata::ATA_CMD_DSM)
+                .features(ata::ATA_DSM_TRIM)
+                .count(dsm_blocks as u16)
+                .lba(0);
+            unsafe {
+                cmd::exec(
+                    self.port(),
+                    self.translator,
+                    &fis,
+                    &[buf],
+                    true,
+                    self.poll_iters,
+                )
+            }
+            .inspect_err(|e| {
+                trace::error!("AHCI discard failed: {:?}", e);
+            })?;
+        }
         Ok(())
     }
 
+    /// Drives that read trimmed sectors as zeros are zeroed with TRIM,
+    /// without transferring any data.
+    fn write_zeros(&mut self, block_id: u64, count: u64) -> DevResult {
+        if self.id.trim_reads_zeros() && !self.is_read_only() {
+            self.discard(block_id, count)
+        } else {
+            crate::write_zeros_by_writes(self, block_id, count)
+        }
+    }
+
+    /// Reads and writes of whole physical sectors avoid a read-modify-write
+    /// on 512e drives, and the largest single command gives the best
+    /// throughput. ATA does not guarantee that a write survives a power loss
+    /// whole, so no atomic write size is reported.
+    fn io_hints(&self) -> IoHints {
+        IoHints {
+            min_io_size: self.physical_block_size(),
+            optimal_io_size: self.max_blocks_per_command() as usize * self.block_size(),
+            max_atomic_write: None,
+        }
+    }
+
+    fn discard_supported(&self) -> bool {
+        self.id.has_trim()
+    }
+
+    fn is_rotational(&self) -> bool {
+        !self.id.is_non_rotational()
+    }
+
     #[inline]
     fn num_blocks(&self) -> u64 {
         // Return the LBA (Logical Block Address) count from the device
@@ -168,3 +1761,575 @@ impl BlockDriverOps for AhciDriver {
         self.device.blk_dev.blksz as usize
     }
 }
+
+#[cfg(feature = "async")]
+impl AhciDriver {
+    /// Transfers `block_count` blocks with a single DMA command, yielding
+    /// until it completes, and retrying after a port reset if it fails.
+    async fn transfer_async(&mut self, block_id: u64, buf: Segment, write: bool) -> DevResult {
+        let fis = self.rw_fis(block_id, buf.len, write);
+
+        let mut retries = 0;
+        loop {
+            unsafe { cmd::issue(self.port(), self.translator, &fis, &[buf], write)? };
+            match cmd::Completion::new(self.port()).await {
+                Ok(()) => return Ok(()),
+                Err(e) if retries == self.max_retries => return Err(e),
+                Err(_) => {
+                    retries += 1;
+                    self.reset_port()?;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl crate::AsyncBlockDriverOps for AhciDriver {
+    #[inline]
+    fn num_blocks(&self) -> u64 {
+        BlockDriverOps::num_blocks(self)
+    }
+
+    #[inline]
+    fn block_size(&self) -> usize {
+        BlockDriverOps::block_size(self)
+    }
+
+    async fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        self.check_buf(buf)?;
+        self.check_range(block_id, buf.len())?;
+        let block_size = BlockDriverOps::block_size(self);
+        let max_blocks = self.max_blocks_per_command() as usize;
+
+        for (i, chunk) in buf.chunks_mut(max_blocks * block_size).enumerate() {
+            let block_id = block_id + (i * max_blocks) as u64;
+            let seg = Segment {
+                addr: chunk.as_mut_ptr() as usize,
+                len: chunk.len(),
+            };
+            self.transfer_async(block_id, seg, false).await?;
+        }
+        Ok(())
+    }
+
+    async fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        if self.is_read_only() {
+            return Err(DevError::Unsupported);
+        }
+        self.check_buf(buf)?;
+        self.check_range(block_id, buf.len())?;
+        let block_size = BlockDriverOps::block_size(self);
+        let max_blocks = self.max_blocks_per_command() as usize;
+
+        for (i, chunk) in buf.chunks(max_blocks * block_size).enumerate() {
+            let block_id = block_id + (i * max_blocks) as u64;
+            let seg = Segment {
+                addr: chunk.as_ptr() as usize,
+                len: chunk.len(),
+            };
+            self.transfer_async(block_id, seg, true).await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> DevResult {
+        if self.is_read_only() {
+            return Err(DevError::Unsupported);
+        }
+        if !self.id.has_write_cache() {
+            return Ok(());
+        }
+        unsafe {
+            cmd::issue(
+                self.port(),
+                self.translator,
+                &Fis::new(self.flush_command()),
+                &[],
+                false,
+            )?
+        };
+        cmd::Completion::new(self.port()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+    use axdriver_base::dma::{DmaAllocator, DmaBuffer};
+    use core::ptr::NonNull;
+
+    /// Port interrupt enable register (PxIE).
+    const PORT_IRQ_MASK: usize = 0x14;
+
+    /// Memory standing in for the registers of port 0 and for the structures
+    /// that `ahci_init` allocates for it.
+    ///
+    /// Nothing executes the commands, so they time out and abort, after which
+    /// the command that was built can be checked.
+    #[repr(C, align(1024))]
+    struct FakePort {
+        cmd_list: [u8; 0x400],
+        host: [u32; 0x40],
+        regs: [u32; 0x20],
+        rx_fis: [u8; 0x100],
+        cmd_tbl: [u8; 0x80 + cmd::AHCI_MAX_SG * 16],
+    }
+
+    /// Devices see the memory at its virtual address.
+    struct Identity;
+
+    impl AddrTranslator for Identity {
+        fn virt_to_phys(&self, va: usize) -> u64 {
+            va as u64
+        }
+
+        fn phys_to_virt(&self, pa: u64) -> usize {
+            pa as usize
+        }
+    }
+
+    /// Allocates DMA memory from the heap, with physical addresses above the
+    /// virtual ones.
+    struct HeapDma;
+
+    const DMA_OFFSET: u64 = 1 << 40;
+
+    impl DmaAllocator for HeapDma {
+        fn alloc_dma(size: usize, align: usize) -> Option<(NonNull<u8>, u64)> {
+            let layout = Layout::from_size_align(size, align).ok()?;
+            let ptr = NonNull::new(unsafe { alloc_zeroed(layout) })?;
+            Some((ptr, ptr.as_ptr() as u64 + DMA_OFFSET))
+        }
+
+        unsafe fn dealloc_dma(vaddr: NonNull<u8>, _paddr: u64, size: usize, align: usize) {
+            dealloc(
+                vaddr.as_ptr(),
+                Layout::from_size_align(size, align).unwrap(),
+            );
+        }
+    }
+
+    /// A buffer aligned for DMA.
+    #[repr(align(8))]
+    struct Buf([u8; 8192]);
+
+    impl FakePort {
+        fn new() -> Box<Self> {
+            let mut port = Box::new(Self {
+                cmd_list: [0; 0x400],
+                host: [0; 0x40],
+                regs: [0; 0x20],
+                rx_fis: [0; 0x100],
+                cmd_tbl: [0; 0x80 + cmd::AHCI_MAX_SG * 16],
+            });
+            // FIS reception and the command engine are running (PxCMD.FRE
+            // and PxCMD.ST), and the device is idle
+            port.regs[cmd::PORT_CMD / 4] = 1 << 4 | 1;
+            port
+        }
+
+        /// A driver for a `num_blocks` blocks disk of `block_size` byte
+        /// blocks on this port.
+        fn driver(&mut self, num_blocks: u64, block_size: usize, lba48: bool) -> AhciDriver {
+            // The port was reset after the last command, which clears PxCI,
+            // and the bits written to PxIS and PxSERR were cleared
+            self.regs[cmd::PORT_CMD_ISSUE / 4] = 0;
+            self.regs[cmd::PORT_IRQ_STAT / 4] = 0;
+            self.regs[cmd::PORT_SCR_ERR / 4] = 0;
+            let mut device = empty_device(self.host.as_mut_ptr() as u64);
+            let port = &mut device.port[0];
+            port.port_mmio = self.regs.as_mut_ptr() as u64;
+            port.cmd_slot = self.cmd_list.as_mut_ptr().cast();
+            port.cmd_slot_dma = port.cmd_slot as u64;
+            port.rx_fis = self.rx_fis.as_mut_ptr() as u64;
+            port.rx_fis_dma = port.rx_fis;
+            port.cmd_tbl = self.cmd_tbl.as_mut_ptr() as u64;
+            port.cmd_tbl_dma = port.cmd_tbl;
+            port.cmd_tbl_sg = self.cmd_tbl[0x80..].as_mut_ptr().cast();
+            device.blk_dev.lba48 = lba48;
+            device.blk_dev.lba = num_blocks;
+            device.blk_dev.blksz = block_size as u64;
+
+            let mut driver = AhciDriver::from_device(device)
+                .with_max_retries(0)
+                .with_poll_iters(1);
+            driver.translator = Some(&Identity);
+            driver
+        }
+
+        /// Whether a command has been issued since the port was created.
+        fn issued(&self) -> bool {
+            self.cmd_list[..4] != [0; 4]
+        }
+
+        /// The command FIS of the last issued command.
+        fn fis(&self) -> &[u8] {
+            &self.cmd_tbl[..20]
+        }
+
+        /// The byte counts of the PRD entries of the last issued command.
+        fn prd_lens(&self) -> Vec<usize> {
+            let nr_sg = u32::from_le_bytes(self.cmd_list[..4].try_into().unwrap()) >> 16;
+            self.cmd_tbl[0x80..]
+                .chunks(16)
+                .take(nr_sg as usize)
+                .map(|prd| {
+                    let flags_size = u32::from_le_bytes(prd[12..].try_into().unwrap());
+                    (flags_size & 0x3f_ffff) as usize + 1
+                })
+                .collect()
+        }
+    }
+
+    #[test]
+    fn lba28_device_only_addresses_the_first_2_pow_28_blocks() {
+        let mut port = FakePort::new();
+        let mut driver = port.driver(1 << 30, 512, false);
+        let mut buf = Buf([0; 8192]);
+
+        assert!(matches!(
+            driver.read_block(1 << 28, &mut buf.0[..512]),
+            Err(DevError::InvalidParam)
+        ));
+        assert!(matches!(
+            driver.write_block((1 << 28) - 1, &buf.0[..1024]),
+            Err(DevError::InvalidParam)
+        ));
+        drop(driver);
+        assert!(!port.issued());
+
+        // The last block is addressed by READ DMA, with LBA bits 24-27 in the
+        // device field
+        let mut driver = port.driver(1 << 30, 512, false);
+        assert!(matches!(
+            driver.read_block((1 << 28) - 1, &mut buf.0[..512]),
+            Err(DevError::Timeout)
+        ));
+        drop(driver);
+        let fis = port.fis();
+        assert_eq!(fis[2], ata::ATA_CMD_READ);
+        assert_eq!(fis[4..7], [0xff; 3]);
+        assert_eq!(fis[7], 0x40 | 0xf);
+        assert_eq!(fis[8..11], [0; 3]);
+        assert_eq!(fis[12], 1);
+    }
+
+    #[test]
+    fn lba48_device_addresses_blocks_beyond_2_pow_28() {
+        let mut port = FakePort::new();
+        let mut driver = port.driver(1 << 30, 512, true);
+        let mut buf = Buf([0; 8192]);
+
+        assert!(matches!(
+            driver.read_block(1 << 28, &mut buf.0[..512]),
+            Err(DevError::Timeout)
+        ));
+        drop(driver);
+        let fis = port.fis();
+        assert_eq!(fis[2], ata::ATA_CMD_READ_EXT);
+        assert_eq!(fis[4..7], [0; 3]);
+        assert_eq!(fis[7], 0x40);
+        assert_eq!(fis[8..11], [0x10, 0, 0]);
+    }
+
+    #[test]
+    fn block_size_comes_from_the_logical_sector_size() {
+        let mut port = FakePort::new();
+        // `ahci_init` assumed 512-byte sectors
+        let mut driver = port.driver(8 * 1000, 512, false);
+        driver.id.0[60] = 1000; // sectors
+        driver.id.0[106] = 0x4000 | 1 << 12; // long logical sectors
+        driver.id.0[117] = 2048; // words per logical sector
+        driver.check_block_size();
+        assert_eq!(driver.block_size(), 4096);
+        assert_eq!(driver.num_blocks(), 1000);
+    }
+
+    #[test]
+    fn commands_count_4096_byte_blocks() {
+        let mut port = FakePort::new();
+        let mut buf = Buf([0; 8192]);
+        for (len, count) in [(4096, 1), (8192, 2)] {
+            let mut driver = port.driver(1000, 4096, true);
+            assert!(matches!(
+                driver.read_block(10, &mut buf.0[..len]),
+                Err(DevError::Timeout)
+            ));
+            drop(driver);
+            let fis = port.fis();
+            assert_eq!(fis[2], ata::ATA_CMD_READ_EXT);
+            assert_eq!(fis[4], 10);
+            assert_eq!(fis[12..14], [count, 0]);
+            assert_eq!(port.prd_lens(), [len]);
+        }
+
+        let mut driver = port.driver(1000, 4096, true);
+        assert!(matches!(
+            driver.read_block(10, &mut buf.0[..512]),
+            Err(DevError::InvalidParam)
+        ));
+    }
+
+    #[test]
+    fn reset_only_reidentifies_the_drive_of_the_port() {
+        let mut port = FakePort::new();
+        let mut driver = port.driver(1000, 512, true);
+        // Nothing answers IDENTIFY DEVICE
+        assert!(driver.reset().is_err());
+        drop(driver);
+        assert_eq!(port.fis()[2], ata::ATA_CMD_IDENTIFY);
+    }
+
+    #[test]
+    fn error_interrupts_are_masked_until_the_port_is_reset() {
+        let mut port = FakePort::new();
+        let mut driver = port.driver(1000, 512, true).with_irq(5);
+        assert_eq!(
+            port.regs[PORT_IRQ_MASK / 4] & cmd::PORT_IRQ_ERROR,
+            cmd::PORT_IRQ_ERROR
+        );
+
+        // Task file error, with the D2H Register FIS of the failed command
+        port.regs[cmd::PORT_IRQ_STAT / 4] = 1 << 30 | 1;
+        assert!(driver.handle_irq().is_err());
+        assert_eq!(port.regs[PORT_IRQ_MASK / 4] & cmd::PORT_IRQ_ERROR, 0);
+        assert_ne!(port.regs[PORT_IRQ_MASK / 4], 0);
+
+        driver.reset_port().unwrap();
+        assert_eq!(
+            port.regs[PORT_IRQ_MASK / 4] & cmd::PORT_IRQ_ERROR,
+            cmd::PORT_IRQ_ERROR
+        );
+    }
+
+    #[test]
+    fn queued_commands_completed_before_an_error_succeed() {
+        let mut port = FakePort::new();
+        let mut driver = port.driver(1000, 512, true);
+        driver.device.cap |= 1 << 30 | 31 << 8; // NCQ, 32 command slots
+        driver.id.0[75] = 31; // queue depth of 32
+        driver.id.0[76] = 1 << 8; // NCQ
+        let mut buf = Buf([0; 8192]);
+        let (a, b) = buf.0.split_at_mut(512);
+        let first = unsafe { driver.submit_read(0, a) }.unwrap();
+        let second = unsafe { driver.submit_read(1, &mut b[..512]) }.unwrap();
+        assert_ne!(first, second);
+
+        // The first command completed, then the second one failed
+        port.regs[cmd::PORT_SCR_ACT / 4] = 1 << second.tag();
+        port.regs[cmd::PORT_IRQ_STAT / 4] = 1 << 30;
+        let mut results = Vec::new();
+        let n = driver.poll_completions(|token, res| results.push((token, res.is_ok())));
+        assert_eq!(n, 2);
+        assert_eq!(results, [(first, true), (second, false)]);
+        assert_eq!(driver.poll_completions(|_, _| unreachable!()), 0);
+    }
+
+    #[test]
+    fn enabling_the_interrupt_keeps_the_other_host_control_bits() {
+        let mut port = FakePort::new();
+        port.host[1] = 1 << 31; // GHC.AE
+        let _driver = port.driver(1000, 512, true).with_irq(5);
+        assert_eq!(port.host[1], 1 << 31 | 1 << 1);
+    }
+
+    #[test]
+    fn port_memory_comes_from_the_dma_allocator() {
+        let mut port = FakePort::new();
+        let mut driver = port.driver(1000, 512, true);
+        driver.dma_alloc = Some(DmaBuffer::<HeapDma>::new_boxed);
+        // The fake port never reports that FIS reception is running
+        assert!(matches!(driver.rebase(), Err(DevError::Timeout)));
+
+        let mem = driver.port_mem.as_ref().unwrap();
+        let clb = mem.phys_addr() + cmd::PORT_MEM_CMD_LIST as u64;
+        let fb = mem.phys_addr() + cmd::PORT_MEM_RX_FIS as u64;
+        assert_eq!(mem.phys_addr(), mem.as_ptr() as u64 + DMA_OFFSET);
+        assert_eq!(driver.port().cmd_slot_dma, clb);
+        assert_eq!(driver.port().rx_fis_dma, fb);
+        assert_eq!(
+            driver.port().cmd_tbl_dma,
+            mem.phys_addr() + cmd::PORT_MEM_CMD_TBL as u64
+        );
+        assert_eq!(port.regs[0], clb as u32);
+        assert_eq!(port.regs[0x04 / 4], (clb >> 32) as u32);
+        assert_eq!(port.regs[0x08 / 4], fb as u32);
+        assert_eq!(port.regs[0x0c / 4], (fb >> 32) as u32);
+    }
+
+    #[test]
+    fn queued_command_tables_come_from_the_dma_allocator() {
+        let mut port = FakePort::new();
+        let mut driver = port.driver(1000, 512, true);
+        driver.dma_alloc = Some(DmaBuffer::<HeapDma>::new_boxed);
+        driver.device.cap |= 1 << 30 | 31 << 8; // NCQ, 32 command slots
+        driver.id.0[75] = 31; // queue depth of 32
+        driver.id.0[76] = 1 << 8; // NCQ
+        let mut buf = Buf([0; 8192]);
+        let tag = unsafe { driver.submit_read(0, &mut buf.0[..512]) }
+            .unwrap()
+            .tag() as usize;
+
+        // The command header of the slot points to the table in DMA memory
+        let hdr = &port.cmd_list[tag * 32..][..16];
+        let tbl_addr = u32::from_le_bytes(hdr[8..12].try_into().unwrap()) as u64
+            | (u32::from_le_bytes(hdr[12..16].try_into().unwrap()) as u64) << 32;
+        assert!(tbl_addr >= DMA_OFFSET);
+        assert_eq!(tbl_addr % 128, 0);
+    }
+
+    #[test]
+    fn staggered_spin_up_spins_up_and_links_up_the_ports() {
+        let mut port = FakePort::new();
+        port.regs[cmd::PORT_CMD / 4] = 0;
+        port.regs[cmd::PORT_SCR_STAT / 4] = 0x3; // PHY ready once spun up
+        let mut device = empty_device(port.host.as_mut_ptr() as u64);
+        device.port_map = 1 << 0;
+
+        let mut delayed = Vec::new();
+        AhciDriver::spin_up_staggered(&mut device, |idx| delayed.push(idx));
+        assert_eq!(delayed, [0]);
+        assert_eq!(device.port_map_linkup, 1 << 0);
+        // The registers of port 0 follow the 0x100 bytes of global registers
+        assert_eq!(device.port[0].port_mmio, port.regs.as_ptr() as u64);
+        assert_eq!(port.regs[cmd::PORT_CMD / 4], 1 << 1);
+
+        // `ahci_init` did not start the port, which needs a DMA allocator
+        let config = AhciDriverBuilder::new();
+        assert!(AhciDriver::probe_ports(device, &config).unwrap().is_empty());
+    }
+
+    #[test]
+    fn atapi_device_refuses_writes_without_issuing_commands() {
+        let mut port = FakePort::new();
+        port.regs[cmd::PORT_SIG / 4] = cmd::SATA_SIG_ATAPI;
+        let mut driver = port.driver(1000, 2048, false);
+        let buf = Buf([0; 8192]);
+        assert!(driver.is_read_only());
+        assert!(BaseDriverOps::capabilities(&driver).contains(DeviceCapabilities::READ_ONLY));
+
+        let data = &buf.0[..2048];
+        assert!(matches!(
+            driver.write_block(0, data),
+            Err(DevError::Unsupported)
+        ));
+        assert!(matches!(
+            driver.write_block_fua(0, data),
+            Err(DevError::Unsupported)
+        ));
+        assert!(matches!(
+            driver.write_block_partial(0, data),
+            Err(DevError::Unsupported)
+        ));
+        assert!(matches!(
+            driver.write_blocks_vectored(0, &[data]),
+            Err(DevError::Unsupported)
+        ));
+        assert!(matches!(driver.flush(), Err(DevError::Unsupported)));
+        assert!(matches!(driver.discard(0, 1), Err(DevError::Unsupported)));
+        assert!(matches!(
+            driver.set_write_cache(true),
+            Err(DevError::Unsupported)
+        ));
+        drop(driver);
+        assert!(!port.issued());
+    }
+
+    #[test]
+    fn accesses_past_the_end_fail_without_issuing_commands() {
+        let mut port = FakePort::new();
+        let mut driver = port.driver(8, 512, true);
+        let mut buf = Buf([0; 8192]);
+
+        assert!(matches!(
+            driver.read_block(8, &mut buf.0[..512]),
+            Err(DevError::InvalidParam)
+        ));
+        assert!(matches!(
+            driver.read_block(7, &mut buf.0[..1024]),
+            Err(DevError::InvalidParam)
+        ));
+        assert!(matches!(
+            driver.write_block(u64::MAX, &buf.0[..512]),
+            Err(DevError::InvalidParam)
+        ));
+        assert!(matches!(
+            driver.read_blocks_vectored(6, &mut [&mut buf.0[..1536]]),
+            Err(DevError::InvalidParam)
+        ));
+        drop(driver);
+        assert!(!port.issued());
+    }
+
+    #[test]
+    fn write_protected_media_refuses_writes_without_issuing_commands() {
+        let mut port = FakePort::new();
+        let mut driver = port.driver(1000, 512, true);
+        assert!(!driver.is_read_only());
+        driver.write_protected = true;
+        assert!(driver.is_read_only());
+        assert!(BaseDriverOps::capabilities(&driver).contains(DeviceCapabilities::READ_ONLY));
+
+        let buf = Buf([0; 8192]);
+        assert!(matches!(
+            driver.write_block(0, &buf.0[..512]),
+            Err(DevError::Unsupported)
+        ));
+        assert!(matches!(driver.flush(), Err(DevError::Unsupported)));
+        drop(driver);
+        assert!(!port.issued());
+    }
+
+    #[test]
+    fn media_status_is_read_from_removable_drives() {
+        let mut port = FakePort::new();
+        let mut driver = port.driver(1000, 512, true);
+        driver.read_media_status();
+        assert!(!port.issued());
+
+        driver.id.0[0] = 1 << 7; // removable media
+        driver.id.0[127] = 0b01; // Removable Media Status Notification
+        driver.write_protected = true;
+        driver.read_media_status();
+        // The fake port never completes the command
+        assert!(!driver.is_read_only());
+        assert!(port.issued());
+        assert_eq!(port.fis()[2], ata::ATA_CMD_GET_MEDIA_STATUS);
+
+        // WP in the error register of a failed GET MEDIA STATUS
+        let tfdata = (ata::ATA_ERR_WP as u32) << 8 | 0x41;
+        assert!(media_write_protected(&Err(DevError::Io), tfdata));
+        assert!(!media_write_protected(&Err(DevError::Io), 0x02 << 8 | 0x41));
+        assert!(!media_write_protected(&Err(DevError::Timeout), tfdata));
+        assert!(!media_write_protected(&Ok(()), 0x50));
+    }
+
+    #[test]
+    fn identify_strings_are_readable() {
+        let mut port = FakePort::new();
+        let mut driver = port.driver(1000, 512, true);
+        let mut id = IdentifyData::empty();
+        for (ofs, s) in [
+            (ata::ATA_ID_PROD, &b"Generic Sisti disk"[..]),
+            (ata::ATA_ID_SERNO, b"     QM00001"),
+            (ata::ATA_ID_FW_REV, b"2.5+    "),
+        ] {
+            for (w, pair) in id.0[ofs..].iter_mut().zip(s.chunks(2)) {
+                *w = u16::from_be_bytes([pair[0], pair[1]]);
+            }
+        }
+        // What a little-endian copy of the words looks like
+        let raw: Vec<u8> = id.0[ata::ATA_ID_PROD..][..9]
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .collect();
+        assert_eq!(raw, b"eGenir ciSts iidks");
+
+        copy_id_strings(&id, &mut driver.device.blk_dev);
+        assert_eq!(driver.model(), "Generic Sisti disk");
+        assert_eq!(driver.serial(), "QM00001");
+        assert_eq!(driver.firmware_revision(), "2.5+");
+    }
+}
@@ -0,0 +1,302 @@
+//! ATA command opcodes and decoding of IDENTIFY DEVICE data.
+
+pub const ATA_CMD_DSM: u8 = 0x06;
+pub const ATA_CMD_READ_EXT: u8 = 0x25;
+pub const ATA_CMD_WRITE_EXT: u8 = 0x35;
+pub const ATA_CMD_WRITE_FUA_EXT: u8 = 0x3d;
+pub const ATA_CMD_FPDMA_READ: u8 = 0x60;
+pub const ATA_CMD_FPDMA_WRITE: u8 = 0x61;
+pub const ATA_CMD_PACKET: u8 = 0xa0;
+pub const ATA_CMD_ID_ATAPI: u8 = 0xa1;
+pub const ATA_CMD_SMART: u8 = 0xb0;
+pub const ATA_CMD_SET_FEATURES: u8 = 0xef;
+pub const ATA_CMD_READ: u8 = 0xc8;
+pub const ATA_CMD_GET_MEDIA_STATUS: u8 = 0xda;
+pub const ATA_CMD_WRITE: u8 = 0xca;
+pub const ATA_CMD_FLUSH: u8 = 0xe7;
+pub const ATA_CMD_FLUSH_EXT: u8 = 0xea;
+pub const ATA_CMD_IDENTIFY: u8 = 0xec;
+
+/// Bit of the error register set by GET MEDIA STATUS for write-protected
+/// media.
+pub const ATA_ERR_WP: u8 = 1 << 6;
+
+/// TRIM function of the DATA SET MANAGEMENT command.
+pub const ATA_DSM_TRIM: u16 = 0x01;
+/// Number of LBA range entries in a 512-byte DATA SET MANAGEMENT block.
+pub const ATA_DSM_ENTRIES_PER_BLOCK: usize = 64;
+/// Maximum number of sectors in a LBA range entry.
+pub const ATA_DSM_MAX_RANGE: u64 = 0xffff;
+
+/// Features of the PACKET command: the data is transferred by DMA.
+pub const ATAPI_FEAT_DMA: u16 = 0x01;
+// SCSI commands sent with PACKET.
+pub const ATAPI_CMD_READ_CAPACITY: u8 = 0x25;
+pub const ATAPI_CMD_READ_10: u8 = 0x28;
+/// Maximum transfer length of a READ(10) command.
+pub const ATAPI_MAX_SECTORS: u32 = 65535;
+/// Sector size of optical media.
+pub const ATAPI_SECT_SIZE: usize = 2048;
+
+// Features of the SET FEATURES command.
+pub const ATA_SETFEATURES_WC_ON: u16 = 0x02;
+pub const ATA_SETFEATURES_WC_OFF: u16 = 0x82;
+
+// Features of the SMART command.
+pub const ATA_SMART_READ_DATA: u16 = 0xd0;
+pub const ATA_SMART_RETURN_STATUS: u16 = 0xda;
+/// LBA of SMART commands, with the 0x4f/0xc2 signature in LBA mid/high.
+pub const ATA_SMART_LBA: u64 = 0xc2_4f00;
+/// LBA mid/high returned by SMART RETURN STATUS if a threshold is exceeded.
+pub const ATA_SMART_LBA_BAD: u64 = 0x2c_f400;
+
+/// Maximum sector count of a 28-bit command.
+pub const ATA_MAX_SECTORS: u32 = 256;
+/// Maximum sector count of a 48-bit command.
+pub const ATA_MAX_SECTORS_LBA48: u32 = 65535;
+/// Maximum sector count of a queued command.
+pub const ATA_MAX_SECTORS_FPDMA: u32 = 65536;
+/// Number of sectors addressable by 28-bit commands.
+pub const ATA_MAX_LBA28_SECTORS: u64 = 1 << 28;
+
+/// Number of 16-bit words in the IDENTIFY DEVICE data.
+pub const ATA_ID_WORDS: usize = 256;
+
+const ATA_ID_CONFIG: usize = 0;
+pub const ATA_ID_SERNO: usize = 10;
+pub const ATA_ID_FW_REV: usize = 23;
+pub const ATA_ID_PROD: usize = 27;
+const ATA_ID_LBA_CAPACITY: usize = 60;
+const ATA_ID_ADDITIONAL_SUPP: usize = 69;
+const ATA_ID_QUEUE_DEPTH: usize = 75;
+const ATA_ID_SATA_CAPABILITY: usize = 76;
+const ATA_ID_COMMAND_SET_1: usize = 82;
+const ATA_ID_COMMAND_SET_2: usize = 83;
+const ATA_ID_CFSSE: usize = 84;
+const ATA_ID_CFS_ENABLE_1: usize = 85;
+const ATA_ID_LBA_CAPACITY_2: usize = 100;
+const ATA_ID_DSM_MAX_BLOCKS: usize = 105;
+const ATA_ID_SECTOR_SIZE: usize = 106;
+const ATA_ID_LOGICAL_SECTOR_SIZE: usize = 117;
+const ATA_ID_MEDIA_STATUS: usize = 127;
+const ATA_ID_DATA_SET_MGMT: usize = 169;
+const ATA_ID_ROT_SPEED: usize = 217;
+
+const ATA_SECT_SIZE: usize = 512;
+
+/// The raw data returned by the IDENTIFY DEVICE command.
+#[derive(Clone)]
+pub struct IdentifyData(pub [u16; ATA_ID_WORDS]);
+
+impl IdentifyData {
+    /// Data of a device that has not been identified, all features are
+    /// reported as unsupported.
+    pub const fn empty() -> Self {
+        Self([0; ATA_ID_WORDS])
+    }
+
+    /// Whether the device has not been identified.
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|&w| w == 0)
+    }
+
+    /// Whether the data describes an ATAPI (packet) device rather than an
+    /// ATA device.
+    pub fn is_atapi(&self) -> bool {
+        self.0[ATA_ID_CONFIG] & (1 << 15) != 0
+    }
+
+    /// Whether the media of the device is removable.
+    pub fn is_removable(&self) -> bool {
+        self.0[ATA_ID_CONFIG] & (1 << 7) != 0
+    }
+
+    /// Whether the device has removable media and reports its status with
+    /// GET MEDIA STATUS, from the Removable Media feature set or the
+    /// Removable Media Status Notification feature set.
+    pub fn has_media_status(&self) -> bool {
+        self.is_removable()
+            && ((self.command_set_valid() && self.0[ATA_ID_COMMAND_SET_1] & (1 << 2) != 0)
+                || self.0[ATA_ID_MEDIA_STATUS] & 0b11 == 0b01)
+    }
+
+    /// Words 82~84 are only meaningful if bits 15:14 of word 83 are `01b`.
+    fn command_set_valid(&self) -> bool {
+        self.0[ATA_ID_COMMAND_SET_2] & 0xc000 == 0x4000
+    }
+
+    /// Whether the device has a volatile write cache.
+    pub fn has_write_cache(&self) -> bool {
+        self.command_set_valid() && self.0[ATA_ID_COMMAND_SET_1] & (1 << 5) != 0
+    }
+
+    /// Whether the volatile write cache is enabled.
+    pub fn write_cache_enabled(&self) -> bool {
+        self.has_write_cache() && self.0[ATA_ID_CFS_ENABLE_1] & (1 << 5) != 0
+    }
+
+    /// Whether the S.M.A.R.T. feature set is supported.
+    pub fn has_smart(&self) -> bool {
+        self.command_set_valid() && self.0[ATA_ID_COMMAND_SET_1] & 1 != 0
+    }
+
+    /// Whether the device supports FLUSH CACHE EXT.
+    pub fn has_flush_ext(&self) -> bool {
+        self.command_set_valid() && self.0[ATA_ID_COMMAND_SET_2] & (1 << 13) != 0
+    }
+
+    /// Whether the device supports WRITE DMA FUA EXT.
+    pub fn has_fua(&self) -> bool {
+        let w = self.0[ATA_ID_CFSSE];
+        self.has_lba48() && w & 0xc000 == 0x4000 && w & (1 << 6) != 0
+    }
+
+    /// Whether the device supports 48-bit addressing.
+    pub fn has_lba48(&self) -> bool {
+        self.command_set_valid() && self.0[ATA_ID_COMMAND_SET_2] & (1 << 10) != 0
+    }
+
+    /// Whether Native Command Queuing is supported.
+    pub fn has_ncq(&self) -> bool {
+        let cap = self.0[ATA_ID_SATA_CAPABILITY];
+        cap != 0xffff && cap & (1 << 8) != 0
+    }
+
+    /// Whether the TRIM function of DATA SET MANAGEMENT is supported.
+    pub fn has_trim(&self) -> bool {
+        self.has_lba48() && self.0[ATA_ID_DATA_SET_MGMT] & 1 != 0
+    }
+
+    /// Whether trimmed sectors are read as zeros (Deterministic Read After
+    /// TRIM and Read Zeroes After TRIM).
+    pub fn trim_reads_zeros(&self) -> bool {
+        let w = self.0[ATA_ID_ADDITIONAL_SUPP];
+        self.has_trim() && w & (1 << 14) != 0 && w & (1 << 5) != 0
+    }
+
+    /// The maximum number of 512-byte blocks of LBA range entries in a DATA
+    /// SET MANAGEMENT command.
+    pub fn max_dsm_blocks(&self) -> usize {
+        (self.0[ATA_ID_DSM_MAX_BLOCKS] as usize).max(1)
+    }
+
+    /// The number of user addressable logical sectors.
+    pub fn n_sectors(&self) -> u64 {
+        let w = &self.0;
+        if self.has_lba48() {
+            (0..4).fold(0, |acc, i| {
+                acc | (w[ATA_ID_LBA_CAPACITY_2 + i] as u64) << (16 * i)
+            })
+        } else {
+            w[ATA_ID_LBA_CAPACITY] as u64 | (w[ATA_ID_LBA_CAPACITY + 1] as u64) << 16
+        }
+    }
+
+    /// The size of a logical sector in bytes.
+    pub fn logical_sector_size(&self) -> usize {
+        let w = &self.0;
+        // Word 106 is valid if bits 15:14 are `01b`, bit 12 tells whether the
+        // logical sector is longer than 256 words.
+        if w[ATA_ID_SECTOR_SIZE] & 0xc000 == 0x4000 && w[ATA_ID_SECTOR_SIZE] & (1 << 12) != 0 {
+            let words = w[ATA_ID_LOGICAL_SECTOR_SIZE] as usize
+                | (w[ATA_ID_LOGICAL_SECTOR_SIZE + 1] as usize) << 16;
+            words * 2
+        } else {
+            ATA_SECT_SIZE
+        }
+    }
+
+    /// The size of a physical sector in bytes.
+    pub fn physical_sector_size(&self) -> usize {
+        let w = self.0[ATA_ID_SECTOR_SIZE];
+        // Bit 13 tells whether there are multiple logical sectors per physical
+        // sector, bits 3:0 give the log2 of their number.
+        if w & 0xc000 == 0x4000 && w & (1 << 13) != 0 {
+            self.logical_sector_size() << (w & 0xf)
+        } else {
+            self.logical_sector_size()
+        }
+    }
+
+    /// Whether the nominal media rotation rate is reported as 1, i.e. the
+    /// media is not rotating.
+    pub fn is_non_rotational(&self) -> bool {
+        self.0[ATA_ID_ROT_SPEED] == 1
+    }
+
+    /// The maximum queue depth for native command queuing, 1 if NCQ is not
+    /// supported.
+    pub fn queue_depth(&self) -> u32 {
+        if self.has_ncq() {
+            (self.0[ATA_ID_QUEUE_DEPTH] & 0x1f) as u32 + 1
+        } else {
+            1
+        }
+    }
+
+    /// Copies the ATA string starting at word `ofs` into `out`, as a
+    /// NUL-terminated string with trailing spaces removed.
+    ///
+    /// ATA strings store the first character of each pair in the high byte
+    /// of the word, whatever the endianness of the CPU, so the bytes of each
+    /// word are swapped on little-endian CPUs.
+    pub fn c_string(&self, ofs: usize, out: &mut [u8]) {
+        let len = out.len() - 1;
+        for (pair, w) in out[..len].chunks_mut(2).zip(&self.0[ofs..]) {
+            pair.copy_from_slice(&w.to_be_bytes()[..pair.len()]);
+        }
+        let end = out[..len].iter().rposition(|&c| c != b' ' && c != 0);
+        out[end.map_or(0, |e| e + 1)..].fill(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// IDENTIFY DEVICE data with `s` stored as an ATA string at word `ofs`.
+    fn with_string(mut id: IdentifyData, ofs: usize, s: &[u8]) -> IdentifyData {
+        for (w, pair) in id.0[ofs..].iter_mut().zip(s.chunks(2)) {
+            *w = (pair[0] as u16) << 8 | *pair.get(1).unwrap_or(&b' ') as u16;
+        }
+        id
+    }
+
+    #[test]
+    fn media_status_is_only_reported_for_removable_media() {
+        let mut id = IdentifyData::empty();
+        id.0[ATA_ID_COMMAND_SET_2] = 0x4000;
+        id.0[ATA_ID_COMMAND_SET_1] = 1 << 2;
+        assert!(!id.is_removable());
+        assert!(!id.has_media_status());
+
+        id.0[ATA_ID_CONFIG] = 1 << 7;
+        assert!(id.is_removable());
+        assert!(id.has_media_status());
+        // Words 82~84 are not valid
+        id.0[ATA_ID_COMMAND_SET_2] = 0;
+        assert!(!id.has_media_status());
+        // Removable Media Status Notification
+        id.0[ATA_ID_MEDIA_STATUS] = 0b01;
+        assert!(id.has_media_status());
+        id.0[ATA_ID_MEDIA_STATUS] = 0b10;
+        assert!(!id.has_media_status());
+    }
+
+    #[test]
+    fn strings_are_unswapped_and_trimmed() {
+        let id = with_string(IdentifyData::empty(), ATA_ID_PROD, b"QEMU HARDDISK       ");
+        // Each word holds its first character in the high byte
+        assert_eq!(id.0[ATA_ID_PROD], u16::from_be_bytes(*b"QE"));
+        let mut out = [0xff; 41];
+        id.c_string(ATA_ID_PROD, &mut out);
+        assert_eq!(&out[..14], b"QEMU HARDDISK\0");
+        assert!(out[14..].iter().all(|&c| c == 0));
+
+        // An odd-length field ends with the high byte of its last word
+        let id = with_string(IdentifyData::empty(), ATA_ID_FW_REV, b"2.5+1234");
+        let mut out = [0xff; 6];
+        id.c_string(ATA_ID_FW_REV, &mut out);
+        assert_eq!(&out, b"2.5+1\0");
+    }
+}
@@ -0,0 +1,92 @@
+//! Measurement of the read throughput of a drive.
+
+use alloc::vec;
+use axdriver_base::{trace, Clock, DevError, DevResult};
+
+use super::AhciDriver;
+use crate::BlockDriverOps;
+
+/// Largest size of a read of [`AhciDriver::selftest_throughput`].
+const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// The result of [`AhciDriver::selftest_throughput`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThroughputReport {
+    /// The number of bytes read.
+    pub bytes: u64,
+    /// The number of reads issued.
+    pub reads: u64,
+    /// The total duration of the reads, in nanoseconds.
+    pub elapsed_nanos: u64,
+}
+
+impl ThroughputReport {
+    /// The throughput in megabytes (10^6 bytes) per second.
+    pub fn mb_per_sec(&self) -> u64 {
+        if self.elapsed_nanos == 0 {
+            return 0;
+        }
+        (self.bytes as u128 * 1000 / self.elapsed_nanos as u128) as u64
+    }
+
+    /// The average duration of a read, in nanoseconds.
+    pub fn avg_latency_nanos(&self) -> u64 {
+        self.elapsed_nanos.checked_div(self.reads).unwrap_or(0)
+    }
+}
+
+impl AhciDriver {
+    /// Measures the sequential read throughput of the drive, by reading
+    /// `blocks` blocks from block 0 in reads of up to 1 MiB, timed with
+    /// `clock`.
+    ///
+    /// Meant as a benchmark on real hardware. Returns
+    /// [`DevError::InvalidParam`] if `blocks` is 0 or exceeds the drive.
+    pub fn selftest_throughput(
+        &mut self,
+        clock: &dyn Clock,
+        blocks: u64,
+    ) -> DevResult<ThroughputReport> {
+        if blocks == 0 || blocks > self.num_blocks() {
+            return Err(DevError::InvalidParam);
+        }
+        let block_size = self.block_size();
+        if block_size == 0 {
+            return Err(DevError::Io);
+        }
+        let chunk_blocks = (MAX_CHUNK_SIZE / block_size)
+            .clamp(1, self.max_blocks_per_command() as usize)
+            .min(blocks as usize);
+        // 2-byte aligned, see `cmd::AHCI_DMA_ALIGN`
+        let mut words = vec![0u16; chunk_blocks * block_size / 2];
+        // SAFETY: the bytes are those of `words`, which is initialized, lives
+        // until the end of the function and is only accessed through `buf`
+        // from now on. `u8` has no alignment requirement.
+        let buf = unsafe {
+            core::slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u8, words.len() * 2)
+        };
+
+        let mut report = ThroughputReport {
+            bytes: 0,
+            reads: 0,
+            elapsed_nanos: 0,
+        };
+        let start = clock.now_nanos();
+        let mut block_id = 0;
+        while block_id < blocks {
+            let n = (blocks - block_id).min(chunk_blocks as u64);
+            self.read_block(block_id, &mut buf[..n as usize * block_size])?;
+            block_id += n;
+            report.reads += 1;
+            report.bytes += n * block_size as u64;
+        }
+        report.elapsed_nanos = clock.now_nanos().saturating_sub(start);
+        trace::info!(
+            "AHCI: read {} bytes in {} ns ({} MB/s)",
+            report.bytes,
+            report.elapsed_nanos,
+            report.mb_per_sec()
+        );
+        Ok(report)
+    }
+}
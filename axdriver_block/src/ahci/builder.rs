@@ -0,0 +1,155 @@
+//! Configuration of an AHCI driver before the controller is initialized.
+
+use alloc::vec::Vec;
+use axdriver_base::dma::{DmaAllocFn, DmaAllocator, DmaBuffer};
+use axdriver_base::{AddrTranslator, DevError, DevResult};
+
+use super::{cmd, AhciCapabilities, AhciDriver, DEFAULT_MAX_RETRIES};
+
+/// A builder of [`AhciDriver`]s, for settings that also apply to the commands
+/// issued while the driver is created.
+///
+/// [`AhciDriver::try_new`] is the same as `AhciDriverBuilder::new().build()`.
+#[derive(Clone, Copy)]
+pub struct AhciDriverBuilder {
+    pub(super) mmio_base: u64,
+    pub(super) translator: Option<&'static dyn AddrTranslator>,
+    pub(super) dma_alloc: Option<DmaAllocFn>,
+    pub(super) poll_iters: usize,
+    pub(super) max_retries: u32,
+    pub(super) max_blocks: Option<u32>,
+}
+
+impl AhciDriverBuilder {
+    /// Creates a builder with the default settings, which discovers the
+    /// controller with `ahci_init`.
+    pub const fn new() -> Self {
+        Self {
+            mmio_base: 0,
+            translator: None,
+            dma_alloc: None,
+            poll_iters: cmd::CMD_POLL_ITERS,
+            max_retries: DEFAULT_MAX_RETRIES,
+            max_blocks: None,
+        }
+    }
+
+    /// Uses the controller whose registers are mapped at `mmio_base`, see
+    /// [`AhciDriver::new_at`].
+    ///
+    /// 0 discovers the controller, which is the default.
+    pub const fn mmio_base(mut self, mmio_base: u64) -> Self {
+        self.mmio_base = mmio_base;
+        self
+    }
+
+    /// Translates the addresses of the memory given to the controller, see
+    /// [`AhciDriver::new_at_with_translator`].
+    pub const fn translator(mut self, translator: &'static dyn AddrTranslator) -> Self {
+        self.translator = Some(translator);
+        self
+    }
+
+    /// Allocates the memory shared with the controller with `A`, instead of
+    /// using the memory allocated by `ahci_init` and the heap: the command
+    /// list, received FIS area and command table of the port, and the command
+    /// tables of queued commands.
+    ///
+    /// Their physical addresses are those given by `A`. Without a
+    /// [`AhciDriverBuilder::translator`], data buffers are assumed to be in
+    /// the same linear mapping as the memory of `A`.
+    pub fn dma_allocator<A: DmaAllocator + 'static>(mut self) -> Self {
+        self.dma_alloc = Some(DmaBuffer::<A>::new_boxed);
+        self
+    }
+
+    /// Sets the number of times the completion of a command is polled before
+    /// it fails with [`DevError::Timeout`].
+    ///
+    /// The default is 10,000,000.
+    pub const fn command_timeout(mut self, poll_iters: usize) -> Self {
+        self.poll_iters = poll_iters;
+        self
+    }
+
+    /// Sets the number of times a failed read/write is retried, after
+    /// resetting the port with [`AhciDriver::reset_port`].
+    ///
+    /// The default is 1, set it to 0 to disable retrying.
+    pub const fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Limits the number of blocks transferred by a single command, e.g. to
+    /// work around a drive that fails large requests.
+    ///
+    /// The default is the limit of the controller and of the drive, see
+    /// [`AhciDriver::max_blocks_per_command`]. Larger values are ignored.
+    pub const fn max_blocks_per_command(mut self, max_blocks: u32) -> Self {
+        self.max_blocks = Some(max_blocks);
+        self
+    }
+
+    /// Initializes the controller and creates the driver.
+    ///
+    /// Returns [`DevError::InvalidParam`] if the command timeout or the
+    /// maximum number of blocks per command is 0, and
+    /// [`DevError::NotPresent`] if no drive is attached to the controller.
+    pub fn build(self) -> DevResult<AhciDriver> {
+        self.check()?;
+        AhciDriver::new_with_config(&self)
+    }
+
+    /// Initializes the controller and creates one driver for each port with
+    /// a linked-up drive, see [`AhciDriver::probe_all`].
+    ///
+    /// The ports that `ahci_init` did not start are only used with a
+    /// [`AhciDriverBuilder::dma_allocator`], which provides their memory.
+    /// Returns [`DevError::InvalidParam`] for the same settings as
+    /// [`AhciDriverBuilder::build`].
+    pub fn probe_all(self) -> DevResult<Vec<AhciDriver>> {
+        self.check()?;
+        let device = AhciDriver::init_device(self.mmio_base)?;
+        AhciDriver::probe_ports(device, &self)
+    }
+
+    /// Like [`AhciDriverBuilder::probe_all`], but first spins up the drives
+    /// that are not yet, one at a time, to limit the peak current drawn on
+    /// boards with many drives.
+    ///
+    /// Before spinning up the drive of port `idx`, calls `delay(idx)`, which
+    /// is expected to wait long enough for the previous drive to finish its
+    /// spin-up. This only applies to controllers with staggered spin-up, see
+    /// [`AhciCapabilities::staggered_spin_up`]; the others spin up all drives
+    /// at power-on. Drives already spun up by `ahci_init` are not delayed.
+    ///
+    /// `ahci_init` does not start the ports of the drives spun up this way,
+    /// so they are started on memory of the
+    /// [`AhciDriverBuilder::dma_allocator`], without which this fails with
+    /// [`DevError::InvalidParam`].
+    pub fn probe_all_staggered(self, delay: impl FnMut(u8)) -> DevResult<Vec<AhciDriver>> {
+        self.check()?;
+        if self.dma_alloc.is_none() {
+            return Err(DevError::InvalidParam);
+        }
+        let mut device = AhciDriver::init_device(self.mmio_base)?;
+        if AhciCapabilities::from_regs(device.cap, device.version).staggered_spin_up {
+            AhciDriver::spin_up_staggered(&mut device, delay);
+        }
+        AhciDriver::probe_ports(device, &self)
+    }
+
+    fn check(&self) -> DevResult {
+        if self.poll_iters == 0 || self.max_blocks == Some(0) {
+            return Err(DevError::InvalidParam);
+        }
+        Ok(())
+    }
+}
+
+impl Default for AhciDriverBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,349 @@
+//! Decoding of the AHCI host capabilities and port state.
+
+// HOST_CAP bits.
+const HOST_CAP_64: u32 = 1 << 31;
+const HOST_CAP_NCQ: u32 = 1 << 30;
+const HOST_CAP_SNTF: u32 = 1 << 29;
+const HOST_CAP_MPS: u32 = 1 << 28;
+const HOST_CAP_SSS: u32 = 1 << 27;
+const HOST_CAP_ALPM: u32 = 1 << 26;
+const HOST_CAP_CLO: u32 = 1 << 24;
+const HOST_CAP_ONLY: u32 = 1 << 18;
+const HOST_CAP_PMP: u32 = 1 << 17;
+const HOST_CAP_FBS: u32 = 1 << 16;
+
+// PORT_SCR_ERR bits, ERR field.
+const SERR_ERR_I: u32 = 1 << 0;
+const SERR_ERR_M: u32 = 1 << 1;
+const SERR_ERR_T: u32 = 1 << 8;
+const SERR_ERR_C: u32 = 1 << 9;
+const SERR_ERR_P: u32 = 1 << 10;
+const SERR_ERR_E: u32 = 1 << 11;
+// PORT_SCR_ERR bits, DIAG field.
+const SERR_DIAG_N: u32 = 1 << 16;
+const SERR_DIAG_I: u32 = 1 << 17;
+const SERR_DIAG_W: u32 = 1 << 18;
+const SERR_DIAG_B: u32 = 1 << 19;
+const SERR_DIAG_D: u32 = 1 << 20;
+const SERR_DIAG_C: u32 = 1 << 21;
+const SERR_DIAG_H: u32 = 1 << 22;
+const SERR_DIAG_S: u32 = 1 << 23;
+const SERR_DIAG_T: u32 = 1 << 24;
+const SERR_DIAG_F: u32 = 1 << 25;
+const SERR_DIAG_X: u32 = 1 << 26;
+
+// PORT_CMD bits.
+const PORT_CMD_FIS_ON: u32 = 1 << 14;
+const PORT_CMD_LIST_ON: u32 = 1 << 15;
+
+/// Capabilities of an AHCI controller, decoded from its `CAP` and `VS`
+/// registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AhciCapabilities {
+    /// Whether the controller can access 64-bit addresses.
+    pub addr64: bool,
+    /// Whether native command queuing is supported.
+    pub ncq: bool,
+    /// Whether SNotification register is supported.
+    pub snotification: bool,
+    /// Whether mechanical presence switches are supported.
+    pub mechanical_presence_switch: bool,
+    /// Whether staggered spin-up is supported.
+    pub staggered_spin_up: bool,
+    /// Whether aggressive link power management is supported.
+    pub aggressive_link_pm: bool,
+    /// Whether command list override is supported.
+    pub command_list_override: bool,
+    /// Whether the controller only supports the AHCI mode (no legacy IDE).
+    pub ahci_only: bool,
+    /// Whether port multipliers are supported.
+    pub port_multiplier: bool,
+    /// Whether FIS-based switching is supported.
+    pub fis_based_switching: bool,
+    /// The maximum supported interface speed generation (1: 1.5 Gbps,
+    /// 2: 3 Gbps, 3: 6 Gbps).
+    pub interface_speed: u8,
+    /// The number of command slots per port.
+    pub num_command_slots: u32,
+    /// The number of ports supported by the controller.
+    pub num_ports: u32,
+    /// The major version of the AHCI specification.
+    pub version_major: u16,
+    /// The minor version of the AHCI specification, e.g. `0x0301` for 1.3.1.
+    pub version_minor: u16,
+}
+
+impl AhciCapabilities {
+    /// Decodes the values of the `CAP` and `VS` registers.
+    pub const fn from_regs(cap: u32, version: u32) -> Self {
+        Self {
+            addr64: cap & HOST_CAP_64 != 0,
+            ncq: cap & HOST_CAP_NCQ != 0,
+            snotification: cap & HOST_CAP_SNTF != 0,
+            mechanical_presence_switch: cap & HOST_CAP_MPS != 0,
+            staggered_spin_up: cap & HOST_CAP_SSS != 0,
+            aggressive_link_pm: cap & HOST_CAP_ALPM != 0,
+            command_list_override: cap & HOST_CAP_CLO != 0,
+            ahci_only: cap & HOST_CAP_ONLY != 0,
+            port_multiplier: cap & HOST_CAP_PMP != 0,
+            fis_based_switching: cap & HOST_CAP_FBS != 0,
+            interface_speed: ((cap >> 20) & 0xf) as u8,
+            num_command_slots: ((cap >> 8) & 0x1f) + 1,
+            num_ports: (cap & 0x1f) + 1,
+            version_major: (version >> 16) as u16,
+            version_minor: version as u16,
+        }
+    }
+}
+
+/// The negotiated speed of a SATA link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SataSpeed {
+    /// SATA I, 1.5 Gbps.
+    Gen1,
+    /// SATA II, 3 Gbps.
+    Gen2,
+    /// SATA III, 6 Gbps.
+    Gen3,
+}
+
+impl SataSpeed {
+    /// Decodes a speed generation, as in the SPD field of `PxSSTS`.
+    pub const fn from_generation(generation: u8) -> Option<Self> {
+        match generation {
+            1 => Some(Self::Gen1),
+            2 => Some(Self::Gen2),
+            3 => Some(Self::Gen3),
+            _ => None,
+        }
+    }
+
+    /// The line rate in megabits per second.
+    pub const fn mbps(self) -> u32 {
+        match self {
+            Self::Gen1 => 1500,
+            Self::Gen2 => 3000,
+            Self::Gen3 => 6000,
+        }
+    }
+}
+
+/// The state of an AHCI controller found by its initialization.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AhciInitReport {
+    /// The address of the registers of the controller.
+    pub mmio_base: u64,
+    /// The bitmap of the implemented ports (`PI` register).
+    pub ports_implemented: u32,
+    /// The bitmap of the ports with a linked-up drive.
+    pub ports_linked_up: u32,
+    /// The index of the port used by the driver.
+    pub port_index: u8,
+    /// The raw `CAP` register.
+    pub cap: u32,
+    /// The raw `CAP2` register.
+    pub cap2: u32,
+    /// The raw `VS` register.
+    pub version: u32,
+    /// The decoded `CAP` and `VS` registers.
+    pub capabilities: AhciCapabilities,
+}
+
+/// The state of an AHCI port, decoded from its registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortInfo {
+    /// The index of the port.
+    pub index: u8,
+    /// The bitmap of the command slots in use (`PxCI` and `PxSACT`).
+    pub slots_in_use: u32,
+    /// The device detection field of `PxSSTS` (3: device present and
+    /// communicating).
+    pub device_detection: u8,
+    /// The negotiated speed of the link from `PxSSTS`, if it is up.
+    pub speed: Option<SataSpeed>,
+    /// The interface power management state of `PxSSTS` (1: active,
+    /// 2: partial, 6: slumber, 8: devsleep).
+    pub power_state: u8,
+    /// The raw `PxSERR` register, the errors reported by the link.
+    pub serror: u32,
+    /// The raw `PxTFD` register, the status and error of the device.
+    pub task_file: u32,
+    /// Whether the command list is running (`PxCMD.CR`).
+    pub command_engine_running: bool,
+    /// Whether FIS reception is running (`PxCMD.FR`).
+    pub fis_receive_running: bool,
+}
+
+impl PortInfo {
+    /// Decodes the values of the `PxSSTS`, `PxSERR`, `PxTFD`, `PxCMD`, `PxCI`
+    /// and `PxSACT` registers of port `index`.
+    pub const fn from_regs(
+        index: u8,
+        sstatus: u32,
+        serror: u32,
+        task_file: u32,
+        cmd: u32,
+        ci: u32,
+        sact: u32,
+    ) -> Self {
+        let device_detection = (sstatus & 0xf) as u8;
+        Self {
+            index,
+            slots_in_use: ci | sact,
+            device_detection,
+            speed: if device_detection == 3 {
+                SataSpeed::from_generation(((sstatus >> 4) & 0xf) as u8)
+            } else {
+                None
+            },
+            power_state: ((sstatus >> 8) & 0xf) as u8,
+            serror,
+            task_file,
+            command_engine_running: cmd & PORT_CMD_LIST_ON != 0,
+            fis_receive_running: cmd & PORT_CMD_FIS_ON != 0,
+        }
+    }
+}
+
+/// The errors recorded by the link of an AHCI port, decoded from its `PxSERR`
+/// register.
+///
+/// Each error is a flag set by the controller when it happens and kept until
+/// it is cleared, not a count: a monitor counts how often a flag comes back
+/// after each clear, e.g. CRC errors for a degraded cable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhyErrors {
+    /// The raw `PxSERR` register.
+    pub raw: u32,
+    /// A data integrity error that was recovered (ERR.I).
+    pub recovered_data_integrity: bool,
+    /// A communication error that was recovered, e.g. from a lost link
+    /// (ERR.M).
+    pub recovered_communication: bool,
+    /// A data integrity error that was not recovered (ERR.T).
+    pub transient_data_integrity: bool,
+    /// A communication or data integrity error that persists (ERR.C).
+    pub persistent_communication: bool,
+    /// A violation of the SATA protocol (ERR.P).
+    pub protocol: bool,
+    /// An internal error of the controller (ERR.E).
+    pub internal: bool,
+    /// The PhyRdy signal changed, e.g. on a connection or removal (DIAG.N).
+    pub phy_ready_change: bool,
+    /// An internal error of the PHY (DIAG.I).
+    pub phy_internal: bool,
+    /// A COMWAKE signal was received (DIAG.W).
+    pub comm_wake: bool,
+    /// A 10b to 8b decoding error (DIAG.B).
+    pub decode: bool,
+    /// A running disparity error (DIAG.D).
+    pub disparity: bool,
+    /// A CRC error in a received FIS (DIAG.C).
+    pub crc: bool,
+    /// A FIS was acknowledged with an error by the device (DIAG.H).
+    pub handshake: bool,
+    /// An invalid state transition of the link layer (DIAG.S).
+    pub link_sequence: bool,
+    /// An invalid state transition of the transport layer (DIAG.T).
+    pub transport_state: bool,
+    /// A FIS of unknown type was received (DIAG.F).
+    pub unknown_fis: bool,
+    /// A COMINIT signal was received, e.g. on a connection (DIAG.X).
+    pub exchanged: bool,
+}
+
+impl PhyErrors {
+    /// Decodes the value of the `PxSERR` register.
+    pub const fn from_reg(serror: u32) -> Self {
+        Self {
+            raw: serror,
+            recovered_data_integrity: serror & SERR_ERR_I != 0,
+            recovered_communication: serror & SERR_ERR_M != 0,
+            transient_data_integrity: serror & SERR_ERR_T != 0,
+            persistent_communication: serror & SERR_ERR_C != 0,
+            protocol: serror & SERR_ERR_P != 0,
+            internal: serror & SERR_ERR_E != 0,
+            phy_ready_change: serror & SERR_DIAG_N != 0,
+            phy_internal: serror & SERR_DIAG_I != 0,
+            comm_wake: serror & SERR_DIAG_W != 0,
+            decode: serror & SERR_DIAG_B != 0,
+            disparity: serror & SERR_DIAG_D != 0,
+            crc: serror & SERR_DIAG_C != 0,
+            handshake: serror & SERR_DIAG_H != 0,
+            link_sequence: serror & SERR_DIAG_S != 0,
+            transport_state: serror & SERR_DIAG_T != 0,
+            unknown_fis: serror & SERR_DIAG_F != 0,
+            exchanged: serror & SERR_DIAG_X != 0,
+        }
+    }
+}
+
+/// The limits of the buffers of a DMA command of an AHCI port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SgLimits {
+    /// The maximum number of segments of a command, the size of its PRD
+    /// table.
+    pub max_segments: usize,
+    /// The maximum length of a segment in bytes.
+    pub max_segment_len: usize,
+    /// The required alignment of the address and length of each segment, in
+    /// bytes.
+    pub alignment: usize,
+    /// The mask of the physical addresses that the controller can access,
+    /// `0xffff_ffff` if it lacks 64-bit addressing.
+    pub dma_mask: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_the_capabilities_of_qemu() {
+        // CAP and VS of the ICH9 AHCI controller emulated by QEMU
+        let caps = AhciCapabilities::from_regs(0xc014_1f05, 0x0001_0000);
+        assert!(caps.addr64);
+        assert!(caps.ncq);
+        assert!(caps.ahci_only);
+        assert!(!caps.staggered_spin_up);
+        assert!(!caps.port_multiplier);
+        assert_eq!(caps.interface_speed, 1);
+        assert_eq!(caps.num_command_slots, 32);
+        assert_eq!(caps.num_ports, 6);
+        assert_eq!((caps.version_major, caps.version_minor), (1, 0));
+    }
+
+    #[test]
+    fn decodes_staggered_spin_up_and_version() {
+        let cap = HOST_CAP_SSS | HOST_CAP_MPS | 3 << 20;
+        let caps = AhciCapabilities::from_regs(cap, 0x0001_0301);
+        assert!(caps.staggered_spin_up);
+        assert!(caps.mechanical_presence_switch);
+        assert!(!caps.addr64);
+        assert!(!caps.ncq);
+        assert_eq!(caps.interface_speed, 3);
+        assert_eq!(caps.num_command_slots, 1);
+        assert_eq!(caps.num_ports, 1);
+        assert_eq!((caps.version_major, caps.version_minor), (1, 0x0301));
+    }
+
+    #[test]
+    fn decodes_the_port_state_and_link_errors() {
+        // Gen2 link up and active, command engine and FIS reception running
+        let sstatus = 0x123;
+        let cmd = PORT_CMD_LIST_ON | PORT_CMD_FIS_ON;
+        let info = PortInfo::from_regs(2, sstatus, 0, 0x50, cmd, 0b01, 0b10);
+        assert_eq!(info.device_detection, 3);
+        assert_eq!(info.speed, Some(SataSpeed::Gen2));
+        assert_eq!(info.power_state, 1);
+        assert_eq!(info.slots_in_use, 0b11);
+        assert!(info.command_engine_running && info.fis_receive_running);
+
+        // No device: no speed, whatever SPD says
+        assert_eq!(PortInfo::from_regs(0, 0x120, 0, 0, 0, 0, 0).speed, None);
+
+        let errors = PhyErrors::from_reg(SERR_DIAG_C | SERR_ERR_T | SERR_DIAG_X);
+        assert!(errors.crc && errors.transient_data_integrity && errors.exchanged);
+        assert!(!errors.protocol && !errors.phy_ready_change);
+    }
+}
@@ -0,0 +1,686 @@
+//! Non-queued command execution and error recovery on an AHCI port.
+//!
+//! Uses command slot 0 and the command list/table that `ahci_init` has set up
+//! for the port, the same way as the read/write functions of the FFI crate.
+//!
+//! `ahci_driver` only exports `ahci_init` and the DMA read/write functions,
+//! and it is not part of this repository, so there is no FFI binding to add
+//! for anything else: FLUSH CACHE, IDENTIFY, SMART, ATAPI packets, and
+//! transfers to buffers that are not linearly mapped are issued from here.
+//! Both paths drive slot 0, so [`issue`] refuses to issue a command while
+//! slot 0 or any queued slot is busy, and the driver checks the same before
+//! calling into `ahci_driver`.
+
+use ahci_driver::libahci::ahci_ioport;
+use axdriver_base::{poll_until, trace, AddrTranslator, DevError, DevResult};
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+
+// Port registers, relative to the port MMIO base.
+const PORT_LST_ADDR: usize = 0x00;
+const PORT_LST_ADDR_HI: usize = 0x04;
+const PORT_FIS_ADDR: usize = 0x08;
+const PORT_FIS_ADDR_HI: usize = 0x0c;
+pub const PORT_IRQ_STAT: usize = 0x10;
+pub const PORT_CMD: usize = 0x18;
+pub const PORT_TFDATA: usize = 0x20;
+pub const PORT_SIG: usize = 0x24;
+pub const PORT_SCR_STAT: usize = 0x28;
+pub const PORT_SCR_CTL: usize = 0x2c;
+pub const PORT_SCR_ERR: usize = 0x30;
+pub const PORT_SCR_ACT: usize = 0x34;
+pub const PORT_CMD_ISSUE: usize = 0x38;
+
+// PORT_CMD bits.
+const PORT_CMD_START: u32 = 1 << 0;
+const PORT_CMD_SPIN_UP: u32 = 1 << 1;
+const PORT_CMD_FIS_RX: u32 = 1 << 4;
+const PORT_CMD_FIS_ON: u32 = 1 << 14;
+const PORT_CMD_LIST_ON: u32 = 1 << 15;
+
+// PORT_IRQ_STAT bits.
+const PORT_IRQ_PHYRDY: u32 = 1 << 22;
+const PORT_IRQ_CONNECT: u32 = 1 << 6;
+const PORT_IRQ_TF_ERR: u32 = 1 << 30;
+const PORT_IRQ_HBUS_ERR: u32 = 1 << 29;
+const PORT_IRQ_HBUS_DATA_ERR: u32 = 1 << 28;
+const PORT_IRQ_IF_ERR: u32 = 1 << 27;
+pub const PORT_IRQ_ERROR: u32 =
+    PORT_IRQ_TF_ERR | PORT_IRQ_HBUS_ERR | PORT_IRQ_HBUS_DATA_ERR | PORT_IRQ_IF_ERR;
+
+// PORT_TFDATA status bits.
+const ATA_BUSY: u32 = 0x80;
+const ATA_DRQ: u32 = 0x08;
+const ATA_ERR: u32 = 0x01;
+
+/// Mask of the DET field in PORT_SCR_STAT and PORT_SCR_CTL.
+const SCR_DET_MASK: u32 = 0xf;
+/// PORT_SCR_STAT.DET: device present and PHY communication established.
+const SCR_STAT_DET_PHY_RDY: u32 = 0x3;
+// PORT_SCR_ERR bits.
+const SERR_DIAG_X: u32 = 1 << 26;
+const SERR_DIAG_N: u32 = 1 << 16;
+/// Shift of the SPD field (current interface speed) in PORT_SCR_STAT.
+const SCR_STAT_SPD_SHIFT: u32 = 4;
+/// PORT_SCR_CTL.DET: perform interface initialization (COMRESET).
+const SCR_CTL_DET_COMRESET: u32 = 0x1;
+
+/// PORT_SIG value of an ATAPI device.
+pub const SATA_SIG_ATAPI: u32 = 0xeb14_0101;
+
+// Command header flags.
+const AHCI_CMD_ATAPI: u32 = 1 << 5;
+const AHCI_CMD_WRITE: u32 = 1 << 6;
+
+/// Offset of the ATAPI command in the command table.
+const AHCI_CMD_TBL_CDB: usize = 0x40;
+/// Length of an ATAPI command.
+pub const ATAPI_CDB_LEN: usize = 12;
+
+/// Required alignment of data buffers (bit 0 of the PRD data base address is
+/// reserved).
+pub const AHCI_DMA_ALIGN: usize = 2;
+/// Number of PRD entries in a command table allocated by `ahci_init`.
+pub const AHCI_MAX_SG: usize = 56;
+/// Maximum number of bytes described by a single PRD entry.
+pub const AHCI_MAX_BYTES_PER_SG: usize = 4 * 1024 * 1024;
+
+/// Layout of the memory of a port allocated by the driver instead of by
+/// `ahci_init`: the command list (1 KiB aligned), the received FIS area (256
+/// bytes aligned) and the command table of slot 0 (128 bytes aligned).
+pub const PORT_MEM_CMD_LIST: usize = 0;
+pub const PORT_MEM_RX_FIS: usize = 0x400;
+pub const PORT_MEM_CMD_TBL: usize = 0x500;
+pub const PORT_MEM_SIZE: usize =
+    PORT_MEM_CMD_TBL + AHCI_CMD_TBL_HDR_SZ + AHCI_MAX_SG * size_of::<PrdEntry>();
+pub const PORT_MEM_ALIGN: usize = 1024;
+
+/// Size of the command FIS, ATAPI command and reserved areas that precede
+/// the PRD table in a command table.
+pub const AHCI_CMD_TBL_HDR_SZ: usize = 0x80;
+
+/// Offset of the received D2H Register FIS in the received FIS area.
+const RX_FIS_D2H_REG: usize = 0x40;
+
+/// Default number of polls of the command issue register before giving up.
+pub const CMD_POLL_ITERS: usize = 10_000_000;
+/// Number of polls of a port register while resetting the port.
+const RESET_POLL_ITERS: usize = 1_000_000;
+/// Spin iterations to keep COMRESET asserted, at least 1ms on any CPU that
+/// runs this driver.
+const COMRESET_DELAY_ITERS: usize = 1_000_000;
+
+/// Command header in the command list (AHCI 1.3, section 4.2.2).
+#[repr(C)]
+struct CmdHeader {
+    opts: u32,
+    status: u32,
+    tbl_addr: u32,
+    tbl_addr_hi: u32,
+    reserved: [u32; 4],
+}
+
+/// Physical region descriptor in the command table (AHCI 1.3, section 4.2.3).
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct PrdEntry {
+    addr: u32,
+    addr_hi: u32,
+    reserved: u32,
+    flags_size: u32,
+}
+
+/// A Register - Host to Device FIS.
+#[derive(Default)]
+pub struct Fis {
+    command: u8,
+    features: u16,
+    device: u8,
+    lba: u64,
+    count: u16,
+    packet: Option<[u8; ATAPI_CDB_LEN]>,
+}
+
+impl PrdEntry {
+    pub const EMPTY: Self = Self {
+        addr: 0,
+        addr_hi: 0,
+        reserved: 0,
+        flags_size: 0,
+    };
+}
+
+impl Fis {
+    const LEN: usize = 20;
+
+    /// Creates a FIS that issues the given ATA command.
+    pub fn new(command: u8) -> Self {
+        Self {
+            command,
+            ..Default::default()
+        }
+    }
+
+    /// Sets the LBA, in LBA addressing mode.
+    pub fn lba(mut self, lba: u64) -> Self {
+        self.lba = lba;
+        self.device = 1 << 6;
+        self
+    }
+
+    /// Sets the LBA of a 28-bit command, whose bits 24-27 go in the device
+    /// field rather than in the LBA (exp) fields.
+    pub fn lba28(mut self, lba: u64) -> Self {
+        self.lba = lba & 0xff_ffff;
+        self.device = 1 << 6 | ((lba >> 24) & 0xf) as u8;
+        self
+    }
+
+    /// Sets the sector count.
+    pub fn count(mut self, count: u16) -> Self {
+        self.count = count;
+        self
+    }
+
+    /// Sets the features field.
+    pub fn features(mut self, features: u16) -> Self {
+        self.features = features;
+        self
+    }
+
+    /// Sets the ATAPI command sent by a PACKET command.
+    pub fn packet(mut self, cdb: [u8; ATAPI_CDB_LEN]) -> Self {
+        self.packet = Some(cdb);
+        self
+    }
+
+    fn to_bytes(&self) -> [u8; Self::LEN] {
+        let lba = self.lba.to_le_bytes();
+        let features = self.features.to_le_bytes();
+        let count = self.count.to_le_bytes();
+        let mut fis = [0; Self::LEN];
+        fis[0] = 0x27; // Register - Host to Device
+        fis[1] = 1 << 7; // Command, not control
+        fis[2] = self.command;
+        fis[3] = features[0];
+        fis[4..7].copy_from_slice(&lba[0..3]);
+        fis[7] = self.device;
+        fis[8..11].copy_from_slice(&lba[3..6]);
+        fis[11] = features[1];
+        fis[12] = count[0];
+        fis[13] = count[1];
+        fis
+    }
+}
+
+/// A region of memory that the device reads from or writes to.
+#[derive(Clone, Copy)]
+pub struct Segment {
+    /// Virtual address of the region.
+    pub addr: usize,
+    /// Length of the region in bytes.
+    pub len: usize,
+}
+
+/// Offset of the registers of port 0 from the controller MMIO base.
+const HOST_PORT_REGS: u64 = 0x100;
+/// Size of the registers of a port.
+const PORT_REGS_SIZE: u64 = 0x80;
+
+/// The address of the registers of port `idx` of the controller whose
+/// registers are at `mmio_base`.
+pub fn port_mmio(mmio_base: u64, idx: usize) -> u64 {
+    mmio_base + HOST_PORT_REGS + PORT_REGS_SIZE * idx as u64
+}
+
+pub fn read_reg(port: &ahci_ioport, reg: usize) -> u32 {
+    unsafe { read_volatile((port.port_mmio as usize + reg) as *const u32) }
+}
+
+pub fn write_reg(port: &ahci_ioport, reg: usize, val: u32) {
+    unsafe { write_volatile((port.port_mmio as usize + reg) as *mut u32, val) }
+}
+
+/// The SPD field of PORT_SCR_STAT, or `None` if no device is present and
+/// communicating.
+pub fn link_speed(port: &ahci_ioport) -> Option<u8> {
+    let stat = read_reg(port, PORT_SCR_STAT);
+    if stat & SCR_DET_MASK != SCR_STAT_DET_PHY_RDY {
+        return None;
+    }
+    Some(((stat >> SCR_STAT_SPD_SHIFT) & 0xf) as u8)
+}
+
+/// Whether a device is present and communicating on the port.
+pub fn device_present(port: &ahci_ioport) -> bool {
+    read_reg(port, PORT_SCR_STAT) & SCR_DET_MASK == SCR_STAT_DET_PHY_RDY
+}
+
+/// Acknowledges the device connections and removals reported by the port
+/// (PxSERR.DIAG.X/N, PxIS.PCS/PRCS), so that the next ones are reported.
+pub fn ack_presence_change(port: &ahci_ioport) {
+    let irq_stat = read_reg(port, PORT_IRQ_STAT) & (PORT_IRQ_CONNECT | PORT_IRQ_PHYRDY);
+    // PxIS.PCS and PxIS.PRCS are cleared with the PxSERR bits they reflect
+    write_reg(port, PORT_SCR_ERR, SERR_DIAG_X | SERR_DIAG_N);
+    write_reg(port, PORT_IRQ_STAT, irq_stat);
+}
+
+/// Whether the drive of the port has been spun up (PxCMD.SUD), which is
+/// always the case without staggered spin-up.
+pub fn spun_up(port: &ahci_ioport) -> bool {
+    read_reg(port, PORT_CMD) & PORT_CMD_SPIN_UP != 0
+}
+
+/// Spins up the drive of the port (PxCMD.SUD), and waits for the link to come
+/// up.
+///
+/// Returns [`DevError::NotPresent`] if no drive communicates on the port.
+pub fn spin_up(port: &ahci_ioport) -> DevResult {
+    let cmd = read_reg(port, PORT_CMD);
+    if cmd & PORT_CMD_SPIN_UP == 0 {
+        write_reg(port, PORT_CMD, cmd | PORT_CMD_SPIN_UP);
+    }
+    wait_reg(port, PORT_SCR_STAT, SCR_DET_MASK, SCR_STAT_DET_PHY_RDY)
+        .map_err(|_| DevError::NotPresent)
+}
+
+/// Clears the link errors recorded in PxSERR, except the connection changes
+/// (DIAG.X/N), which are left to [`ack_presence_change`].
+pub fn clear_phy_errors(port: &ahci_ioport) {
+    let serr = read_reg(port, PORT_SCR_ERR) & !(SERR_DIAG_X | SERR_DIAG_N);
+    if serr != 0 {
+        write_reg(port, PORT_SCR_ERR, serr);
+    }
+}
+
+/// Polls `reg` until the bits in `mask` equal `val`.
+fn wait_reg(port: &ahci_ioport, reg: usize, mask: u32, val: u32) -> DevResult {
+    poll_until(|| read_reg(port, reg) & mask == val, RESET_POLL_ITERS)
+}
+
+/// Brings the port back from an error state, following the error recovery
+/// sequence of AHCI 1.3 section 6.2.2.
+///
+/// The command engine is stopped (which also clears any outstanding command),
+/// the error bits are cleared, a COMRESET is issued if the device is still
+/// busy, then FIS reception and the command engine are restarted.
+///
+/// Returns [`DevError::Timeout`] if the port does not reach the expected state
+/// at any step.
+pub fn reset_port(port: &ahci_ioport) -> DevResult {
+    // 1. Stop the command engine.
+    let cmd = read_reg(port, PORT_CMD);
+    write_reg(port, PORT_CMD, cmd & !PORT_CMD_START);
+    wait_reg(port, PORT_CMD, PORT_CMD_LIST_ON, 0)?;
+
+    // 2. Clear the error bits.
+    write_reg(port, PORT_SCR_ERR, !0);
+    write_reg(port, PORT_IRQ_STAT, !0);
+
+    // 3. Reset the link if the device did not go idle.
+    if read_reg(port, PORT_TFDATA) & (ATA_BUSY | ATA_DRQ) != 0 {
+        trace::warn!("AHCI: device still busy, issuing COMRESET");
+        let sctl = read_reg(port, PORT_SCR_CTL) & !SCR_DET_MASK;
+        write_reg(port, PORT_SCR_CTL, sctl | SCR_CTL_DET_COMRESET);
+        for _ in 0..COMRESET_DELAY_ITERS {
+            core::hint::spin_loop();
+        }
+        write_reg(port, PORT_SCR_CTL, sctl);
+        wait_reg(port, PORT_SCR_STAT, SCR_DET_MASK, SCR_STAT_DET_PHY_RDY)?;
+        write_reg(port, PORT_SCR_ERR, !0);
+        wait_reg(port, PORT_TFDATA, ATA_BUSY | ATA_DRQ, 0)?;
+    }
+
+    // 4. Restart FIS reception and the command engine.
+    let cmd = read_reg(port, PORT_CMD);
+    if cmd & PORT_CMD_FIS_RX == 0 {
+        write_reg(port, PORT_CMD, cmd | PORT_CMD_FIS_RX);
+        wait_reg(port, PORT_CMD, PORT_CMD_FIS_ON, PORT_CMD_FIS_ON)?;
+    }
+    let cmd = read_reg(port, PORT_CMD);
+    write_reg(port, PORT_CMD, cmd | PORT_CMD_START);
+    Ok(())
+}
+
+/// Returns the LBA field of the last D2H Register FIS received by the port.
+pub fn d2h_fis_lba(port: &ahci_ioport) -> u64 {
+    let fis = (port.rx_fis as usize + RX_FIS_D2H_REG) as *const u8;
+    let mut lba = [0; 8];
+    for (i, ofs) in [4, 5, 6, 8, 9, 10].into_iter().enumerate() {
+        lba[i] = unsafe { read_volatile(fis.add(ofs)) };
+    }
+    u64::from_le_bytes(lba)
+}
+
+/// Translates a virtual address into the physical address seen by the device.
+///
+/// Without a translator, assumes that `addr` lives in the same linear mapping
+/// as the command table that `ahci_init` allocated.
+pub fn virt_to_phys(port: &ahci_ioport, tr: Option<&dyn AddrTranslator>, addr: usize) -> u64 {
+    match tr {
+        Some(tr) => tr.virt_to_phys(addr),
+        None => (addr as u64)
+            .wrapping_sub(port.cmd_tbl)
+            .wrapping_add(port.cmd_tbl_dma),
+    }
+}
+
+/// Stops the command engine, then FIS reception, so that the controller no
+/// longer accesses the command list, command tables and received FIS area of
+/// the port. Any outstanding command is aborted.
+///
+/// Returns [`DevError::Timeout`] if the port does not stop.
+pub fn stop_port(port: &ahci_ioport) -> DevResult {
+    let cmd = read_reg(port, PORT_CMD) & !PORT_CMD_START;
+    write_reg(port, PORT_CMD, cmd);
+    wait_reg(port, PORT_CMD, PORT_CMD_LIST_ON, 0)?;
+    write_reg(port, PORT_CMD, cmd & !PORT_CMD_FIS_RX);
+    wait_reg(port, PORT_CMD, PORT_CMD_FIS_ON, 0)
+}
+
+/// Recomputes the physical addresses of the command list, received FIS area
+/// and command table of the port with `tr`, and points the port to them if
+/// they differ from those computed by `ahci_init`.
+///
+/// Returns [`DevError::Timeout`] if the port cannot be stopped.
+pub fn rebase_port(port: &mut ahci_ioport, tr: &dyn AddrTranslator) -> DevResult {
+    port.cmd_tbl_dma = tr.virt_to_phys(port.cmd_tbl as usize);
+    let clb = tr.virt_to_phys(port.cmd_slot as usize);
+    let fb = tr.virt_to_phys(port.rx_fis as usize);
+    if (clb, fb) == (port.cmd_slot_dma, port.rx_fis_dma) {
+        return Ok(());
+    }
+    trace::debug!(
+        "AHCI: moving command list to {:#x} and received FIS to {:#x}",
+        clb,
+        fb
+    );
+
+    port.cmd_slot_dma = clb;
+    port.rx_fis_dma = fb;
+    start_port(port)
+}
+
+/// Points the port to the command list and received FIS area at the
+/// physical addresses `port.cmd_slot_dma` and `port.rx_fis_dma`, then starts
+/// FIS reception and the command engine, following AHCI 1.3 section 10.3.
+///
+/// Returns [`DevError::Timeout`] if the port cannot be stopped or started.
+pub fn start_port(port: &ahci_ioport) -> DevResult {
+    // The bases may only be changed while the command engine and FIS
+    // reception are stopped.
+    let cmd = read_reg(port, PORT_CMD) & !(PORT_CMD_START | PORT_CMD_FIS_RX);
+    stop_port(port)?;
+
+    let (clb, fb) = (port.cmd_slot_dma, port.rx_fis_dma);
+    write_reg(port, PORT_LST_ADDR, clb as u32);
+    write_reg(port, PORT_LST_ADDR_HI, (clb >> 32) as u32);
+    write_reg(port, PORT_FIS_ADDR, fb as u32);
+    write_reg(port, PORT_FIS_ADDR_HI, (fb >> 32) as u32);
+
+    write_reg(port, PORT_CMD, cmd | PORT_CMD_FIS_RX);
+    wait_reg(port, PORT_CMD, PORT_CMD_FIS_ON, PORT_CMD_FIS_ON)?;
+    // The command engine may only be started once the device is idle
+    write_reg(port, PORT_SCR_ERR, !0);
+    wait_reg(port, PORT_TFDATA, ATA_BUSY | ATA_DRQ, 0)?;
+    write_reg(port, PORT_CMD, cmd | PORT_CMD_FIS_RX | PORT_CMD_START);
+    Ok(())
+}
+
+/// Issues a command on slot 0 of the port and waits for its completion.
+///
+/// `segments` are transferred from the device if `write` is `false`, or to
+/// the device otherwise.
+///
+/// Returns [`DevError::Timeout`] if the command does not complete within
+/// `max_iters` polls. The port is then reset with [`reset_port`], which
+/// aborts the command, so that the device no longer accesses `segments` once
+/// this function returns.
+///
+/// # Safety
+///
+/// The port must have been started by `ahci_init`, and the memory described
+/// by `segments` must stay valid until this function returns.
+pub unsafe fn exec(
+    port: &ahci_ioport,
+    tr: Option<&dyn AddrTranslator>,
+    fis: &Fis,
+    segments: &[Segment],
+    write: bool,
+    max_iters: usize,
+) -> DevResult {
+    issue(port, tr, fis, segments, write)?;
+    let mut res = None;
+    let timeout = poll_until(
+        || {
+            res = poll(port);
+            res.is_some()
+        },
+        max_iters,
+    );
+    if let Err(e) = timeout {
+        trace::error!("AHCI: command {:#x} timed out", fis.command);
+        if reset_port(port).is_err() {
+            trace::error!("AHCI: failed to abort command {:#x}", fis.command);
+        }
+        return Err(e);
+    }
+    res.unwrap()
+}
+
+/// Issues a command on slot 0 of the port without waiting for it, the
+/// completion must then be polled with [`poll`].
+///
+/// # Safety
+///
+/// The port must have been started by `ahci_init`, and the memory described
+/// by `segments` must stay valid until the command completes or the port is
+/// reset.
+pub unsafe fn issue(
+    port: &ahci_ioport,
+    tr: Option<&dyn AddrTranslator>,
+    fis: &Fis,
+    segments: &[Segment],
+    write: bool,
+) -> DevResult {
+    if read_reg(port, PORT_CMD_ISSUE) & 1 != 0
+        || read_reg(port, PORT_SCR_ACT) != 0
+        || read_reg(port, PORT_TFDATA) & (ATA_BUSY | ATA_DRQ) != 0
+    {
+        return Err(DevError::ResourceBusy);
+    }
+
+    let tbl = port.cmd_tbl as usize;
+    prepare(
+        port,
+        tr,
+        0,
+        tbl,
+        port.cmd_tbl_dma,
+        port.cmd_tbl_sg as usize,
+        fis,
+        segments,
+        write,
+    )?;
+
+    // Issue the command.
+    fence(Ordering::SeqCst);
+    write_reg(port, PORT_IRQ_STAT, read_reg(port, PORT_IRQ_STAT));
+    write_reg(port, PORT_CMD_ISSUE, 1);
+    Ok(())
+}
+
+/// Fills the command table at `tbl` (with its PRD table at `sg`) and the
+/// command header of `slot`, without issuing the command.
+///
+/// # Safety
+///
+/// `tbl` and `sg` must point to a command table that is not in use, whose
+/// physical address is `tbl_dma`.
+#[allow(clippy::too_many_arguments)]
+pub unsafe fn prepare(
+    port: &ahci_ioport,
+    tr: Option<&dyn AddrTranslator>,
+    slot: usize,
+    tbl: usize,
+    tbl_dma: u64,
+    sg: usize,
+    fis: &Fis,
+    segments: &[Segment],
+    write: bool,
+) -> DevResult {
+    // Fill the command FIS and the PRD table.
+    let tbl = tbl as *mut u8;
+    for (i, b) in fis.to_bytes().iter().enumerate() {
+        write_volatile(tbl.add(i), *b);
+    }
+    if let Some(cdb) = &fis.packet {
+        for (i, b) in cdb.iter().enumerate() {
+            write_volatile(tbl.add(AHCI_CMD_TBL_CDB + i), *b);
+        }
+    }
+    let sg = sg as *mut PrdEntry;
+    let mut nr_sg = 0;
+    for seg in segments {
+        let mut off = 0;
+        while off < seg.len {
+            if nr_sg == AHCI_MAX_SG {
+                return Err(DevError::InvalidParam);
+            }
+            let len = (seg.len - off).min(AHCI_MAX_BYTES_PER_SG);
+            let addr = virt_to_phys(port, tr, seg.addr + off);
+            write_volatile(
+                sg.add(nr_sg),
+                PrdEntry {
+                    addr: addr as u32,
+                    addr_hi: (addr >> 32) as u32,
+                    reserved: 0,
+                    flags_size: (len - 1) as u32 & 0x3f_ffff,
+                },
+            );
+            nr_sg += 1;
+            off += len;
+        }
+    }
+
+    // Fill the command header of the slot.
+    let mut opts = (Fis::LEN / 4) as u32 | (nr_sg as u32) << 16;
+    if write {
+        opts |= AHCI_CMD_WRITE;
+    }
+    if fis.packet.is_some() {
+        opts |= AHCI_CMD_ATAPI;
+    }
+    write_volatile(
+        (port.cmd_slot as *mut CmdHeader).add(slot),
+        CmdHeader {
+            opts,
+            status: 0,
+            tbl_addr: tbl_dma as u32,
+            tbl_addr_hi: (tbl_dma >> 32) as u32,
+            reserved: [0; 4],
+        },
+    );
+    Ok(())
+}
+
+/// Checks whether the command issued by [`issue`] has completed.
+///
+/// Returns `None` if it is still running, or the result of the command.
+pub fn poll(port: &ahci_ioport) -> Option<DevResult> {
+    let irq_stat = read_reg(port, PORT_IRQ_STAT);
+    if irq_stat & PORT_IRQ_ERROR == 0 && read_reg(port, PORT_CMD_ISSUE) & 1 != 0 {
+        return None;
+    }
+    fence(Ordering::SeqCst);
+
+    let tfdata = read_reg(port, PORT_TFDATA);
+    write_reg(port, PORT_IRQ_STAT, irq_stat);
+    if irq_stat & PORT_IRQ_ERROR != 0 || tfdata & ATA_ERR != 0 {
+        trace::error!(
+            "AHCI: command failed, irq_stat {:#x}, tfdata {:#x}",
+            irq_stat,
+            tfdata
+        );
+        if irq_stat & PORT_IRQ_ERROR != 0 {
+            return Some(Err(DevError::Other("port in error state")));
+        }
+        return Some(Err(DevError::Io));
+    }
+    Some(Ok(()))
+}
+
+/// Completion of a command issued by [`issue`], polled without blocking.
+///
+/// The command is aborted by resetting the port if the future is dropped
+/// before it completes, so that the device stops accessing the buffers.
+#[cfg(feature = "async")]
+pub struct Completion<'a> {
+    port: &'a ahci_ioport,
+    done: bool,
+}
+
+#[cfg(feature = "async")]
+impl<'a> Completion<'a> {
+    pub fn new(port: &'a ahci_ioport) -> Self {
+        Self { port, done: false }
+    }
+}
+
+#[cfg(feature = "async")]
+impl core::future::Future for Completion<'_> {
+    type Output = DevResult;
+
+    fn poll(
+        mut self: core::pin::Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+    ) -> core::task::Poll<DevResult> {
+        match poll(self.port) {
+            Some(res) => {
+                self.done = true;
+                core::task::Poll::Ready(res)
+            }
+            None => {
+                cx.waker().wake_by_ref();
+                core::task::Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl Drop for Completion<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            trace::warn!("AHCI: command cancelled, resetting the port");
+            let _ = reset_port(self.port);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lba48_fis_has_the_lba_in_the_lba_fields() {
+        let fis = Fis::new(0x25)
+            .lba(0x0a0b_0c0d_0e0f)
+            .count(0x0102)
+            .to_bytes();
+        assert_eq!(fis[2], 0x25);
+        assert_eq!(fis[4..7], [0x0f, 0x0e, 0x0d]);
+        assert_eq!(fis[7], 0x40);
+        assert_eq!(fis[8..11], [0x0c, 0x0b, 0x0a]);
+        assert_eq!(fis[12..14], [0x02, 0x01]);
+    }
+
+    #[test]
+    fn lba28_fis_has_the_high_lba_bits_in_the_device_field() {
+        let fis = Fis::new(0xc8).lba28(0x0abc_def1).count(8).to_bytes();
+        assert_eq!(fis[4..7], [0xf1, 0xde, 0xbc]);
+        assert_eq!(fis[7], 0x40 | 0xa);
+        assert_eq!(fis[8..11], [0, 0, 0]);
+        assert_eq!(fis[12], 8);
+    }
+}
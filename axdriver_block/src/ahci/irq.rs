@@ -0,0 +1,100 @@
+//! Interrupt handling of an AHCI port.
+//!
+//! `ahci_init` leaves interrupts masked, and commands are completed by
+//! polling the port registers. Enabling interrupts does not change that: the
+//! interrupt handler only acknowledges them, the result of a command is still
+//! taken from the port registers by whoever waits for it.
+
+use ahci_driver::libahci::ahci_device;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::cmd::{self, PORT_IRQ_ERROR, PORT_IRQ_STAT};
+
+// Global HBA registers, relative to the controller MMIO base.
+const HOST_CTL: usize = 0x04;
+const HOST_IRQ_STAT: usize = 0x08;
+
+/// HOST_CTL: global interrupt enable.
+const HOST_IRQ_EN: u32 = 1 << 1;
+
+/// Port interrupt enable register, relative to the port MMIO base.
+const PORT_IRQ_MASK: usize = 0x14;
+
+// PORT_IRQ_STAT bits of a completed command.
+const PORT_IRQ_D2H_REG_FIS: u32 = 1 << 0;
+const PORT_IRQ_PIOS_FIS: u32 = 1 << 1;
+const PORT_IRQ_DMAS_FIS: u32 = 1 << 2;
+const PORT_IRQ_SDB_FIS: u32 = 1 << 3;
+const PORT_IRQ_SG_DONE: u32 = 1 << 5;
+const PORT_IRQ_COMPLETE: u32 = PORT_IRQ_D2H_REG_FIS
+    | PORT_IRQ_PIOS_FIS
+    | PORT_IRQ_DMAS_FIS
+    | PORT_IRQ_SDB_FIS
+    | PORT_IRQ_SG_DONE;
+
+/// Serializes the read-modify-writes of HOST_CTL, which the drivers of the
+/// ports of a controller share while they are used concurrently.
+static HOST_CTL_LOCK: AtomicBool = AtomicBool::new(false);
+
+fn read_host_reg(device: &ahci_device, reg: usize) -> u32 {
+    unsafe { read_volatile((device.mmio_base as usize + reg) as *const u32) }
+}
+
+fn write_host_reg(device: &ahci_device, reg: usize, val: u32) {
+    unsafe { write_volatile((device.mmio_base as usize + reg) as *mut u32, val) }
+}
+
+/// Unmasks the completion and error interrupts of the enabled port, and the
+/// interrupt of the controller.
+pub fn enable(device: &ahci_device) {
+    let idx = device.port_idx as usize;
+    let port = &device.port[idx];
+    cmd::write_reg(
+        port,
+        PORT_IRQ_STAT,
+        cmd::read_reg(port, PORT_IRQ_STAT) & PORT_IRQ_COMPLETE,
+    );
+    write_host_reg(device, HOST_IRQ_STAT, 1 << idx);
+    cmd::write_reg(port, PORT_IRQ_MASK, PORT_IRQ_COMPLETE | PORT_IRQ_ERROR);
+    while HOST_CTL_LOCK
+        .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        core::hint::spin_loop();
+    }
+    write_host_reg(
+        device,
+        HOST_CTL,
+        read_host_reg(device, HOST_CTL) | HOST_IRQ_EN,
+    );
+    HOST_CTL_LOCK.store(false, Ordering::Release);
+}
+
+/// Masks the interrupts of the enabled port.
+///
+/// The interrupt of the controller stays enabled for the other ports.
+pub fn disable(device: &ahci_device) {
+    let port = &device.port[device.port_idx as usize];
+    cmd::write_reg(port, PORT_IRQ_MASK, 0);
+}
+
+/// Acknowledges the interrupt of the enabled port, and returns its interrupt
+/// status.
+///
+/// Error bits are left set, so that the failure is reported to the waiter of
+/// the command, which then recovers the port. Until then the error
+/// interrupts are masked, as they would fire again as soon as they are
+/// acknowledged; [`enable`] unmasks them once the port is recovered.
+pub fn ack(device: &ahci_device) -> u32 {
+    let idx = device.port_idx as usize;
+    let port = &device.port[idx];
+    let stat = cmd::read_reg(port, PORT_IRQ_STAT);
+    if stat & PORT_IRQ_ERROR != 0 {
+        cmd::write_reg(port, PORT_IRQ_MASK, PORT_IRQ_COMPLETE);
+    }
+    cmd::write_reg(port, PORT_IRQ_STAT, stat & PORT_IRQ_COMPLETE);
+    // The port status must be cleared before the controller one
+    write_host_reg(device, HOST_IRQ_STAT, 1 << idx);
+    stat
+}
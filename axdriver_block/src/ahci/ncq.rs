@@ -0,0 +1,215 @@
+//! Native Command Queuing on an AHCI port.
+//!
+//! `ahci_init` only allocates a command table for slot 0, so the queue
+//! allocates one table per slot. They come from the DMA allocator of the
+//! driver if it has one, otherwise from the heap, and their physical
+//! addresses are then translated like those of data buffers.
+
+extern crate alloc;
+
+use ahci_driver::libahci::ahci_ioport;
+use alloc::{boxed::Box, vec::Vec};
+use axdriver_base::dma::{DmaAllocFn, DmaMemory};
+use axdriver_base::{trace, AddrTranslator, DevError, DevResult};
+use core::sync::atomic::{fence, Ordering};
+
+use super::ata::{ATA_CMD_FPDMA_READ, ATA_CMD_FPDMA_WRITE};
+use super::cmd::{
+    self, Fis, PrdEntry, Segment, AHCI_CMD_TBL_HDR_SZ, AHCI_MAX_SG, PORT_CMD_ISSUE, PORT_IRQ_ERROR,
+    PORT_IRQ_STAT, PORT_SCR_ACT,
+};
+
+/// Command table of a slot (AHCI 1.3, section 4.2.3).
+#[repr(C, align(128))]
+struct CmdTable {
+    hdr: [u8; AHCI_CMD_TBL_HDR_SZ],
+    prdt: [PrdEntry; AHCI_MAX_SG],
+}
+
+/// The command tables of the slots.
+enum Tables {
+    Heap(Box<[CmdTable]>),
+    Dma(Box<dyn DmaMemory>),
+}
+
+/// Token identifying a command submitted to the queue, returned again when
+/// the command completes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandToken(u8);
+
+impl CommandToken {
+    /// The NCQ tag (i.e. command slot) of the command.
+    pub const fn tag(&self) -> u8 {
+        self.0
+    }
+}
+
+/// Queued commands of a port.
+pub struct Queue {
+    tables: Tables,
+    depth: u32,
+    /// Tags of the submitted commands that have not been reported yet.
+    outstanding: u32,
+    /// Tags of the outstanding commands aborted by a port reset.
+    aborted: u32,
+}
+
+impl Queue {
+    /// Creates a queue with `depth` command slots, whose command tables are
+    /// allocated with `dma_alloc` if it is given.
+    pub fn new(depth: u32, dma_alloc: Option<DmaAllocFn>) -> DevResult<Self> {
+        let tables = match dma_alloc {
+            Some(alloc) => Tables::Dma(alloc(
+                depth as usize * size_of::<CmdTable>(),
+                align_of::<CmdTable>(),
+            )?),
+            None => Tables::Heap(
+                (0..depth)
+                    .map(|_| CmdTable {
+                        hdr: [0; AHCI_CMD_TBL_HDR_SZ],
+                        prdt: [PrdEntry::EMPTY; AHCI_MAX_SG],
+                    })
+                    .collect::<Vec<_>>()
+                    .into_boxed_slice(),
+            ),
+        };
+        Ok(Self {
+            tables,
+            depth,
+            outstanding: 0,
+            aborted: 0,
+        })
+    }
+
+    /// The virtual and physical addresses of the command table of `tag`.
+    fn table(
+        &mut self,
+        port: &ahci_ioport,
+        tr: Option<&dyn AddrTranslator>,
+        tag: u32,
+    ) -> (usize, u64) {
+        let offset = tag as usize * size_of::<CmdTable>();
+        match &mut self.tables {
+            Tables::Heap(tables) => {
+                let tbl = &mut tables[tag as usize] as *mut CmdTable as usize;
+                (tbl, cmd::virt_to_phys(port, tr, tbl))
+            }
+            Tables::Dma(mem) => (
+                mem.as_ptr() as usize + offset,
+                mem.phys_addr() + offset as u64,
+            ),
+        }
+    }
+
+    /// Marks all outstanding commands as failed, after the port was reset.
+    pub fn abort_all(&mut self) {
+        self.aborted = self.outstanding;
+    }
+
+    /// Submits a READ/WRITE FPDMA QUEUED command of `block_count` blocks
+    /// starting from `block_id`.
+    ///
+    /// # Safety
+    ///
+    /// The port must have been started by `ahci_init`, and the memory
+    /// described by `segments` must stay valid until the command is reported
+    /// complete by [`Queue::poll`].
+    pub unsafe fn submit(
+        &mut self,
+        port: &ahci_ioport,
+        tr: Option<&dyn AddrTranslator>,
+        block_id: u64,
+        block_count: u32,
+        segments: &[Segment],
+        write: bool,
+    ) -> DevResult<CommandToken> {
+        // Non-queued commands must not be mixed with queued ones
+        if self.outstanding == 0 && cmd::read_reg(port, PORT_CMD_ISSUE) != 0 {
+            return Err(DevError::Again);
+        }
+        let depth = self.depth;
+        let tag = (!self.outstanding).trailing_zeros();
+        if tag >= depth {
+            return Err(DevError::Again);
+        }
+
+        let command = if write {
+            ATA_CMD_FPDMA_WRITE
+        } else {
+            ATA_CMD_FPDMA_READ
+        };
+        // A sector count of 0 means 65536
+        let fis = Fis::new(command)
+            .lba(block_id)
+            .features(block_count as u16)
+            .count((tag as u16) << 3);
+        let (tbl, tbl_dma) = self.table(port, tr, tag);
+        cmd::prepare(
+            port,
+            tr,
+            tag as usize,
+            tbl,
+            tbl_dma,
+            tbl + AHCI_CMD_TBL_HDR_SZ,
+            &fis,
+            segments,
+            write,
+        )?;
+
+        fence(Ordering::SeqCst);
+        cmd::write_reg(port, PORT_SCR_ACT, 1 << tag);
+        cmd::write_reg(port, PORT_CMD_ISSUE, 1 << tag);
+        self.outstanding |= 1 << tag;
+        Ok(CommandToken(tag as u8))
+    }
+
+    /// Reports the commands that have completed to `f`, and returns how many
+    /// there were.
+    ///
+    /// If the port has reported an error, the outstanding commands that the
+    /// device has not completed fail (it aborts them) and the port must be
+    /// reset before submitting new ones; `true` is then returned along with
+    /// the count.
+    pub fn poll(
+        &mut self,
+        port: &ahci_ioport,
+        mut f: impl FnMut(CommandToken, DevResult),
+    ) -> (usize, bool) {
+        let mut report = |tags: u32, res: fn() -> DevResult| {
+            for tag in 0..u32::BITS {
+                if tags & (1 << tag) != 0 {
+                    f(CommandToken(tag as u8), res());
+                }
+            }
+            tags.count_ones() as usize
+        };
+
+        let aborted = self.aborted;
+        self.outstanding &= !aborted;
+        self.aborted = 0;
+        let mut n = report(aborted, || Err(DevError::Io));
+        if self.outstanding == 0 {
+            return (n, false);
+        }
+
+        let irq_stat = cmd::read_reg(port, PORT_IRQ_STAT);
+        if irq_stat & PORT_IRQ_ERROR != 0 {
+            trace::error!("AHCI: queued command failed, irq_stat {:#x}", irq_stat);
+            // The device clears the PxSACT bits of the commands it completed
+            // before the error
+            let failed = self.outstanding & cmd::read_reg(port, PORT_SCR_ACT);
+            n += report(self.outstanding & !failed, || Ok(()));
+            n += report(failed, || Err(DevError::Io));
+            self.outstanding = 0;
+            return (n, true);
+        }
+        let active = cmd::read_reg(port, PORT_SCR_ACT) | cmd::read_reg(port, PORT_CMD_ISSUE);
+        let done = self.outstanding & !active;
+        if done != 0 {
+            cmd::write_reg(port, PORT_IRQ_STAT, irq_stat);
+            self.outstanding &= !done;
+            n += report(done, || Ok(()));
+        }
+        (n, false)
+    }
+}
@@ -0,0 +1,114 @@
+//! Decoding of S.M.A.R.T. data.
+
+use axdriver_base::trace;
+
+const SMART_ATTR_OFFSET: usize = 2;
+const SMART_ATTR_LEN: usize = 12;
+const SMART_ATTR_COUNT: usize = 30;
+
+// Attribute IDs.
+const SMART_ATTR_REALLOCATED_SECTORS: u8 = 5;
+const SMART_ATTR_POWER_ON_HOURS: u8 = 9;
+const SMART_ATTR_AIRFLOW_TEMPERATURE: u8 = 190;
+const SMART_ATTR_TEMPERATURE: u8 = 194;
+
+/// Length of the data returned by SMART READ DATA.
+pub const SMART_DATA_LEN: usize = 512;
+
+/// Health information of a drive, from S.M.A.R.T.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SmartStatus {
+    /// `false` if the drive reports that a threshold has been exceeded, i.e.
+    /// it is likely to fail.
+    pub healthy: bool,
+    /// The current temperature in degrees Celsius.
+    pub temperature_celsius: Option<u8>,
+    /// The number of hours the drive has been powered on.
+    pub power_on_hours: Option<u64>,
+    /// The number of sectors that have been reallocated.
+    pub reallocated_sectors: Option<u64>,
+}
+
+impl SmartStatus {
+    /// Parses the attribute table in the data returned by SMART READ DATA.
+    ///
+    /// `healthy` is the result of SMART RETURN STATUS. Attributes that are
+    /// not reported by the drive are left as `None`.
+    pub fn from_data(data: &[u8; SMART_DATA_LEN], healthy: bool) -> Self {
+        let checksum = data.iter().fold(0u8, |acc, &b| acc.wrapping_add(b));
+        if checksum != 0 {
+            trace::warn!("SMART: bad checksum of the data structure");
+        }
+
+        let mut status = Self {
+            healthy,
+            temperature_celsius: None,
+            power_on_hours: None,
+            reallocated_sectors: None,
+        };
+        let table = &data[SMART_ATTR_OFFSET..SMART_ATTR_OFFSET + SMART_ATTR_COUNT * SMART_ATTR_LEN];
+        for attr in table.chunks_exact(SMART_ATTR_LEN) {
+            // id (1 byte), flags (2 bytes), current and worst values (1 byte
+            // each), then 6 bytes of little-endian raw value
+            let mut raw = [0; 8];
+            raw[..6].copy_from_slice(&attr[5..11]);
+            let raw = u64::from_le_bytes(raw);
+            match attr[0] {
+                SMART_ATTR_REALLOCATED_SECTORS => status.reallocated_sectors = Some(raw),
+                // Some drives store minutes or seconds in the upper bytes
+                SMART_ATTR_POWER_ON_HOURS => status.power_on_hours = Some(raw & 0xffff_ffff),
+                SMART_ATTR_TEMPERATURE => status.temperature_celsius = Some(raw as u8),
+                SMART_ATTR_AIRFLOW_TEMPERATURE => {
+                    status.temperature_celsius.get_or_insert(raw as u8);
+                }
+                _ => {}
+            }
+        }
+        status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes attribute `id` with raw value `raw` in entry `idx` of the table.
+    fn set_attr(data: &mut [u8; SMART_DATA_LEN], idx: usize, id: u8, raw: u64) {
+        let attr = &mut data[SMART_ATTR_OFFSET + idx * SMART_ATTR_LEN..][..SMART_ATTR_LEN];
+        attr[0] = id;
+        attr[3] = 100;
+        attr[4] = 100;
+        attr[5..11].copy_from_slice(&raw.to_le_bytes()[..6]);
+    }
+
+    #[test]
+    fn parses_the_attributes() {
+        let mut data = [0; SMART_DATA_LEN];
+        data[0] = 0x10; // revision
+        set_attr(&mut data, 0, SMART_ATTR_REALLOCATED_SECTORS, 3);
+        set_attr(&mut data, 1, SMART_ATTR_POWER_ON_HOURS, 0x1234_0000_2710);
+        set_attr(&mut data, 2, SMART_ATTR_AIRFLOW_TEMPERATURE, 30);
+        set_attr(&mut data, 5, SMART_ATTR_TEMPERATURE, 0x0014_0028_0024);
+        let status = SmartStatus::from_data(&data, true);
+        assert_eq!(
+            status,
+            SmartStatus {
+                healthy: true,
+                temperature_celsius: Some(0x24),
+                power_on_hours: Some(10000),
+                reallocated_sectors: Some(3),
+            }
+        );
+    }
+
+    #[test]
+    fn missing_attributes_are_none() {
+        let mut data = [0; SMART_DATA_LEN];
+        set_attr(&mut data, 29, SMART_ATTR_AIRFLOW_TEMPERATURE, 41);
+        let status = SmartStatus::from_data(&data, false);
+        assert!(!status.healthy);
+        assert_eq!(status.temperature_celsius, Some(41));
+        assert_eq!(status.power_on_hours, None);
+        assert_eq!(status.reallocated_sectors, None);
+    }
+}
@@ -0,0 +1,159 @@
+//! Asynchronous interface of block storage drivers.
+//!
+//! Only `core` is needed: the returned futures can be driven by any executor,
+//! and this module makes no allocations. Drivers wake the task themselves
+//! whenever it needs to be polled again, so no timer or interrupt support is
+//! required from the executor.
+
+use core::future::Future;
+
+use crate::{
+    BaseDriverOps, BlockDriverOps, DevResult, DeviceCapabilities, DeviceStats, DeviceType,
+};
+
+/// Asynchronous operations of a block storage device driver.
+///
+/// They have the same semantics as their counterparts in [`BlockDriverOps`],
+/// but yield to the executor instead of blocking while the device is busy.
+///
+/// Dropping a future before it completes cancels the operation, the content of
+/// the affected blocks is then unspecified.
+pub trait AsyncBlockDriverOps: BaseDriverOps {
+    /// The number of blocks in this storage device.
+    fn num_blocks(&self) -> u64;
+    /// The size of each block in bytes.
+    fn block_size(&self) -> usize;
+
+    /// Reads blocked data from the given block.
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> impl Future<Output = DevResult>;
+
+    /// Writes blocked data to the given block.
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> impl Future<Output = DevResult>;
+
+    /// Flushes the device to write all pending data to the storage.
+    fn flush(&mut self) -> impl Future<Output = DevResult>;
+}
+
+/// Adapts a synchronous [`BlockDriverOps`] to [`AsyncBlockDriverOps`].
+///
+/// There is no blocking thread pool in `no_std` environments, so every
+/// operation runs to completion when its future is first polled. Executors
+/// that must not be stalled should poll these futures on a dedicated task or
+/// CPU. For the same reason, the wrapper does not report
+/// [`DeviceCapabilities::ASYNC`]: its capabilities are those of the wrapped
+/// driver.
+pub struct Blocking<D>(D);
+
+impl<D: BlockDriverOps> Blocking<D> {
+    /// Wraps a synchronous driver.
+    pub const fn new(inner: D) -> Self {
+        Self(inner)
+    }
+
+    /// Returns a reference to the wrapped driver.
+    pub const fn inner(&self) -> &D {
+        &self.0
+    }
+
+    /// Unwraps the synchronous driver.
+    pub fn into_inner(self) -> D {
+        self.0
+    }
+}
+
+impl<D: BlockDriverOps> BaseDriverOps for Blocking<D> {
+    fn device_name(&self) -> &str {
+        self.0.device_name()
+    }
+
+    fn device_type(&self) -> DeviceType {
+        self.0.device_type()
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        self.0.capabilities()
+    }
+
+    fn stats(&self) -> DeviceStats {
+        self.0.stats()
+    }
+
+    fn reset(&mut self) -> DevResult {
+        self.0.reset()
+    }
+}
+
+impl<D: BlockDriverOps> AsyncBlockDriverOps for Blocking<D> {
+    #[inline]
+    fn num_blocks(&self) -> u64 {
+        self.0.num_blocks()
+    }
+
+    #[inline]
+    fn block_size(&self) -> usize {
+        self.0.block_size()
+    }
+
+    async fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        self.0.read_block(block_id, buf)
+    }
+
+    async fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        self.0.write_block(block_id, buf)
+    }
+
+    async fn flush(&mut self) -> DevResult {
+        self.0.flush()
+    }
+}
+
+#[cfg(all(test, feature = "ramdisk"))]
+mod tests {
+    use super::*;
+    use crate::ramdisk::SliceDisk;
+    use core::pin::pin;
+    use core::task::{Context, Poll, Waker};
+
+    /// Polls `future` once, which is enough for those of [`Blocking`].
+    fn poll_once<F: Future>(future: F) -> F::Output {
+        let mut cx = Context::from_waker(Waker::noop());
+        match pin!(future).poll(&mut cx) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("the operation did not complete inline"),
+        }
+    }
+
+    #[test]
+    fn operations_complete_when_first_polled() {
+        let mut data = [0; 4 * 512];
+        let mut disk = Blocking::new(SliceDisk::new(&mut data, 512));
+        assert_eq!((disk.num_blocks(), disk.block_size()), (4, 512));
+
+        poll_once(disk.write_block(1, &[1; 1024])).unwrap();
+        let mut buf = [0; 512];
+        poll_once(disk.read_block(2, &mut buf)).unwrap();
+        assert_eq!(buf, [1; 512]);
+        poll_once(disk.flush()).unwrap();
+        assert!(poll_once(disk.read_block(4, &mut buf)).is_err());
+
+        let data = disk.into_inner().into_inner();
+        assert!(data[512..1536].iter().all(|&b| b == 1));
+    }
+
+    #[test]
+    fn the_wrapped_driver_is_reported() {
+        let mut data = [0; 4 * 512];
+        let mut disk = Blocking::new(SliceDisk::new(&mut data, 512));
+        assert_eq!(disk.device_name(), "slicedisk");
+        assert_eq!(disk.capabilities(), disk.inner().capabilities());
+        assert!(!disk.capabilities().contains(DeviceCapabilities::ASYNC));
+
+        poll_once(disk.write_block(0, &[1; 512])).unwrap();
+        let mut buf = [0; 512];
+        poll_once(disk.read_block(0, &mut buf)).unwrap();
+        assert!(poll_once(disk.read_block(4, &mut buf)).is_err());
+        let stats = disk.stats();
+        assert_eq!((stats.reads, stats.writes, stats.read_errors), (1, 1, 1));
+        assert_eq!(stats.bytes_written, 512);
+    }
+}
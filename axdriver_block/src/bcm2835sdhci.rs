@@ -2,7 +2,7 @@
 
 extern crate alloc;
 use crate::BlockDriverOps;
-use axdriver_base::{BaseDriverOps, DevError, DevResult, DeviceType};
+use axdriver_base::{trace, BaseDriverOps, DevError, DevResult, DeviceType};
 use bcm2835_sdhci::Bcm2835SDhci::{EmmcCtl, BLOCK_SIZE};
 use bcm2835_sdhci::SDHCIError;
 
@@ -14,10 +14,10 @@ impl SDHCIDriver {
     pub fn try_new() -> DevResult<SDHCIDriver> {
         let mut ctrl = EmmcCtl::new();
         if ctrl.init() == 0 {
-            log::info!("BCM2835 sdhci: successfully initialized");
+            trace::info!("BCM2835 sdhci: successfully initialized");
             Ok(SDHCIDriver(ctrl))
         } else {
-            log::warn!("BCM2835 sdhci: init failed");
+            trace::warn!("BCM2835 sdhci: init failed");
             Err(DevError::Io)
         }
     }
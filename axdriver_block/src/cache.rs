@@ -0,0 +1,317 @@
+//! Block caches.
+
+extern crate alloc;
+
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+
+use crate::{
+    BaseDriverOps, BlockDriverOps, DevError, DevResult, DeviceCapabilities, DeviceStats,
+    DeviceType, IoHints,
+};
+
+/// A dirty block held by [`WriteBackCache`].
+struct Dirty {
+    data: Vec<u8>,
+    /// Time of the last write, the key of the block in the LRU list.
+    last_write: u64,
+}
+
+/// A write-back cache of dirty blocks in front of a block device.
+///
+/// Writes are kept in memory until [`BlockDriverOps::flush`] is called, or
+/// until the least recently written block is evicted to make room for a new
+/// one. Adjacent dirty blocks are written back by a single request.
+///
+/// Dirty blocks are lost if the cache is dropped without being flushed, use
+/// [`WriteBackCache::into_inner`] to get the device back.
+pub struct WriteBackCache<D> {
+    inner: D,
+    capacity: usize,
+    dirty: BTreeMap<u64, Dirty>,
+    /// Block IDs of the dirty blocks, ordered by their last write.
+    lru: BTreeMap<u64, u64>,
+    clock: u64,
+}
+
+impl<D: BlockDriverOps> WriteBackCache<D> {
+    /// Creates a cache holding at most `capacity_blocks` dirty blocks.
+    ///
+    /// With a capacity of 0, writes go straight to the device.
+    pub fn new(inner: D, capacity_blocks: usize) -> Self {
+        Self {
+            inner,
+            capacity: capacity_blocks,
+            dirty: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// Returns a reference to the underlying device.
+    pub const fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// The number of dirty blocks in the cache.
+    pub fn dirty_blocks(&self) -> usize {
+        self.dirty.len()
+    }
+
+    /// Writes back all dirty blocks, then unwraps the underlying device.
+    pub fn into_inner(mut self) -> DevResult<D> {
+        self.write_back_all()?;
+        Ok(self.inner)
+    }
+
+    /// Checks an access of `len` bytes from `block_id`, and returns the number
+    /// of blocks.
+    fn check(&self, block_id: u64, len: usize) -> DevResult<u64> {
+        let block_size = self.inner.block_size();
+        if block_size == 0 {
+            return Err(DevError::Io);
+        }
+        if !len.is_multiple_of(block_size) {
+            return Err(DevError::InvalidParam);
+        }
+        let count = (len / block_size) as u64;
+        match block_id.checked_add(count) {
+            Some(end) if end <= self.inner.num_blocks() => Ok(count),
+            _ => Err(DevError::InvalidParam),
+        }
+    }
+
+    /// Writes back the run of adjacent dirty blocks containing `block_id`.
+    fn write_back_run(&mut self, block_id: u64) -> DevResult {
+        let mut start = block_id;
+        while start > 0 && self.dirty.contains_key(&(start - 1)) {
+            start -= 1;
+        }
+        let mut end = block_id + 1;
+        while self.dirty.contains_key(&end) {
+            end += 1;
+        }
+
+        let bufs: Vec<&[u8]> = self
+            .dirty
+            .range(start..end)
+            .map(|(_, b)| &b.data[..])
+            .collect();
+        self.inner.write_blocks_vectored(start, &bufs)?;
+        for id in start..end {
+            if let Some(block) = self.dirty.remove(&id) {
+                self.lru.remove(&block.last_write);
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes back all dirty blocks, in ascending order.
+    fn write_back_all(&mut self) -> DevResult {
+        while let Some((&block_id, _)) = self.dirty.first_key_value() {
+            self.write_back_run(block_id)?;
+        }
+        Ok(())
+    }
+
+    /// Drops the dirty blocks from `start` to `end` without writing them back.
+    fn drop_dirty(&mut self, start: u64, end: u64) {
+        let ids: Vec<u64> = self.dirty.range(start..end).map(|(&id, _)| id).collect();
+        for id in ids {
+            let block = self.dirty.remove(&id).unwrap();
+            self.lru.remove(&block.last_write);
+        }
+    }
+
+    /// Writes back all dirty blocks, in the order of their last write.
+    fn write_back_in_order(&mut self) -> DevResult {
+        while let Some((_, &block_id)) = self.lru.first_key_value() {
+            self.write_back_run(block_id)?;
+        }
+        Ok(())
+    }
+}
+
+impl<D: BlockDriverOps> BaseDriverOps for WriteBackCache<D> {
+    fn device_name(&self) -> &str {
+        self.inner.device_name()
+    }
+
+    fn device_type(&self) -> DeviceType {
+        self.inner.device_type()
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn stats(&self) -> DeviceStats {
+        self.inner.stats()
+    }
+}
+
+impl<D: BlockDriverOps> BlockDriverOps for WriteBackCache<D> {
+    #[inline]
+    fn num_blocks(&self) -> u64 {
+        self.inner.num_blocks()
+    }
+
+    #[inline]
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        let count = self.check(block_id, buf.len())?;
+        let block_size = self.inner.block_size();
+        let range = block_id..block_id + count;
+
+        // Read from the device only if some blocks are not in the cache
+        if self.dirty.range(range.clone()).count() as u64 != count {
+            self.inner.read_block(block_id, buf)?;
+        }
+        for (id, block) in self.dirty.range(range) {
+            let offset = (id - block_id) as usize * block_size;
+            buf[offset..offset + block_size].copy_from_slice(&block.data);
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        self.check(block_id, buf.len())?;
+        if self.capacity == 0 {
+            return self.inner.write_block(block_id, buf);
+        }
+
+        let block_size = self.inner.block_size();
+        for (i, data) in buf.chunks_exact(block_size).enumerate() {
+            let id = block_id + i as u64;
+            self.clock += 1;
+            if let Some(block) = self.dirty.get_mut(&id) {
+                block.data.copy_from_slice(data);
+                self.lru.remove(&block.last_write);
+                block.last_write = self.clock;
+            } else {
+                if self.dirty.len() == self.capacity {
+                    let (_, lru_id) = self.lru.first_key_value().unwrap();
+                    self.write_back_run(*lru_id)?;
+                }
+                let mut block = vec![0; block_size];
+                block.copy_from_slice(data);
+                self.dirty.insert(
+                    id,
+                    Dirty {
+                        data: block,
+                        last_write: self.clock,
+                    },
+                );
+            }
+            self.lru.insert(self.clock, id);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> DevResult {
+        self.write_back_all()?;
+        self.inner.flush()
+    }
+
+    /// Writes back all dirty blocks, oldest first, before the barrier of the
+    /// device, so that blocks written after it cannot be written back before
+    /// them.
+    fn write_barrier(&mut self) -> DevResult {
+        self.write_back_in_order()?;
+        self.inner.write_barrier()
+    }
+
+    /// Writes directly to the device, dropping the dirty blocks that it
+    /// overwrites.
+    fn write_block_fua(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        let count = self.check(block_id, buf.len())?;
+        self.inner.write_block_fua(block_id, buf)?;
+        self.drop_dirty(block_id, block_id + count);
+        Ok(())
+    }
+
+    fn discard(&mut self, block_id: u64, count: u64) -> DevResult {
+        let end = block_id.checked_add(count).ok_or(DevError::InvalidParam)?;
+        self.inner.discard(block_id, count)?;
+        self.drop_dirty(block_id, end);
+        Ok(())
+    }
+
+    fn discard_supported(&self) -> bool {
+        self.inner.discard_supported()
+    }
+
+    fn io_hints(&self) -> IoHints {
+        self.inner.io_hints()
+    }
+
+    fn is_rotational(&self) -> bool {
+        self.inner.is_rotational()
+    }
+}
+
+#[cfg(all(test, feature = "ramdisk"))]
+mod tests {
+    use super::*;
+    use crate::ramdisk::RamDisk;
+
+    fn read(dev: &mut impl BlockDriverOps, block_id: u64, count: usize) -> Vec<u8> {
+        let mut buf = vec![0; count * 512];
+        dev.read_block(block_id, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn reads_see_the_cached_writes() {
+        let mut cache = WriteBackCache::new(RamDisk::from_bytes(&[0xa5; 8 * 512], 512), 4);
+        cache.write_block(2, &[1; 2 * 512]).unwrap();
+        cache.write_block(3, &[2; 512]).unwrap();
+        assert_eq!(cache.dirty_blocks(), 2);
+        assert_eq!(cache.inner().stats().writes, 0);
+
+        // Fully cached, then partly cached reads
+        assert_eq!(read(&mut cache, 3, 1), [2; 512]);
+        let buf = read(&mut cache, 1, 4);
+        assert!(buf[..512].iter().all(|&b| b == 0xa5));
+        assert!(buf[512..1024].iter().all(|&b| b == 1));
+        assert!(buf[1024..1536].iter().all(|&b| b == 2));
+        assert!(buf[1536..].iter().all(|&b| b == 0xa5));
+
+        cache.flush().unwrap();
+        assert_eq!(cache.dirty_blocks(), 0);
+        assert_eq!(cache.inner().stats().bytes_written, 2 * 512);
+        let mut disk = cache.into_inner().unwrap();
+        assert_eq!(read(&mut disk, 1, 4), buf);
+    }
+
+    #[test]
+    fn the_least_recently_written_block_is_evicted() {
+        let mut cache = WriteBackCache::new(RamDisk::new(8, 512), 2);
+        cache.write_block(0, &[1; 512]).unwrap();
+        cache.write_block(4, &[2; 512]).unwrap();
+        cache.write_block(0, &[3; 512]).unwrap();
+        cache.write_block(6, &[4; 512]).unwrap();
+        assert_eq!(cache.dirty_blocks(), 2);
+        assert_eq!(cache.inner().stats().writes, 1);
+
+        let mut disk = cache.into_inner().unwrap();
+        assert_eq!(read(&mut disk, 0, 1), [3; 512]);
+        assert_eq!(read(&mut disk, 4, 1), [2; 512]);
+        assert_eq!(read(&mut disk, 6, 1), [4; 512]);
+    }
+
+    #[test]
+    fn fua_writes_and_discards_drop_the_cached_blocks() {
+        let mut cache = WriteBackCache::new(RamDisk::new(8, 512), 4);
+        cache.write_block(1, &[1; 2 * 512]).unwrap();
+        cache.write_block_fua(2, &[2; 512]).unwrap();
+        assert_eq!(cache.dirty_blocks(), 1);
+        assert_eq!(read(&mut cache, 1, 2)[512..], [2; 512]);
+        assert!(matches!(
+            cache.write_block(7, &[0; 2 * 512]),
+            Err(DevError::InvalidParam)
+        ));
+    }
+}
@@ -0,0 +1,88 @@
+//! Helpers for debugging block drivers.
+
+use core::fmt;
+
+/// Formats `buf` as a hex dump, 16 bytes per line, each line starting with
+/// its offset from `base_offset` and ending with the bytes as ASCII.
+///
+/// The dump is written as it is formatted, without allocating, e.g. in
+/// `log::debug!("{}", hexdump(&buf, 0))`. Lines are separated by `\n`,
+/// without one after the last line. Offsets past `u64::MAX` wrap around to 0.
+pub fn hexdump(buf: &[u8], base_offset: u64) -> impl fmt::Display + '_ {
+    HexDump { buf, base_offset }
+}
+
+struct HexDump<'a> {
+    buf: &'a [u8],
+    base_offset: u64,
+}
+
+impl fmt::Display for HexDump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const LINE: usize = 16;
+        for (i, line) in self.buf.chunks(LINE).enumerate() {
+            if i != 0 {
+                f.write_str("\n")?;
+            }
+            let offset = self.base_offset.wrapping_add((i * LINE) as u64);
+            write!(f, "{:08x} ", offset)?;
+            for j in 0..LINE {
+                if j % 8 == 0 {
+                    f.write_str(" ")?;
+                }
+                match line.get(j) {
+                    Some(byte) => write!(f, "{:02x} ", byte)?,
+                    None => f.write_str("   ")?,
+                }
+            }
+            f.write_str(" |")?;
+            for &byte in line {
+                let c = if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                };
+                write!(f, "{}", c)?;
+            }
+            f.write_str("|")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+
+    use super::*;
+    use alloc::format;
+
+    #[test]
+    fn lines_have_the_offset_the_bytes_and_the_ascii() {
+        let buf = *b"Hello, world!\0\x7f\n\x10\x11 ~";
+        assert_eq!(
+            format!("{}", hexdump(&buf, 0x100)),
+            "00000100  48 65 6c 6c 6f 2c 20 77  6f 72 6c 64 21 00 7f 0a  |Hello, world!...|\n\
+             00000110  10 11 20 7e                                       |.. ~|"
+        );
+    }
+
+    #[test]
+    fn dumps_have_no_trailing_newline() {
+        assert_eq!(format!("{}", hexdump(&[], 0)), "");
+        assert_eq!(
+            format!("{}", hexdump(&[0x41; 16], 0)),
+            "00000000  41 41 41 41 41 41 41 41  41 41 41 41 41 41 41 41  |AAAAAAAAAAAAAAAA|"
+        );
+    }
+
+    #[test]
+    fn offsets_wrap_around() {
+        let dump = format!("{}", hexdump(&[0; 33], u64::MAX - 15));
+        let mut offsets = dump.lines().map(|line| line.split(' ').next().unwrap());
+        assert_eq!(offsets.next(), Some("fffffffffffffff0"));
+        assert_eq!(offsets.next(), Some("00000000"));
+        assert_eq!(offsets.next(), Some("00000010"));
+        assert_eq!(offsets.next(), None);
+    }
+}
@@ -0,0 +1,205 @@
+//! Block devices backed by a file, for hosted environments.
+
+extern crate std;
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::BlockDriverOps;
+use axdriver_base::{BaseDriverOps, DevError, DevResult, DeviceType};
+
+/// A block device that stores data in a file (e.g., a disk image).
+pub struct FileDisk {
+    file: File,
+    block_size: usize,
+    num_blocks: u64,
+}
+
+impl FileDisk {
+    /// Opens the disk image at `path` for reading and writing.
+    ///
+    /// The size of the device is the size of the file rounded down to the
+    /// block size.
+    pub fn open<P: AsRef<Path>>(path: P, block_size: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Self::new(file, block_size)
+    }
+
+    /// Creates a block device backed by an opened file.
+    ///
+    /// The size of the device is the size of the file rounded down to the
+    /// block size.
+    pub fn new(file: File, block_size: usize) -> io::Result<Self> {
+        if block_size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "block size must not be 0",
+            ));
+        }
+        let num_blocks = file.metadata()?.len() / block_size as u64;
+        Ok(Self {
+            file,
+            block_size,
+            num_blocks,
+        })
+    }
+
+    /// Seeks to `block_id`, after checking an access of `len` bytes from it.
+    fn seek(&mut self, block_id: u64, len: usize) -> DevResult {
+        if !len.is_multiple_of(self.block_size) {
+            return Err(DevError::InvalidParam);
+        }
+        let count = (len / self.block_size) as u64;
+        if block_id
+            .checked_add(count)
+            .is_none_or(|end| end > self.num_blocks)
+        {
+            return Err(DevError::InvalidParam);
+        }
+        self.file
+            .seek(SeekFrom::Start(block_id * self.block_size as u64))
+            .map_err(as_dev_err)?;
+        Ok(())
+    }
+}
+
+impl BaseDriverOps for FileDisk {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+
+    fn device_name(&self) -> &str {
+        "file-disk"
+    }
+}
+
+impl BlockDriverOps for FileDisk {
+    #[inline]
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    #[inline]
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        self.seek(block_id, buf.len())?;
+        self.file.read_exact(buf).map_err(as_dev_err)
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        self.seek(block_id, buf.len())?;
+        self.file.write_all(buf).map_err(as_dev_err)
+    }
+
+    fn flush(&mut self) -> DevResult {
+        self.file.sync_all().map_err(as_dev_err)
+    }
+}
+
+fn as_dev_err(e: io::Error) -> DevError {
+    match e.kind() {
+        io::ErrorKind::PermissionDenied => DevError::Unsupported,
+        io::ErrorKind::OutOfMemory => DevError::NoMemory,
+        io::ErrorKind::TimedOut => DevError::Timeout,
+        io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => DevError::Again,
+        _ => DevError::Io,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::{format, fs, process, vec::Vec};
+
+    /// A file in the temporary directory, removed when dropped.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, len: usize) -> Self {
+            let path = std::env::temp_dir().join(format!("file-disk-{}-{name}", process::id()));
+            let content: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            fs::write(&path, content).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn blocks_are_read_back() {
+        let file = TempFile::new("round-trip", 4 * 16);
+        let mut disk = FileDisk::open(&file.0, 16).unwrap();
+        assert_eq!((disk.num_blocks(), disk.block_size()), (4, 16));
+
+        let mut buf = [0; 16];
+        disk.read_block(1, &mut buf).unwrap();
+        assert!(buf.iter().zip(16u8..).all(|(&b, i)| b == i));
+        disk.write_block(2, &[0xaa; 32]).unwrap();
+        disk.flush().unwrap();
+
+        let mut buf = [0; 48];
+        disk.read_block(1, &mut buf).unwrap();
+        assert_eq!(buf[0], 16);
+        assert!(buf[16..].iter().all(|&b| b == 0xaa));
+        let content = fs::read(&file.0).unwrap();
+        assert!(content[32..].iter().all(|&b| b == 0xaa));
+        assert_eq!(content[31], 31);
+    }
+
+    #[test]
+    fn partial_last_blocks_are_not_part_of_the_disk() {
+        let file = TempFile::new("partial", 2 * 16 + 10);
+        let mut disk = FileDisk::open(&file.0, 16).unwrap();
+        assert_eq!(disk.num_blocks(), 2);
+
+        let mut buf = [0; 16];
+        assert!(matches!(
+            disk.read_block(2, &mut buf),
+            Err(DevError::InvalidParam)
+        ));
+        assert!(matches!(
+            disk.write_block(1, &[0xaa; 32]),
+            Err(DevError::InvalidParam)
+        ));
+        disk.write_block(1, &[0xaa; 16]).unwrap();
+        let content = fs::read(&file.0).unwrap();
+        assert_eq!(content.len(), 2 * 16 + 10);
+        assert!(content[32..].iter().zip(32u8..).all(|(&b, i)| b == i));
+    }
+
+    #[test]
+    fn invalid_accesses_are_rejected() {
+        let file = TempFile::new("invalid", 4 * 16);
+        let mut disk = FileDisk::open(&file.0, 16).unwrap();
+        let mut buf = [0; 16];
+        for block_id in [4, u64::MAX] {
+            assert!(matches!(
+                disk.read_block(block_id, &mut buf),
+                Err(DevError::InvalidParam)
+            ));
+            assert!(matches!(
+                disk.write_block(block_id, &buf),
+                Err(DevError::InvalidParam)
+            ));
+        }
+        assert!(matches!(
+            disk.read_block(0, &mut buf[..10]),
+            Err(DevError::InvalidParam)
+        ));
+
+        let err = FileDisk::new(File::open(&file.0).unwrap(), 0)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(FileDisk::open(file.0.with_extension("missing"), 16).is_err());
+    }
+}
@@ -0,0 +1,366 @@
+//! Byte-granular access to block devices.
+
+extern crate alloc;
+
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use alloc::vec::Vec;
+use core::ptr::NonNull;
+
+use crate::{BlockDriverOps, DevError, DevResult};
+
+/// Default alignment of the scratch buffers of [`BlockReader`] and
+/// [`BlockWriter`].
+const SCRATCH_ALIGN: usize = 64;
+
+/// A zeroed buffer of a block, aligned for DMA, to be reused for partial-block
+/// reads and writes.
+pub struct ScratchBuffer {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+// SAFETY: The buffer is owned by `ScratchBuffer` like a `Box<[u8]>`: it is
+// only accessed through `&self` for reads and `&mut self` for writes.
+unsafe impl Send for ScratchBuffer {}
+unsafe impl Sync for ScratchBuffer {}
+
+/// Reads byte ranges of a block device, which need not be aligned to blocks.
+///
+/// The blocks that are only partially read go through a scratch buffer, the
+/// others are read directly into the buffer of the caller.
+pub struct BlockReader<'a, D: ?Sized> {
+    dev: &'a mut D,
+    scratch: ScratchBuffer,
+}
+
+/// Writes byte ranges of a block device, which need not be aligned to blocks.
+///
+/// The blocks that are only partially written are read, modified and written
+/// back, the others are written directly from the buffer of the caller.
+pub struct BlockWriter<'a, D: ?Sized> {
+    dev: &'a mut D,
+    scratch: ScratchBuffer,
+}
+
+/// Reads a block device from start to end, in chunks of a given number of
+/// blocks.
+pub struct BlockStream<'a, D: ?Sized> {
+    dev: &'a mut D,
+    chunk_blocks: u64,
+    next: u64,
+}
+
+/// A part of a byte range of a device, within a single block or made of
+/// whole blocks.
+struct Span {
+    block_id: u64,
+    /// Offset of the range in the first block.
+    offset: usize,
+    /// Offset of the part in the byte range.
+    start: usize,
+    len: usize,
+}
+
+/// Splits `len` bytes at `offset` into an unaligned head, whole blocks and an
+/// unaligned tail, after checking that they are within the device.
+fn spans<D: BlockDriverOps + ?Sized>(dev: &D, offset: u64, len: usize) -> DevResult<Vec<Span>> {
+    let end = offset
+        .checked_add(len as u64)
+        .ok_or(DevError::InvalidParam)?;
+    if end > dev.capacity_bytes() {
+        return Err(DevError::InvalidParam);
+    }
+    let block_size = dev.block_size() as u64;
+    if block_size == 0 {
+        return Err(DevError::Io);
+    }
+    let mut spans = Vec::with_capacity(3);
+    let mut pos = offset;
+    while pos < end {
+        let (block_id, in_block) = (pos / block_size, (pos % block_size) as usize);
+        let span_len = if in_block != 0 || end - pos < block_size {
+            (block_size - in_block as u64).min(end - pos)
+        } else {
+            (end - pos) / block_size * block_size
+        };
+        spans.push(Span {
+            block_id,
+            offset: in_block,
+            start: (pos - offset) as usize,
+            len: span_len as usize,
+        });
+        pos += span_len;
+    }
+    Ok(spans)
+}
+
+impl ScratchBuffer {
+    /// Allocates a buffer of `block_size` bytes, aligned to `align` bytes.
+    ///
+    /// Returns [`DevError::InvalidParam`] if `block_size` is 0 or `align` is
+    /// not a power of two, or [`DevError::NoMemory`] if the buffer cannot be
+    /// allocated.
+    pub fn new(block_size: usize, align: usize) -> DevResult<Self> {
+        if block_size == 0 {
+            return Err(DevError::InvalidParam);
+        }
+        let layout =
+            Layout::from_size_align(block_size, align).map_err(|_| DevError::InvalidParam)?;
+        // SAFETY: `layout` has a non-zero size.
+        let ptr = NonNull::new(unsafe { alloc_zeroed(layout) }).ok_or(DevError::NoMemory)?;
+        Ok(Self { ptr, layout })
+    }
+
+    /// Allocates a buffer of a block of `dev`, aligned to `align` bytes.
+    fn for_device<D: BlockDriverOps + ?Sized>(dev: &D, align: usize) -> DevResult<Self> {
+        match dev.block_size() {
+            0 => Err(DevError::Io),
+            block_size => Self::new(block_size, align),
+        }
+    }
+
+    /// The size of the buffer in bytes.
+    pub const fn len(&self) -> usize {
+        self.layout.size()
+    }
+
+    /// Returns `false`, the buffer is never empty.
+    pub const fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// The contents of the buffer.
+    pub fn as_slice(&self) -> &[u8] {
+        // SAFETY: `ptr` points to `len()` initialized bytes that live as long
+        // as `self`, and nothing can write them while `self` is borrowed.
+        unsafe { core::slice::from_raw_parts(self.ptr.as_ptr(), self.len()) }
+    }
+
+    /// The contents of the buffer, for reading into it.
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        // SAFETY: as in `as_slice`, and `&mut self` makes the access
+        // exclusive.
+        unsafe { core::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len()) }
+    }
+}
+
+impl Drop for ScratchBuffer {
+    fn drop(&mut self) {
+        // SAFETY: `ptr` was allocated with `layout` in `new`.
+        unsafe { dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
+
+impl<'a, D: BlockDriverOps + ?Sized> BlockReader<'a, D> {
+    /// Creates a reader of `dev`, with a scratch buffer aligned to 64 bytes.
+    ///
+    /// Returns [`DevError::Io`] if the block size of `dev` is 0, or
+    /// [`DevError::NoMemory`] if the scratch buffer cannot be allocated.
+    pub fn new(dev: &'a mut D) -> DevResult<Self> {
+        Self::with_alignment(dev, SCRATCH_ALIGN)
+    }
+
+    /// Creates a reader of `dev`, with a scratch buffer aligned to `align`
+    /// bytes, for devices whose DMA needs a larger alignment.
+    ///
+    /// Returns [`DevError::InvalidParam`] if `align` is not a power of two,
+    /// and fails like [`BlockReader::new`] otherwise.
+    pub fn with_alignment(dev: &'a mut D, align: usize) -> DevResult<Self> {
+        let scratch = ScratchBuffer::for_device(&*dev, align)?;
+        Ok(Self { dev, scratch })
+    }
+
+    /// Reads `buf.len()` bytes starting from byte `offset` of the device.
+    ///
+    /// Returns [`DevError::InvalidParam`] if the range exceeds the device.
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> DevResult {
+        for span in spans(self.dev, offset, buf.len())? {
+            let out = &mut buf[span.start..span.start + span.len];
+            if span.len % self.scratch.len() == 0 && span.offset == 0 {
+                self.dev.read_block(span.block_id, out)?;
+            } else {
+                self.dev
+                    .read_block(span.block_id, self.scratch.as_mut_slice())?;
+                out.copy_from_slice(&self.scratch.as_slice()[span.offset..span.offset + span.len]);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a, D: BlockDriverOps + ?Sized> BlockWriter<'a, D> {
+    /// Creates a writer of `dev`, with a scratch buffer aligned to 64 bytes.
+    ///
+    /// Returns [`DevError::Io`] if the block size of `dev` is 0, or
+    /// [`DevError::NoMemory`] if the scratch buffer cannot be allocated.
+    pub fn new(dev: &'a mut D) -> DevResult<Self> {
+        Self::with_alignment(dev, SCRATCH_ALIGN)
+    }
+
+    /// Creates a writer of `dev`, with a scratch buffer aligned to `align`
+    /// bytes, for devices whose DMA needs a larger alignment.
+    ///
+    /// Returns [`DevError::InvalidParam`] if `align` is not a power of two,
+    /// and fails like [`BlockWriter::new`] otherwise.
+    pub fn with_alignment(dev: &'a mut D, align: usize) -> DevResult<Self> {
+        let scratch = ScratchBuffer::for_device(&*dev, align)?;
+        Ok(Self { dev, scratch })
+    }
+
+    /// Writes `buf` starting from byte `offset` of the device.
+    ///
+    /// Returns [`DevError::InvalidParam`] if the range exceeds the device.
+    /// If it fails, a part of `buf` may have been written.
+    pub fn write_at(&mut self, offset: u64, buf: &[u8]) -> DevResult {
+        for span in spans(self.dev, offset, buf.len())? {
+            let data = &buf[span.start..span.start + span.len];
+            if span.len % self.scratch.len() == 0 && span.offset == 0 {
+                self.dev.write_block(span.block_id, data)?;
+            } else {
+                self.dev
+                    .read_block(span.block_id, self.scratch.as_mut_slice())?;
+                self.scratch.as_mut_slice()[span.offset..span.offset + span.len]
+                    .copy_from_slice(data);
+                self.dev
+                    .write_block(span.block_id, self.scratch.as_slice())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a, D: BlockDriverOps + ?Sized> BlockStream<'a, D> {
+    /// Creates a stream of `dev` from block 0, reading `chunk_blocks` blocks
+    /// at a time.
+    ///
+    /// A `chunk_blocks` of 0 is rounded up to 1 block.
+    pub fn new(dev: &'a mut D, chunk_blocks: u64) -> Self {
+        Self {
+            dev,
+            chunk_blocks: chunk_blocks.max(1),
+            next: 0,
+        }
+    }
+
+    /// The block where the next chunk starts.
+    pub fn position(&self) -> u64 {
+        self.next
+    }
+
+    /// Reads the next chunk into the start of `buf`, and returns its size in
+    /// bytes, or `None` at the end of the device.
+    ///
+    /// A chunk is `chunk_blocks` blocks, or fewer if `buf` cannot hold them or
+    /// the device ends first. Returns [`DevError::InvalidParam`] if not even a
+    /// block can be read. If the read fails, the next call reads the same
+    /// chunk again.
+    pub fn next_chunk(&mut self, buf: &mut [u8]) -> DevResult<Option<usize>> {
+        let block_size = self.dev.block_size();
+        if block_size == 0 {
+            return Err(DevError::Io);
+        }
+        let remaining = self.dev.num_blocks().saturating_sub(self.next);
+        if remaining == 0 {
+            return Ok(None);
+        }
+        let blocks = self
+            .chunk_blocks
+            .min((buf.len() / block_size) as u64)
+            .min(remaining);
+        if blocks == 0 {
+            return Err(DevError::InvalidParam);
+        }
+        let len = blocks as usize * block_size;
+        self.dev.read_block(self.next, &mut buf[..len])?;
+        self.next += blocks;
+        Ok(Some(len))
+    }
+}
+
+#[cfg(all(test, feature = "ramdisk"))]
+mod tests {
+    use super::*;
+    use crate::ramdisk::RamDisk;
+
+    fn pattern_disk(num_blocks: u64) -> RamDisk {
+        let data: Vec<u8> = (0..num_blocks as usize * 512).map(|i| i as u8).collect();
+        RamDisk::from_bytes(&data, 512)
+    }
+
+    #[test]
+    fn stream_clamps_the_last_chunk() {
+        let mut disk = pattern_disk(5);
+        let mut stream = BlockStream::new(&mut disk, 2);
+        let mut buf = [0; 4 * 512];
+        let (mut read, mut lens) = (Vec::new(), Vec::new());
+        while let Some(len) = stream.next_chunk(&mut buf).unwrap() {
+            read.extend_from_slice(&buf[..len]);
+            lens.push(len);
+        }
+        assert_eq!(stream.position(), 5);
+        assert_eq!(lens, [1024, 1024, 512]);
+        assert!(read.iter().enumerate().all(|(i, &b)| b == i as u8));
+    }
+
+    #[test]
+    fn stream_rounds_empty_chunks_up() {
+        let mut disk = pattern_disk(3);
+        let mut stream = BlockStream::new(&mut disk, 0);
+        let mut buf = [0; 2 * 512];
+        assert_eq!(stream.next_chunk(&mut buf).unwrap(), Some(512));
+        assert_eq!(stream.position(), 1);
+        assert!(matches!(
+            stream.next_chunk(&mut buf[..511]),
+            Err(DevError::InvalidParam)
+        ));
+        assert_eq!(stream.position(), 1);
+    }
+
+    #[test]
+    fn scratch_buffer_is_aligned_and_zeroed() {
+        let mut scratch = ScratchBuffer::new(520, 4096).unwrap();
+        assert_eq!(scratch.len(), 520);
+        assert_eq!(scratch.as_slice().as_ptr() as usize % 4096, 0);
+        assert!(scratch.as_slice().iter().all(|&b| b == 0));
+        scratch.as_mut_slice()[519] = 1;
+        assert_eq!(scratch.as_slice()[519], 1);
+        assert!(matches!(
+            ScratchBuffer::new(0, 64),
+            Err(DevError::InvalidParam)
+        ));
+        assert!(matches!(
+            ScratchBuffer::new(512, 48),
+            Err(DevError::InvalidParam)
+        ));
+    }
+
+    #[test]
+    fn unaligned_io_goes_through_an_aligned_scratch_buffer() {
+        let mut disk = pattern_disk(4);
+        let mut buf = [0; 700];
+        BlockReader::with_alignment(&mut disk, 4096)
+            .unwrap()
+            .read_at(300, &mut buf)
+            .unwrap();
+        assert!(buf.iter().enumerate().all(|(i, &b)| b == (i + 300) as u8));
+
+        let mut writer = BlockWriter::with_alignment(&mut disk, 4096).unwrap();
+        assert_eq!(writer.scratch.as_slice().as_ptr() as usize % 4096, 0);
+        writer.write_at(1000, &[0xff; 100]).unwrap();
+        let mut block = [0; 512];
+        disk.read_block(1, &mut block).unwrap();
+        for (i, &b) in block.iter().enumerate() {
+            let pos = 512 + i;
+            let expected = if (1000..1100).contains(&pos) {
+                0xff
+            } else {
+                pos as u8
+            };
+            assert_eq!(b, expected);
+        }
+        assert!(matches!(
+            BlockReader::with_alignment(&mut disk, 3),
+            Err(DevError::InvalidParam)
+        ));
+    }
+}
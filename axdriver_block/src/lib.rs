@@ -3,27 +3,119 @@
 #![no_std]
 #![cfg_attr(doc, feature(doc_auto_cfg))]
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
 #[cfg(feature = "ramdisk")]
 pub mod ramdisk;
 
+#[cfg(all(feature = "ramdisk", feature = "alloc"))]
+pub mod ring;
+
+#[cfg(feature = "std")]
+pub mod file;
+
 #[cfg(feature = "bcm2835-sdhci")]
 pub mod bcm2835sdhci;
 
-#[cfg(feature = "ahci_driver")]
+#[cfg(feature = "ahci")]
 pub mod ahci;
 
+#[cfg(feature = "alloc")]
+pub mod cache;
+pub mod debug;
+#[cfg(feature = "alloc")]
+pub mod io;
+pub mod partition;
+#[cfg(feature = "alloc")]
+pub mod raid;
+#[cfg(feature = "alloc")]
+pub mod readahead;
+pub mod retry;
+#[cfg(feature = "alloc")]
+pub mod verify;
+
+#[cfg(feature = "alloc")]
+mod read_only;
+
+#[cfg(feature = "async")]
+mod async_ops;
+
 #[doc(no_inline)]
-pub use axdriver_base::{BaseDriverOps, DevError, DevResult, DeviceType};
+pub use axdriver_base::{
+    BaseDriverOps, DevError, DevResult, DeviceCapabilities, DeviceStats, DeviceType, HotplugDriver,
+    HotplugEvent, IrqDriver,
+};
+
+pub use self::partition::Partition;
+#[cfg(feature = "alloc")]
+pub use self::read_only::ReadOnly;
+
+#[cfg(feature = "async")]
+pub use self::async_ops::{AsyncBlockDriverOps, Blocking};
+
+/// Preferred I/O sizes of a block storage device, all in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoHints {
+    /// The smallest I/O size that avoids a read-modify-write on the device,
+    /// e.g. the physical sector size.
+    pub min_io_size: usize,
+    /// The I/O size that gives the best throughput, e.g. the largest size of
+    /// a single hardware command.
+    pub optimal_io_size: usize,
+    /// The largest write that the device performs atomically, or `None` if
+    /// the device does not guarantee it.
+    pub max_atomic_write: Option<usize>,
+}
+
+/// Size of the buffer of zeros written by the default
+/// [`BlockDriverOps::write_zeros`].
+#[cfg(feature = "alloc")]
+const ZEROS_BUF_SIZE: usize = 64 * 1024;
+
+/// Size of the static buffer of zeros written by the default
+/// [`BlockDriverOps::write_zeros`] without the `alloc` feature, which bounds
+/// the block size that it supports.
+#[cfg(not(feature = "alloc"))]
+const ZEROS_BUF_SIZE: usize = 4096;
 
 /// Operations that require a block storage device driver to implement.
 pub trait BlockDriverOps: BaseDriverOps {
     /// The number of blocks in this storage device.
     ///
-    /// The total size of the device is `num_blocks() * block_size()`.
+    /// The total size of the device is given by
+    /// [`BlockDriverOps::capacity_bytes`].
     fn num_blocks(&self) -> u64;
     /// The size of each block in bytes.
+    ///
+    /// It may be 0 if the device is not ready, e.g. not identified yet, in
+    /// which case reads and writes fail with [`DevError::Io`].
     fn block_size(&self) -> usize;
 
+    /// The total size of this storage device in bytes.
+    ///
+    /// # Examples
+    ///
+    /// Reading the whole device into memory:
+    ///
+    /// ```
+    /// use axdriver_block::{BlockDriverOps, DevResult};
+    ///
+    /// fn read_all(dev: &mut impl BlockDriverOps) -> DevResult<Vec<u8>> {
+    ///     let mut buf = vec![0; dev.capacity_bytes() as usize];
+    ///     dev.read_block(0, &mut buf)?;
+    ///     Ok(buf)
+    /// }
+    /// ```
+    fn capacity_bytes(&self) -> u64 {
+        self.num_blocks() * self.block_size() as u64
+    }
+
+    /// Whether this storage device has no blocks.
+    fn is_empty(&self) -> bool {
+        self.num_blocks() == 0
+    }
+
     /// Reads blocked data from the given block.
     ///
     /// The size of the buffer may exceed the block size, in which case multiple
@@ -36,6 +128,302 @@ pub trait BlockDriverOps: BaseDriverOps {
     /// contiguous blocks will be written.
     fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult;
 
+    /// Reads blocked data from the given block, stopping early if the device
+    /// does.
+    ///
+    /// Returns the number of bytes read, which is a multiple of the block
+    /// size. The default implementation calls [`BlockDriverOps::read_block`],
+    /// so it reads either all of `buf` or fails.
+    fn read_block_partial(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult<usize> {
+        self.read_block(block_id, buf)?;
+        Ok(buf.len())
+    }
+
+    /// Writes blocked data to the given block, stopping early if the device
+    /// does.
+    ///
+    /// Returns the number of bytes written, which is a multiple of the block
+    /// size. The default implementation calls [`BlockDriverOps::write_block`],
+    /// so it writes either all of `buf` or fails.
+    fn write_block_partial(&mut self, block_id: u64, buf: &[u8]) -> DevResult<usize> {
+        self.write_block(block_id, buf)?;
+        Ok(buf.len())
+    }
+
+    /// Like [`BlockDriverOps::read_block`], but returns [`DevError::Again`]
+    /// instead of waiting if the device cannot take the request now, e.g.
+    /// when all its command slots are used by queued commands.
+    ///
+    /// There is no dedicated "busy" error: like `EAGAIN` for non-blocking
+    /// file descriptors, [`DevError::Again`] tells that nothing was done and
+    /// that the same request can be retried later, which is not an I/O
+    /// failure. [`DevError::ResourceBusy`] is for blocking operations.
+    ///
+    /// The default implementation calls [`BlockDriverOps::read_block`].
+    fn try_read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        self.read_block(block_id, buf)
+    }
+
+    /// Like [`BlockDriverOps::write_block`], but returns [`DevError::Again`]
+    /// instead of waiting if the device cannot take the request now.
+    ///
+    /// The default implementation calls [`BlockDriverOps::write_block`].
+    fn try_write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        self.write_block(block_id, buf)
+    }
+
+    /// Reads contiguous blocks starting from the given block into multiple
+    /// buffers, which are filled in order.
+    ///
+    /// The total size of the buffers must be a multiple of the block size. The
+    /// default implementation calls [`BlockDriverOps::read_block`] for each
+    /// buffer, so it also requires every buffer to be a multiple of the block
+    /// size.
+    fn read_blocks_vectored(&mut self, block_id: u64, bufs: &mut [&mut [u8]]) -> DevResult {
+        let block_size = self.block_size();
+        if block_size == 0 {
+            return Err(DevError::Io);
+        }
+        if bufs.iter().any(|buf| !buf.len().is_multiple_of(block_size)) {
+            return Err(DevError::InvalidParam);
+        }
+        let mut block_id = block_id;
+        for buf in bufs {
+            self.read_block(block_id, buf)?;
+            block_id += (buf.len() / block_size) as u64;
+        }
+        Ok(())
+    }
+
+    /// Writes multiple buffers, in order, to contiguous blocks starting from
+    /// the given block.
+    ///
+    /// The total size of the buffers must be a multiple of the block size. The
+    /// default implementation calls [`BlockDriverOps::write_block`] for each
+    /// buffer, so it also requires every buffer to be a multiple of the block
+    /// size.
+    fn write_blocks_vectored(&mut self, block_id: u64, bufs: &[&[u8]]) -> DevResult {
+        let block_size = self.block_size();
+        if block_size == 0 {
+            return Err(DevError::Io);
+        }
+        if bufs.iter().any(|buf| !buf.len().is_multiple_of(block_size)) {
+            return Err(DevError::InvalidParam);
+        }
+        let mut block_id = block_id;
+        for buf in bufs {
+            self.write_block(block_id, buf)?;
+            block_id += (buf.len() / block_size) as u64;
+        }
+        Ok(())
+    }
+
     /// Flushes the device to write all pending data to the storage.
+    ///
+    /// When it returns successfully, every write that completed before the
+    /// call is on stable storage, even if the device has a volatile write
+    /// cache. Writes issued after it returns are never written before it.
     fn flush(&mut self) -> DevResult;
+
+    /// Orders the writes: every write that completed before the call reaches
+    /// the storage before any write issued after it.
+    ///
+    /// Unlike [`BlockDriverOps::flush`], the writes need not be on stable
+    /// storage when it returns. The default implementation calls
+    /// [`BlockDriverOps::flush`], which is sufficient.
+    fn write_barrier(&mut self) -> DevResult {
+        self.flush()
+    }
+
+    /// Writes blocked data to the given block, and only returns once it is on
+    /// stable storage.
+    ///
+    /// Other pending writes are not flushed. The default implementation calls
+    /// [`BlockDriverOps::write_block`] then [`BlockDriverOps::flush`], devices
+    /// with [`DeviceCapabilities::FUA`] do it with a single write.
+    fn write_block_fua(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        self.write_block(block_id, buf)?;
+        self.flush()
+    }
+
+    /// Tells the device that `count` blocks starting from `block_id` are no
+    /// longer in use (e.g., TRIM for SSDs).
+    ///
+    /// Returns [`DevError::InvalidParam`] if the range exceeds
+    /// [`BlockDriverOps::num_blocks`], and [`DevError::Unsupported`] if the
+    /// device does not support it. An empty range always succeeds.
+    fn discard(&mut self, block_id: u64, count: u64) -> DevResult {
+        if block_id
+            .checked_add(count)
+            .is_none_or(|end| end > self.num_blocks())
+        {
+            return Err(DevError::InvalidParam);
+        }
+        if count == 0 {
+            return Ok(());
+        }
+        Err(DevError::Unsupported)
+    }
+
+    /// Whether [`BlockDriverOps::discard`] is supported.
+    fn discard_supported(&self) -> bool {
+        false
+    }
+
+    /// Whether the media is rotational (e.g., a hard disk), so that seeks
+    /// are expensive, rather than solid-state.
+    ///
+    /// The default implementation returns `true`, the safe assumption when
+    /// it is unknown.
+    fn is_rotational(&self) -> bool {
+        true
+    }
+
+    /// Fills `count` blocks starting from `block_id` with zeros.
+    ///
+    /// Returns [`DevError::InvalidParam`] if the range exceeds
+    /// [`BlockDriverOps::num_blocks`]. The default implementation writes a
+    /// buffer of zeros with [`BlockDriverOps::write_block`], drivers override
+    /// it if the device can zero blocks by itself. Without the `alloc`
+    /// feature, it returns [`DevError::Unsupported`] for blocks larger than
+    /// 4 KiB.
+    fn write_zeros(&mut self, block_id: u64, count: u64) -> DevResult {
+        write_zeros_by_writes(self, block_id, count)
+    }
+
+    /// The preferred I/O sizes of the device.
+    ///
+    /// The default implementation reports the block size for the I/O sizes,
+    /// and no atomic writes.
+    fn io_hints(&self) -> IoHints {
+        let block_size = self.block_size();
+        IoHints {
+            min_io_size: block_size,
+            optimal_io_size: block_size,
+            max_atomic_write: None,
+        }
+    }
+}
+
+/// Implements [`BlockDriverOps::write_zeros`] with plain writes, for drivers
+/// that only zero blocks by themselves in some cases.
+pub(crate) fn write_zeros_by_writes<D: BlockDriverOps + ?Sized>(
+    dev: &mut D,
+    block_id: u64,
+    count: u64,
+) -> DevResult {
+    let end = block_id
+        .checked_add(count)
+        .filter(|&end| end <= dev.num_blocks())
+        .ok_or(DevError::InvalidParam)?;
+    if count == 0 {
+        return Ok(());
+    }
+    let block_size = dev.block_size();
+    if block_size == 0 {
+        return Err(DevError::Io);
+    }
+    let buf_blocks = ((ZEROS_BUF_SIZE / block_size).max(1) as u64).min(count);
+    #[cfg(feature = "alloc")]
+    let zeros = alloc::vec![0; buf_blocks as usize * block_size];
+    #[cfg(not(feature = "alloc"))]
+    let zeros = {
+        static ZEROS: [u8; ZEROS_BUF_SIZE] = [0; ZEROS_BUF_SIZE];
+        if block_size > ZEROS_BUF_SIZE {
+            return Err(DevError::Unsupported);
+        }
+        &ZEROS
+    };
+    let mut block_id = block_id;
+    while block_id < end {
+        let n = buf_blocks.min(end - block_id);
+        dev.write_block(block_id, &zeros[..n as usize * block_size])?;
+        block_id += n;
+    }
+    Ok(())
+}
+
+/// A block storage device, usable as a trait object.
+///
+/// It is implemented for every [`BlockDriverOps`], so that devices of
+/// different types can be stored together:
+///
+/// ```
+/// use axdriver_block::BoxedBlockDevice;
+///
+/// fn total_capacity(devices: &[BoxedBlockDevice]) -> u64 {
+///     devices.iter().map(|dev| dev.capacity_bytes()).sum()
+/// }
+/// ```
+pub trait BlockDevice: BlockDriverOps {}
+
+impl<T: BlockDriverOps + ?Sized> BlockDevice for T {}
+
+/// An owned, type-erased block storage device.
+#[cfg(feature = "alloc")]
+pub type BoxedBlockDevice = alloc::boxed::Box<dyn BlockDevice + Send>;
+
+#[cfg(all(test, feature = "ramdisk", feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::ramdisk::RamDisk;
+
+    #[test]
+    fn default_discard_empty_range_is_a_no_op() {
+        let mut disk = RamDisk::new(8, 512);
+        assert!(!disk.discard_supported());
+        assert!(matches!(disk.discard(3, 0), Ok(())));
+        assert!(matches!(disk.discard(8, 0), Ok(())));
+        assert!(matches!(disk.discard(0, 1), Err(DevError::Unsupported)));
+        assert!(matches!(disk.discard(9, 0), Err(DevError::InvalidParam)));
+        assert!(matches!(
+            disk.discard(u64::MAX, 2),
+            Err(DevError::InvalidParam)
+        ));
+    }
+
+    #[test]
+    fn default_write_zeros_clears_the_range() {
+        let mut disk = RamDisk::from_bytes(&[0xa5; 4 * 512], 512);
+        assert!(matches!(write_zeros_by_writes(&mut disk, 2, 0), Ok(())));
+        assert!(matches!(write_zeros_by_writes(&mut disk, 1, 2), Ok(())));
+        let mut buf = [0; 4 * 512];
+        disk.read_block(0, &mut buf).unwrap();
+        assert!(buf[..512].iter().all(|&b| b == 0xa5));
+        assert!(buf[512..3 * 512].iter().all(|&b| b == 0));
+        assert!(buf[3 * 512..].iter().all(|&b| b == 0xa5));
+        assert!(matches!(
+            write_zeros_by_writes(&mut disk, 3, 2),
+            Err(DevError::InvalidParam)
+        ));
+    }
+
+    #[test]
+    fn wrappers_forward_the_stats_of_their_device() {
+        let mut disk = RamDisk::new(8, 512);
+        let mut buf = [0; 1024];
+        disk.write_block(0, &buf).unwrap();
+        assert!(disk.read_block(8, &mut buf).is_err());
+        let expected = DeviceStats {
+            writes: 1,
+            bytes_written: 1024,
+            read_errors: 1,
+            ..DeviceStats::default()
+        };
+        assert_eq!(disk.stats(), expected);
+
+        let mut dev = Partition::new(
+            verify::CrcGuard::new(cache::WriteBackCache::new(ReadOnly::new(disk), 4)),
+            2,
+            4,
+        )
+        .unwrap();
+        dev.read_block(0, &mut buf).unwrap();
+        let expected = DeviceStats {
+            reads: 1,
+            bytes_read: 1024,
+            ..expected
+        };
+        assert_eq!(dev.stats(), expected);
+    }
 }
@@ -0,0 +1,446 @@
+//! Partitions of block devices, and discovery of the MBR/GPT partition
+//! tables.
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+#[cfg(feature = "alloc")]
+use alloc::{vec, vec::Vec};
+#[cfg(feature = "alloc")]
+use axdriver_base::trace;
+
+use crate::{
+    BaseDriverOps, BlockDriverOps, DevError, DevResult, DeviceCapabilities, DeviceStats,
+    DeviceType, IoHints,
+};
+
+/// Size of the MBR, at the start of block 0.
+#[cfg(feature = "alloc")]
+const MBR_SIZE: usize = 512;
+/// Offset of the partition table in the MBR.
+#[cfg(feature = "alloc")]
+const MBR_TABLE_OFFSET: usize = 446;
+/// Number of (primary) partition entries in the MBR.
+#[cfg(feature = "alloc")]
+const MBR_ENTRIES: usize = 4;
+/// Size of an MBR partition entry.
+#[cfg(feature = "alloc")]
+const MBR_ENTRY_SIZE: usize = 16;
+/// Boot signature at the end of the MBR.
+#[cfg(feature = "alloc")]
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+/// MBR partition type of a GPT protective partition.
+#[cfg(feature = "alloc")]
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xee;
+
+/// Signature at the start of the GPT header.
+#[cfg(feature = "alloc")]
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+/// Minimum size of a GPT partition entry.
+#[cfg(feature = "alloc")]
+const GPT_MIN_ENTRY_SIZE: usize = 128;
+/// Upper bound on the number of GPT partition entries that are read.
+#[cfg(feature = "alloc")]
+const GPT_MAX_ENTRIES: usize = 1024;
+
+/// A contiguous range of blocks of a device, exposed as a block device of its
+/// own.
+///
+/// Block IDs are relative to the start of the partition, and accesses beyond
+/// its end fail with [`DevError::InvalidParam`].
+pub struct Partition<D> {
+    inner: D,
+    start_block: u64,
+    block_count: u64,
+}
+
+impl<D: BlockDriverOps> Partition<D> {
+    /// Creates a partition of `block_count` blocks starting from
+    /// `start_block` on the device.
+    ///
+    /// Returns [`DevError::InvalidParam`] if it does not fit in the device.
+    pub fn new(inner: D, start_block: u64, block_count: u64) -> DevResult<Self> {
+        match start_block.checked_add(block_count) {
+            Some(end) if end <= inner.num_blocks() => Ok(Self {
+                inner,
+                start_block,
+                block_count,
+            }),
+            _ => Err(DevError::InvalidParam),
+        }
+    }
+
+    /// The first block of the partition on the underlying device.
+    pub const fn start_block(&self) -> u64 {
+        self.start_block
+    }
+
+    /// Returns a reference to the underlying device.
+    pub const fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// Unwraps the underlying device.
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    /// Translates an access of `count` blocks starting from `block_id` to a
+    /// block ID of the underlying device.
+    fn translate(&self, block_id: u64, count: u64) -> DevResult<u64> {
+        match block_id.checked_add(count) {
+            Some(end) if end <= self.block_count => Ok(self.start_block + block_id),
+            _ => Err(DevError::InvalidParam),
+        }
+    }
+
+    /// The number of blocks covered by `len` bytes.
+    fn blocks_of(&self, len: usize) -> DevResult<u64> {
+        let block_size = self.inner.block_size();
+        if block_size == 0 {
+            return Err(DevError::Io);
+        }
+        if !len.is_multiple_of(block_size) {
+            return Err(DevError::InvalidParam);
+        }
+        Ok((len / block_size) as u64)
+    }
+}
+
+impl<D: BlockDriverOps> BaseDriverOps for Partition<D> {
+    fn device_name(&self) -> &str {
+        self.inner.device_name()
+    }
+
+    fn device_type(&self) -> DeviceType {
+        self.inner.device_type()
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn stats(&self) -> DeviceStats {
+        self.inner.stats()
+    }
+}
+
+impl<D: BlockDriverOps> BlockDriverOps for Partition<D> {
+    #[inline]
+    fn num_blocks(&self) -> u64 {
+        self.block_count
+    }
+
+    #[inline]
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        let block_id = self.translate(block_id, self.blocks_of(buf.len())?)?;
+        self.inner.read_block(block_id, buf)
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        let block_id = self.translate(block_id, self.blocks_of(buf.len())?)?;
+        self.inner.write_block(block_id, buf)
+    }
+
+    fn try_read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        let block_id = self.translate(block_id, self.blocks_of(buf.len())?)?;
+        self.inner.try_read_block(block_id, buf)
+    }
+
+    fn try_write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        let block_id = self.translate(block_id, self.blocks_of(buf.len())?)?;
+        self.inner.try_write_block(block_id, buf)
+    }
+
+    fn read_blocks_vectored(&mut self, block_id: u64, bufs: &mut [&mut [u8]]) -> DevResult {
+        let len = bufs.iter().map(|buf| buf.len()).sum();
+        let block_id = self.translate(block_id, self.blocks_of(len)?)?;
+        self.inner.read_blocks_vectored(block_id, bufs)
+    }
+
+    fn write_blocks_vectored(&mut self, block_id: u64, bufs: &[&[u8]]) -> DevResult {
+        let len = bufs.iter().map(|buf| buf.len()).sum();
+        let block_id = self.translate(block_id, self.blocks_of(len)?)?;
+        self.inner.write_blocks_vectored(block_id, bufs)
+    }
+
+    fn flush(&mut self) -> DevResult {
+        self.inner.flush()
+    }
+
+    fn write_barrier(&mut self) -> DevResult {
+        self.inner.write_barrier()
+    }
+
+    fn write_block_fua(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        let block_id = self.translate(block_id, self.blocks_of(buf.len())?)?;
+        self.inner.write_block_fua(block_id, buf)
+    }
+
+    fn discard(&mut self, block_id: u64, count: u64) -> DevResult {
+        let block_id = self.translate(block_id, count)?;
+        self.inner.discard(block_id, count)
+    }
+
+    fn discard_supported(&self) -> bool {
+        self.inner.discard_supported()
+    }
+
+    fn write_zeros(&mut self, block_id: u64, count: u64) -> DevResult {
+        let block_id = self.translate(block_id, count)?;
+        self.inner.write_zeros(block_id, count)
+    }
+
+    fn io_hints(&self) -> IoHints {
+        self.inner.io_hints()
+    }
+
+    fn is_rotational(&self) -> bool {
+        self.inner.is_rotational()
+    }
+}
+
+/// Type of a partition, as recorded in the partition table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionType {
+    /// MBR partition type byte.
+    Mbr(u8),
+    /// GPT partition type GUID, in its on-disk (mixed-endian) byte order.
+    Gpt([u8; 16]),
+}
+
+/// A partition found by [`scan_partitions`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartitionEntry {
+    /// The first block of the partition.
+    pub start_lba: u64,
+    /// The number of blocks in the partition.
+    pub num_blocks: u64,
+    /// The partition type.
+    pub kind: PartitionType,
+}
+
+impl PartitionEntry {
+    /// Exposes this partition of `dev` as a block device.
+    pub fn open<D: BlockDriverOps>(&self, dev: D) -> DevResult<Partition<D>> {
+        Partition::new(dev, self.start_lba, self.num_blocks)
+    }
+}
+
+/// Reads the partition table of a block device.
+///
+/// A GPT is read if block 0 holds a protective MBR, otherwise the primary
+/// partitions of the MBR are returned (extended partitions are not followed).
+/// LBAs are in units of [`BlockDriverOps::block_size`], as on 4Kn disks.
+/// Returns an empty vector if the disk is not partitioned.
+#[cfg(feature = "alloc")]
+pub fn scan_partitions<D: BlockDriverOps>(dev: &mut D) -> DevResult<Vec<PartitionEntry>> {
+    let block_size = dev.block_size();
+    if block_size == 0 {
+        return Err(DevError::Io);
+    }
+    let mut mbr = vec![0; MBR_SIZE.div_ceil(block_size) * block_size];
+    dev.read_block(0, &mut mbr)?;
+    if mbr[MBR_SIZE - 2..MBR_SIZE] != MBR_SIGNATURE {
+        return Ok(Vec::new());
+    }
+
+    let mut parts = Vec::new();
+    for i in 0..MBR_ENTRIES {
+        let entry = &mbr[MBR_TABLE_OFFSET + i * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        let kind = entry[4];
+        if kind == MBR_TYPE_GPT_PROTECTIVE {
+            return scan_gpt(dev);
+        }
+        let start_lba = u32::from_le_bytes(entry[8..12].try_into().unwrap()) as u64;
+        let num_blocks = u32::from_le_bytes(entry[12..16].try_into().unwrap()) as u64;
+        // Boot sectors of unpartitioned volumes (e.g., FAT) have the same
+        // signature, their "partition table" is garbage
+        if entry[0] & 0x7f != 0 || start_lba + num_blocks > dev.num_blocks() {
+            return Ok(Vec::new());
+        }
+        if kind != 0 && num_blocks != 0 {
+            parts.push(PartitionEntry {
+                start_lba,
+                num_blocks,
+                kind: PartitionType::Mbr(kind),
+            });
+        }
+    }
+    Ok(parts)
+}
+
+/// Reads the GPT whose header is in block 1.
+#[cfg(feature = "alloc")]
+fn scan_gpt<D: BlockDriverOps>(dev: &mut D) -> DevResult<Vec<PartitionEntry>> {
+    let block_size = dev.block_size();
+    let mut header = vec![0; block_size];
+    dev.read_block(1, &mut header)?;
+    if header.len() < 92 || &header[..8] != GPT_SIGNATURE {
+        trace::warn!("GPT: bad header signature");
+        return Ok(Vec::new());
+    }
+    let entries_lba = u64::from_le_bytes(header[72..80].try_into().unwrap());
+    let num_entries = u32::from_le_bytes(header[80..84].try_into().unwrap()) as usize;
+    let entry_size = u32::from_le_bytes(header[84..88].try_into().unwrap()) as usize;
+    // The entry size is 128 << n, so entries never straddle blocks
+    let table_len = num_entries
+        .checked_mul(entry_size)
+        .filter(|_| entry_size.is_power_of_two() && entry_size <= block_size)
+        .filter(|_| entry_size >= GPT_MIN_ENTRY_SIZE && num_entries <= GPT_MAX_ENTRIES);
+    let Some(table_len) = table_len else {
+        trace::warn!(
+            "GPT: unsupported table of {} entries of {} bytes",
+            num_entries,
+            entry_size
+        );
+        return Ok(Vec::new());
+    };
+    let table_blocks = table_len.div_ceil(block_size);
+    if entries_lba
+        .checked_add(table_blocks as u64)
+        .is_none_or(|end| end > dev.num_blocks())
+    {
+        trace::warn!("GPT: partition table at {} exceeds the disk", entries_lba);
+        return Ok(Vec::new());
+    }
+
+    let mut table = vec![0; table_blocks * block_size];
+    dev.read_block(entries_lba, &mut table)?;
+    let mut parts = Vec::new();
+    for entry in table.chunks_exact(entry_size).take(num_entries) {
+        let kind: [u8; 16] = entry[..16].try_into().unwrap();
+        if kind == [0; 16] {
+            continue;
+        }
+        let first_lba = u64::from_le_bytes(entry[32..40].try_into().unwrap());
+        let last_lba = u64::from_le_bytes(entry[40..48].try_into().unwrap());
+        if last_lba < first_lba || last_lba >= dev.num_blocks() {
+            trace::warn!("GPT: invalid partition {}..={}", first_lba, last_lba);
+            continue;
+        }
+        parts.push(PartitionEntry {
+            start_lba: first_lba,
+            num_blocks: last_lba - first_lba + 1,
+            kind: PartitionType::Gpt(kind),
+        });
+    }
+    Ok(parts)
+}
+
+#[cfg(all(test, feature = "ramdisk", feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::ramdisk::RamDisk;
+
+    const LINUX_FS: [u8; 16] = [
+        0xaf, 0x3d, 0xc6, 0x0f, 0x83, 0x84, 0x72, 0x47, 0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47, 0x7d,
+        0xe4,
+    ];
+
+    fn mbr_entry(mbr: &mut [u8], i: usize, kind: u8, start: u32, len: u32) {
+        let entry = &mut mbr[MBR_TABLE_OFFSET + i * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        entry[4] = kind;
+        entry[8..12].copy_from_slice(&start.to_le_bytes());
+        entry[12..16].copy_from_slice(&len.to_le_bytes());
+    }
+
+    /// A disk with a protective MBR and a GPT of `num_entries` entries of
+    /// `entry_size` bytes from block 2, of which the first two are used.
+    fn gpt_disk(block_size: usize, num_entries: u32, entry_size: u32) -> RamDisk {
+        let mut data = vec![0; 64 * block_size];
+        mbr_entry(&mut data, 0, MBR_TYPE_GPT_PROTECTIVE, 1, 63);
+        data[MBR_SIZE - 2..MBR_SIZE].copy_from_slice(&MBR_SIGNATURE);
+        let header = &mut data[block_size..];
+        header[..8].copy_from_slice(GPT_SIGNATURE);
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&num_entries.to_le_bytes());
+        header[84..88].copy_from_slice(&entry_size.to_le_bytes());
+        let entry_size = entry_size as usize;
+        for (i, (first, last)) in [(34u64, 40u64), (41, 63)].into_iter().enumerate() {
+            let entry = &mut data[2 * block_size + i * entry_size..];
+            entry[..16].copy_from_slice(&LINUX_FS);
+            entry[32..40].copy_from_slice(&first.to_le_bytes());
+            entry[40..48].copy_from_slice(&last.to_le_bytes());
+        }
+        RamDisk::from_bytes(&data, block_size)
+    }
+
+    #[test]
+    fn unpartitioned_disk_has_no_partitions() {
+        let mut disk = RamDisk::new(16, 512);
+        assert_eq!(scan_partitions(&mut disk).unwrap(), []);
+    }
+
+    #[test]
+    fn mbr_primary_partitions() {
+        for block_size in [512, 4096] {
+            let mut data = vec![0; 32 * block_size];
+            mbr_entry(&mut data, 0, 0x83, 2, 10);
+            mbr_entry(&mut data, 2, 0x0c, 12, 20);
+            data[MBR_SIZE - 2..MBR_SIZE].copy_from_slice(&MBR_SIGNATURE);
+            let mut disk = RamDisk::from_bytes(&data, block_size);
+            assert_eq!(
+                scan_partitions(&mut disk).unwrap(),
+                [
+                    PartitionEntry {
+                        start_lba: 2,
+                        num_blocks: 10,
+                        kind: PartitionType::Mbr(0x83),
+                    },
+                    PartitionEntry {
+                        start_lba: 12,
+                        num_blocks: 20,
+                        kind: PartitionType::Mbr(0x0c),
+                    },
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn gpt_partitions() {
+        for block_size in [512, 4096] {
+            let mut disk = gpt_disk(block_size, 128, 128);
+            assert_eq!(
+                scan_partitions(&mut disk).unwrap(),
+                [
+                    PartitionEntry {
+                        start_lba: 34,
+                        num_blocks: 7,
+                        kind: PartitionType::Gpt(LINUX_FS),
+                    },
+                    PartitionEntry {
+                        start_lba: 41,
+                        num_blocks: 23,
+                        kind: PartitionType::Gpt(LINUX_FS),
+                    },
+                ]
+            );
+        }
+        assert_eq!(
+            scan_partitions(&mut gpt_disk(512, 4, 256)).unwrap().len(),
+            2
+        );
+    }
+
+    #[test]
+    fn gpt_with_bad_entry_size_is_ignored() {
+        // Not a power of two, larger than a block, and overflowing
+        for entry_size in [136, 1024, u32::MAX] {
+            let mut disk = gpt_disk(512, 4, entry_size.min(136));
+            let mut header = [0; 512];
+            disk.read_block(1, &mut header).unwrap();
+            header[84..88].copy_from_slice(&entry_size.to_le_bytes());
+            disk.write_block(1, &header).unwrap();
+            assert_eq!(scan_partitions(&mut disk).unwrap(), []);
+        }
+        // The table does not fit on the disk
+        let mut disk = gpt_disk(512, 1024, 128);
+        assert_eq!(scan_partitions(&mut disk).unwrap(), []);
+    }
+}
@@ -0,0 +1,717 @@
+//! Block devices built from several other block devices (RAID).
+
+extern crate alloc;
+
+use alloc::vec;
+use axdriver_base::trace;
+
+use crate::{
+    BaseDriverOps, BlockDriverOps, DevError, DevResult, DeviceCapabilities, DeviceStats,
+    DeviceType, IoHints,
+};
+
+/// A device that stripes its blocks over two devices (RAID 0).
+///
+/// The blocks are grouped into stripes of `stripe_blocks` blocks, which are
+/// stored alternately on the first and the second device. Requests that
+/// cross a stripe boundary are split between them.
+///
+/// Both devices must have the same block size. Each of them provides as many
+/// whole stripes as the smaller one, the rest of the larger one is unused.
+pub struct Stripe<A, B> {
+    a: A,
+    b: B,
+    stripe_blocks: u64,
+    num_blocks: u64,
+}
+
+/// A part of a request that is stored on a single device.
+struct Chunk {
+    /// Whether it is on the second device.
+    second: bool,
+    /// The first block on the device.
+    block_id: u64,
+    /// The offset of the first block in the request, in blocks.
+    offset: u64,
+    /// The number of blocks.
+    count: u64,
+}
+
+impl<A: BlockDriverOps, B: BlockDriverOps> Stripe<A, B> {
+    /// Stripes `a` and `b` with stripes of `stripe_blocks` blocks.
+    ///
+    /// Returns [`DevError::InvalidParam`] if `stripe_blocks` is 0 or if the
+    /// devices have different block sizes.
+    pub fn new(a: A, b: B, stripe_blocks: u64) -> DevResult<Self> {
+        if stripe_blocks == 0 || a.block_size() != b.block_size() {
+            return Err(DevError::InvalidParam);
+        }
+        let stripes = a.num_blocks().min(b.num_blocks()) / stripe_blocks;
+        Ok(Self {
+            a,
+            b,
+            stripe_blocks,
+            num_blocks: stripes * stripe_blocks * 2,
+        })
+    }
+
+    /// The number of blocks of each stripe.
+    pub const fn stripe_blocks(&self) -> u64 {
+        self.stripe_blocks
+    }
+
+    /// Returns references to the two devices.
+    pub const fn inner(&self) -> (&A, &B) {
+        (&self.a, &self.b)
+    }
+
+    /// Unwraps the two devices.
+    pub fn into_inner(self) -> (A, B) {
+        (self.a, self.b)
+    }
+
+    /// Returns the number of blocks of a buffer of `len` bytes.
+    fn blocks_of(&self, len: usize) -> DevResult<u64> {
+        let block_size = self.block_size();
+        if block_size == 0 {
+            return Err(DevError::Io);
+        }
+        if !len.is_multiple_of(block_size) {
+            return Err(DevError::InvalidParam);
+        }
+        Ok((len / block_size) as u64)
+    }
+
+    /// Splits `count` blocks from `block_id` into the parts of each stripe,
+    /// after checking that they are within the device.
+    fn chunks(&self, block_id: u64, count: u64) -> DevResult<impl Iterator<Item = Chunk>> {
+        let end = block_id.checked_add(count).ok_or(DevError::InvalidParam)?;
+        if end > self.num_blocks {
+            return Err(DevError::InvalidParam);
+        }
+        let stripe_blocks = self.stripe_blocks;
+        let mut pos = block_id;
+        Ok(core::iter::from_fn(move || {
+            if pos >= end {
+                return None;
+            }
+            let (stripe, in_stripe) = (pos / stripe_blocks, pos % stripe_blocks);
+            let chunk = Chunk {
+                second: stripe % 2 == 1,
+                block_id: stripe / 2 * stripe_blocks + in_stripe,
+                offset: pos - block_id,
+                count: (stripe_blocks - in_stripe).min(end - pos),
+            };
+            pos += chunk.count;
+            Some(chunk)
+        }))
+    }
+
+    /// Returns the byte range of `chunk` in the buffer of the request.
+    fn buf_range(&self, chunk: &Chunk) -> core::ops::Range<usize> {
+        let block_size = self.block_size();
+        let start = chunk.offset as usize * block_size;
+        start..start + chunk.count as usize * block_size
+    }
+}
+
+impl<A: BlockDriverOps, B: BlockDriverOps> BaseDriverOps for Stripe<A, B> {
+    fn device_name(&self) -> &str {
+        "stripe"
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let (a, b) = (self.a.capabilities(), self.b.capabilities());
+        // Read-only if either is, other features only if both have them
+        (a & b) | ((a | b) & DeviceCapabilities::READ_ONLY)
+    }
+
+    fn reset(&mut self) -> DevResult {
+        self.a.reset()?;
+        self.b.reset()
+    }
+
+    /// The sums of the counters of both devices.
+    fn stats(&self) -> DeviceStats {
+        self.a.stats() + self.b.stats()
+    }
+}
+
+impl<A: BlockDriverOps, B: BlockDriverOps> BlockDriverOps for Stripe<A, B> {
+    #[inline]
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    #[inline]
+    fn block_size(&self) -> usize {
+        self.a.block_size()
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        for chunk in self.chunks(block_id, self.blocks_of(buf.len())?)? {
+            let buf = &mut buf[self.buf_range(&chunk)];
+            if chunk.second {
+                self.b.read_block(chunk.block_id, buf)?;
+            } else {
+                self.a.read_block(chunk.block_id, buf)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        for chunk in self.chunks(block_id, self.blocks_of(buf.len())?)? {
+            let buf = &buf[self.buf_range(&chunk)];
+            if chunk.second {
+                self.b.write_block(chunk.block_id, buf)?;
+            } else {
+                self.a.write_block(chunk.block_id, buf)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> DevResult {
+        self.a.flush()?;
+        self.b.flush()
+    }
+
+    fn write_barrier(&mut self) -> DevResult {
+        self.a.write_barrier()?;
+        self.b.write_barrier()
+    }
+
+    fn write_block_fua(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        for chunk in self.chunks(block_id, self.blocks_of(buf.len())?)? {
+            let buf = &buf[self.buf_range(&chunk)];
+            if chunk.second {
+                self.b.write_block_fua(chunk.block_id, buf)?;
+            } else {
+                self.a.write_block_fua(chunk.block_id, buf)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn discard(&mut self, block_id: u64, count: u64) -> DevResult {
+        let chunks = self.chunks(block_id, count)?;
+        if count != 0 && !self.discard_supported() {
+            return Err(DevError::Unsupported);
+        }
+        for chunk in chunks {
+            if chunk.second {
+                self.b.discard(chunk.block_id, chunk.count)?;
+            } else {
+                self.a.discard(chunk.block_id, chunk.count)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn discard_supported(&self) -> bool {
+        self.a.discard_supported() && self.b.discard_supported()
+    }
+
+    fn write_zeros(&mut self, block_id: u64, count: u64) -> DevResult {
+        for chunk in self.chunks(block_id, count)? {
+            if chunk.second {
+                self.b.write_zeros(chunk.block_id, chunk.count)?;
+            } else {
+                self.a.write_zeros(chunk.block_id, chunk.count)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Requests of a whole stripe on each device keep both of them busy.
+    /// Larger writes than a block may be split between the devices, so they
+    /// are never atomic.
+    fn io_hints(&self) -> IoHints {
+        let (a, b) = (self.a.io_hints(), self.b.io_hints());
+        let block_size = self.block_size();
+        IoHints {
+            min_io_size: a.min_io_size.max(b.min_io_size),
+            optimal_io_size: self.stripe_blocks as usize * block_size * 2,
+            max_atomic_write: a
+                .max_atomic_write
+                .zip(b.max_atomic_write)
+                .map(|(a, b)| a.min(b).min(block_size)),
+        }
+    }
+
+    fn is_rotational(&self) -> bool {
+        self.a.is_rotational() || self.b.is_rotational()
+    }
+}
+
+/// A device that stores the same data on two devices (RAID 1).
+///
+/// Writes, flushes and discards go to both devices, reads to the first one,
+/// or to the second one if the first one fails with [`DevError::Io`],
+/// [`DevError::Timeout`] or [`DevError::BadBlock`].
+///
+/// If an operation succeeds on only one device and the other one fails with
+/// one of those errors, it fails with [`DevError::Degraded`], and the data
+/// read is still in the buffer. Other errors, such as
+/// [`DevError::Unsupported`], are returned as they are.
+pub struct Mirror<A, B> {
+    a: A,
+    b: B,
+    verify_reads: bool,
+}
+
+impl<A: BlockDriverOps, B: BlockDriverOps> Mirror<A, B> {
+    /// Mirrors `a` and `b`, whose common size is that of the smaller one.
+    ///
+    /// Returns [`DevError::InvalidParam`] if the devices have different
+    /// block sizes.
+    pub fn new(a: A, b: B) -> DevResult<Self> {
+        if a.block_size() != b.block_size() {
+            return Err(DevError::InvalidParam);
+        }
+        Ok(Self {
+            a,
+            b,
+            verify_reads: false,
+        })
+    }
+
+    /// Reads every block from both devices, and fails with
+    /// [`DevError::Degraded`] if the copies differ.
+    ///
+    /// The data of the first device is returned in that case.
+    pub fn with_verify_reads(mut self) -> Self {
+        self.verify_reads = true;
+        self
+    }
+
+    /// Returns references to the two devices.
+    pub const fn inner(&self) -> (&A, &B) {
+        (&self.a, &self.b)
+    }
+
+    /// Unwraps the two devices.
+    pub fn into_inner(self) -> (A, B) {
+        (self.a, self.b)
+    }
+
+    fn check_range(&self, block_id: u64, count: u64) -> DevResult {
+        match block_id.checked_add(count) {
+            Some(end) if end <= self.num_blocks() => Ok(()),
+            _ => Err(DevError::InvalidParam),
+        }
+    }
+
+    fn check_buf(&self, block_id: u64, len: usize) -> DevResult {
+        let block_size = self.block_size();
+        if block_size == 0 {
+            return Err(DevError::Io);
+        }
+        if !len.is_multiple_of(block_size) {
+            return Err(DevError::InvalidParam);
+        }
+        self.check_range(block_id, (len / block_size) as u64)
+    }
+}
+
+/// Whether the device can fail while its mirror still has the data.
+const fn is_device_failure(e: &DevError) -> bool {
+    matches!(e, DevError::Io | DevError::Timeout | DevError::BadBlock)
+}
+
+/// Combines the results of an operation on both devices.
+fn mirrored(a: DevResult, b: DevResult) -> DevResult {
+    match (a, b) {
+        (Ok(()), Ok(())) => Ok(()),
+        (Err(e), Err(_)) => Err(e),
+        (Ok(()), Err(e)) | (Err(e), Ok(())) if is_device_failure(&e) => {
+            trace::warn!("mirror: device failed: {:?}", e);
+            Err(DevError::Degraded)
+        }
+        (Ok(()), Err(e)) | (Err(e), Ok(())) => Err(e),
+    }
+}
+
+impl<A: BlockDriverOps, B: BlockDriverOps> BaseDriverOps for Mirror<A, B> {
+    fn device_name(&self) -> &str {
+        "mirror"
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let (a, b) = (self.a.capabilities(), self.b.capabilities());
+        (a & b) | ((a | b) & DeviceCapabilities::READ_ONLY)
+    }
+
+    fn reset(&mut self) -> DevResult {
+        self.a.reset()?;
+        self.b.reset()
+    }
+
+    /// The sums of the counters of both devices.
+    fn stats(&self) -> DeviceStats {
+        self.a.stats() + self.b.stats()
+    }
+}
+
+impl<A: BlockDriverOps, B: BlockDriverOps> BlockDriverOps for Mirror<A, B> {
+    #[inline]
+    fn num_blocks(&self) -> u64 {
+        self.a.num_blocks().min(self.b.num_blocks())
+    }
+
+    #[inline]
+    fn block_size(&self) -> usize {
+        self.a.block_size()
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        self.check_buf(block_id, buf.len())?;
+        match self.a.read_block(block_id, buf) {
+            Ok(()) if self.verify_reads => {
+                let mut copy = vec![0; buf.len()];
+                match self.b.read_block(block_id, &mut copy) {
+                    Ok(()) if copy == *buf => Ok(()),
+                    Ok(()) => {
+                        trace::warn!("mirror: copies of blocks from {} differ", block_id);
+                        Err(DevError::Degraded)
+                    }
+                    Err(e) => mirrored(Ok(()), Err(e)),
+                }
+            }
+            Ok(()) => Ok(()),
+            Err(e) if is_device_failure(&e) => mirrored(Err(e), self.b.read_block(block_id, buf)),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        self.check_buf(block_id, buf.len())?;
+        mirrored(
+            self.a.write_block(block_id, buf),
+            self.b.write_block(block_id, buf),
+        )
+    }
+
+    fn flush(&mut self) -> DevResult {
+        mirrored(self.a.flush(), self.b.flush())
+    }
+
+    fn write_barrier(&mut self) -> DevResult {
+        mirrored(self.a.write_barrier(), self.b.write_barrier())
+    }
+
+    fn write_block_fua(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        self.check_buf(block_id, buf.len())?;
+        mirrored(
+            self.a.write_block_fua(block_id, buf),
+            self.b.write_block_fua(block_id, buf),
+        )
+    }
+
+    fn discard(&mut self, block_id: u64, count: u64) -> DevResult {
+        self.check_range(block_id, count)?;
+        if count == 0 {
+            return Ok(());
+        }
+        if !self.discard_supported() {
+            return Err(DevError::Unsupported);
+        }
+        mirrored(
+            self.a.discard(block_id, count),
+            self.b.discard(block_id, count),
+        )
+    }
+
+    fn discard_supported(&self) -> bool {
+        self.a.discard_supported() && self.b.discard_supported()
+    }
+
+    fn write_zeros(&mut self, block_id: u64, count: u64) -> DevResult {
+        self.check_range(block_id, count)?;
+        mirrored(
+            self.a.write_zeros(block_id, count),
+            self.b.write_zeros(block_id, count),
+        )
+    }
+
+    fn io_hints(&self) -> IoHints {
+        let (a, b) = (self.a.io_hints(), self.b.io_hints());
+        IoHints {
+            min_io_size: a.min_io_size.max(b.min_io_size),
+            optimal_io_size: a.optimal_io_size.min(b.optimal_io_size),
+            max_atomic_write: a
+                .max_atomic_write
+                .zip(b.max_atomic_write)
+                .map(|(a, b)| a.min(b)),
+        }
+    }
+
+    /// Reads may go to either device, so seeks are expensive if either of
+    /// them is rotational.
+    fn is_rotational(&self) -> bool {
+        self.a.is_rotational() || self.b.is_rotational()
+    }
+}
+
+#[cfg(all(test, feature = "ramdisk"))]
+mod tests {
+    use super::*;
+    use crate::ramdisk::RamDisk;
+    use alloc::vec::Vec;
+
+    /// A RAM disk whose writes, and optionally reads, fail with a given
+    /// error.
+    struct FailingDisk {
+        inner: RamDisk,
+        error: Option<fn() -> DevError>,
+        read_error: Option<fn() -> DevError>,
+    }
+
+    impl FailingDisk {
+        fn new(error: Option<fn() -> DevError>) -> Self {
+            Self {
+                inner: RamDisk::new(16, 512),
+                error,
+                read_error: None,
+            }
+        }
+
+        /// A disk whose reads fail with `error`, and whose blocks are filled
+        /// with `fill`.
+        fn failing_reads(error: fn() -> DevError, fill: u8) -> Self {
+            let mut disk = Self::new(None);
+            disk.write_block(0, &[fill; 16 * 512]).unwrap();
+            disk.read_error = Some(error);
+            disk
+        }
+    }
+
+    impl BaseDriverOps for FailingDisk {
+        fn device_name(&self) -> &str {
+            "failing"
+        }
+
+        fn device_type(&self) -> DeviceType {
+            DeviceType::Block
+        }
+    }
+
+    impl BlockDriverOps for FailingDisk {
+        fn num_blocks(&self) -> u64 {
+            self.inner.num_blocks()
+        }
+
+        fn block_size(&self) -> usize {
+            self.inner.block_size()
+        }
+
+        fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+            match self.read_error {
+                Some(error) => Err(error()),
+                None => self.inner.read_block(block_id, buf),
+            }
+        }
+
+        fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+            match self.error {
+                Some(error) => Err(error()),
+                None => self.inner.write_block(block_id, buf),
+            }
+        }
+
+        fn flush(&mut self) -> DevResult {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn mirror_degrades_only_on_device_failures() {
+        let buf = [0x5a; 512];
+        let mut mirror = Mirror::new(FailingDisk::new(None), FailingDisk::new(None)).unwrap();
+        assert!(mirror.write_block(0, &buf).is_ok());
+
+        for error in [|| DevError::Io, || DevError::Timeout, || DevError::BadBlock] {
+            let mut mirror =
+                Mirror::new(FailingDisk::new(None), FailingDisk::new(Some(error))).unwrap();
+            assert!(matches!(
+                mirror.write_block(0, &buf),
+                Err(DevError::Degraded)
+            ));
+        }
+
+        let mut mirror = Mirror::new(
+            FailingDisk::new(Some(|| DevError::Unsupported)),
+            FailingDisk::new(None),
+        )
+        .unwrap();
+        assert!(matches!(
+            mirror.write_block(0, &buf),
+            Err(DevError::Unsupported)
+        ));
+        let mut mirror = Mirror::new(
+            FailingDisk::new(None),
+            FailingDisk::new(Some(|| DevError::InvalidParam)),
+        )
+        .unwrap();
+        assert!(matches!(
+            mirror.write_block(0, &buf),
+            Err(DevError::InvalidParam)
+        ));
+    }
+
+    /// A RAM disk of 16 blocks of 512 bytes filled with `fill`.
+    fn filled(fill: u8) -> RamDisk {
+        let mut disk = RamDisk::new(16, 512);
+        disk.write_block(0, &[fill; 16 * 512]).unwrap();
+        disk
+    }
+
+    #[test]
+    fn mirror_reads_fall_back_to_the_second_device() {
+        let mut buf = [0; 512];
+        let mut mirror =
+            Mirror::new(FailingDisk::failing_reads(|| DevError::Io, 1), filled(2)).unwrap();
+        // The data of the second device is read, but the mirror is degraded
+        assert!(matches!(
+            mirror.read_block(3, &mut buf),
+            Err(DevError::Degraded)
+        ));
+        assert!(buf.iter().all(|&x| x == 2));
+
+        // Other errors are not device failures
+        let mut mirror = Mirror::new(
+            FailingDisk::failing_reads(|| DevError::Unsupported, 1),
+            filled(2),
+        )
+        .unwrap();
+        assert!(matches!(
+            mirror.read_block(3, &mut buf),
+            Err(DevError::Unsupported)
+        ));
+
+        // The error of the first device when both fail
+        let mut mirror = Mirror::new(
+            FailingDisk::failing_reads(|| DevError::Timeout, 1),
+            FailingDisk::failing_reads(|| DevError::Io, 2),
+        )
+        .unwrap();
+        assert!(matches!(
+            mirror.read_block(3, &mut buf),
+            Err(DevError::Timeout)
+        ));
+    }
+
+    #[test]
+    fn verified_reads_fail_when_the_copies_differ() {
+        let mut buf = [0; 2 * 512];
+        let mut mirror = Mirror::new(filled(1), filled(1))
+            .unwrap()
+            .with_verify_reads();
+        mirror.read_block(0, &mut buf).unwrap();
+        assert!(buf.iter().all(|&x| x == 1));
+
+        // The data of the first device is returned
+        let mut mirror = Mirror::new(filled(1), filled(2))
+            .unwrap()
+            .with_verify_reads();
+        assert!(matches!(
+            mirror.read_block(0, &mut buf),
+            Err(DevError::Degraded)
+        ));
+        assert!(buf.iter().all(|&x| x == 1));
+
+        let mut mirror = Mirror::new(filled(1), FailingDisk::failing_reads(|| DevError::Io, 1))
+            .unwrap()
+            .with_verify_reads();
+        assert!(matches!(
+            mirror.read_block(0, &mut buf),
+            Err(DevError::Degraded)
+        ));
+        assert!(buf.iter().all(|&x| x == 1));
+    }
+
+    #[test]
+    fn mirror_stats_are_the_sums_of_both_devices() {
+        let mut mirror = Mirror::new(RamDisk::new(4, 512), RamDisk::new(4, 512)).unwrap();
+        mirror.write_block(0, &[1; 2 * 512]).unwrap();
+        mirror.read_block(0, &mut [0; 512]).unwrap();
+        let stats = mirror.stats();
+        assert_eq!((stats.writes, stats.bytes_written), (2, 2 * 2 * 512));
+        assert_eq!((stats.reads, stats.bytes_read), (1, 512));
+    }
+
+    #[test]
+    fn mirror_is_rotational_if_either_device_is() {
+        let mirror = Mirror::new(RamDisk::new(4, 512), FailingDisk::new(None)).unwrap();
+        assert!(mirror.is_rotational());
+        let mirror = Mirror::new(RamDisk::new(4, 512), RamDisk::new(4, 512)).unwrap();
+        assert!(!mirror.is_rotational());
+    }
+
+    #[test]
+    fn stripe_splits_requests_at_stripe_boundaries() {
+        let mut stripe = Stripe::new(RamDisk::new(8, 512), RamDisk::new(10, 512), 2).unwrap();
+        assert_eq!(stripe.num_blocks(), 16);
+        let data: Vec<u8> = (0..3 * 512).map(|i| (i / 512 + 1) as u8).collect();
+        // Blocks 1 to 3: block 1 of the first stripe, then the second stripe
+        stripe.write_block(1, &data).unwrap();
+
+        let mut buf = [0; 3 * 512];
+        stripe.read_block(1, &mut buf).unwrap();
+        assert_eq!(buf[..], data[..]);
+
+        let (mut a, mut b) = stripe.into_inner();
+        let mut block = [0; 512];
+        a.read_block(1, &mut block).unwrap();
+        assert!(block.iter().all(|&x| x == 1));
+        b.read_block(0, &mut block).unwrap();
+        assert!(block.iter().all(|&x| x == 2));
+        b.read_block(1, &mut block).unwrap();
+        assert!(block.iter().all(|&x| x == 3));
+        a.read_block(0, &mut block).unwrap();
+        assert!(block.iter().all(|&x| x == 0));
+        a.read_block(2, &mut block).unwrap();
+        assert!(block.iter().all(|&x| x == 0));
+    }
+
+    #[test]
+    fn stripe_rejects_out_of_range_requests() {
+        let mut stripe = Stripe::new(RamDisk::new(4, 512), RamDisk::new(4, 512), 2).unwrap();
+        let mut buf = [0; 2 * 512];
+        assert!(matches!(
+            stripe.read_block(7, &mut buf),
+            Err(DevError::InvalidParam)
+        ));
+        assert!(matches!(
+            stripe.read_block(0, &mut buf[..100]),
+            Err(DevError::InvalidParam)
+        ));
+        assert!(matches!(
+            Stripe::new(RamDisk::new(4, 512), RamDisk::new(4, 4096), 2),
+            Err(DevError::InvalidParam)
+        ));
+    }
+
+    #[test]
+    fn stripe_stats_are_the_sums_of_both_devices() {
+        let mut stripe = Stripe::new(RamDisk::new(4, 512), RamDisk::new(4, 512), 2).unwrap();
+        // Blocks 1 to 3, on both devices
+        stripe.write_block(1, &[1; 3 * 512]).unwrap();
+        stripe.read_block(2, &mut [0; 512]).unwrap();
+        let stats = stripe.stats();
+        assert_eq!((stats.writes, stats.bytes_written), (2, 3 * 512));
+        assert_eq!((stats.reads, stats.bytes_read), (1, 512));
+        let (a, b) = stripe.inner();
+        assert_eq!(a.stats().writes, 1);
+        assert_eq!(b.stats().bytes_written, 2 * 512);
+    }
+}
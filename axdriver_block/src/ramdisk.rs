@@ -1,31 +1,135 @@
 //! Mock block devices that store data in RAM.
+//!
+//! [`SliceDisk`] works on a buffer of the caller and needs no allocator,
+//! [`RamDisk`] owns its data and needs the `alloc` feature.
 
+#[cfg(feature = "alloc")]
 extern crate alloc;
 
 use crate::BlockDriverOps;
+#[cfg(feature = "alloc")]
 use alloc::{vec, vec::Vec};
-use axdriver_base::{BaseDriverOps, DevError, DevResult, DeviceType};
+use axdriver_base::{BaseDriverOps, DevError, DevResult, DeviceStats, DeviceType};
 
-const BLOCK_SIZE: usize = 512;
+#[cfg(feature = "alloc")]
+const DEFAULT_BLOCK_SIZE: usize = 512;
 
 /// A RAM disk that stores data in a vector.
-#[derive(Default)]
-pub struct RamDisk {
-    size: usize,
-    data: Vec<u8>,
+#[cfg(feature = "alloc")]
+pub struct RamDisk(MemBlocks<Vec<u8>>);
+
+/// A RAM disk of a fixed capacity, that stores data in a borrowed buffer.
+///
+/// It does not allocate, e.g. to use a static buffer as a disk:
+///
+/// ```
+/// use axdriver_block::{ramdisk::SliceDisk, BlockDriverOps};
+///
+/// static mut DISK: [u8; 4096] = [0; 4096];
+///
+/// let disk = SliceDisk::new(unsafe { &mut *core::ptr::addr_of_mut!(DISK) }, 512);
+/// assert_eq!(disk.num_blocks(), 8);
+/// ```
+pub struct SliceDisk<'a>(MemBlocks<&'a mut [u8]>);
+
+/// Blocks stored in `data`, whose length is a multiple of `block_size`: the
+/// disk of both [`RamDisk`] and [`SliceDisk`].
+struct MemBlocks<B> {
+    block_size: usize,
+    data: B,
+    stats: DeviceStats,
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> MemBlocks<B> {
+    fn new(data: B, block_size: usize) -> Self {
+        Self {
+            block_size,
+            data,
+            stats: DeviceStats::default(),
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.data.as_ref().len()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        (self.size() / self.block_size) as u64
+    }
+
+    /// Returns the byte range of `len` bytes starting from block `block_id`.
+    fn range(&self, block_id: u64, len: usize) -> DevResult<core::ops::Range<usize>> {
+        if !len.is_multiple_of(self.block_size) {
+            return Err(DevError::InvalidParam);
+        }
+        let start = usize::try_from(block_id)
+            .ok()
+            .and_then(|id| id.checked_mul(self.block_size))
+            .ok_or(DevError::InvalidParam)?;
+        match start.checked_add(len) {
+            Some(end) if end <= self.size() => Ok(start..end),
+            _ => Err(DevError::InvalidParam),
+        }
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        let result = self.range(block_id, buf.len());
+        self.stats.record(false, buf.len(), &result);
+        buf.copy_from_slice(&self.data.as_ref()[result?]);
+        Ok(())
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        let result = self.range(block_id, buf.len());
+        self.stats.record(true, buf.len(), &result);
+        self.data.as_mut()[result?].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn write_zeros(&mut self, block_id: u64, count: u64) -> DevResult {
+        let len = usize::try_from(count)
+            .ok()
+            .and_then(|count| count.checked_mul(self.block_size))
+            .ok_or(DevError::InvalidParam)?;
+        let range = self.range(block_id, len)?;
+        self.data.as_mut()[range].fill(0);
+        Ok(())
+    }
 }
 
+#[cfg(feature = "alloc")]
 impl RamDisk {
-    /// Creates a new RAM disk with the given size hint.
+    /// Creates a new zero-filled RAM disk of `num_blocks` blocks of
+    /// `block_size` bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `block_size` is 0, or if the size of the RAM disk overflows
+    /// `usize`.
+    pub fn new(num_blocks: u64, block_size: usize) -> Self {
+        assert_ne!(block_size, 0, "block size must not be 0");
+        let size = usize::try_from(num_blocks)
+            .ok()
+            .and_then(|n| n.checked_mul(block_size))
+            .expect("RAM disk size overflows usize");
+        Self(MemBlocks::new(vec![0; size], block_size))
+    }
+
+    /// Creates a new RAM disk of `block_size`-byte blocks from the existing
+    /// data.
     ///
     /// The actual size of the RAM disk will be aligned upwards to the block
-    /// size (512 bytes).
-    pub fn new(size_hint: usize) -> Self {
-        let size = align_up(size_hint);
-        Self {
-            size,
-            data: vec![0; size],
-        }
+    /// size, the extra bytes are zero-filled.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `block_size` is 0.
+    pub fn from_bytes(buf: &[u8], block_size: usize) -> Self {
+        assert_ne!(block_size, 0, "block size must not be 0");
+        let size = buf.len().div_ceil(block_size) * block_size;
+        let mut data = vec![0; size];
+        data[..buf.len()].copy_from_slice(buf);
+        Self(MemBlocks::new(data, block_size))
     }
 
     /// Creates a new RAM disk from the exiting data.
@@ -33,18 +137,23 @@ impl RamDisk {
     /// The actual size of the RAM disk will be aligned upwards to the block
     /// size (512 bytes).
     pub fn from(buf: &[u8]) -> Self {
-        let size = align_up(buf.len());
-        let mut data = vec![0; size];
-        data[..buf.len()].copy_from_slice(buf);
-        Self { size, data }
+        Self::from_bytes(buf, DEFAULT_BLOCK_SIZE)
     }
 
     /// Returns the size of the RAM disk in bytes.
-    pub const fn size(&self) -> usize {
-        self.size
+    pub fn size(&self) -> usize {
+        self.0.size()
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl Default for RamDisk {
+    fn default() -> Self {
+        Self::new(0, DEFAULT_BLOCK_SIZE)
     }
 }
 
+#[cfg(feature = "alloc")]
 impl BaseDriverOps for RamDisk {
     fn device_type(&self) -> DeviceType {
         DeviceType::Block
@@ -53,48 +162,212 @@ impl BaseDriverOps for RamDisk {
     fn device_name(&self) -> &str {
         "ramdisk"
     }
+
+    fn stats(&self) -> DeviceStats {
+        self.0.stats
+    }
 }
 
+#[cfg(feature = "alloc")]
 impl BlockDriverOps for RamDisk {
     #[inline]
     fn num_blocks(&self) -> u64 {
-        (self.size / BLOCK_SIZE) as u64
+        self.0.num_blocks()
     }
 
     #[inline]
     fn block_size(&self) -> usize {
-        BLOCK_SIZE
+        self.0.block_size
     }
 
     fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
-        let offset = block_id as usize * BLOCK_SIZE;
-        if offset + buf.len() > self.size {
-            return Err(DevError::Io);
-        }
-        if buf.len() % BLOCK_SIZE != 0 {
-            return Err(DevError::InvalidParam);
-        }
-        buf.copy_from_slice(&self.data[offset..offset + buf.len()]);
-        Ok(())
+        self.0.read_block(block_id, buf)
     }
 
     fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
-        let offset = block_id as usize * BLOCK_SIZE;
-        if offset + buf.len() > self.size {
-            return Err(DevError::Io);
-        }
-        if buf.len() % BLOCK_SIZE != 0 {
-            return Err(DevError::InvalidParam);
-        }
-        self.data[offset..offset + buf.len()].copy_from_slice(buf);
+        self.0.write_block(block_id, buf)
+    }
+
+    fn flush(&mut self) -> DevResult {
         Ok(())
     }
 
+    fn is_rotational(&self) -> bool {
+        false
+    }
+
+    fn write_zeros(&mut self, block_id: u64, count: u64) -> DevResult {
+        self.0.write_zeros(block_id, count)
+    }
+}
+
+impl<'a> SliceDisk<'a> {
+    /// Creates a RAM disk of `block_size`-byte blocks over `data`, whose
+    /// contents are kept.
+    ///
+    /// The bytes after the last whole block of `data` are not used.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `block_size` is 0.
+    pub fn new(data: &'a mut [u8], block_size: usize) -> Self {
+        assert_ne!(block_size, 0, "block size must not be 0");
+        let size = data.len() / block_size * block_size;
+        Self(MemBlocks::new(&mut data[..size], block_size))
+    }
+
+    /// Returns the size of the RAM disk in bytes.
+    pub fn size(&self) -> usize {
+        self.0.size()
+    }
+
+    /// Gives the buffer back.
+    pub fn into_inner(self) -> &'a mut [u8] {
+        self.0.data
+    }
+}
+
+impl BaseDriverOps for SliceDisk<'_> {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+
+    fn device_name(&self) -> &str {
+        "slicedisk"
+    }
+
+    fn stats(&self) -> DeviceStats {
+        self.0.stats
+    }
+}
+
+impl BlockDriverOps for SliceDisk<'_> {
+    #[inline]
+    fn num_blocks(&self) -> u64 {
+        self.0.num_blocks()
+    }
+
+    #[inline]
+    fn block_size(&self) -> usize {
+        self.0.block_size
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        self.0.read_block(block_id, buf)
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        self.0.write_block(block_id, buf)
+    }
+
     fn flush(&mut self) -> DevResult {
         Ok(())
     }
+
+    fn is_rotational(&self) -> bool {
+        false
+    }
+
+    fn write_zeros(&mut self, block_id: u64, count: u64) -> DevResult {
+        self.0.write_zeros(block_id, count)
+    }
 }
 
-const fn align_up(val: usize) -> usize {
-    (val + BLOCK_SIZE - 1) & !(BLOCK_SIZE - 1)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slice_disk_reads_and_writes_the_buffer() {
+        let mut data = [0xa5; 3 * 512 + 100];
+        let mut disk = SliceDisk::new(&mut data, 512);
+        assert_eq!(disk.num_blocks(), 3);
+        assert_eq!(disk.size(), 3 * 512);
+
+        let mut buf = [0; 512];
+        disk.write_block(1, &[1; 512]).unwrap();
+        disk.read_block(1, &mut buf).unwrap();
+        assert_eq!(buf, [1; 512]);
+        disk.write_zeros(2, 1).unwrap();
+        assert!(matches!(
+            disk.read_block(3, &mut buf),
+            Err(DevError::InvalidParam)
+        ));
+        assert!(matches!(
+            disk.write_block(0, &buf[..100]),
+            Err(DevError::InvalidParam)
+        ));
+        assert_eq!(disk.stats().writes, 1);
+        assert_eq!(disk.stats().read_errors, 1);
+
+        let data = disk.into_inner();
+        assert!(data[..512].iter().all(|&b| b == 0xa5));
+        assert!(data[512..1024].iter().all(|&b| b == 1));
+        assert!(data[1024..].iter().all(|&b| b == 0));
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn ram_disk_bounds_are_checked() {
+        let mut disk = RamDisk::new(4, 512);
+        assert_eq!((disk.num_blocks(), disk.size()), (4, 4 * 512));
+        let mut buf = [0; 1024];
+        disk.write_block(2, &[1; 1024]).unwrap();
+        disk.read_block(2, &mut buf).unwrap();
+        assert_eq!(buf, [1; 1024]);
+
+        for block_id in [3, 4, u64::MAX] {
+            assert!(matches!(
+                disk.read_block(block_id, &mut buf),
+                Err(DevError::InvalidParam)
+            ));
+            assert!(matches!(
+                disk.write_block(block_id, &buf),
+                Err(DevError::InvalidParam)
+            ));
+        }
+        assert!(matches!(
+            disk.write_zeros(3, 2),
+            Err(DevError::InvalidParam)
+        ));
+        assert!(matches!(
+            disk.write_zeros(0, u64::MAX),
+            Err(DevError::InvalidParam)
+        ));
+        disk.write_zeros(3, 1).unwrap();
+        disk.read_block(2, &mut buf).unwrap();
+        assert!(buf[..512].iter().all(|&b| b == 1));
+        assert!(buf[512..].iter().all(|&b| b == 0));
+
+        let stats = disk.stats();
+        assert_eq!((stats.reads, stats.writes), (2, 1));
+        assert_eq!((stats.read_errors, stats.write_errors), (3, 3));
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn ram_disk_rejects_partial_blocks() {
+        let mut disk = RamDisk::from_bytes(&[0xa5; 1000], 512);
+        // Rounded up to whole blocks, with zeros
+        assert_eq!(disk.num_blocks(), 2);
+        let mut buf = [0; 1024];
+        disk.read_block(0, &mut buf).unwrap();
+        assert!(buf[..1000].iter().all(|&b| b == 0xa5));
+        assert!(buf[1000..].iter().all(|&b| b == 0));
+
+        assert!(matches!(
+            disk.read_block(0, &mut buf[..100]),
+            Err(DevError::InvalidParam)
+        ));
+        assert!(matches!(
+            disk.write_block(1, &buf[..513]),
+            Err(DevError::InvalidParam)
+        ));
+        // Nothing was written
+        disk.read_block(1, &mut buf[..512]).unwrap();
+        assert!(buf[..488].iter().all(|&b| b == 0xa5));
+        // Empty buffers are within bounds
+        disk.read_block(2, &mut []).unwrap();
+        assert_eq!(RamDisk::default().num_blocks(), 0);
+    }
 }
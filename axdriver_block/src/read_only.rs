@@ -0,0 +1,155 @@
+//! Read-only view of a block device.
+
+extern crate alloc;
+
+use alloc::{format, string::String};
+
+use crate::{
+    BaseDriverOps, BlockDriverOps, DevError, DevResult, DeviceCapabilities, DeviceStats,
+    DeviceType, IoHints,
+};
+
+/// A wrapper that exposes a block device as read-only.
+///
+/// Reads are forwarded to the inner device, while writes, flushes and discards
+/// fail with [`DevError::Unsupported`].
+pub struct ReadOnly<D> {
+    inner: D,
+    name: String,
+}
+
+impl<D: BlockDriverOps> ReadOnly<D> {
+    /// Wraps a block device, whose name is suffixed by `-ro`.
+    pub fn new(inner: D) -> Self {
+        let name = format!("{}-ro", inner.device_name());
+        Self { inner, name }
+    }
+
+    /// Returns a reference to the wrapped device.
+    pub const fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// Unwraps the block device.
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+}
+
+impl<D: BlockDriverOps> BaseDriverOps for ReadOnly<D> {
+    fn device_name(&self) -> &str {
+        &self.name
+    }
+
+    fn device_type(&self) -> DeviceType {
+        self.inner.device_type()
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let caps = self.inner.capabilities() | DeviceCapabilities::READ_ONLY;
+        caps - DeviceCapabilities::FLUSH - DeviceCapabilities::DISCARD - DeviceCapabilities::FUA
+    }
+
+    fn reset(&mut self) -> DevResult {
+        self.inner.reset()
+    }
+
+    fn stats(&self) -> DeviceStats {
+        self.inner.stats()
+    }
+}
+
+impl<D: BlockDriverOps> BlockDriverOps for ReadOnly<D> {
+    #[inline]
+    fn num_blocks(&self) -> u64 {
+        self.inner.num_blocks()
+    }
+
+    #[inline]
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        self.inner.read_block(block_id, buf)
+    }
+
+    fn write_block(&mut self, _block_id: u64, _buf: &[u8]) -> DevResult {
+        Err(DevError::Unsupported)
+    }
+
+    fn try_read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        self.inner.try_read_block(block_id, buf)
+    }
+
+    fn read_blocks_vectored(&mut self, block_id: u64, bufs: &mut [&mut [u8]]) -> DevResult {
+        self.inner.read_blocks_vectored(block_id, bufs)
+    }
+
+    fn write_blocks_vectored(&mut self, _block_id: u64, _bufs: &[&[u8]]) -> DevResult {
+        Err(DevError::Unsupported)
+    }
+
+    fn flush(&mut self) -> DevResult {
+        Err(DevError::Unsupported)
+    }
+
+    fn write_barrier(&mut self) -> DevResult {
+        Err(DevError::Unsupported)
+    }
+
+    fn write_block_fua(&mut self, _block_id: u64, _buf: &[u8]) -> DevResult {
+        Err(DevError::Unsupported)
+    }
+
+    fn write_zeros(&mut self, _block_id: u64, _count: u64) -> DevResult {
+        Err(DevError::Unsupported)
+    }
+
+    fn io_hints(&self) -> IoHints {
+        self.inner.io_hints()
+    }
+
+    fn is_rotational(&self) -> bool {
+        self.inner.is_rotational()
+    }
+}
+
+#[cfg(all(test, feature = "ramdisk"))]
+mod tests {
+    use super::*;
+    use crate::ramdisk::RamDisk;
+
+    #[test]
+    fn writes_fail_and_reads_are_forwarded() {
+        let mut dev = ReadOnly::new(RamDisk::from_bytes(&[0xa5; 4 * 512], 512));
+        assert_eq!(dev.device_name(), "ramdisk-ro");
+        assert_eq!(dev.num_blocks(), 4);
+        assert_eq!(dev.block_size(), 512);
+        assert!(dev.capabilities().contains(DeviceCapabilities::READ_ONLY));
+
+        let buf = [0; 512];
+        assert!(matches!(
+            dev.write_block(1, &buf),
+            Err(DevError::Unsupported)
+        ));
+        assert!(matches!(
+            dev.write_blocks_vectored(1, &[&buf]),
+            Err(DevError::Unsupported)
+        ));
+        assert!(matches!(
+            dev.write_block_fua(1, &buf),
+            Err(DevError::Unsupported)
+        ));
+        assert!(matches!(dev.write_zeros(1, 1), Err(DevError::Unsupported)));
+        assert!(matches!(dev.flush(), Err(DevError::Unsupported)));
+        assert!(matches!(dev.discard(1, 1), Err(DevError::Unsupported)));
+
+        let mut buf = [0; 2 * 512];
+        dev.read_block(1, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 0xa5));
+        assert_eq!(dev.stats().writes, 0);
+        assert_eq!(dev.stats().reads, 1);
+        assert_eq!(dev.into_inner().stats().write_errors, 0);
+    }
+}
@@ -0,0 +1,267 @@
+//! Prefetching of sequential reads.
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+use crate::{
+    BaseDriverOps, BlockDriverOps, DevError, DevResult, DeviceCapabilities, DeviceStats,
+    DeviceType, IoHints,
+};
+
+/// A wrapper that reads ahead of sequential reads of a block device.
+///
+/// A read that starts where the previous one ended also fetches the next
+/// `window_blocks` blocks, and the next reads are served from them until they
+/// run out. Other reads go directly to the device. Writes go directly to the
+/// device, and drop the prefetched blocks that they overlap.
+pub struct ReadAhead<D> {
+    inner: D,
+    window_blocks: u64,
+    /// Blocks prefetched from `start`
+    buf: Vec<u8>,
+    start: u64,
+    /// Block following the previous read
+    next: Option<u64>,
+}
+
+impl<D: BlockDriverOps> ReadAhead<D> {
+    /// Wraps a block device, prefetching `window_blocks` blocks when reads
+    /// are sequential.
+    pub const fn new(inner: D, window_blocks: u64) -> Self {
+        Self {
+            inner,
+            window_blocks,
+            buf: Vec::new(),
+            start: 0,
+            next: None,
+        }
+    }
+
+    /// Returns a reference to the wrapped device.
+    pub const fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// Unwraps the block device.
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    /// The number of blocks covered by `len` bytes, 0 if the block size is.
+    fn blocks_of(&self, len: usize) -> u64 {
+        len.checked_div(self.inner.block_size()).unwrap_or(0) as u64
+    }
+
+    /// The number of blocks prefetched.
+    fn buf_blocks(&self) -> u64 {
+        self.blocks_of(self.buf.len())
+    }
+
+    /// Drops the prefetched blocks if they overlap `count` blocks from
+    /// `block_id`.
+    fn invalidate(&mut self, block_id: u64, count: u64) {
+        let end = block_id.saturating_add(count);
+        if block_id < self.start + self.buf_blocks() && self.start < end {
+            self.buf.clear();
+        }
+    }
+
+    /// Reads `blocks` blocks from `block_id` into the prefetch buffer.
+    fn prefetch(&mut self, block_id: u64, blocks: u64) -> DevResult {
+        let mut buf = core::mem::take(&mut self.buf);
+        buf.resize(blocks as usize * self.inner.block_size(), 0);
+        match self.inner.read_block(block_id, &mut buf) {
+            Ok(()) => {
+                self.buf = buf;
+                self.start = block_id;
+                Ok(())
+            }
+            Err(e) => {
+                buf.clear();
+                self.buf = buf;
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<D: BlockDriverOps> BaseDriverOps for ReadAhead<D> {
+    fn device_name(&self) -> &str {
+        self.inner.device_name()
+    }
+
+    fn device_type(&self) -> DeviceType {
+        self.inner.device_type()
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn reset(&mut self) -> DevResult {
+        self.buf.clear();
+        self.next = None;
+        self.inner.reset()
+    }
+
+    fn stats(&self) -> DeviceStats {
+        self.inner.stats()
+    }
+}
+
+impl<D: BlockDriverOps> BlockDriverOps for ReadAhead<D> {
+    #[inline]
+    fn num_blocks(&self) -> u64 {
+        self.inner.num_blocks()
+    }
+
+    #[inline]
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        let block_size = self.block_size();
+        if block_size == 0 {
+            return Err(DevError::Io);
+        }
+        if !buf.len().is_multiple_of(block_size) {
+            return Err(DevError::InvalidParam);
+        }
+        let count = (buf.len() / block_size) as u64;
+        let end = block_id.checked_add(count).ok_or(DevError::InvalidParam)?;
+        let sequential = self.next == Some(block_id);
+        self.next = Some(end);
+
+        if block_id >= self.start && end <= self.start + self.buf_blocks() {
+            let offset = (block_id - self.start) as usize * block_size;
+            buf.copy_from_slice(&self.buf[offset..offset + buf.len()]);
+            return Ok(());
+        }
+        let blocks = (count + self.window_blocks).min(self.num_blocks().saturating_sub(block_id));
+        if sequential && self.window_blocks != 0 && blocks > count {
+            // Read what was asked for anyway if the prefetch fails, e.g. at
+            // a bad block beyond the request
+            if self.prefetch(block_id, blocks).is_ok() {
+                buf.copy_from_slice(&self.buf[..buf.len()]);
+                return Ok(());
+            }
+        }
+        self.inner.read_block(block_id, buf)
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        self.invalidate(block_id, self.blocks_of(buf.len()));
+        self.inner.write_block(block_id, buf)
+    }
+
+    fn write_blocks_vectored(&mut self, block_id: u64, bufs: &[&[u8]]) -> DevResult {
+        let len: usize = bufs.iter().map(|buf| buf.len()).sum();
+        self.invalidate(block_id, self.blocks_of(len));
+        self.inner.write_blocks_vectored(block_id, bufs)
+    }
+
+    fn flush(&mut self) -> DevResult {
+        self.inner.flush()
+    }
+
+    fn write_barrier(&mut self) -> DevResult {
+        self.inner.write_barrier()
+    }
+
+    fn write_block_fua(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        self.invalidate(block_id, self.blocks_of(buf.len()));
+        self.inner.write_block_fua(block_id, buf)
+    }
+
+    fn discard(&mut self, block_id: u64, count: u64) -> DevResult {
+        self.invalidate(block_id, count);
+        self.inner.discard(block_id, count)
+    }
+
+    fn discard_supported(&self) -> bool {
+        self.inner.discard_supported()
+    }
+
+    fn write_zeros(&mut self, block_id: u64, count: u64) -> DevResult {
+        self.invalidate(block_id, count);
+        self.inner.write_zeros(block_id, count)
+    }
+
+    fn io_hints(&self) -> IoHints {
+        self.inner.io_hints()
+    }
+
+    fn is_rotational(&self) -> bool {
+        self.inner.is_rotational()
+    }
+}
+
+#[cfg(all(test, feature = "ramdisk"))]
+mod tests {
+    use super::*;
+    use crate::ramdisk::RamDisk;
+
+    /// A disk whose block `i` is filled with `i`.
+    fn disk(num_blocks: u8) -> RamDisk {
+        let data: Vec<u8> = (0..num_blocks).flat_map(|i| [i; 512]).collect();
+        RamDisk::from_bytes(&data, 512)
+    }
+
+    fn read_one(dev: &mut ReadAhead<RamDisk>, block_id: u64) -> u8 {
+        let mut buf = [0xff; 512];
+        dev.read_block(block_id, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == buf[0]));
+        buf[0]
+    }
+
+    #[test]
+    fn sequential_reads_hit_the_prefetched_blocks() {
+        let mut dev = ReadAhead::new(disk(16), 4);
+        assert_eq!(read_one(&mut dev, 0), 0);
+        // Prefetches blocks 1 to 5
+        assert_eq!(read_one(&mut dev, 1), 1);
+        assert_eq!(dev.stats().reads, 2);
+        assert_eq!(dev.stats().bytes_read, 6 * 512);
+        for i in 2..6 {
+            assert_eq!(read_one(&mut dev, i), i as u8);
+        }
+        assert_eq!(dev.stats().reads, 2);
+
+        // The window is cut at the end of the device
+        for i in 6..16 {
+            assert_eq!(read_one(&mut dev, i), i as u8);
+        }
+        assert_eq!(dev.stats().bytes_read, 16 * 512);
+    }
+
+    #[test]
+    fn random_reads_are_not_prefetched() {
+        let mut dev = ReadAhead::new(disk(16), 4);
+        for i in [3, 9, 1, 12, 7] {
+            assert_eq!(read_one(&mut dev, i), i as u8);
+        }
+        assert_eq!(dev.stats().reads, 5);
+        assert_eq!(dev.stats().bytes_read, 5 * 512);
+    }
+
+    #[test]
+    fn overlapping_writes_drop_the_prefetched_blocks() {
+        let mut dev = ReadAhead::new(disk(16), 4);
+        read_one(&mut dev, 0);
+        read_one(&mut dev, 1);
+        dev.write_block(3, &[0xaa; 512]).unwrap();
+        assert_eq!(read_one(&mut dev, 2), 2);
+        assert_eq!(read_one(&mut dev, 3), 0xaa);
+
+        // A write outside of the window keeps it
+        read_one(&mut dev, 4);
+        let reads = dev.stats().reads;
+        dev.write_block(0, &[0xbb; 512]).unwrap();
+        assert_eq!(read_one(&mut dev, 5), 5);
+        assert_eq!(dev.stats().reads, reads);
+        dev.write_zeros(6, 1).unwrap();
+        assert_eq!(read_one(&mut dev, 6), 0);
+    }
+}
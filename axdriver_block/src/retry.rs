@@ -0,0 +1,311 @@
+//! Retrying of failed reads and writes.
+
+use crate::{
+    BaseDriverOps, BlockDriverOps, DevError, DevResult, DeviceCapabilities, DeviceStats,
+    DeviceType, IoHints,
+};
+use axdriver_base::trace;
+
+/// A wrapper that retries the reads and writes of a block device that fail
+/// with [`DevError::Io`] or [`DevError::Timeout`].
+///
+/// Other errors, e.g. [`DevError::InvalidParam`], are returned immediately.
+/// Flushes, discards and zeroing are not retried.
+pub struct Retrying<D> {
+    inner: D,
+    max_retries: u32,
+    reset: bool,
+    backoff: usize,
+}
+
+impl<D: BlockDriverOps> Retrying<D> {
+    /// Wraps a block device, retrying each failed read/write at most
+    /// `max_retries` times.
+    pub const fn new(inner: D, max_retries: u32) -> Self {
+        Self {
+            inner,
+            max_retries,
+            reset: false,
+            backoff: 0,
+        }
+    }
+
+    /// Resets the device with [`BaseDriverOps::reset`] before each retry.
+    ///
+    /// If the reset fails, the error of the read/write is returned.
+    pub const fn with_reset(mut self) -> Self {
+        self.reset = true;
+        self
+    }
+
+    /// Waits before each retry, for `spins` iterations of a busy loop before
+    /// the first one and twice as many before each of the next ones.
+    pub const fn with_backoff(mut self, spins: usize) -> Self {
+        self.backoff = spins;
+        self
+    }
+
+    /// Returns a reference to the wrapped device.
+    pub const fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// Unwraps the block device.
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    fn retry<T>(&mut self, mut op: impl FnMut(&mut D) -> DevResult<T>) -> DevResult<T> {
+        let mut retries = 0;
+        loop {
+            match op(&mut self.inner) {
+                Err(e @ (DevError::Io | DevError::Timeout)) if retries < self.max_retries => {
+                    trace::warn!(
+                        "{}: retrying after {:?} ({}/{})",
+                        self.inner.device_name(),
+                        e,
+                        retries + 1,
+                        self.max_retries
+                    );
+                    for _ in 0..backoff_spins(self.backoff, retries) {
+                        core::hint::spin_loop();
+                    }
+                    if self.reset && self.inner.reset().is_err() {
+                        return Err(e);
+                    }
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// The number of spins before retry `retries` (from 0), `backoff` doubled
+/// `retries` times, or `usize::MAX` if it overflows.
+fn backoff_spins(backoff: usize, retries: u32) -> usize {
+    if backoff == 0 {
+        return 0;
+    }
+    1usize
+        .checked_shl(retries)
+        .and_then(|factor| backoff.checked_mul(factor))
+        .unwrap_or(usize::MAX)
+}
+
+impl<D: BlockDriverOps> BaseDriverOps for Retrying<D> {
+    fn device_name(&self) -> &str {
+        self.inner.device_name()
+    }
+
+    fn device_type(&self) -> DeviceType {
+        self.inner.device_type()
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn reset(&mut self) -> DevResult {
+        self.inner.reset()
+    }
+
+    fn stats(&self) -> DeviceStats {
+        self.inner.stats()
+    }
+}
+
+impl<D: BlockDriverOps> BlockDriverOps for Retrying<D> {
+    #[inline]
+    fn num_blocks(&self) -> u64 {
+        self.inner.num_blocks()
+    }
+
+    #[inline]
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        self.retry(|inner| inner.read_block(block_id, buf))
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        self.retry(|inner| inner.write_block(block_id, buf))
+    }
+
+    fn try_read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        self.retry(|inner| inner.try_read_block(block_id, buf))
+    }
+
+    fn try_write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        self.retry(|inner| inner.try_write_block(block_id, buf))
+    }
+
+    fn read_blocks_vectored(&mut self, block_id: u64, bufs: &mut [&mut [u8]]) -> DevResult {
+        self.retry(|inner| inner.read_blocks_vectored(block_id, bufs))
+    }
+
+    fn write_blocks_vectored(&mut self, block_id: u64, bufs: &[&[u8]]) -> DevResult {
+        self.retry(|inner| inner.write_blocks_vectored(block_id, bufs))
+    }
+
+    fn flush(&mut self) -> DevResult {
+        self.inner.flush()
+    }
+
+    fn write_barrier(&mut self) -> DevResult {
+        self.inner.write_barrier()
+    }
+
+    fn write_block_fua(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        self.retry(|inner| inner.write_block_fua(block_id, buf))
+    }
+
+    fn discard(&mut self, block_id: u64, count: u64) -> DevResult {
+        self.inner.discard(block_id, count)
+    }
+
+    fn discard_supported(&self) -> bool {
+        self.inner.discard_supported()
+    }
+
+    fn write_zeros(&mut self, block_id: u64, count: u64) -> DevResult {
+        self.inner.write_zeros(block_id, count)
+    }
+
+    fn io_hints(&self) -> IoHints {
+        self.inner.io_hints()
+    }
+
+    fn is_rotational(&self) -> bool {
+        self.inner.is_rotational()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::discriminant;
+
+    /// A device whose reads fail with `error` until `failures` is 0, and
+    /// whose resets fail if `reset_fails`.
+    struct FlakyDisk {
+        error: fn() -> DevError,
+        failures: u32,
+        reads: u32,
+        resets: u32,
+        reset_fails: bool,
+    }
+
+    impl FlakyDisk {
+        fn new(error: fn() -> DevError, failures: u32) -> Self {
+            Self {
+                error,
+                failures,
+                reads: 0,
+                resets: 0,
+                reset_fails: false,
+            }
+        }
+    }
+
+    impl BaseDriverOps for FlakyDisk {
+        fn device_name(&self) -> &str {
+            "flaky"
+        }
+
+        fn device_type(&self) -> DeviceType {
+            DeviceType::Block
+        }
+
+        fn reset(&mut self) -> DevResult {
+            self.resets += 1;
+            if self.reset_fails {
+                return Err(DevError::Unsupported);
+            }
+            Ok(())
+        }
+    }
+
+    impl BlockDriverOps for FlakyDisk {
+        fn num_blocks(&self) -> u64 {
+            4
+        }
+
+        fn block_size(&self) -> usize {
+            512
+        }
+
+        fn read_block(&mut self, _block_id: u64, buf: &mut [u8]) -> DevResult {
+            self.reads += 1;
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err((self.error)());
+            }
+            buf.fill(1);
+            Ok(())
+        }
+
+        fn write_block(&mut self, _block_id: u64, _buf: &[u8]) -> DevResult {
+            Ok(())
+        }
+
+        fn flush(&mut self) -> DevResult {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn io_errors_and_timeouts_are_retried_up_to_max_retries() {
+        let mut buf = [0; 512];
+        for error in [|| DevError::Io, || DevError::Timeout] {
+            let mut dev = Retrying::new(FlakyDisk::new(error, 3), 3);
+            dev.read_block(0, &mut buf).unwrap();
+            assert_eq!(buf, [1; 512]);
+            assert_eq!(dev.inner().reads, 4);
+
+            let mut dev = Retrying::new(FlakyDisk::new(error, 4), 3);
+            let e = dev.read_block(0, &mut buf).unwrap_err();
+            assert_eq!(discriminant(&e), discriminant(&error()));
+            assert_eq!(dev.inner().reads, 4);
+        }
+    }
+
+    #[test]
+    fn other_errors_are_not_retried() {
+        let mut dev = Retrying::new(FlakyDisk::new(|| DevError::InvalidParam, 1), 3);
+        assert!(matches!(
+            dev.read_block(0, &mut [0; 512]),
+            Err(DevError::InvalidParam)
+        ));
+        assert_eq!(dev.inner().reads, 1);
+    }
+
+    #[test]
+    fn retries_give_up_when_the_reset_fails() {
+        let mut buf = [0; 512];
+        let mut dev = Retrying::new(FlakyDisk::new(|| DevError::Io, 2), 3).with_reset();
+        dev.read_block(0, &mut buf).unwrap();
+        assert_eq!(dev.inner().resets, 2);
+
+        let mut disk = FlakyDisk::new(|| DevError::Timeout, 2);
+        disk.reset_fails = true;
+        let mut dev = Retrying::new(disk, 3).with_reset().with_backoff(1);
+        assert!(matches!(
+            dev.read_block(0, &mut buf),
+            Err(DevError::Timeout)
+        ));
+        assert_eq!((dev.inner().reads, dev.inner().resets), (1, 1));
+    }
+
+    #[test]
+    fn the_backoff_doubles_and_saturates() {
+        assert_eq!(backoff_spins(10, 0), 10);
+        assert_eq!(backoff_spins(10, 3), 80);
+        assert_eq!(backoff_spins(0, 100), 0);
+        assert_eq!(backoff_spins(usize::MAX / 2 + 1, 1), usize::MAX);
+        assert_eq!(backoff_spins(3, usize::BITS - 1), usize::MAX);
+        assert_eq!(backoff_spins(1, usize::BITS), usize::MAX);
+    }
+}
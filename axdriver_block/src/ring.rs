@@ -0,0 +1,211 @@
+//! A RAM disk used as a circular log, for testing.
+
+use core::ops::Range;
+
+use crate::ramdisk::RamDisk;
+use crate::{BaseDriverOps, BlockDriverOps, DevError, DevResult, DeviceStats, DeviceType};
+
+/// A [`RamDisk`] that keeps track of where it was last written, as the head
+/// of a circular log.
+///
+/// The head is the block following the last write, and goes back to block 0
+/// when a write ends at the last block, which counts as a wrap. Data can be
+/// appended at the head with [`RingDisk::append`].
+pub struct RingDisk {
+    disk: RamDisk,
+    head: u64,
+    wraps: u64,
+    last_write: Option<Range<u64>>,
+}
+
+impl RingDisk {
+    /// Creates a disk of `num_blocks` blocks of `block_size` bytes, filled
+    /// with zeros.
+    pub fn new(num_blocks: u64, block_size: usize) -> Self {
+        Self::from_disk(RamDisk::new(num_blocks, block_size))
+    }
+
+    /// Uses an existing RAM disk, with the head at block 0.
+    pub const fn from_disk(disk: RamDisk) -> Self {
+        Self {
+            disk,
+            head: 0,
+            wraps: 0,
+            last_write: None,
+        }
+    }
+
+    /// The block following the last write, where [`RingDisk::append`] writes.
+    pub const fn head_block(&self) -> u64 {
+        self.head
+    }
+
+    /// The number of times the head went back to block 0.
+    pub const fn wrap_count(&self) -> u64 {
+        self.wraps
+    }
+
+    /// The blocks written by the last write, `None` if nothing was written.
+    ///
+    /// An append that wrapped around is reported as its part from block 0.
+    pub fn last_write(&self) -> Option<Range<u64>> {
+        self.last_write.clone()
+    }
+
+    /// Writes `buf` at the head, continuing from block 0 if it reaches the
+    /// end of the disk, and returns the block where it started.
+    ///
+    /// Returns [`DevError::InvalidParam`] if `buf` is larger than the disk.
+    pub fn append(&mut self, buf: &[u8]) -> DevResult<u64> {
+        let block_size = self.block_size();
+        if !buf.len().is_multiple_of(block_size) || buf.len() as u64 > self.capacity_bytes() {
+            return Err(DevError::InvalidParam);
+        }
+        let start = self.head;
+        let first = ((self.num_blocks() - start) as usize * block_size).min(buf.len());
+        self.write_block(start, &buf[..first])?;
+        if first < buf.len() {
+            self.write_block(0, &buf[first..])?;
+        }
+        Ok(start)
+    }
+
+    /// Returns a reference to the RAM disk.
+    pub const fn inner(&self) -> &RamDisk {
+        &self.disk
+    }
+
+    /// Unwraps the RAM disk.
+    pub fn into_inner(self) -> RamDisk {
+        self.disk
+    }
+}
+
+impl BaseDriverOps for RingDisk {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+
+    fn device_name(&self) -> &str {
+        "ringdisk"
+    }
+
+    fn stats(&self) -> DeviceStats {
+        self.disk.stats()
+    }
+}
+
+impl BlockDriverOps for RingDisk {
+    #[inline]
+    fn num_blocks(&self) -> u64 {
+        self.disk.num_blocks()
+    }
+
+    #[inline]
+    fn block_size(&self) -> usize {
+        self.disk.block_size()
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        self.disk.read_block(block_id, buf)
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        self.disk.write_block(block_id, buf)?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let end = block_id + (buf.len() / self.block_size()) as u64;
+        self.last_write = Some(block_id..end);
+        if end == self.num_blocks() {
+            self.head = 0;
+            self.wraps += 1;
+        } else {
+            self.head = end;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> DevResult {
+        self.disk.flush()
+    }
+
+    fn is_rotational(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blocks(disk: &mut RingDisk) -> [u8; 4] {
+        let mut buf = [0; 4 * 4];
+        disk.read_block(0, &mut buf).unwrap();
+        core::array::from_fn(|i| buf[i * 4])
+    }
+
+    #[test]
+    fn the_head_follows_the_last_write() {
+        let mut disk = RingDisk::new(4, 4);
+        assert_eq!((disk.head_block(), disk.wrap_count()), (0, 0));
+        assert_eq!(disk.last_write(), None);
+
+        disk.write_block(1, &[1; 8]).unwrap();
+        assert_eq!(disk.head_block(), 3);
+        assert_eq!(disk.last_write(), Some(1..3));
+        // Empty and failed writes leave it alone
+        disk.write_block(0, &[]).unwrap();
+        assert!(disk.write_block(3, &[1; 8]).is_err());
+        assert_eq!(disk.head_block(), 3);
+        assert_eq!(disk.last_write(), Some(1..3));
+
+        assert_eq!(disk.append(&[2; 4]).unwrap(), 3);
+        assert_eq!(disk.head_block(), 0);
+        assert_eq!(disk.wrap_count(), 1);
+        assert_eq!(disk.last_write(), Some(3..4));
+        assert_eq!(blocks(&mut disk), [0, 1, 1, 2]);
+    }
+
+    #[test]
+    fn writes_ending_at_the_last_block_wrap() {
+        let mut disk = RingDisk::new(4, 4);
+        for i in 1..=3 {
+            disk.write_block(2, &[0; 8]).unwrap();
+            assert_eq!(disk.head_block(), 0);
+            assert_eq!(disk.wrap_count(), i);
+        }
+        // Filling the whole disk wraps once
+        assert_eq!(disk.append(&[1; 16]).unwrap(), 0);
+        assert_eq!(disk.head_block(), 0);
+        assert_eq!(disk.wrap_count(), 4);
+        assert_eq!(disk.last_write(), Some(0..4));
+    }
+
+    #[test]
+    fn appends_wrap_across_the_end() {
+        let mut disk = RingDisk::new(4, 4);
+        disk.append(&[1; 12]).unwrap();
+        assert_eq!(disk.head_block(), 3);
+
+        let mut buf = [0; 12];
+        buf[4..].fill(2);
+        buf[..4].fill(3);
+        assert_eq!(disk.append(&buf).unwrap(), 3);
+        assert_eq!(disk.head_block(), 2);
+        assert_eq!(disk.wrap_count(), 1);
+        // Reported as the part from block 0
+        assert_eq!(disk.last_write(), Some(0..2));
+        assert_eq!(blocks(&mut disk), [2, 2, 1, 3]);
+    }
+
+    #[test]
+    fn invalid_appends_are_rejected() {
+        let mut disk = RingDisk::new(4, 4);
+        disk.append(&[1; 8]).unwrap();
+        assert!(matches!(disk.append(&[2; 6]), Err(DevError::InvalidParam)));
+        assert!(matches!(disk.append(&[2; 20]), Err(DevError::InvalidParam)));
+        assert_eq!((disk.head_block(), disk.wrap_count()), (2, 0));
+        assert_eq!(blocks(&mut disk), [1, 1, 0, 0]);
+    }
+}
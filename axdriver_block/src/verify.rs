@@ -0,0 +1,306 @@
+//! Integrity verification of block data.
+
+extern crate alloc;
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use axdriver_base::trace;
+
+use crate::{
+    BaseDriverOps, BlockDriverOps, DevError, DevResult, DeviceCapabilities, DeviceStats,
+    DeviceType, IoHints,
+};
+
+/// A wrapper that checks the data read from a block device against the CRC32
+/// of what was written.
+///
+/// The CRC of every block written through the wrapper is kept in memory, and
+/// a read of a block whose content differs fails with
+/// [`DevError::BadBlock`]. Blocks never written through it are not checked.
+///
+/// Meant for testing and debugging, e.g. to catch DMA bugs.
+pub struct CrcGuard<D> {
+    inner: D,
+    crcs: BTreeMap<u64, u32>,
+}
+
+impl<D: BlockDriverOps> CrcGuard<D> {
+    /// Wraps a block device.
+    pub const fn new(inner: D) -> Self {
+        Self {
+            inner,
+            crcs: BTreeMap::new(),
+        }
+    }
+
+    /// Returns a reference to the wrapped device.
+    pub const fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// Unwraps the block device.
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    /// Records the CRCs of the blocks of `buf`, written from `block_id` with
+    /// `result`.
+    ///
+    /// If the write failed, part of the blocks may have been written, so
+    /// their CRCs are forgotten instead.
+    fn record(&mut self, block_id: u64, buf: &[u8], result: &DevResult) {
+        let block_size = self.inner.block_size();
+        if block_size == 0 {
+            return;
+        }
+        if result.is_err() {
+            self.forget(block_id, buf.len().div_ceil(block_size) as u64);
+            return;
+        }
+        for (i, data) in buf.chunks_exact(block_size).enumerate() {
+            self.crcs.insert(block_id + i as u64, crc32(data));
+        }
+    }
+
+    /// Forgets the CRCs of `count` blocks from `block_id`, whose content is
+    /// no longer known.
+    fn forget(&mut self, block_id: u64, count: u64) {
+        let end = block_id.saturating_add(count);
+        let ids: Vec<u64> = self.crcs.range(block_id..end).map(|(&id, _)| id).collect();
+        for id in ids {
+            self.crcs.remove(&id);
+        }
+    }
+}
+
+impl<D: BlockDriverOps> BaseDriverOps for CrcGuard<D> {
+    fn device_name(&self) -> &str {
+        self.inner.device_name()
+    }
+
+    fn device_type(&self) -> DeviceType {
+        self.inner.device_type()
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn stats(&self) -> DeviceStats {
+        self.inner.stats()
+    }
+}
+
+impl<D: BlockDriverOps> BlockDriverOps for CrcGuard<D> {
+    #[inline]
+    fn num_blocks(&self) -> u64 {
+        self.inner.num_blocks()
+    }
+
+    #[inline]
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        let block_size = self.inner.block_size();
+        if block_size == 0 {
+            return Err(DevError::Io);
+        }
+        self.inner.read_block(block_id, buf)?;
+        for (i, data) in buf.chunks_exact(block_size).enumerate() {
+            let id = block_id + i as u64;
+            if let Some(&crc) = self.crcs.get(&id) {
+                if crc32(data) != crc {
+                    trace::error!("CRC mismatch of block {}", id);
+                    return Err(DevError::BadBlock);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        let result = self.inner.write_block(block_id, buf);
+        self.record(block_id, buf, &result);
+        result
+    }
+
+    fn flush(&mut self) -> DevResult {
+        self.inner.flush()
+    }
+
+    fn write_barrier(&mut self) -> DevResult {
+        self.inner.write_barrier()
+    }
+
+    fn write_block_fua(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        let result = self.inner.write_block_fua(block_id, buf);
+        self.record(block_id, buf, &result);
+        result
+    }
+
+    fn discard(&mut self, block_id: u64, count: u64) -> DevResult {
+        block_id.checked_add(count).ok_or(DevError::InvalidParam)?;
+        self.inner.discard(block_id, count)?;
+        // The content of discarded blocks is unspecified
+        self.forget(block_id, count);
+        Ok(())
+    }
+
+    fn discard_supported(&self) -> bool {
+        self.inner.discard_supported()
+    }
+
+    fn io_hints(&self) -> IoHints {
+        self.inner.io_hints()
+    }
+
+    fn is_rotational(&self) -> bool {
+        self.inner.is_rotational()
+    }
+}
+
+/// Lookup table of the CRC32 (IEEE 802.3) of every byte.
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Computes the CRC32 (IEEE 802.3) of `data`.
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &b| {
+        (crc >> 8) ^ CRC32_TABLE[((crc ^ b as u32) & 0xff) as usize]
+    })
+}
+
+#[cfg(all(test, feature = "ramdisk"))]
+mod tests {
+    use super::*;
+    use crate::ramdisk::RamDisk;
+
+    /// A RAM disk that supports discards, and whose writes can fail after
+    /// their first block.
+    struct TestDisk {
+        inner: RamDisk,
+        fail_writes: bool,
+    }
+
+    impl BaseDriverOps for TestDisk {
+        fn device_name(&self) -> &str {
+            "test"
+        }
+
+        fn device_type(&self) -> DeviceType {
+            DeviceType::Block
+        }
+    }
+
+    impl BlockDriverOps for TestDisk {
+        fn num_blocks(&self) -> u64 {
+            self.inner.num_blocks()
+        }
+
+        fn block_size(&self) -> usize {
+            self.inner.block_size()
+        }
+
+        fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+            self.inner.read_block(block_id, buf)
+        }
+
+        fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+            if self.fail_writes {
+                self.inner.write_block(block_id, &buf[..512])?;
+                return Err(DevError::Io);
+            }
+            self.inner.write_block(block_id, buf)
+        }
+
+        fn flush(&mut self) -> DevResult {
+            Ok(())
+        }
+
+        fn discard(&mut self, block_id: u64, count: u64) -> DevResult {
+            self.inner.write_zeros(block_id, count)
+        }
+
+        fn discard_supported(&self) -> bool {
+            true
+        }
+    }
+
+    fn guard() -> CrcGuard<TestDisk> {
+        CrcGuard::new(TestDisk {
+            inner: RamDisk::new(8, 512),
+            fail_writes: false,
+        })
+    }
+
+    #[test]
+    fn corrupted_blocks_fail_with_bad_block() {
+        let mut guard = guard();
+        guard.write_block(2, &[1; 2 * 512]).unwrap();
+        let mut buf = [0; 2 * 512];
+        guard.read_block(2, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 1));
+
+        guard.inner.write_block(3, &[2; 512]).unwrap();
+        assert!(matches!(
+            guard.read_block(2, &mut buf),
+            Err(DevError::BadBlock)
+        ));
+        guard.read_block(2, &mut buf[..512]).unwrap();
+    }
+
+    #[test]
+    fn unwritten_blocks_are_not_checked() {
+        let mut guard = guard();
+        guard.inner.write_block(0, &[3; 512]).unwrap();
+        let mut buf = [0; 2 * 512];
+        guard.read_block(0, &mut buf).unwrap();
+        assert!(buf[..512].iter().all(|&b| b == 3));
+        assert!(buf[512..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn discards_and_failed_writes_forget_the_crcs() {
+        let mut guard = guard();
+        guard.write_block(0, &[1; 4 * 512]).unwrap();
+        guard.discard(1, 2).unwrap();
+        guard.inner.write_block(1, &[2; 2 * 512]).unwrap();
+        let mut buf = [0; 4 * 512];
+        guard.read_block(0, &mut buf[..3 * 512]).unwrap();
+        // Block 3 is still checked
+        guard.inner.write_block(3, &[2; 512]).unwrap();
+        assert!(matches!(
+            guard.read_block(0, &mut buf),
+            Err(DevError::BadBlock)
+        ));
+
+        // Only the first block of the failed write reached the disk
+        guard.write_block(4, &[1; 2 * 512]).unwrap();
+        guard.inner.fail_writes = true;
+        assert!(matches!(
+            guard.write_block(4, &[5; 2 * 512]),
+            Err(DevError::Io)
+        ));
+        guard.read_block(4, &mut buf[..2 * 512]).unwrap();
+        assert!(buf[..512].iter().all(|&b| b == 5));
+        assert!(buf[512..2 * 512].iter().all(|&b| b == 1));
+    }
+}
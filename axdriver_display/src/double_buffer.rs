@@ -0,0 +1,229 @@
+//! Double-buffered rendering on top of a display driver.
+
+use alloc::{vec, vec::Vec};
+
+use crate::{
+    BaseDriverOps, CursorImage, DevError, DevResult, DeviceType, DisplayDriverOps, DisplayInfo,
+    DisplayMode, FrameBuffer,
+};
+
+/// A display whose framebuffer is a back buffer in memory, copied to the
+/// framebuffer of the device as a whole by [`DoubleBuffered::present`].
+///
+/// Drawing never shows on the screen half-done, whatever the device flushes
+/// in the meantime.
+pub struct DoubleBuffered<D> {
+    inner: D,
+    back: Vec<u8>,
+}
+
+impl<D: DisplayDriverOps> DoubleBuffered<D> {
+    /// Creates a back buffer of the size of the framebuffer of `inner`,
+    /// initialized with its current content.
+    pub fn new(mut inner: D) -> Self {
+        let back = inner.fb().as_slice().to_vec();
+        Self { inner, back }
+    }
+
+    /// Copies the back buffer to the framebuffer of the device, and flushes
+    /// it to the screen.
+    ///
+    /// Returns [`DevError::BadState`] if the framebuffer of the device is no
+    /// longer the size of the back buffer, e.g. because the device switched
+    /// modes by itself.
+    pub fn present(&mut self) -> DevResult {
+        let mut fb = self.inner.fb();
+        if fb.len() != self.back.len() {
+            return Err(DevError::BadState);
+        }
+        fb.as_mut_slice().copy_from_slice(&self.back);
+        self.inner.flush()
+    }
+
+    /// Returns a reference to the inner display.
+    pub const fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// Consumes the wrapper, returning the inner display. The content of the
+    /// back buffer that was not presented is lost.
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+}
+
+impl<D: DisplayDriverOps> BaseDriverOps for DoubleBuffered<D> {
+    fn device_name(&self) -> &str {
+        self.inner.device_name()
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Display
+    }
+}
+
+impl<D: DisplayDriverOps> DisplayDriverOps for DoubleBuffered<D> {
+    fn info(&self) -> DisplayInfo {
+        let mut info = self.inner.info();
+        info.fb_base_vaddr = self.back.as_ptr() as usize;
+        info
+    }
+
+    /// Returns the back buffer.
+    fn fb(&mut self) -> FrameBuffer<'_> {
+        FrameBuffer::from_slice(&mut self.back)
+    }
+
+    fn need_flush(&self) -> bool {
+        true
+    }
+
+    /// Presents the back buffer, see [`DoubleBuffered::present`].
+    fn flush(&mut self) -> DevResult {
+        self.present()
+    }
+
+    fn set_cursor(&mut self, image: &CursorImage, hot_x: u32, hot_y: u32) -> DevResult {
+        self.inner.set_cursor(image, hot_x, hot_y)
+    }
+
+    fn move_cursor(&mut self, x: u32, y: u32) -> DevResult {
+        self.inner.move_cursor(x, y)
+    }
+
+    fn supported_modes(&self) -> &[DisplayMode] {
+        self.inner.supported_modes()
+    }
+
+    /// Switches the mode of the inner display, and replaces the back buffer
+    /// with a blank one of the new framebuffer size.
+    fn set_mode(&mut self, mode: DisplayMode) -> DevResult {
+        self.inner.set_mode(mode)?;
+        self.back = vec![0; self.inner.info().fb_size];
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{put_pixel, PixelFormat, Rgb888};
+
+    const MODES: [DisplayMode; 2] = [
+        DisplayMode {
+            width: 4,
+            height: 3,
+        },
+        DisplayMode {
+            width: 2,
+            height: 2,
+        },
+    ];
+
+    /// A display in memory that counts its flushes and can switch modes.
+    struct TestDisplay {
+        mode: DisplayMode,
+        fb: Vec<u8>,
+        flushes: usize,
+    }
+
+    impl TestDisplay {
+        fn new() -> Self {
+            Self {
+                mode: MODES[0],
+                fb: vec![0; 4 * 3 * 4],
+                flushes: 0,
+            }
+        }
+    }
+
+    impl BaseDriverOps for TestDisplay {
+        fn device_name(&self) -> &str {
+            "test-display"
+        }
+
+        fn device_type(&self) -> DeviceType {
+            DeviceType::Display
+        }
+    }
+
+    impl DisplayDriverOps for TestDisplay {
+        fn info(&self) -> DisplayInfo {
+            DisplayInfo {
+                width: self.mode.width,
+                height: self.mode.height,
+                format: PixelFormat::Bgra8888,
+                fb_base_vaddr: self.fb.as_ptr() as usize,
+                fb_size: self.fb.len(),
+            }
+        }
+
+        fn fb(&mut self) -> FrameBuffer<'_> {
+            FrameBuffer::from_slice(&mut self.fb)
+        }
+
+        fn need_flush(&self) -> bool {
+            true
+        }
+
+        fn flush(&mut self) -> DevResult {
+            self.flushes += 1;
+            Ok(())
+        }
+
+        fn supported_modes(&self) -> &[DisplayMode] {
+            &MODES
+        }
+
+        fn set_mode(&mut self, mode: DisplayMode) -> DevResult {
+            self.mode = mode;
+            self.fb = vec![0; mode.width as usize * mode.height as usize * 4];
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn drawing_shows_once_presented() {
+        let mut display = DoubleBuffered::new(TestDisplay::new());
+        let info = display.info();
+        assert_eq!(info.fb_size, 4 * 3 * 4);
+        assert_eq!(info.fb_base_vaddr, display.back.as_ptr() as usize);
+
+        let color = Rgb888::new(0x12, 0x34, 0x56);
+        put_pixel(display.fb().as_mut_slice(), &info, 1, 2, color);
+        assert!(display.inner().fb.iter().all(|&b| b == 0));
+        assert_eq!(display.inner().flushes, 0);
+
+        display.present().unwrap();
+        let offset = 2 * info.stride() + 4;
+        assert_eq!(
+            display.inner().fb[offset..offset + 4],
+            [0x56, 0x34, 0x12, 0xff]
+        );
+        assert_eq!(display.inner().fb, display.back);
+        assert_eq!(display.inner().flushes, 1);
+        display.flush().unwrap();
+        assert_eq!(display.inner().flushes, 2);
+    }
+
+    #[test]
+    fn switching_modes_replaces_the_back_buffer() {
+        let mut display = DoubleBuffered::new(TestDisplay::new());
+        display.fb().as_mut_slice().fill(0xff);
+        display.set_mode(MODES[1]).unwrap();
+        assert_eq!(display.info().fb_size, 2 * 2 * 4);
+        assert_eq!(display.fb().as_slice(), [0; 2 * 2 * 4]);
+        display.present().unwrap();
+        assert_eq!(display.inner().fb, [0; 2 * 2 * 4]);
+    }
+
+    #[test]
+    fn presenting_to_a_resized_framebuffer_fails() {
+        let mut display = DoubleBuffered::new(TestDisplay::new());
+        // The device switches modes behind the back of the wrapper
+        display.inner.set_mode(MODES[1]).unwrap();
+        assert!(matches!(display.present(), Err(DevError::BadState)));
+        assert_eq!(display.inner().flushes, 0);
+        assert_eq!(display.inner().fb, [0; 2 * 2 * 4]);
+    }
+}
@@ -2,9 +2,41 @@
 
 #![no_std]
 
+extern crate alloc;
+
+mod double_buffer;
+mod null;
+mod pixel;
+
 #[doc(no_inline)]
 pub use axdriver_base::{BaseDriverOps, DevError, DevResult, DeviceType};
 
+pub use self::double_buffer::DoubleBuffered;
+pub use self::null::NullDisplay;
+pub use self::pixel::{convert_row, put_pixel, PixelFormat, Rgb888};
+
+/// A resolution of the display.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisplayMode {
+    /// The visible width.
+    pub width: u32,
+    /// The visible height.
+    pub height: u32,
+}
+
+/// The image of a hardware cursor.
+#[derive(Debug, Clone, Copy)]
+pub struct CursorImage<'a> {
+    /// The width in pixels.
+    pub width: u32,
+    /// The height in pixels.
+    pub height: u32,
+    /// The layout of the pixels.
+    pub format: PixelFormat,
+    /// The pixels, row by row without padding.
+    pub pixels: &'a [u8],
+}
+
 /// The information of the graphics device.
 #[derive(Debug, Clone, Copy)]
 pub struct DisplayInfo {
@@ -12,17 +44,41 @@ pub struct DisplayInfo {
     pub width: u32,
     /// The visible height.
     pub height: u32,
+    /// The layout of the pixels.
+    pub format: PixelFormat,
     /// The base virtual address of the framebuffer.
     pub fb_base_vaddr: usize,
     /// The size of the framebuffer in bytes.
     pub fb_size: usize,
 }
 
+impl DisplayInfo {
+    /// The number of bytes between the starts of two consecutive rows of the
+    /// framebuffer.
+    pub const fn stride(&self) -> usize {
+        self.width as usize * self.format.bytes_per_pixel()
+    }
+
+    /// Clamps the rectangle at (`x`, `y`) of `w` × `h` pixels to the visible
+    /// area, returning it as `(x, y, w, h)`.
+    ///
+    /// Returns [`DevError::InvalidParam`] if it is entirely off-screen or
+    /// empty.
+    pub fn clamp_rect(&self, x: u32, y: u32, w: u32, h: u32) -> DevResult<(u32, u32, u32, u32)> {
+        if x >= self.width || y >= self.height || w == 0 || h == 0 {
+            return Err(DevError::InvalidParam);
+        }
+        let w = w.min(self.width - x);
+        let h = h.min(self.height - y);
+        Ok((x, y, w, h))
+    }
+}
+
 /// The framebuffer.
 ///
 /// It's a special memory buffer that mapped from the device memory.
 pub struct FrameBuffer<'a> {
-    _raw: &'a mut [u8],
+    raw: &'a mut [u8],
 }
 
 impl<'a> FrameBuffer<'a> {
@@ -33,13 +89,34 @@ impl<'a> FrameBuffer<'a> {
     /// Caller must insure that the given memory region is valid and accessible.
     pub unsafe fn from_raw_parts_mut(ptr: *mut u8, len: usize) -> Self {
         Self {
-            _raw: core::slice::from_raw_parts_mut(ptr, len),
+            raw: core::slice::from_raw_parts_mut(ptr, len),
         }
     }
 
     /// Use the given slice as the framebuffer.
     pub fn from_slice(slice: &'a mut [u8]) -> Self {
-        Self { _raw: slice }
+        Self { raw: slice }
+    }
+
+    /// The framebuffer memory, with rows of [`DisplayInfo::stride`] bytes.
+    pub fn as_slice(&self) -> &[u8] {
+        self.raw
+    }
+
+    /// The mutable framebuffer memory, with rows of [`DisplayInfo::stride`]
+    /// bytes.
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        self.raw
+    }
+
+    /// The size of the framebuffer in bytes.
+    pub fn len(&self) -> usize {
+        self.raw.len()
+    }
+
+    /// Whether the framebuffer is empty.
+    pub fn is_empty(&self) -> bool {
+        self.raw.is_empty()
     }
 }
 
@@ -49,11 +126,67 @@ pub trait DisplayDriverOps: BaseDriverOps {
     fn info(&self) -> DisplayInfo;
 
     /// Get the framebuffer.
-    fn fb(&self) -> FrameBuffer;
+    ///
+    /// The borrow of the driver keeps it from being flushed while the
+    /// framebuffer is being drawn to.
+    fn fb(&mut self) -> FrameBuffer<'_>;
 
     /// Whether need to flush the framebuffer to the screen.
     fn need_flush(&self) -> bool;
 
     /// Flush framebuffer to the screen.
     fn flush(&mut self) -> DevResult;
+
+    /// Flush the rectangle at (`x`, `y`) of `w` × `h` pixels to the screen.
+    ///
+    /// The rectangle is clamped to the visible area, and
+    /// [`DevError::InvalidParam`] is returned if it is entirely off-screen.
+    /// The default implementation flushes the whole framebuffer.
+    fn flush_region(&mut self, x: u32, y: u32, w: u32, h: u32) -> DevResult {
+        self.info().clamp_rect(x, y, w, h)?;
+        self.flush()
+    }
+
+    /// Sets the image of the hardware cursor, whose hot spot is at
+    /// (`hot_x`, `hot_y`) in the image.
+    ///
+    /// The default implementation returns [`DevError::Unsupported`].
+    fn set_cursor(&mut self, _image: &CursorImage, _hot_x: u32, _hot_y: u32) -> DevResult {
+        Err(DevError::Unsupported)
+    }
+
+    /// Moves the hot spot of the hardware cursor to (`x`, `y`).
+    ///
+    /// The default implementation returns [`DevError::Unsupported`].
+    fn move_cursor(&mut self, _x: u32, _y: u32) -> DevResult {
+        Err(DevError::Unsupported)
+    }
+
+    /// The resolutions the display can be switched to with
+    /// [`DisplayDriverOps::set_mode`].
+    ///
+    /// The default implementation returns none.
+    fn supported_modes(&self) -> &[DisplayMode] {
+        &[]
+    }
+
+    /// Switches the display to the given resolution, resizing the
+    /// framebuffer.
+    ///
+    /// Returns [`DevError::InvalidParam`] if `mode` is not one of
+    /// [`DisplayDriverOps::supported_modes`]. The default implementation
+    /// cannot switch modes: it accepts a supported mode that is the current
+    /// resolution, without doing anything, and returns
+    /// [`DevError::Unsupported`] for the other supported modes.
+    fn set_mode(&mut self, mode: DisplayMode) -> DevResult {
+        if !self.supported_modes().contains(&mode) {
+            return Err(DevError::InvalidParam);
+        }
+        let info = self.info();
+        if (info.width, info.height) == (mode.width, mode.height) {
+            Ok(())
+        } else {
+            Err(DevError::Unsupported)
+        }
+    }
 }
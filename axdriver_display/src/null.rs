@@ -0,0 +1,111 @@
+//! A display without hardware, for headless systems and testing.
+
+use alloc::{vec, vec::Vec};
+
+use crate::{
+    BaseDriverOps, DevResult, DeviceType, DisplayDriverOps, DisplayInfo, FrameBuffer, PixelFormat,
+};
+
+/// A display whose framebuffer is only kept in memory.
+///
+/// Flushing does nothing, and what is drawn can be read back through
+/// [`DisplayDriverOps::fb`].
+pub struct NullDisplay {
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    fb: Vec<u8>,
+}
+
+impl NullDisplay {
+    /// Creates a display of `width` × `height` pixels in the given format,
+    /// with a zeroed framebuffer.
+    pub fn new(width: u32, height: u32, format: PixelFormat) -> Self {
+        let fb_size = width as usize * height as usize * format.bytes_per_pixel();
+        Self {
+            width,
+            height,
+            format,
+            fb: vec![0; fb_size],
+        }
+    }
+}
+
+impl BaseDriverOps for NullDisplay {
+    fn device_name(&self) -> &str {
+        "null-display"
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Display
+    }
+}
+
+impl DisplayDriverOps for NullDisplay {
+    fn info(&self) -> DisplayInfo {
+        DisplayInfo {
+            width: self.width,
+            height: self.height,
+            format: self.format,
+            fb_base_vaddr: self.fb.as_ptr() as usize,
+            fb_size: self.fb.len(),
+        }
+    }
+
+    fn fb(&mut self) -> FrameBuffer<'_> {
+        FrameBuffer::from_slice(&mut self.fb)
+    }
+
+    fn need_flush(&self) -> bool {
+        false
+    }
+
+    fn flush(&mut self) -> DevResult {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{put_pixel, DisplayMode, Rgb888};
+
+    #[test]
+    fn pixels_are_read_back_from_the_framebuffer() {
+        let mut display = NullDisplay::new(4, 3, PixelFormat::Bgra8888);
+        let info = display.info();
+        assert_eq!((info.width, info.height, info.fb_size), (4, 3, 4 * 3 * 4));
+        assert_eq!(info.stride(), 16);
+        assert!(display.fb().as_slice().iter().all(|&b| b == 0));
+
+        let color = Rgb888::new(0x12, 0x34, 0x56);
+        put_pixel(display.fb().as_mut_slice(), &info, 2, 1, color);
+        display.flush().unwrap();
+        display.flush_region(2, 1, 1, 1).unwrap();
+        assert!(display.flush_region(4, 0, 1, 1).is_err());
+
+        let fb = display.fb();
+        let offset = info.stride() + 2 * 4;
+        assert_eq!(
+            &fb.as_slice()[offset..offset + 4],
+            &[0x56, 0x34, 0x12, 0xff]
+        );
+        assert_eq!(info.format.decode(&fb.as_slice()[offset..]), color);
+        assert_eq!(fb.as_slice().iter().filter(|&&b| b != 0).count(), 4);
+    }
+
+    #[test]
+    fn modes_cannot_be_switched() {
+        let mut display = NullDisplay::new(4, 3, PixelFormat::Bgra8888);
+        assert!(display.supported_modes().is_empty());
+        // Not even to the current resolution, which is not a supported mode
+        let mode = DisplayMode {
+            width: 4,
+            height: 3,
+        };
+        assert!(matches!(
+            display.set_mode(mode),
+            Err(crate::DevError::InvalidParam)
+        ));
+    }
+}
@@ -0,0 +1,239 @@
+//! Pixel formats and conversions between them.
+
+use crate::DisplayInfo;
+
+/// The layout of a pixel in the framebuffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// 32 bits per pixel, blue in the lowest byte, then green, red and alpha.
+    Bgra8888,
+    /// 32 bits per pixel, red in the lowest byte, then green, blue and alpha.
+    Rgba8888,
+    /// 32 bits per pixel, `0xXXRRGGBB` in little endian, the top byte is
+    /// unused.
+    Xrgb8888,
+    /// 16 bits per pixel, `RRRRRGGGGGGBBBBB` in little endian.
+    Rgb565,
+}
+
+/// A color with 8 bits per channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Rgb888 {
+    /// The red channel.
+    pub r: u8,
+    /// The green channel.
+    pub g: u8,
+    /// The blue channel.
+    pub b: u8,
+}
+
+impl Rgb888 {
+    /// Creates a color from its channels.
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+}
+
+impl PixelFormat {
+    /// The size of a pixel in bytes.
+    pub const fn bytes_per_pixel(self) -> usize {
+        match self {
+            Self::Bgra8888 | Self::Rgba8888 | Self::Xrgb8888 => 4,
+            Self::Rgb565 => 2,
+        }
+    }
+
+    /// Writes `color` as a pixel at the start of `dst`, which must be at
+    /// least [`PixelFormat::bytes_per_pixel`] long. Alpha channels are set to
+    /// opaque.
+    pub fn encode(self, color: Rgb888, dst: &mut [u8]) {
+        let Rgb888 { r, g, b } = color;
+        match self {
+            Self::Bgra8888 | Self::Xrgb8888 => dst[..4].copy_from_slice(&[b, g, r, 0xff]),
+            Self::Rgba8888 => dst[..4].copy_from_slice(&[r, g, b, 0xff]),
+            Self::Rgb565 => {
+                let v = (r as u16 >> 3) << 11 | (g as u16 >> 2) << 5 | b as u16 >> 3;
+                dst[..2].copy_from_slice(&v.to_le_bytes());
+            }
+        }
+    }
+
+    /// Reads the pixel at the start of `src`, which must be at least
+    /// [`PixelFormat::bytes_per_pixel`] long.
+    pub fn decode(self, src: &[u8]) -> Rgb888 {
+        match self {
+            Self::Bgra8888 | Self::Xrgb8888 => Rgb888::new(src[2], src[1], src[0]),
+            Self::Rgba8888 => Rgb888::new(src[0], src[1], src[2]),
+            Self::Rgb565 => {
+                let v = u16::from_le_bytes([src[0], src[1]]);
+                // Replicate the high bits into the low ones, so that full
+                // intensity stays 0xff.
+                let r = (v >> 11) as u8 & 0x1f;
+                let g = (v >> 5) as u8 & 0x3f;
+                let b = v as u8 & 0x1f;
+                Rgb888::new(r << 3 | r >> 2, g << 2 | g >> 4, b << 3 | b >> 2)
+            }
+        }
+    }
+}
+
+/// Sets the pixel at (`x`, `y`) of the framebuffer `fb` described by `info`.
+///
+/// Pixels outside the visible area or the framebuffer are ignored.
+pub fn put_pixel(fb: &mut [u8], info: &DisplayInfo, x: u32, y: u32, color: Rgb888) {
+    if x >= info.width || y >= info.height {
+        return;
+    }
+    let bpp = info.format.bytes_per_pixel();
+    let offset = y as usize * info.stride() + x as usize * bpp;
+    if let Some(dst) = fb.get_mut(offset..offset + bpp) {
+        info.format.encode(color, dst);
+    }
+}
+
+/// Converts the pixels of `src` in `src_fmt` into `dst` in `dst_fmt`.
+///
+/// As many pixels as both slices hold are converted, and their number is
+/// returned. Alpha channels are set to opaque.
+pub fn convert_row(
+    src_fmt: PixelFormat,
+    dst_fmt: PixelFormat,
+    src: &[u8],
+    dst: &mut [u8],
+) -> usize {
+    let src_pixels = src.chunks_exact(src_fmt.bytes_per_pixel());
+    let dst_pixels = dst.chunks_exact_mut(dst_fmt.bytes_per_pixel());
+    let mut count = 0;
+    for (s, d) in src_pixels.zip(dst_pixels) {
+        dst_fmt.encode(src_fmt.decode(s), d);
+        count += 1;
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMATS: [PixelFormat; 4] = [
+        PixelFormat::Bgra8888,
+        PixelFormat::Rgba8888,
+        PixelFormat::Xrgb8888,
+        PixelFormat::Rgb565,
+    ];
+
+    #[test]
+    fn pixels_are_encoded_in_the_format_layout() {
+        let color = Rgb888::new(0x12, 0x34, 0x56);
+        let mut buf = [0; 4];
+        PixelFormat::Bgra8888.encode(color, &mut buf);
+        assert_eq!(buf, [0x56, 0x34, 0x12, 0xff]);
+        PixelFormat::Rgba8888.encode(color, &mut buf);
+        assert_eq!(buf, [0x12, 0x34, 0x56, 0xff]);
+        PixelFormat::Xrgb8888.encode(color, &mut buf);
+        assert_eq!(u32::from_le_bytes(buf) & 0xff_ffff, 0x12_3456);
+
+        // Only the first two bytes are written
+        let mut buf = [0xaa; 4];
+        PixelFormat::Rgb565.encode(Rgb888::new(0xff, 0x00, 0xff), &mut buf);
+        assert_eq!(buf, [0x1f, 0xf8, 0xaa, 0xaa]);
+        PixelFormat::Rgb565.encode(Rgb888::new(0x00, 0xff, 0x00), &mut buf);
+        assert_eq!(u16::from_le_bytes([buf[0], buf[1]]), 0x07e0);
+    }
+
+    #[test]
+    fn pixels_are_decoded_from_the_format_layout() {
+        let color = Rgb888::new(0x12, 0x34, 0x56);
+        assert_eq!(PixelFormat::Bgra8888.decode(&[0x56, 0x34, 0x12, 0]), color);
+        assert_eq!(PixelFormat::Rgba8888.decode(&[0x12, 0x34, 0x56, 0]), color);
+        assert_eq!(
+            PixelFormat::Xrgb8888.decode(&0x0012_3456u32.to_le_bytes()),
+            color
+        );
+        assert_eq!(
+            PixelFormat::Rgb565.decode(&0x07e0u16.to_le_bytes()),
+            Rgb888::new(0, 0xff, 0)
+        );
+    }
+
+    #[test]
+    fn pixels_round_trip() {
+        let colors = [
+            Rgb888::new(0, 0, 0),
+            Rgb888::new(0xff, 0xff, 0xff),
+            Rgb888::new(0x12, 0x34, 0x56),
+        ];
+        for format in FORMATS {
+            for color in colors {
+                let mut buf = [0; 4];
+                format.encode(color, &mut buf);
+                let decoded = format.decode(&buf);
+                if format == PixelFormat::Rgb565 {
+                    // The low bits are lost and replicated from the high ones
+                    let trunc = |c: u8, bits: u32| {
+                        let c = c >> (8 - bits);
+                        c << (8 - bits) | c >> (2 * bits - 8)
+                    };
+                    let expected =
+                        Rgb888::new(trunc(color.r, 5), trunc(color.g, 6), trunc(color.b, 5));
+                    assert_eq!(decoded, expected);
+                } else {
+                    assert_eq!(decoded, color);
+                }
+            }
+        }
+        // Full intensity and black survive the truncation
+        let mut buf = [0; 2];
+        PixelFormat::Rgb565.encode(Rgb888::new(0xff, 0xff, 0xff), &mut buf);
+        assert_eq!(buf, [0xff, 0xff]);
+        assert_eq!(
+            PixelFormat::Rgb565.decode(&buf),
+            Rgb888::new(0xff, 0xff, 0xff)
+        );
+        PixelFormat::Rgb565.encode(Rgb888::new(0x07, 0x03, 0x07), &mut buf);
+        assert_eq!(PixelFormat::Rgb565.decode(&buf), Rgb888::new(0, 0, 0));
+    }
+
+    #[test]
+    fn rows_are_converted_between_formats() {
+        let src = [0x56, 0x34, 0x12, 0x00, 0xff, 0x00, 0x00, 0x00];
+        let mut dst = [0; 8];
+        let count = convert_row(PixelFormat::Bgra8888, PixelFormat::Rgba8888, &src, &mut dst);
+        assert_eq!(count, 2);
+        assert_eq!(dst, [0x12, 0x34, 0x56, 0xff, 0x00, 0x00, 0xff, 0xff]);
+
+        // Down to 16 bits, only as many pixels as the destination holds
+        let mut dst = [0; 3];
+        let count = convert_row(PixelFormat::Bgra8888, PixelFormat::Rgb565, &src, &mut dst);
+        assert_eq!(count, 1);
+        assert_eq!(u16::from_le_bytes([dst[0], dst[1]]), 0x11aa);
+        assert_eq!(dst[2], 0);
+
+        // Up from 16 bits, only as many pixels as the source holds
+        let src = [0x1f, 0x00, 0xe0, 0x07, 0x00];
+        let mut dst = [0; 12];
+        let count = convert_row(PixelFormat::Rgb565, PixelFormat::Xrgb8888, &src, &mut dst);
+        assert_eq!(count, 2);
+        assert_eq!(dst[..8], [0xff, 0x00, 0x00, 0xff, 0x00, 0xff, 0x00, 0xff]);
+        assert_eq!(dst[8..], [0; 4]);
+    }
+
+    #[test]
+    fn pixels_outside_the_visible_area_are_ignored() {
+        let info = DisplayInfo {
+            width: 2,
+            height: 2,
+            format: PixelFormat::Rgb565,
+            fb_base_vaddr: 0,
+            fb_size: 6,
+        };
+        // A framebuffer shorter than the visible area
+        let mut fb = [0; 6];
+        let white = Rgb888::new(0xff, 0xff, 0xff);
+        put_pixel(&mut fb, &info, 1, 0, white);
+        put_pixel(&mut fb, &info, 2, 0, white);
+        put_pixel(&mut fb, &info, 0, 2, white);
+        put_pixel(&mut fb, &info, 1, 1, white);
+        assert_eq!(fb, [0, 0, 0xff, 0xff, 0, 0]);
+    }
+}
@@ -3,9 +3,8 @@ use alloc::collections::VecDeque;
 use alloc::vec::Vec;
 use core::ptr::NonNull;
 
-use axdriver_base::{BaseDriverOps, DevError, DevResult, DeviceType};
+use axdriver_base::{trace, BaseDriverOps, DevError, DevResult, DeviceType};
 use fxmac_rs::{self, xmac_init, FXmac, FXmacGetMacAddress, FXmacLwipPortTx, FXmacRecvHandler};
-use log::*;
 
 use crate::{EthernetAddress, NetBufPtr, NetDriverOps};
 
@@ -28,12 +27,12 @@ unsafe impl Send for FXmacNic {}
 impl FXmacNic {
     /// initialize fxmac driver
     pub fn init(mapped_regs: usize) -> DevResult<Self> {
-        info!("FXmacNic init @ {:#x}", mapped_regs);
+        trace::info!("FXmacNic init @ {:#x}", mapped_regs);
         let rx_buffer_queue = VecDeque::with_capacity(QS);
 
         let mut hwaddr: [u8; 6] = [0; 6];
         FXmacGetMacAddress(&mut hwaddr, 0);
-        info!("Got FXmac HW address: {:x?}", hwaddr);
+        trace::info!("Got FXmac HW address: {:x?}", hwaddr);
 
         let inner = xmac_init(&hwaddr);
         let dev = Self {
@@ -98,7 +97,7 @@ impl NetDriverOps for FXmacNic {
                 None => Err(DevError::Again),
                 Some(packets) => {
                     for packet in packets {
-                        debug!("received packet length {}", packet.len());
+                        trace::debug!("received packet length {}", packet.len());
                         let mut buf = Box::new(packet);
                         let buf_ptr = buf.as_mut_ptr() as *mut u8;
                         let buf_len = buf.len();
@@ -1,8 +1,8 @@
 use core::convert::From;
 use core::{mem::ManuallyDrop, ptr::NonNull};
 
-use alloc::{collections::VecDeque, sync::Arc};
-use axdriver_base::{BaseDriverOps, DevError, DevResult, DeviceType};
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use axdriver_base::{trace, BaseDriverOps, DevError, DevResult, DeviceStats, DeviceType};
 use ixgbe_driver::{IxgbeDevice, IxgbeError, IxgbeNetBuf, MemPool, NicDevice};
 pub use ixgbe_driver::{IxgbeHal, PhysAddr, INTEL_82599, INTEL_VEND};
 
@@ -17,11 +17,15 @@ const MEM_POOL_ENTRY_SIZE: usize = 2048;
 
 /// The ixgbe NIC device driver.
 ///
-/// `QS` is the ixgbe queue size, `QN` is the ixgbe queue num.
+/// `QS` is the ixgbe queue size, `QN` is the ixgbe queue num. Each of the `QN`
+/// queue pairs can be used with [`NetDriverOps::transmit_on`] and
+/// [`NetDriverOps::receive_from`].
 pub struct IxgbeNic<H: IxgbeHal, const QS: usize, const QN: u16> {
     inner: IxgbeDevice<H, QS>,
     mem_pool: Arc<MemPool>,
-    rx_buffer_queue: VecDeque<NetBufPtr>,
+    /// Packets received in a batch but not returned yet, for each queue.
+    rx_buffer_queues: Vec<VecDeque<NetBufPtr>>,
+    stats: DeviceStats,
 }
 
 unsafe impl<H: IxgbeHal, const QS: usize, const QN: u16> Sync for IxgbeNic<H, QS, QN> {}
@@ -34,17 +38,28 @@ impl<H: IxgbeHal, const QS: usize, const QN: u16> IxgbeNic<H, QS, QN> {
         let mem_pool = MemPool::allocate::<H>(MEM_POOL, MEM_POOL_ENTRY_SIZE)
             .map_err(|_| DevError::NoMemory)?;
         let inner = IxgbeDevice::<H, QS>::init(base, len, QN, QN, &mem_pool).map_err(|err| {
-            log::error!("Failed to initialize ixgbe device: {:?}", err);
+            trace::error!("Failed to initialize ixgbe device: {:?}", err);
             DevError::BadState
         })?;
 
-        let rx_buffer_queue = VecDeque::with_capacity(RX_BUFFER_SIZE);
+        let rx_buffer_queues = (0..QN)
+            .map(|_| VecDeque::with_capacity(RX_BUFFER_SIZE))
+            .collect();
         Ok(Self {
             inner,
             mem_pool,
-            rx_buffer_queue,
+            rx_buffer_queues,
+            stats: DeviceStats::default(),
         })
     }
+
+    fn queue_id(queue: usize) -> DevResult<u16> {
+        if queue < QN as usize {
+            Ok(queue as u16)
+        } else {
+            Err(DevError::InvalidParam)
+        }
+    }
 }
 
 impl<H: IxgbeHal, const QS: usize, const QN: u16> BaseDriverOps for IxgbeNic<H, QS, QN> {
@@ -55,6 +70,10 @@ impl<H: IxgbeHal, const QS: usize, const QN: u16> BaseDriverOps for IxgbeNic<H,
     fn device_type(&self) -> DeviceType {
         DeviceType::Net
     }
+
+    fn stats(&self) -> DeviceStats {
+        self.stats
+    }
 }
 
 impl<H: IxgbeHal, const QS: usize, const QN: u16> NetDriverOps for IxgbeNic<H, QS, QN> {
@@ -71,7 +90,7 @@ impl<H: IxgbeHal, const QS: usize, const QN: u16> NetDriverOps for IxgbeNic<H, Q
     }
 
     fn can_receive(&self) -> bool {
-        !self.rx_buffer_queue.is_empty() || self.inner.can_receive(0).unwrap()
+        !self.rx_buffer_queues[0].is_empty() || self.inner.can_receive(0).unwrap()
     }
 
     fn can_transmit(&self) -> bool {
@@ -93,39 +112,77 @@ impl<H: IxgbeHal, const QS: usize, const QN: u16> NetDriverOps for IxgbeNic<H, Q
     }
 
     fn receive(&mut self) -> DevResult<NetBufPtr> {
-        if !self.can_receive() {
+        self.receive_from(0)
+    }
+
+    fn num_queues(&self) -> usize {
+        QN as usize
+    }
+
+    fn receive_from(&mut self, queue: usize) -> DevResult<NetBufPtr> {
+        let result = self.receive_packet(queue);
+        let len = result.as_ref().map_or(0, |buf| buf.packet_len());
+        self.stats.record(false, len, &result);
+        result
+    }
+
+    fn transmit(&mut self, tx_buf: NetBufPtr) -> DevResult {
+        self.transmit_on(0, tx_buf)
+    }
+
+    fn transmit_on(&mut self, queue: usize, tx_buf: NetBufPtr) -> DevResult {
+        let len = tx_buf.packet_len();
+        let result = self.send_packet(queue, tx_buf);
+        self.stats.record(true, len, &result);
+        result
+    }
+
+    fn alloc_tx_buffer(&mut self, size: usize) -> DevResult<NetBufPtr> {
+        let tx_buf = IxgbeNetBuf::alloc(&self.mem_pool, size).map_err(|_| DevError::NoMemory)?;
+        Ok(NetBufPtr::from(tx_buf))
+    }
+}
+
+impl<H: IxgbeHal, const QS: usize, const QN: u16> IxgbeNic<H, QS, QN> {
+    /// Receives a packet from `queue`, without counting it.
+    fn receive_packet(&mut self, queue: usize) -> DevResult<NetBufPtr> {
+        let qid = Self::queue_id(queue)?;
+        let rx_buffer_queue = &mut self.rx_buffer_queues[queue];
+        if let Some(rx_buf) = rx_buffer_queue.pop_front() {
+            // RX buffer have received packets.
+            return Ok(rx_buf);
+        }
+        if !self.inner.can_receive(qid).unwrap() {
             return Err(DevError::Again);
         }
-        if !self.rx_buffer_queue.is_empty() {
-            // RX buffer have received packets.
-            Ok(self.rx_buffer_queue.pop_front().unwrap())
-        } else {
-            let f = |rx_buf| {
-                let rx_buf = NetBufPtr::from(rx_buf);
-                self.rx_buffer_queue.push_back(rx_buf);
-            };
-
-            // RX queue is empty, receive from ixgbe NIC.
-            match self.inner.receive_packets(0, RECV_BATCH_SIZE, f) {
-                Ok(recv_nums) => {
-                    if recv_nums == 0 {
-                        // No packet is received, it is impossible things.
-                        panic!("Error: No receive packets.")
-                    } else {
-                        Ok(self.rx_buffer_queue.pop_front().unwrap())
-                    }
+        let f = |rx_buf| {
+            let rx_buf = NetBufPtr::from(rx_buf);
+            rx_buffer_queue.push_back(rx_buf);
+        };
+
+        // RX queue is empty, receive from ixgbe NIC.
+        match self.inner.receive_packets(qid, RECV_BATCH_SIZE, f) {
+            Ok(recv_nums) => {
+                if recv_nums == 0 {
+                    // No packet is received, it is impossible things.
+                    panic!("Error: No receive packets.")
+                } else {
+                    Ok(self.rx_buffer_queues[queue].pop_front().unwrap())
                 }
-                Err(e) => match e {
-                    IxgbeError::NotReady => Err(DevError::Again),
-                    _ => Err(DevError::BadState),
-                },
             }
+            Err(e) => match e {
+                IxgbeError::NotReady => Err(DevError::Again),
+                _ => Err(DevError::BadState),
+            },
         }
     }
 
-    fn transmit(&mut self, tx_buf: NetBufPtr) -> DevResult {
+    /// Transmits a packet on `queue`, without counting it.
+    fn send_packet(&mut self, queue: usize, tx_buf: NetBufPtr) -> DevResult {
+        // Converted first, so that the buffer returns to the pool on errors
         let tx_buf = ixgbe_ptr_to_buf(tx_buf, &self.mem_pool)?;
-        match self.inner.send(0, tx_buf) {
+        let qid = Self::queue_id(queue)?;
+        match self.inner.send(qid, tx_buf) {
             Ok(_) => Ok(()),
             Err(err) => match err {
                 IxgbeError::QueueFull => Err(DevError::Again),
@@ -133,11 +190,6 @@ impl<H: IxgbeHal, const QS: usize, const QN: u16> NetDriverOps for IxgbeNic<H, Q
             },
         }
     }
-
-    fn alloc_tx_buffer(&mut self, size: usize) -> DevResult<NetBufPtr> {
-        let tx_buf = IxgbeNetBuf::alloc(&self.mem_pool, size).map_err(|_| DevError::NoMemory)?;
-        Ok(NetBufPtr::from(tx_buf))
-    }
 }
 
 impl From<IxgbeNetBuf> for NetBufPtr {
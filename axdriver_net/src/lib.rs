@@ -11,16 +11,58 @@ pub mod fxmac;
 pub mod ixgbe;
 mod net_buf;
 
+use core::fmt;
 use core::ptr::NonNull;
 
 #[doc(no_inline)]
-pub use axdriver_base::{BaseDriverOps, DevError, DevResult, DeviceType};
+pub use axdriver_base::{BaseDriverOps, DevError, DevResult, DeviceStats, DeviceType};
 
 pub use self::net_buf::{NetBuf, NetBufBox, NetBufPool};
 
 /// The ethernet address of the NIC (MAC address).
+///
+/// It is displayed as colon-separated hexadecimal bytes, e.g.
+/// `52:54:00:12:34:56`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct EthernetAddress(pub [u8; 6]);
 
+impl fmt::Display for EthernetAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+bitflags::bitflags! {
+    /// Offloads requested for a transmitted packet, see
+    /// [`NetDriverOps::transmit_with`].
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct TxFlags: u32 {
+        /// The device computes the IPv4 header checksum.
+        const CSUM_IPV4 = 1 << 0;
+        /// The device computes the TCP checksum.
+        const CSUM_TCP = 1 << 1;
+        /// The device computes the UDP checksum.
+        const CSUM_UDP = 1 << 2;
+        /// The device segments the TCP packet (TCP segmentation offload).
+        const TSO = 1 << 3;
+    }
+}
+
+/// The state of the link of a NIC, see [`NetDriverOps::link_status`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkStatus {
+    /// Packets can be sent and received.
+    Up {
+        /// The speed of the link in Mbit/s, `None` if unknown.
+        speed_mbps: Option<u32>,
+        /// Whether the link is full duplex, `None` if unknown.
+        full_duplex: Option<bool>,
+    },
+    /// There is no link, e.g. the cable is unplugged.
+    Down,
+}
+
 /// Operations that require a network device (NIC) driver to implement.
 pub trait NetDriverOps: BaseDriverOps {
     /// The ethernet address of the NIC.
@@ -32,6 +74,17 @@ pub trait NetDriverOps: BaseDriverOps {
     /// Whether can receive packets.
     fn can_receive(&self) -> bool;
 
+    /// The state of the link.
+    ///
+    /// The default implementation reports the link as up, at an unknown
+    /// speed, for devices that cannot tell.
+    fn link_status(&self) -> LinkStatus {
+        LinkStatus::Up {
+            speed_mbps: None,
+            full_duplex: None,
+        }
+    }
+
     /// Size of the receive queue.
     fn rx_queue_size(&self) -> usize;
 
@@ -65,6 +118,76 @@ pub trait NetDriverOps: BaseDriverOps {
     /// Allocate a memory buffer of a specified size for network transmission,
     /// returns [`DevResult`]
     fn alloc_tx_buffer(&mut self, size: usize) -> DevResult<NetBufPtr>;
+
+    /// The offloads that [`NetDriverOps::transmit_with`] can perform.
+    ///
+    /// The default implementation returns none.
+    fn tx_offloads(&self) -> TxFlags {
+        TxFlags::empty()
+    }
+
+    /// Transmits a packet with the given offloads, like
+    /// [`NetDriverOps::transmit`].
+    ///
+    /// `flags` must be within [`NetDriverOps::tx_offloads`]: callers check
+    /// it before transmitting, and compute the other checksums or segment
+    /// the packet themselves. Other flags fail with [`DevError::Unsupported`]
+    /// and, as for the other errors, the buffer is not given back. The
+    /// default implementation only accepts empty `flags`. The virtio-net
+    /// driver of `axdriver_virtio` honors the checksum flags, see its
+    /// documentation.
+    fn transmit_with(&mut self, tx_buf: NetBufPtr, flags: TxFlags) -> DevResult {
+        if !self.tx_offloads().contains(flags) {
+            return Err(DevError::Unsupported);
+        }
+        self.transmit(tx_buf)
+    }
+
+    /// Enables or disables the reception of all frames, whatever their
+    /// destination address.
+    ///
+    /// The default implementation returns [`DevError::Unsupported`].
+    fn set_promiscuous(&mut self, _on: bool) -> DevResult {
+        Err(DevError::Unsupported)
+    }
+
+    /// Sets the additional unicast and multicast addresses whose frames are
+    /// received, replacing the previous ones.
+    ///
+    /// The default implementation returns [`DevError::Unsupported`].
+    fn set_mac_filter(&mut self, _addrs: &[EthernetAddress]) -> DevResult {
+        Err(DevError::Unsupported)
+    }
+
+    /// Number of transmit/receive queue pairs.
+    ///
+    /// [`NetDriverOps::transmit`] and [`NetDriverOps::receive`] use queue 0.
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    /// Transmits a packet on the given queue, like
+    /// [`NetDriverOps::transmit`].
+    ///
+    /// Drivers with several queues return [`DevError::InvalidParam`] if
+    /// `queue` is not less than [`NetDriverOps::num_queues`], and recycle the
+    /// buffer. The default implementation, for devices with a single queue,
+    /// transmits on queue 0 whatever `queue`.
+    fn transmit_on(&mut self, _queue: usize, tx_buf: NetBufPtr) -> DevResult {
+        self.transmit(tx_buf)
+    }
+
+    /// Receives a packet from the given queue, like
+    /// [`NetDriverOps::receive`].
+    ///
+    /// Returns [`DevError::InvalidParam`] if `queue` is not less than
+    /// [`NetDriverOps::num_queues`].
+    fn receive_from(&mut self, queue: usize) -> DevResult<NetBufPtr> {
+        if queue != 0 {
+            return Err(DevError::InvalidParam);
+        }
+        self.receive()
+    }
 }
 
 /// A raw buffer struct for network device.
@@ -106,3 +229,104 @@ impl NetBufPtr {
         unsafe { core::slice::from_raw_parts_mut(self.buf_ptr.as_ptr(), self.len) }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+
+    use super::*;
+    use alloc::{sync::Arc, vec::Vec};
+
+    /// A single-queue device that keeps the packets it transmits.
+    struct Wire {
+        pool: Arc<NetBufPool>,
+        sent: Vec<Vec<u8>>,
+    }
+
+    impl BaseDriverOps for Wire {
+        fn device_name(&self) -> &str {
+            "wire"
+        }
+
+        fn device_type(&self) -> DeviceType {
+            DeviceType::Net
+        }
+    }
+
+    impl NetDriverOps for Wire {
+        fn mac_address(&self) -> EthernetAddress {
+            EthernetAddress([0x52, 0x54, 0, 0x12, 0x34, 0x56])
+        }
+
+        fn can_transmit(&self) -> bool {
+            true
+        }
+
+        fn can_receive(&self) -> bool {
+            false
+        }
+
+        fn rx_queue_size(&self) -> usize {
+            0
+        }
+
+        fn tx_queue_size(&self) -> usize {
+            1
+        }
+
+        fn recycle_rx_buffer(&mut self, _rx_buf: NetBufPtr) -> DevResult {
+            Err(DevError::Unsupported)
+        }
+
+        fn recycle_tx_buffers(&mut self) -> DevResult {
+            Ok(())
+        }
+
+        fn transmit(&mut self, tx_buf: NetBufPtr) -> DevResult {
+            self.sent.push(tx_buf.packet().to_vec());
+            // SAFETY: the buffer comes from `alloc_tx_buffer`.
+            drop(unsafe { NetBuf::from_buf_ptr(tx_buf) });
+            Ok(())
+        }
+
+        fn receive(&mut self) -> DevResult<NetBufPtr> {
+            Err(DevError::Again)
+        }
+
+        fn alloc_tx_buffer(&mut self, size: usize) -> DevResult<NetBufPtr> {
+            let mut tx_buf = self.pool.alloc_boxed()?;
+            tx_buf.set_packet_len(size);
+            Ok(tx_buf.into_buf_ptr())
+        }
+    }
+
+    fn wire() -> Wire {
+        Wire {
+            pool: NetBufPool::new(2, 1526).unwrap(),
+            sent: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn single_queue_devices_transmit_on_queue_0() {
+        let mut dev = wire();
+        assert_eq!(dev.num_queues(), 1);
+        for queue in [0, 1, 7] {
+            let mut tx_buf = dev.alloc_tx_buffer(1).unwrap();
+            tx_buf.packet_mut()[0] = queue as u8;
+            dev.transmit_on(queue, tx_buf).unwrap();
+        }
+        assert_eq!(dev.sent, [[0], [1], [7]]);
+        assert_eq!(dev.pool.available(), 2);
+        assert!(matches!(dev.receive_from(1), Err(DevError::InvalidParam)));
+    }
+
+    #[test]
+    fn offloads_are_none_by_default() {
+        let mut dev = wire();
+        assert_eq!(dev.tx_offloads(), TxFlags::empty());
+        let tx_buf = dev.alloc_tx_buffer(1).unwrap();
+        dev.transmit_with(tx_buf, TxFlags::empty()).unwrap();
+        assert_eq!(dev.sent.len(), 1);
+    }
+}
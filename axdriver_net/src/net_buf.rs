@@ -1,13 +1,17 @@
 extern crate alloc;
 
 use crate::{DevError, DevResult, NetBufPtr};
-use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
 use core::ptr::NonNull;
 use spin::Mutex;
 
 const MIN_BUFFER_LEN: usize = 1526;
 const MAX_BUFFER_LEN: usize = 65535;
 
+/// Alignment of each buffer, so that buffers do not share cache lines.
+const BUFFER_ALIGN: usize = 64;
+
 /// A RAII network buffer wrapped in a [`Box`].
 pub type NetBufBox = Box<NetBuf>;
 
@@ -132,17 +136,25 @@ impl Drop for NetBuf {
 
 /// A pool of [`NetBuf`]s to speed up buffer allocation.
 ///
-/// It divides a large memory into several equal parts for each buffer.
+/// It divides a large memory into several equal parts for each buffer, each
+/// aligned to 64 bytes.
 pub struct NetBufPool {
     capacity: usize,
     buf_len: usize,
-    pool: Vec<u8>,
+    pool: NonNull<u8>,
+    layout: Layout,
     free_list: Mutex<Vec<usize>>,
 }
 
+unsafe impl Send for NetBufPool {}
+unsafe impl Sync for NetBufPool {}
+
 impl NetBufPool {
     /// Creates a new pool with the given `capacity`, and all buffer lengths are
     /// set to `buf_len`.
+    ///
+    /// Returns [`DevError::NoMemory`] if the memory of the pool cannot be
+    /// allocated.
     pub fn new(capacity: usize, buf_len: usize) -> DevResult<Arc<Self>> {
         if capacity == 0 {
             return Err(DevError::InvalidParam);
@@ -151,15 +163,21 @@ impl NetBufPool {
             return Err(DevError::InvalidParam);
         }
 
-        let pool = vec![0; capacity * buf_len];
+        let stride = buf_len.next_multiple_of(BUFFER_ALIGN);
+        let layout = capacity
+            .checked_mul(stride)
+            .and_then(|size| Layout::from_size_align(size, BUFFER_ALIGN).ok())
+            .ok_or(DevError::InvalidParam)?;
+        let pool = NonNull::new(unsafe { alloc_zeroed(layout) }).ok_or(DevError::NoMemory)?;
         let mut free_list = Vec::with_capacity(capacity);
         for i in 0..capacity {
-            free_list.push(i * buf_len);
+            free_list.push(i * stride);
         }
         Ok(Arc::new(Self {
             capacity,
             buf_len,
             pool,
+            layout,
             free_list: Mutex::new(free_list),
         }))
     }
@@ -174,14 +192,20 @@ impl NetBufPool {
         self.buf_len
     }
 
+    /// Returns the number of buffers that are not allocated.
+    pub fn available(&self) -> usize {
+        self.free_list.lock().len()
+    }
+
     /// Allocates a buffer from the pool.
     ///
-    /// Returns `None` if no buffer is available.
-    pub fn alloc(self: &Arc<Self>) -> Option<NetBuf> {
-        let pool_offset = self.free_list.lock().pop()?;
-        let buf_ptr =
-            unsafe { NonNull::new(self.pool.as_ptr().add(pool_offset) as *mut u8).unwrap() };
-        Some(NetBuf {
+    /// Returns [`DevError::NoMemory`] if no buffer is available.
+    pub fn alloc(self: &Arc<Self>) -> DevResult<NetBuf> {
+        let pool_offset = self.free_list.lock().pop().ok_or(DevError::NoMemory)?;
+        // SAFETY: the offsets of the free list are those of buffers within
+        // the pool.
+        let buf_ptr = unsafe { self.pool.add(pool_offset) };
+        Ok(NetBuf {
             header_len: 0,
             packet_len: 0,
             capacity: self.buf_len,
@@ -193,16 +217,104 @@ impl NetBufPool {
 
     /// Allocates a buffer wrapped in a [`Box`] from the pool.
     ///
-    /// Returns `None` if no buffer is available.
-    pub fn alloc_boxed(self: &Arc<Self>) -> Option<NetBufBox> {
-        Some(Box::new(self.alloc()?))
+    /// Returns [`DevError::NoMemory`] if no buffer is available.
+    pub fn alloc_boxed(self: &Arc<Self>) -> DevResult<NetBufBox> {
+        Ok(Box::new(self.alloc()?))
     }
 
     /// Deallocates a buffer at the given offset.
     ///
-    /// `pool_offset` must be a multiple of `buf_len`.
+    /// `pool_offset` must be the offset of a buffer allocated by
+    /// [`NetBufPool::alloc`].
     fn dealloc(&self, pool_offset: usize) {
-        debug_assert_eq!(pool_offset % self.buf_len, 0);
+        debug_assert_eq!(pool_offset % BUFFER_ALIGN, 0);
         self.free_list.lock().push(pool_offset);
     }
 }
+
+impl Drop for NetBufPool {
+    fn drop(&mut self) {
+        // Allocated buffers hold a reference to the pool, so they have all
+        // been returned by now.
+        unsafe { dealloc(self.pool.as_ptr(), self.layout) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffers_are_aligned_and_counted() {
+        let pool = NetBufPool::new(3, 1600).unwrap();
+        assert_eq!((pool.capacity(), pool.buffer_len()), (3, 1600));
+        assert_eq!(pool.available(), 3);
+
+        let a = pool.alloc_boxed().unwrap();
+        let mut b = pool.alloc().unwrap();
+        assert_eq!(pool.available(), 1);
+        for buf in [&*a, &b] {
+            assert_eq!(buf.raw_buf().as_ptr() as usize % BUFFER_ALIGN, 0);
+            assert_eq!(buf.capacity(), 1600);
+            assert!(buf.packet_with_header().is_empty());
+        }
+        // The buffers do not overlap
+        let (a_start, b_start) = (a.raw_buf().as_ptr() as usize, b.raw_buf().as_ptr() as usize);
+        assert!(a_start.abs_diff(b_start) >= 1600);
+
+        b.set_header_len(2);
+        b.set_packet_len(4);
+        b.raw_buf_mut()[..6].copy_from_slice(&[1, 2, 3, 4, 5, 6]);
+        assert_eq!(b.header(), [1, 2]);
+        assert_eq!(b.packet(), [3, 4, 5, 6]);
+
+        drop(a);
+        assert_eq!(pool.available(), 2);
+        drop(b);
+        assert_eq!(pool.available(), 3);
+    }
+
+    #[test]
+    fn exhausted_pools_reuse_freed_buffers() {
+        let pool = NetBufPool::new(2, MIN_BUFFER_LEN).unwrap();
+        let a = pool.alloc_boxed().unwrap();
+        let b = pool.alloc_boxed().unwrap();
+        assert_eq!(pool.available(), 0);
+        assert!(matches!(pool.alloc(), Err(DevError::NoMemory)));
+        assert!(matches!(pool.alloc_boxed(), Err(DevError::NoMemory)));
+
+        let freed = b.raw_buf().as_ptr();
+        drop(b);
+        let c = pool.alloc_boxed().unwrap();
+        assert_eq!(c.raw_buf().as_ptr(), freed);
+        assert!(matches!(pool.alloc(), Err(DevError::NoMemory)));
+
+        // Buffers survive as NetBufPtrs and return once restored
+        let ptr = a.into_buf_ptr();
+        assert_eq!(pool.available(), 0);
+        // SAFETY: the pointer comes from `into_buf_ptr`.
+        drop(unsafe { NetBuf::from_buf_ptr(ptr) });
+        drop(c);
+        assert_eq!(pool.available(), 2);
+    }
+
+    #[test]
+    fn invalid_pools_are_rejected() {
+        assert!(matches!(
+            NetBufPool::new(0, 2048),
+            Err(DevError::InvalidParam)
+        ));
+        assert!(matches!(
+            NetBufPool::new(1, MIN_BUFFER_LEN - 1),
+            Err(DevError::InvalidParam)
+        ));
+        assert!(matches!(
+            NetBufPool::new(1, MAX_BUFFER_LEN + 1),
+            Err(DevError::InvalidParam)
+        ));
+        assert!(matches!(
+            NetBufPool::new(usize::MAX, 2048),
+            Err(DevError::InvalidParam)
+        ));
+    }
+}
@@ -0,0 +1,589 @@
+//! Typed access to the configuration space of PCI functions.
+
+use core::ptr::{read_volatile, write_volatile};
+
+use crate::{Cam, DeviceFunction};
+
+// Offsets of registers in the configuration space header.
+const PCI_VENDOR_ID: u16 = 0x00;
+const PCI_DEVICE_ID: u16 = 0x02;
+pub(crate) const PCI_COMMAND: u16 = 0x04;
+pub(crate) const PCI_STATUS: u16 = 0x06;
+const PCI_REVISION: u16 = 0x08;
+const PCI_PROG_IF: u16 = 0x09;
+const PCI_SUBCLASS: u16 = 0x0a;
+const PCI_CLASS: u16 = 0x0b;
+const PCI_HEADER_TYPE: u16 = 0x0e;
+const PCI_BAR0: u16 = 0x10;
+pub(crate) const PCI_CAPABILITY_LIST: u16 = 0x34;
+
+/// The function has a capability list.
+pub(crate) const PCI_STATUS_CAP_LIST: u16 = 1 << 4;
+
+/// Maximum number of capabilities followed, malformed lists may loop. There is
+/// room for 48 capabilities of 4 bytes after the header.
+const MAX_CAPABILITIES: usize = 48;
+
+// Bits of the command register.
+const PCI_COMMAND_IO: u16 = 1 << 0;
+const PCI_COMMAND_MEMORY: u16 = 1 << 1;
+const PCI_COMMAND_MASTER: u16 = 1 << 2;
+pub(crate) const PCI_COMMAND_INTX_DISABLE: u16 = 1 << 10;
+const PCI_COMMAND_DECODE: u16 = PCI_COMMAND_IO | PCI_COMMAND_MEMORY;
+
+// BAR bits.
+const BAR_IO: u32 = 1 << 0;
+const BAR_MEM_TYPE_64: u32 = 0b10 << 1;
+const BAR_MEM_TYPE_MASK: u32 = 0b11 << 1;
+const BAR_MEM_PREFETCHABLE: u32 = 1 << 3;
+const BAR_MEM_ADDR_MASK: u32 = !0xf;
+const BAR_IO_ADDR_MASK: u32 = !0x3;
+
+/// Access to the configuration space of the PCI functions behind a
+/// memory-mapped configuration access mechanism.
+pub struct ConfigSpace {
+    mmio_base: *mut u8,
+    cam: Cam,
+}
+
+impl ConfigSpace {
+    /// Creates an accessor of the configuration space mapped at `mmio_base`.
+    ///
+    /// # Safety
+    ///
+    /// `mmio_base` must be a valid pointer to an appropriately-mapped MMIO
+    /// region of at least `cam.size()` bytes, the same as for
+    /// [`PciRoot::new`](crate::PciRoot::new).
+    pub const unsafe fn new(mmio_base: *mut u8, cam: Cam) -> Self {
+        Self { mmio_base, cam }
+    }
+
+    /// Returns a pointer to the register at `offset` of a function.
+    fn reg(&self, bdf: DeviceFunction, offset: u16) -> *mut u8 {
+        let (bus, device, function) =
+            (bdf.bus as usize, bdf.device as usize, bdf.function as usize);
+        let (base, limit) = match self.cam {
+            Cam::MmioCam => (bus << 16 | device << 11 | function << 8, 0x100),
+            Cam::Ecam => (bus << 20 | device << 15 | function << 12, 0x1000),
+        };
+        assert!(
+            (offset as usize) < limit && bdf.device < 32 && bdf.function < 8,
+            "invalid configuration space access"
+        );
+        unsafe { self.mmio_base.add(base + offset as usize) }
+    }
+
+    /// Reads a 32-bit register, `offset` must be 4-byte aligned.
+    pub fn read_config_u32(&self, bdf: DeviceFunction, offset: u16) -> u32 {
+        unsafe { read_volatile(self.reg(bdf, offset & !3) as *const u32) }
+    }
+
+    /// Reads a 16-bit register, `offset` must be 2-byte aligned.
+    pub fn read_config_u16(&self, bdf: DeviceFunction, offset: u16) -> u16 {
+        unsafe { read_volatile(self.reg(bdf, offset & !1) as *const u16) }
+    }
+
+    /// Reads an 8-bit register.
+    pub fn read_config_u8(&self, bdf: DeviceFunction, offset: u16) -> u8 {
+        unsafe { read_volatile(self.reg(bdf, offset)) }
+    }
+
+    /// Writes a 32-bit register, `offset` must be 4-byte aligned.
+    pub fn write_config_u32(&self, bdf: DeviceFunction, offset: u16, val: u32) {
+        unsafe { write_volatile(self.reg(bdf, offset & !3) as *mut u32, val) }
+    }
+
+    /// Writes a 16-bit register, `offset` must be 2-byte aligned.
+    ///
+    /// The neighboring register is not written, which matters for registers
+    /// with write-1-to-clear bits such as the status register.
+    pub fn write_config_u16(&self, bdf: DeviceFunction, offset: u16, val: u16) {
+        unsafe { write_volatile(self.reg(bdf, offset & !1) as *mut u16, val) }
+    }
+
+    /// Writes an 8-bit register.
+    pub fn write_config_u8(&self, bdf: DeviceFunction, offset: u16, val: u8) {
+        unsafe { write_volatile(self.reg(bdf, offset), val) }
+    }
+
+    /// Returns the function at `bdf`.
+    pub const fn device(&self, bdf: DeviceFunction) -> PciDevice<'_> {
+        PciDevice { config: self, bdf }
+    }
+}
+
+/// A base address register, sized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    /// A memory BAR.
+    Memory {
+        /// The base address.
+        base: u64,
+        /// The size of the region in bytes.
+        size: u64,
+        /// Whether the region is prefetchable.
+        prefetchable: bool,
+        /// Whether it is a 64-bit BAR, which spans two BAR slots.
+        is_64bit: bool,
+    },
+    /// An I/O BAR.
+    Io {
+        /// The base address.
+        base: u32,
+        /// The size of the region in bytes.
+        size: u32,
+    },
+}
+
+/// The identification and BARs of a PCI function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciHeader {
+    /// The vendor ID.
+    pub vendor_id: u16,
+    /// The device ID.
+    pub device_id: u16,
+    /// The class code.
+    pub class: u8,
+    /// The subclass code.
+    pub subclass: u8,
+    /// The programming interface code.
+    pub prog_if: u8,
+    /// The revision ID.
+    pub revision: u8,
+    /// The header type, without the multi-function bit.
+    pub header_type: u8,
+    /// The BARs, `None` for unimplemented ones and for the upper half of
+    /// 64-bit BARs. Bridges only have the first two.
+    pub bars: [Option<Bar>; 6],
+}
+
+/// An entry of the capability list of a function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    /// The capability ID, e.g. `0x05` for MSI or `0x11` for MSI-X.
+    pub id: u8,
+    /// The offset of the capability in the configuration space.
+    pub offset: u8,
+}
+
+/// Iterator over the capability list of a function, created by
+/// [`PciDevice::capabilities`].
+pub struct CapabilityIter<'a> {
+    device: PciDevice<'a>,
+    next: u8,
+    hops: usize,
+}
+
+impl Iterator for CapabilityIter<'_> {
+    type Item = Capability;
+
+    fn next(&mut self) -> Option<Capability> {
+        if self.next == 0 || self.hops == MAX_CAPABILITIES {
+            return None;
+        }
+        self.hops += 1;
+        let offset = self.next;
+        let id = self.device.read_config_u8(offset as u16);
+        self.next = self.device.read_config_u8(offset as u16 + 1) & 0xfc;
+        Some(Capability { id, offset })
+    }
+}
+
+/// A function on the PCI bus.
+#[derive(Clone, Copy)]
+pub struct PciDevice<'a> {
+    config: &'a ConfigSpace,
+    bdf: DeviceFunction,
+}
+
+impl PciDevice<'_> {
+    /// The bus, device and function numbers.
+    pub const fn bdf(&self) -> DeviceFunction {
+        self.bdf
+    }
+
+    /// Reads a 32-bit register of the configuration space.
+    pub fn read_config_u32(&self, offset: u16) -> u32 {
+        self.config.read_config_u32(self.bdf, offset)
+    }
+
+    /// Reads a 16-bit register of the configuration space.
+    pub fn read_config_u16(&self, offset: u16) -> u16 {
+        self.config.read_config_u16(self.bdf, offset)
+    }
+
+    /// Reads an 8-bit register of the configuration space.
+    pub fn read_config_u8(&self, offset: u16) -> u8 {
+        self.config.read_config_u8(self.bdf, offset)
+    }
+
+    /// Writes a 32-bit register of the configuration space.
+    pub fn write_config_u32(&self, offset: u16, val: u32) {
+        self.config.write_config_u32(self.bdf, offset, val)
+    }
+
+    /// Writes a 16-bit register of the configuration space.
+    pub fn write_config_u16(&self, offset: u16, val: u16) {
+        self.config.write_config_u16(self.bdf, offset, val)
+    }
+
+    /// Writes an 8-bit register of the configuration space.
+    pub fn write_config_u8(&self, offset: u16, val: u8) {
+        self.config.write_config_u8(self.bdf, offset, val)
+    }
+
+    /// Sets `bits` in the command register, leaving the others unchanged.
+    fn set_command_bits(&self, bits: u16) {
+        let command = self.read_config_u16(PCI_COMMAND);
+        if command & bits != bits {
+            self.write_config_u16(PCI_COMMAND, command | bits);
+        }
+    }
+
+    /// Lets the function initiate DMA on the bus.
+    ///
+    /// Devices that have been set up but do not transfer any data often just
+    /// lack this bit.
+    pub fn enable_bus_mastering(&self) {
+        self.set_command_bits(PCI_COMMAND_MASTER);
+    }
+
+    /// Lets the function respond to accesses to its memory BARs.
+    pub fn enable_mmio(&self) {
+        self.set_command_bits(PCI_COMMAND_MEMORY);
+    }
+
+    /// Lets the function respond to accesses to its I/O BARs.
+    pub fn enable_io(&self) {
+        self.set_command_bits(PCI_COMMAND_IO);
+    }
+
+    /// Returns an iterator over the capability list.
+    ///
+    /// At most 48 entries are returned, so that a malformed list that loops
+    /// does not hang the caller.
+    pub fn capabilities(&self) -> CapabilityIter<'_> {
+        let next = if self.read_config_u16(PCI_STATUS) & PCI_STATUS_CAP_LIST != 0 {
+            self.read_config_u8(PCI_CAPABILITY_LIST) & 0xfc
+        } else {
+            0
+        };
+        CapabilityIter {
+            device: *self,
+            next,
+            hops: 0,
+        }
+    }
+
+    /// Returns the first capability with the given ID.
+    pub fn find_capability(&self, id: u8) -> Option<Capability> {
+        self.capabilities().find(|cap| cap.id == id)
+    }
+
+    /// Reads the header of the function, returns `None` if there is no
+    /// function at this address.
+    ///
+    /// The BARs are sized by writing all ones to them and reading them back,
+    /// with the memory and I/O decoding of the function disabled meanwhile.
+    pub fn header(&self) -> Option<PciHeader> {
+        let vendor_id = self.read_config_u16(PCI_VENDOR_ID);
+        if vendor_id == 0xffff {
+            return None;
+        }
+        let header_type = self.read_config_u8(PCI_HEADER_TYPE) & 0x7f;
+        let num_bars = match header_type {
+            0 => 6,
+            1 => 2,
+            _ => 0,
+        };
+
+        let command = self.read_config_u16(PCI_COMMAND);
+        self.write_config_u16(PCI_COMMAND, command & !PCI_COMMAND_DECODE);
+        let mut bars = [None; 6];
+        let mut i = 0;
+        while i < num_bars {
+            let bar = self.size_bar(i, i + 1 < num_bars);
+            bars[i] = bar;
+            i += match bar {
+                Some(Bar::Memory { is_64bit: true, .. }) => 2,
+                _ => 1,
+            };
+        }
+        self.write_config_u16(PCI_COMMAND, command);
+
+        Some(PciHeader {
+            vendor_id,
+            device_id: self.read_config_u16(PCI_DEVICE_ID),
+            class: self.read_config_u8(PCI_CLASS),
+            subclass: self.read_config_u8(PCI_SUBCLASS),
+            prog_if: self.read_config_u8(PCI_PROG_IF),
+            revision: self.read_config_u8(PCI_REVISION),
+            header_type,
+            bars,
+        })
+    }
+
+    /// Sizes BAR `index`, whose decoding must be disabled. `has_next` tells
+    /// whether it can be the lower half of a 64-bit BAR.
+    fn size_bar(&self, index: usize, has_next: bool) -> Option<Bar> {
+        let offset = PCI_BAR0 + index as u16 * 4;
+        let (orig, mask) = self.probe_bar(offset);
+        let is_64bit =
+            orig & BAR_IO == 0 && orig & BAR_MEM_TYPE_MASK == BAR_MEM_TYPE_64 && has_next;
+        let hi = is_64bit.then(|| self.probe_bar(offset + 4));
+        decode_bar(orig, mask, hi)
+    }
+
+    /// Returns the value of the BAR at `offset`, and the value read back
+    /// after writing all ones to it, then restores it.
+    fn probe_bar(&self, offset: u16) -> (u32, u32) {
+        let orig = self.read_config_u32(offset);
+        self.write_config_u32(offset, !0);
+        let mask = self.read_config_u32(offset);
+        self.write_config_u32(offset, orig);
+        (orig, mask)
+    }
+}
+
+/// Decodes a BAR whose value is `orig`, and reads back as `mask` once all
+/// ones are written to it. `hi` are the same values of the upper half of a
+/// 64-bit memory BAR.
+fn decode_bar(orig: u32, mask: u32, hi: Option<(u32, u32)>) -> Option<Bar> {
+    if orig & BAR_IO != 0 {
+        let size = (!(mask & BAR_IO_ADDR_MASK)).wrapping_add(1) & 0xffff;
+        return (size != 0).then_some(Bar::Io {
+            base: orig & BAR_IO_ADDR_MASK,
+            size,
+        });
+    }
+
+    let prefetchable = orig & BAR_MEM_PREFETCHABLE != 0;
+    if let Some((orig_hi, mask_hi)) = hi {
+        let mask = (mask_hi as u64) << 32 | (mask & BAR_MEM_ADDR_MASK) as u64;
+        let base = (orig_hi as u64) << 32 | (orig & BAR_MEM_ADDR_MASK) as u64;
+        let size = (!mask).wrapping_add(1);
+        return (mask != 0).then_some(Bar::Memory {
+            base,
+            size,
+            prefetchable,
+            is_64bit: true,
+        });
+    }
+
+    let mask = mask & BAR_MEM_ADDR_MASK;
+    (mask != 0).then_some(Bar::Memory {
+        base: (orig & BAR_MEM_ADDR_MASK) as u64,
+        size: (!mask).wrapping_add(1) as u64,
+        prefetchable,
+        is_64bit: false,
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// The configuration space of function 00:00.0, with a memory-mapped
+    /// configuration access mechanism.
+    #[repr(C, align(4))]
+    pub(crate) struct FakeConfig(pub(crate) [u8; 0x100]);
+
+    impl FakeConfig {
+        pub(crate) fn read_u16(&self, offset: usize) -> u16 {
+            u16::from_le_bytes([self.0[offset], self.0[offset + 1]])
+        }
+
+        pub(crate) fn read_u32(&self, offset: usize) -> u32 {
+            u32::from_le_bytes(self.0[offset..][..4].try_into().unwrap())
+        }
+
+        pub(crate) fn write_u16(&mut self, offset: usize, value: u16) {
+            self.0[offset..][..2].copy_from_slice(&value.to_le_bytes());
+        }
+
+        pub(crate) fn write_u32(&mut self, offset: usize, value: u32) {
+            self.0[offset..][..4].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    pub(crate) const BDF: DeviceFunction = DeviceFunction {
+        bus: 0,
+        device: 0,
+        function: 0,
+    };
+
+    #[test]
+    fn enable_sets_only_the_command_bits() {
+        let mut regs = FakeConfig([0; 0x100]);
+        regs.0[PCI_COMMAND as usize..][..2]
+            .copy_from_slice(&PCI_COMMAND_INTX_DISABLE.to_le_bytes());
+        // Write-1-to-clear error bits of the status register, and the
+        // capability list bit
+        regs.0[PCI_STATUS as usize..][..2].copy_from_slice(&0xf810u16.to_le_bytes());
+
+        let config = unsafe { ConfigSpace::new(regs.0.as_mut_ptr(), Cam::MmioCam) };
+        let dev = config.device(BDF);
+        dev.enable_mmio();
+        assert_eq!(
+            dev.read_config_u16(PCI_COMMAND),
+            PCI_COMMAND_INTX_DISABLE | PCI_COMMAND_MEMORY
+        );
+        dev.enable_bus_mastering();
+        dev.enable_bus_mastering();
+        dev.enable_io();
+        let expected =
+            PCI_COMMAND_INTX_DISABLE | PCI_COMMAND_MEMORY | PCI_COMMAND_MASTER | PCI_COMMAND_IO;
+        assert_eq!(regs.read_u16(PCI_COMMAND as usize), expected);
+        assert_eq!(regs.read_u16(PCI_STATUS as usize), 0xf810);
+    }
+
+    #[test]
+    fn memory_bars_are_sized() {
+        // 4 KiB, prefetchable
+        assert_eq!(
+            decode_bar(0xfebf_0008, 0xffff_f008, None),
+            Some(Bar::Memory {
+                base: 0xfebf_0000,
+                size: 0x1000,
+                prefetchable: true,
+                is_64bit: false,
+            })
+        );
+        // 8 GiB above 4 GiB, sized with the upper half
+        assert_eq!(
+            decode_bar(0x0000_0004, 0x0000_0004, Some((0x1, 0xffff_fffe))),
+            Some(Bar::Memory {
+                base: 0x1_0000_0000,
+                size: 0x2_0000_0000,
+                prefetchable: false,
+                is_64bit: true,
+            })
+        );
+        // 1 MiB, with its upper half all zeros
+        assert_eq!(
+            decode_bar(0xc000_000c, 0xfff0_000c, Some((0, !0))),
+            Some(Bar::Memory {
+                base: 0xc000_0000,
+                size: 0x10_0000,
+                prefetchable: true,
+                is_64bit: true,
+            })
+        );
+        // Unimplemented
+        assert_eq!(decode_bar(0, 0, None), None);
+    }
+
+    #[test]
+    fn io_bars_are_sized() {
+        assert_eq!(
+            decode_bar(0xc041, 0xffff_ffe1, None),
+            Some(Bar::Io {
+                base: 0xc040,
+                size: 0x20,
+            })
+        );
+        // The upper 16 bits need not be implemented
+        assert_eq!(
+            decode_bar(0x1001, 0x0000_ff01, None),
+            Some(Bar::Io {
+                base: 0x1000,
+                size: 0x100,
+            })
+        );
+    }
+
+    #[test]
+    fn sizing_restores_the_bars_and_the_command() {
+        let mut regs = FakeConfig([0; 0x100]);
+        let command = PCI_COMMAND_IO | PCI_COMMAND_MEMORY | PCI_COMMAND_MASTER;
+        regs.0[PCI_COMMAND as usize..][..2].copy_from_slice(&command.to_le_bytes());
+        // A 32-bit memory BAR, a 64-bit one and an I/O BAR
+        let bars: [u32; 4] = [0xfebf_0000, 0x0000_000c, 0x1, 0xc041];
+        for (i, bar) in bars.iter().enumerate() {
+            regs.0[PCI_BAR0 as usize + 4 * i..][..4].copy_from_slice(&bar.to_le_bytes());
+        }
+
+        let config = unsafe { ConfigSpace::new(regs.0.as_mut_ptr(), Cam::MmioCam) };
+        let header = config.device(BDF).header().unwrap();
+        assert!(matches!(
+            header.bars[0],
+            Some(Bar::Memory {
+                base: 0xfebf_0000,
+                is_64bit: false,
+                ..
+            })
+        ));
+        assert!(matches!(
+            header.bars[1],
+            Some(Bar::Memory {
+                base: 0x1_0000_0000,
+                prefetchable: true,
+                is_64bit: true,
+                ..
+            })
+        ));
+        assert_eq!(header.bars[2], None);
+        assert!(matches!(header.bars[3], Some(Bar::Io { base: 0xc040, .. })));
+        for (i, &bar) in bars.iter().enumerate() {
+            let offset = PCI_BAR0 as usize + 4 * i;
+            assert_eq!(regs.0[offset..][..4], bar.to_le_bytes());
+        }
+        assert_eq!(regs.read_u16(PCI_COMMAND as usize), command);
+    }
+
+    #[test]
+    fn capabilities_follow_the_list() {
+        let mut regs = FakeConfig([0; 0x100]);
+        regs.0[PCI_STATUS as usize] = PCI_STATUS_CAP_LIST as u8;
+        // The low two bits of the pointers are reserved
+        regs.0[PCI_CAPABILITY_LIST as usize] = 0x43;
+        regs.0[0x40..0x42].copy_from_slice(&[0x05, 0x51]);
+        regs.0[0x50..0x52].copy_from_slice(&[0x11, 0x00]);
+
+        let config = unsafe { ConfigSpace::new(regs.0.as_mut_ptr(), Cam::MmioCam) };
+        let device = config.device(BDF);
+        let mut caps = device.capabilities();
+        assert_eq!(
+            caps.next(),
+            Some(Capability {
+                id: 0x05,
+                offset: 0x40
+            })
+        );
+        assert_eq!(
+            caps.next(),
+            Some(Capability {
+                id: 0x11,
+                offset: 0x50
+            })
+        );
+        assert_eq!(caps.next(), None);
+        assert_eq!(device.find_capability(0x09), None);
+    }
+
+    #[test]
+    fn looping_capability_lists_end() {
+        let mut regs = FakeConfig([0; 0x100]);
+        regs.0[PCI_STATUS as usize] = PCI_STATUS_CAP_LIST as u8;
+        regs.0[PCI_CAPABILITY_LIST as usize] = 0x40;
+        // A capability that is its own successor
+        regs.0[0x40..0x42].copy_from_slice(&[0x09, 0x40]);
+
+        let config = unsafe { ConfigSpace::new(regs.0.as_mut_ptr(), Cam::MmioCam) };
+        let device = config.device(BDF);
+        assert_eq!(device.capabilities().count(), MAX_CAPABILITIES);
+        assert!(device.capabilities().all(|cap| cap.offset == 0x40));
+        assert_eq!(device.find_capability(0x05), None);
+    }
+
+    #[test]
+    fn functions_without_a_capability_list_have_no_capabilities() {
+        let mut regs = FakeConfig([0; 0x100]);
+        // Ignored without the capability list bit
+        regs.0[PCI_CAPABILITY_LIST as usize] = 0x40;
+        regs.0[0x40..0x42].copy_from_slice(&[0x05, 0x00]);
+
+        let config = unsafe { ConfigSpace::new(regs.0.as_mut_ptr(), Cam::MmioCam) };
+        assert_eq!(config.device(BDF).capabilities().next(), None);
+    }
+}
@@ -1,18 +1,82 @@
 //! Structures and functions for PCI bus operations.
 //!
-//! Currently, it just re-exports structures from the crate [virtio-drivers][1]
-//! and its module [`virtio_drivers::transport::pci::bus`][2].
+//! It re-exports structures from the crate [virtio-drivers][1] and its module
+//! [`virtio_drivers::transport::pci::bus`][2], and maps PCI class codes to the
+//! types of devices that have drivers. [`ConfigSpace`] gives typed access to
+//! the configuration space of the functions, including their MSI and MSI-X
+//! capabilities.
 //!
 //! [1]: https://docs.rs/virtio-drivers/latest/virtio_drivers/
 //! [2]: https://docs.rs/virtio-drivers/latest/virtio_drivers/transport/pci/bus/index.html
 
 #![no_std]
 
+mod config;
+mod msi;
+
+pub use self::config::{Bar, Capability, CapabilityIter, ConfigSpace, PciDevice, PciHeader};
+pub use self::msi::{MsixEntry, MsixTable};
 pub use virtio_drivers::transport::pci::bus::{BarInfo, Cam, HeaderType, MemoryBarType, PciError};
 pub use virtio_drivers::transport::pci::bus::{
     CapabilityInfo, Command, DeviceFunction, DeviceFunctionInfo, PciRoot, Status,
 };
 
+use axdriver_base::DeviceType;
+
+/// Index of the BAR holding the registers of an AHCI controller (ABAR).
+const AHCI_BAR: u8 = 5;
+
+/// Returns the type of the driver to instantiate for a PCI function with the
+/// given class code, or `None` if there is no driver for it.
+///
+/// - Mass storage, SATA, AHCI 1.0: [`DeviceType::Block`].
+/// - Network, Ethernet: [`DeviceType::Net`].
+/// - Display (VGA, 3D or others): [`DeviceType::Display`].
+///
+/// Virtio devices are identified by their vendor and device IDs instead.
+pub const fn driver_for_class(class: u8, subclass: u8, prog_if: u8) -> Option<DeviceType> {
+    match (class, subclass, prog_if) {
+        (0x01, 0x06, 0x01) => Some(DeviceType::Block),
+        (0x02, 0x00, _) => Some(DeviceType::Net),
+        (0x03, _, _) => Some(DeviceType::Display),
+        _ => None,
+    }
+}
+
+/// Returns the physical address of the registers of an AHCI controller,
+/// mapped by its BAR5.
+///
+/// Returns `None` if BAR5 is not an assigned memory BAR.
+pub fn ahci_mmio_base(root: &mut PciRoot, bdf: DeviceFunction) -> Option<u64> {
+    match root.bar_info(bdf, AHCI_BAR).ok()? {
+        BarInfo::Memory { address, .. } if address != 0 => Some(address),
+        _ => None,
+    }
+}
+
+/// Prepares an AHCI controller for its driver and returns the physical
+/// address of its registers, mapped by its BAR5.
+///
+/// Memory space decoding and bus mastering are enabled, without which the
+/// controller cannot be accessed or perform DMA. The returned address is to be
+/// mapped and passed to `AhciDriver::new_at` of `axdriver_block`.
+///
+/// Returns `None` if the function is not an AHCI controller, or if BAR5 is not
+/// an assigned memory BAR.
+pub fn ahci_probe(dev: &PciDevice) -> Option<u64> {
+    let header = dev.header()?;
+    if driver_for_class(header.class, header.subclass, header.prog_if) != Some(DeviceType::Block) {
+        return None;
+    }
+    let base = match header.bars[AHCI_BAR as usize]? {
+        Bar::Memory { base, .. } if base != 0 => base,
+        _ => return None,
+    };
+    dev.enable_mmio();
+    dev.enable_bus_mastering();
+    Some(base)
+}
+
 /// Used to allocate MMIO regions for PCI BARs.
 pub struct PciRangeAllocator {
     _start: u64,
@@ -0,0 +1,391 @@
+//! Message signaled interrupts (MSI and MSI-X).
+
+use core::ptr::{read_volatile, write_volatile};
+
+use axdriver_base::{DevError, DevResult};
+
+use crate::config::{PCI_COMMAND, PCI_COMMAND_INTX_DISABLE};
+use crate::PciDevice;
+
+const PCI_CAP_ID_MSI: u8 = 0x05;
+const PCI_CAP_ID_MSIX: u8 = 0x11;
+
+// Registers of the MSI capability, relative to its offset.
+const MSI_FLAGS: u16 = 0x02;
+const MSI_FLAGS_ENABLE: u16 = 1 << 0;
+const MSI_FLAGS_QSIZE: u16 = 0b111 << 4;
+const MSI_FLAGS_64BIT: u16 = 1 << 7;
+const MSI_ADDRESS_LO: u16 = 0x04;
+const MSI_ADDRESS_HI: u16 = 0x08;
+const MSI_DATA_32: u16 = 0x08;
+const MSI_DATA_64: u16 = 0x0c;
+
+// Registers of the MSI-X capability, relative to its offset.
+const MSIX_FLAGS: u16 = 0x02;
+const MSIX_FLAGS_QSIZE: u16 = 0x7ff;
+const MSIX_FLAGS_MASKALL: u16 = 1 << 14;
+const MSIX_FLAGS_ENABLE: u16 = 1 << 15;
+const MSIX_TABLE: u16 = 0x04;
+const MSIX_TABLE_BIR: u32 = 0x7;
+
+// Layout of an entry of the MSI-X table.
+const MSIX_ENTRY_SIZE: usize = 16;
+const MSIX_ENTRY_ADDRESS_LO: usize = 0x0;
+const MSIX_ENTRY_ADDRESS_HI: usize = 0x4;
+const MSIX_ENTRY_DATA: usize = 0x8;
+const MSIX_ENTRY_VECTOR_CTRL: usize = 0xc;
+const MSIX_ENTRY_CTRL_MASKBIT: u32 = 1 << 0;
+
+/// The message of an entry of the MSI-X table.
+///
+/// The address and data encoding is platform-specific, e.g. on x86 the
+/// address selects the local APIC and the data holds the vector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsixEntry {
+    /// The address written to by the device.
+    pub address: u64,
+    /// The data written to the address.
+    pub data: u32,
+    /// Whether the entry is masked.
+    pub masked: bool,
+}
+
+/// The location of the MSI-X table of a function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsixTable {
+    /// The index of the BAR holding the table.
+    pub bar: u8,
+    /// The offset of the table in the BAR.
+    pub offset: u32,
+    /// The number of entries of the table.
+    pub size: u16,
+}
+
+impl PciDevice<'_> {
+    /// Returns the offset of the first capability with the given ID.
+    fn find_cap(&self, id: u8) -> Option<u16> {
+        self.find_capability(id).map(|cap| cap.offset as u16)
+    }
+
+    /// Whether the function has the MSI capability.
+    pub fn supports_msi(&self) -> bool {
+        self.find_cap(PCI_CAP_ID_MSI).is_some()
+    }
+
+    /// Whether the function has the MSI-X capability.
+    pub fn supports_msix(&self) -> bool {
+        self.find_cap(PCI_CAP_ID_MSIX).is_some()
+    }
+
+    /// Returns the location of the MSI-X table, or `None` if MSI-X is not
+    /// supported.
+    pub fn msix_table(&self) -> Option<MsixTable> {
+        let cap = self.find_cap(PCI_CAP_ID_MSIX)?;
+        let flags = self.read_config_u16(cap + MSIX_FLAGS);
+        let table = self.read_config_u32(cap + MSIX_TABLE);
+        Some(MsixTable {
+            bar: (table & MSIX_TABLE_BIR) as u8,
+            offset: table & !MSIX_TABLE_BIR,
+            size: (flags & MSIX_FLAGS_QSIZE) + 1,
+        })
+    }
+
+    /// Enables MSI with a single message, writing `vector` to `address`.
+    ///
+    /// MSI-X and legacy INTx interrupts are disabled.
+    ///
+    /// Returns [`DevError::Unsupported`] if the function has no MSI
+    /// capability, or [`DevError::InvalidParam`] if `address` does not fit in
+    /// the 32-bit address register of the capability or `vector` in its 16-bit
+    /// data register.
+    pub fn enable_msi(&self, address: u64, vector: u32) -> DevResult {
+        let cap = self.find_cap(PCI_CAP_ID_MSI).ok_or(DevError::Unsupported)?;
+        let data = u16::try_from(vector).map_err(|_| DevError::InvalidParam)?;
+        let flags = self.read_config_u16(cap + MSI_FLAGS);
+        let is_64bit = flags & MSI_FLAGS_64BIT != 0;
+        if !is_64bit && address > u32::MAX as u64 {
+            return Err(DevError::InvalidParam);
+        }
+        self.disable_msix();
+
+        self.write_config_u16(
+            cap + MSI_FLAGS,
+            flags & !(MSI_FLAGS_ENABLE | MSI_FLAGS_QSIZE),
+        );
+        self.write_config_u32(cap + MSI_ADDRESS_LO, address as u32);
+        if is_64bit {
+            self.write_config_u32(cap + MSI_ADDRESS_HI, (address >> 32) as u32);
+            self.write_config_u16(cap + MSI_DATA_64, data);
+        } else {
+            self.write_config_u16(cap + MSI_DATA_32, data);
+        }
+        self.disable_intx();
+        self.write_config_u16(
+            cap + MSI_FLAGS,
+            (flags & !MSI_FLAGS_QSIZE) | MSI_FLAGS_ENABLE,
+        );
+        Ok(())
+    }
+
+    /// Enables MSI-X, programming the first `entries.len()` entries of the
+    /// table.
+    ///
+    /// MSI and legacy INTx interrupts are disabled.
+    ///
+    /// Returns [`DevError::Unsupported`] if the function has no MSI-X
+    /// capability, or [`DevError::InvalidParam`] if there are more entries
+    /// than the table holds.
+    ///
+    /// # Safety
+    ///
+    /// `bar_base` must be the virtual address of the mapped memory BAR given
+    /// by [`msix_table`](Self::msix_table), and the mapping must cover the
+    /// table.
+    pub unsafe fn enable_msix(&self, bar_base: *mut u8, entries: &[MsixEntry]) -> DevResult {
+        let cap = self
+            .find_cap(PCI_CAP_ID_MSIX)
+            .ok_or(DevError::Unsupported)?;
+        let table = self.msix_table().ok_or(DevError::Unsupported)?;
+        if entries.len() > table.size as usize {
+            return Err(DevError::InvalidParam);
+        }
+        self.disable_msi();
+
+        // Mask all vectors while the table is being written.
+        let flags = self.read_config_u16(cap + MSIX_FLAGS);
+        self.write_config_u16(
+            cap + MSIX_FLAGS,
+            flags | MSIX_FLAGS_ENABLE | MSIX_FLAGS_MASKALL,
+        );
+        let table_base = bar_base.add(table.offset as usize);
+        for (i, entry) in entries.iter().enumerate() {
+            let reg = |ofs| table_base.add(i * MSIX_ENTRY_SIZE + ofs) as *mut u32;
+            write_volatile(reg(MSIX_ENTRY_ADDRESS_LO), entry.address as u32);
+            write_volatile(reg(MSIX_ENTRY_ADDRESS_HI), (entry.address >> 32) as u32);
+            write_volatile(reg(MSIX_ENTRY_DATA), entry.data);
+            let ctrl = read_volatile(reg(MSIX_ENTRY_VECTOR_CTRL));
+            let ctrl = if entry.masked {
+                ctrl | MSIX_ENTRY_CTRL_MASKBIT
+            } else {
+                ctrl & !MSIX_ENTRY_CTRL_MASKBIT
+            };
+            write_volatile(reg(MSIX_ENTRY_VECTOR_CTRL), ctrl);
+        }
+        self.disable_intx();
+        self.write_config_u16(
+            cap + MSIX_FLAGS,
+            (flags | MSIX_FLAGS_ENABLE) & !MSIX_FLAGS_MASKALL,
+        );
+        Ok(())
+    }
+
+    fn disable_msi(&self) {
+        if let Some(cap) = self.find_cap(PCI_CAP_ID_MSI) {
+            let flags = self.read_config_u16(cap + MSI_FLAGS);
+            self.write_config_u16(cap + MSI_FLAGS, flags & !MSI_FLAGS_ENABLE);
+        }
+    }
+
+    fn disable_msix(&self) {
+        if let Some(cap) = self.find_cap(PCI_CAP_ID_MSIX) {
+            let flags = self.read_config_u16(cap + MSIX_FLAGS);
+            self.write_config_u16(cap + MSIX_FLAGS, flags & !MSIX_FLAGS_ENABLE);
+        }
+    }
+
+    fn disable_intx(&self) {
+        let command = self.read_config_u16(PCI_COMMAND);
+        self.write_config_u16(PCI_COMMAND, command | PCI_COMMAND_INTX_DISABLE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::{FakeConfig, BDF};
+    use crate::config::{PCI_CAPABILITY_LIST, PCI_STATUS, PCI_STATUS_CAP_LIST};
+    use crate::{Cam, ConfigSpace};
+
+    const MSI_CAP: usize = 0x40;
+    const MSIX_CAP: usize = 0x50;
+
+    /// Returns a configuration space with an MSI capability whose flags are
+    /// `msi_flags`, followed by an MSI-X one whose flags are `msix_flags` if
+    /// given.
+    fn fake_config(msi_flags: u16, msix_flags: Option<u16>) -> FakeConfig {
+        let mut regs = FakeConfig([0; 0x100]);
+        regs.write_u16(PCI_STATUS as usize, PCI_STATUS_CAP_LIST);
+        regs.0[PCI_CAPABILITY_LIST as usize] = MSI_CAP as u8;
+        regs.0[MSI_CAP] = PCI_CAP_ID_MSI;
+        regs.write_u16(MSI_CAP + MSI_FLAGS as usize, msi_flags);
+        if let Some(flags) = msix_flags {
+            regs.0[MSI_CAP + 1] = MSIX_CAP as u8;
+            regs.0[MSIX_CAP] = PCI_CAP_ID_MSIX;
+            regs.write_u16(MSIX_CAP + MSIX_FLAGS as usize, flags);
+            // The table is at offset 0x20 of BAR 2
+            regs.write_u32(MSIX_CAP + MSIX_TABLE as usize, 0x20 | 2);
+        }
+        regs
+    }
+
+    #[test]
+    fn msi_is_enabled_with_a_32bit_address() {
+        // Two messages enabled by the firmware
+        let mut regs = fake_config(0b001 << 4, Some(MSIX_FLAGS_ENABLE));
+        let config = unsafe { ConfigSpace::new(regs.0.as_mut_ptr(), Cam::MmioCam) };
+        let dev = config.device(BDF);
+        assert!(dev.supports_msi());
+        dev.enable_msi(0xfee0_0000, 0x31).unwrap();
+
+        let flags = regs.read_u16(MSI_CAP + MSI_FLAGS as usize);
+        assert_eq!(flags, MSI_FLAGS_ENABLE);
+        assert_eq!(
+            regs.read_u32(MSI_CAP + MSI_ADDRESS_LO as usize),
+            0xfee0_0000
+        );
+        assert_eq!(regs.read_u16(MSI_CAP + MSI_DATA_32 as usize), 0x31);
+        assert_eq!(regs.read_u16(MSIX_CAP + MSIX_FLAGS as usize), 0);
+        assert_eq!(
+            regs.read_u16(PCI_COMMAND as usize),
+            PCI_COMMAND_INTX_DISABLE
+        );
+    }
+
+    #[test]
+    fn msi_is_enabled_with_a_64bit_address() {
+        let mut regs = fake_config(MSI_FLAGS_64BIT, None);
+        let config = unsafe { ConfigSpace::new(regs.0.as_mut_ptr(), Cam::MmioCam) };
+        config
+            .device(BDF)
+            .enable_msi(0x1_fee0_1000, 0xffff)
+            .unwrap();
+
+        let flags = regs.read_u16(MSI_CAP + MSI_FLAGS as usize);
+        assert_eq!(flags, MSI_FLAGS_64BIT | MSI_FLAGS_ENABLE);
+        assert_eq!(
+            regs.read_u32(MSI_CAP + MSI_ADDRESS_LO as usize),
+            0xfee0_1000
+        );
+        assert_eq!(regs.read_u32(MSI_CAP + MSI_ADDRESS_HI as usize), 0x1);
+        assert_eq!(regs.read_u16(MSI_CAP + MSI_DATA_64 as usize), 0xffff);
+    }
+
+    #[test]
+    fn msi_rejects_what_the_registers_cannot_hold() {
+        let mut regs = fake_config(0, Some(MSIX_FLAGS_ENABLE));
+        let config = unsafe { ConfigSpace::new(regs.0.as_mut_ptr(), Cam::MmioCam) };
+        let dev = config.device(BDF);
+        assert!(matches!(
+            dev.enable_msi(0x1_0000_0000, 0x31),
+            Err(DevError::InvalidParam)
+        ));
+        assert!(matches!(
+            dev.enable_msi(0xfee0_0000, 0x1_0000),
+            Err(DevError::InvalidParam)
+        ));
+        // Nothing was touched
+        assert_eq!(regs.read_u16(MSI_CAP + MSI_FLAGS as usize), 0);
+        assert_eq!(regs.read_u32(MSI_CAP + MSI_ADDRESS_LO as usize), 0);
+        assert_eq!(
+            regs.read_u16(MSIX_CAP + MSIX_FLAGS as usize),
+            MSIX_FLAGS_ENABLE
+        );
+        assert_eq!(regs.read_u16(PCI_COMMAND as usize), 0);
+    }
+
+    #[test]
+    fn functions_without_the_capabilities_are_unsupported() {
+        let mut regs = FakeConfig([0; 0x100]);
+        let config = unsafe { ConfigSpace::new(regs.0.as_mut_ptr(), Cam::MmioCam) };
+        let dev = config.device(BDF);
+        assert!(!dev.supports_msi());
+        assert!(!dev.supports_msix());
+        assert_eq!(dev.msix_table(), None);
+        assert!(matches!(
+            dev.enable_msi(0xfee0_0000, 0x31),
+            Err(DevError::Unsupported)
+        ));
+        let mut bar = [0u32; 16];
+        assert!(matches!(
+            unsafe { dev.enable_msix(bar.as_mut_ptr().cast(), &[]) },
+            Err(DevError::Unsupported)
+        ));
+    }
+
+    #[test]
+    fn msix_programs_the_table() {
+        // Four entries, with MSI enabled by the firmware
+        let mut regs = fake_config(MSI_FLAGS_ENABLE, Some(3));
+        // The entries are masked after reset
+        let mut bar = [0u32; 8 + 4 * 4];
+        for entry in bar[8..].chunks_mut(4) {
+            entry[3] = MSIX_ENTRY_CTRL_MASKBIT;
+        }
+
+        let config = unsafe { ConfigSpace::new(regs.0.as_mut_ptr(), Cam::MmioCam) };
+        let dev = config.device(BDF);
+        assert!(dev.supports_msix());
+        assert_eq!(
+            dev.msix_table(),
+            Some(MsixTable {
+                bar: 2,
+                offset: 0x20,
+                size: 4,
+            })
+        );
+        let entries = [
+            MsixEntry {
+                address: 0xfee0_0000,
+                data: 0x31,
+                masked: false,
+            },
+            MsixEntry {
+                address: 0x1_fee0_1000,
+                data: 0x32,
+                masked: true,
+            },
+        ];
+        unsafe { dev.enable_msix(bar.as_mut_ptr().cast(), &entries) }.unwrap();
+
+        assert_eq!(bar[8..12], [0xfee0_0000, 0, 0x31, 0]);
+        assert_eq!(
+            bar[12..16],
+            [0xfee0_1000, 0x1, 0x32, MSIX_ENTRY_CTRL_MASKBIT]
+        );
+        // The other entries are left alone
+        assert_eq!(bar[16..20], [0, 0, 0, MSIX_ENTRY_CTRL_MASKBIT]);
+        assert!(bar[..8].iter().all(|&word| word == 0));
+        assert_eq!(
+            regs.read_u16(MSIX_CAP + MSIX_FLAGS as usize),
+            MSIX_FLAGS_ENABLE | 3
+        );
+        assert_eq!(regs.read_u16(MSI_CAP + MSI_FLAGS as usize), 0);
+        assert_eq!(
+            regs.read_u16(PCI_COMMAND as usize),
+            PCI_COMMAND_INTX_DISABLE
+        );
+    }
+
+    #[test]
+    fn msix_rejects_more_entries_than_the_table_holds() {
+        let mut regs = fake_config(0, Some(0));
+        let mut bar = [0u32; 8 + 4];
+        let entry = MsixEntry {
+            address: 0xfee0_0000,
+            data: 0x31,
+            masked: false,
+        };
+
+        let config = unsafe { ConfigSpace::new(regs.0.as_mut_ptr(), Cam::MmioCam) };
+        assert!(matches!(
+            unsafe {
+                config
+                    .device(BDF)
+                    .enable_msix(bar.as_mut_ptr().cast(), &[entry; 2])
+            },
+            Err(DevError::InvalidParam)
+        ));
+        assert!(bar.iter().all(|&word| word == 0));
+        assert_eq!(regs.read_u16(MSIX_CAP + MSIX_FLAGS as usize), 0);
+    }
+}
@@ -1,11 +1,111 @@
-use crate::as_dev_err;
-use axdriver_base::{BaseDriverOps, DevResult, DeviceType};
+use crate::features::{self, F_RING_EVENT_IDX, F_RING_INDIRECT_DESC, F_VERSION_1};
+use crate::queue::{self, VirtQueue};
+use axdriver_base::{
+    BaseDriverOps, DevError, DevResult, DeviceCapabilities, DeviceStats, DeviceType,
+};
 use axdriver_block::BlockDriverOps;
-use virtio_drivers::{device::blk::VirtIOBlk as InnerDev, transport::Transport, Hal};
+use core::ptr::NonNull;
+use virtio_drivers::transport::{DeviceStatus, Transport};
+use virtio_drivers::Hal;
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+/// The size of the sectors in which requests are addressed.
+const SECTOR_SIZE: usize = 512;
+
+/// Size of the request queue.
+const QUEUE_SIZE: usize = 16;
+
+const F_SEG_MAX: u64 = 1 << 2;
+const F_RO: u64 = 1 << 5;
+const F_FLUSH: u64 = 1 << 9;
+const F_DISCARD: u64 = 1 << 13;
+
+/// Features offered by the driver: VIRTIO_BLK_F_SEG_MAX, VIRTIO_BLK_F_RO,
+/// VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_DISCARD and the ring features.
+const SUPPORTED_FEATURES: u64 =
+    F_SEG_MAX | F_RO | F_FLUSH | F_DISCARD | F_RING_INDIRECT_DESC | F_RING_EVENT_IDX | F_VERSION_1;
+
+// Request types.
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+const T_DISCARD: u32 = 11;
+
+// Request status.
+const S_OK: u8 = 0;
+const S_UNSUPP: u8 = 2;
+
+/// The configuration space of a block device, up to the fields of
+/// VIRTIO_BLK_F_DISCARD, which a device without it may not have.
+#[repr(C)]
+struct BlkConfig {
+    /// The capacity in sectors, in two halves since the configuration space
+    /// may only be 4-byte aligned.
+    capacity_low: u32,
+    capacity_high: u32,
+    size_max: u32,
+    seg_max: u32,
+    geometry: u32,
+    blk_size: u32,
+    topology: [u32; 2],
+    writeback: u8,
+    unused0: u8,
+    num_queues: u16,
+}
+
+/// The configuration space of a block device with VIRTIO_BLK_F_DISCARD.
+#[repr(C)]
+struct BlkDiscardConfig {
+    base: BlkConfig,
+    max_discard_sectors: u32,
+    max_discard_seg: u32,
+    discard_sector_alignment: u32,
+}
+
+/// How discard requests are to be split, from the configuration space.
+#[derive(Clone, Copy)]
+struct DiscardLimits {
+    /// Maximum number of sectors of a segment, a multiple of `alignment`
+    /// unless it is smaller
+    max_sectors: u64,
+    /// Maximum number of segments of a request
+    max_segs: usize,
+    /// Sectors at which segments are split, 1 if not aligned
+    alignment: u64,
+}
 
 /// The VirtIO block device driver.
+///
+/// Reads and writes are VIRTIO_BLK_T_IN/OUT requests, and [`flush`] is a
+/// VIRTIO_BLK_T_FLUSH request if the device has a write cache. The buffers of
+/// [`read_blocks_vectored`] and [`write_blocks_vectored`] are the segments of
+/// as few requests as VIRTIO_BLK_F_SEG_MAX and the queue allow, which take a
+/// single descriptor of the ring each if indirect descriptors are negotiated.
+///
+/// Blocks are the 512-byte sectors in which the device reports its capacity
+/// and addresses requests, whatever its `blk_size` is.
+///
+/// [`discard`] posts VIRTIO_BLK_T_DISCARD requests if VIRTIO_BLK_F_DISCARD is
+/// negotiated, split into segments of at most `max_discard_sectors` sectors,
+/// at multiples of `discard_sector_alignment`, and into requests of at most
+/// `max_discard_seg` segments.
+///
+/// [`flush`]: BlockDriverOps::flush
+/// [`discard`]: BlockDriverOps::discard
+/// [`read_blocks_vectored`]: BlockDriverOps::read_blocks_vectored
+/// [`write_blocks_vectored`]: BlockDriverOps::write_blocks_vectored
 pub struct VirtIoBlkDev<H: Hal, T: Transport> {
-    inner: InnerDev<H, T>,
+    transport: T,
+    queue: VirtQueue<H, QUEUE_SIZE>,
+    capacity: u64,
+    features: u64,
+    /// Maximum number of data segments of a request
+    max_segs: usize,
+    discard: Option<DiscardLimits>,
+    stats: DeviceStats,
 }
 
 unsafe impl<H: Hal, T: Transport> Send for VirtIoBlkDev<H, T> {}
@@ -14,11 +114,162 @@ unsafe impl<H: Hal, T: Transport> Sync for VirtIoBlkDev<H, T> {}
 impl<H: Hal, T: Transport> VirtIoBlkDev<H, T> {
     /// Creates a new driver instance and initializes the device, or returns
     /// an error if any step fails.
-    pub fn try_new(transport: T) -> DevResult<Self> {
+    pub fn try_new(mut transport: T) -> DevResult<Self> {
+        let features = features::negotiate(&mut transport, SUPPORTED_FEATURES)?;
+        let config: NonNull<BlkConfig> =
+            transport.config_space().map_err(|_| DevError::BadState)?;
+        // SAFETY: the configuration space is mapped as long as the transport.
+        let (capacity, seg_max) = unsafe {
+            let config = config.as_ptr();
+            let low = (&raw const (*config).capacity_low).read_volatile();
+            let high = (&raw const (*config).capacity_high).read_volatile();
+            let seg_max = (&raw const (*config).seg_max).read_volatile();
+            ((high as u64) << 32 | low as u64, seg_max)
+        };
+        let discard = if features & F_DISCARD != 0 {
+            let config: NonNull<BlkDiscardConfig> =
+                transport.config_space().map_err(|_| DevError::BadState)?;
+            // SAFETY: the configuration space is mapped as long as the
+            // transport.
+            Some(unsafe { discard_limits(config) })
+        } else {
+            None
+        };
+
+        let queue = VirtQueue::new(
+            &mut transport,
+            0,
+            features & F_RING_INDIRECT_DESC != 0,
+            features & F_RING_EVENT_IDX != 0,
+        );
+        let queue = match queue {
+            Ok(queue) => queue,
+            Err(e) => {
+                transport.set_status(DeviceStatus::FAILED);
+                return Err(e);
+            }
+        };
+        transport.finish_init();
+
+        // Each request also has a header and a status descriptor
+        let mut max_segs = queue.max_chain_len() - 2;
+        if features & F_SEG_MAX != 0 && seg_max != 0 {
+            max_segs = max_segs.min(seg_max as usize);
+        }
         Ok(Self {
-            inner: InnerDev::new(transport).map_err(as_dev_err)?,
+            transport,
+            queue,
+            capacity,
+            features,
+            max_segs,
+            discard,
+            stats: DeviceStats::default(),
         })
     }
+
+    /// The feature bits negotiated with the device, see
+    /// [`VirtIoFeatures`](crate::VirtIoFeatures) to decode them.
+    pub const fn negotiated_features(&self) -> u64 {
+        self.features
+    }
+
+    const fn readonly(&self) -> bool {
+        self.features & F_RO != 0
+    }
+
+    /// Checks an access of `len` bytes from `block_id`, and returns whether
+    /// it is empty, in which case there is nothing to do.
+    fn check(&self, block_id: u64, len: usize) -> DevResult<bool> {
+        if !len.is_multiple_of(SECTOR_SIZE) {
+            return Err(DevError::InvalidParam);
+        }
+        match block_id.checked_add((len / SECTOR_SIZE) as u64) {
+            Some(end) if end <= self.capacity => Ok(len == 0),
+            _ => Err(DevError::InvalidParam),
+        }
+    }
+
+    /// Sends a request with the header of `type_` and `sector`, then the
+    /// `inputs` and the `outputs`, and waits for its status.
+    fn request(
+        &mut self,
+        type_: u32,
+        sector: u64,
+        inputs: &[&[u8]],
+        outputs: &mut [&mut [u8]],
+    ) -> DevResult {
+        let mut header = [0; 16];
+        header[..4].copy_from_slice(&type_.to_le_bytes());
+        header[8..].copy_from_slice(&sector.to_le_bytes());
+        let mut status = [0xff];
+
+        let mut ins = Vec::with_capacity(inputs.len() + 1);
+        ins.push(&header[..]);
+        ins.extend_from_slice(inputs);
+        let mut outs: Vec<&mut [u8]> = Vec::with_capacity(outputs.len() + 1);
+        outs.extend(outputs.iter_mut().map(|buf| &mut **buf));
+        outs.push(&mut status);
+        self.queue
+            .add_notify_wait_pop(&mut self.transport, &ins, &mut outs)?;
+        match status[0] {
+            S_OK => Ok(()),
+            S_UNSUPP => Err(DevError::Unsupported),
+            _ => Err(DevError::Io),
+        }
+    }
+
+    fn read_blocks(&mut self, block_id: u64, bufs: &mut [&mut [u8]]) -> DevResult {
+        let mut sector = block_id;
+        for chunk in bufs.chunks_mut(self.max_segs) {
+            let len: usize = chunk.iter().map(|buf| buf.len()).sum();
+            self.request(T_IN, sector, &[], chunk)?;
+            sector += (len / SECTOR_SIZE) as u64;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, block_id: u64, bufs: &[&[u8]]) -> DevResult {
+        if self.readonly() {
+            return Err(DevError::Unsupported);
+        }
+        let mut sector = block_id;
+        for chunk in bufs.chunks(self.max_segs) {
+            let len: usize = chunk.iter().map(|buf| buf.len()).sum();
+            self.request(T_OUT, sector, chunk, &mut [])?;
+            sector += (len / SECTOR_SIZE) as u64;
+        }
+        Ok(())
+    }
+}
+
+impl<H: Hal, T: Transport> Drop for VirtIoBlkDev<H, T> {
+    fn drop(&mut self) {
+        // Stop the device before the queue memory is freed
+        queue::reset_device(&mut self.transport, [self.queue.index()]);
+    }
+}
+
+/// Reads the discard limits from the configuration space.
+///
+/// # Safety
+///
+/// `config` must point to the mapped configuration space.
+unsafe fn discard_limits(config: NonNull<BlkDiscardConfig>) -> DiscardLimits {
+    let config = config.as_ptr();
+    let (max_sectors, max_segs, alignment) = unsafe {
+        (
+            (&raw const (*config).max_discard_sectors).read_volatile(),
+            (&raw const (*config).max_discard_seg).read_volatile(),
+            (&raw const (*config).discard_sector_alignment).read_volatile(),
+        )
+    };
+    let max_sectors = max_sectors.max(1) as u64;
+    let alignment = (alignment as u64).clamp(1, max_sectors);
+    DiscardLimits {
+        max_sectors: max_sectors / alignment * alignment,
+        max_segs: max_segs.max(1) as usize,
+        alignment,
+    }
 }
 
 impl<H: Hal, T: Transport> BaseDriverOps for VirtIoBlkDev<H, T> {
@@ -29,32 +280,312 @@ impl<H: Hal, T: Transport> BaseDriverOps for VirtIoBlkDev<H, T> {
     fn device_type(&self) -> DeviceType {
         DeviceType::Block
     }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        if self.readonly() {
+            DeviceCapabilities::READ_ONLY
+        } else if self.discard.is_some() {
+            DeviceCapabilities::FLUSH | DeviceCapabilities::DISCARD
+        } else {
+            DeviceCapabilities::FLUSH
+        }
+    }
+
+    fn stats(&self) -> DeviceStats {
+        self.stats
+    }
 }
 
 impl<H: Hal, T: Transport> BlockDriverOps for VirtIoBlkDev<H, T> {
     #[inline]
     fn num_blocks(&self) -> u64 {
-        self.inner.capacity()
+        self.capacity
     }
 
     #[inline]
     fn block_size(&self) -> usize {
-        virtio_drivers::device::blk::SECTOR_SIZE
+        SECTOR_SIZE
     }
 
     fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
-        self.inner
-            .read_blocks(block_id as _, buf)
-            .map_err(as_dev_err)
+        if self.check(block_id, buf.len())? {
+            return Ok(());
+        }
+        let result = self.read_blocks(block_id, &mut [buf]);
+        self.stats.record(false, buf.len(), &result);
+        result
     }
 
     fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
-        self.inner
-            .write_blocks(block_id as _, buf)
-            .map_err(as_dev_err)
+        if self.check(block_id, buf.len())? {
+            return Ok(());
+        }
+        let result = self.write_blocks(block_id, &[buf]);
+        self.stats.record(true, buf.len(), &result);
+        result
+    }
+
+    fn read_blocks_vectored(&mut self, block_id: u64, bufs: &mut [&mut [u8]]) -> DevResult {
+        if bufs
+            .iter()
+            .any(|buf| !buf.len().is_multiple_of(SECTOR_SIZE))
+        {
+            return Err(DevError::InvalidParam);
+        }
+        let len = bufs.iter().map(|buf| buf.len()).sum();
+        if self.check(block_id, len)? {
+            return Ok(());
+        }
+        let mut bufs: Vec<&mut [u8]> = bufs
+            .iter_mut()
+            .filter(|buf| !buf.is_empty())
+            .map(|buf| &mut **buf)
+            .collect();
+        let result = self.read_blocks(block_id, &mut bufs);
+        self.stats.record(false, len, &result);
+        result
+    }
+
+    fn write_blocks_vectored(&mut self, block_id: u64, bufs: &[&[u8]]) -> DevResult {
+        if bufs
+            .iter()
+            .any(|buf| !buf.len().is_multiple_of(SECTOR_SIZE))
+        {
+            return Err(DevError::InvalidParam);
+        }
+        let len = bufs.iter().map(|buf| buf.len()).sum();
+        if self.check(block_id, len)? {
+            return Ok(());
+        }
+        let bufs: Vec<&[u8]> = bufs.iter().copied().filter(|buf| !buf.is_empty()).collect();
+        let result = self.write_blocks(block_id, &bufs);
+        self.stats.record(true, len, &result);
+        result
     }
 
     fn flush(&mut self) -> DevResult {
+        if self.features & F_FLUSH == 0 {
+            return Ok(());
+        }
+        self.request(T_FLUSH, 0, &[], &mut [])
+    }
+
+    fn discard(&mut self, block_id: u64, count: u64) -> DevResult {
+        let end = block_id.checked_add(count).ok_or(DevError::InvalidParam)?;
+        if end > self.capacity {
+            return Err(DevError::InvalidParam);
+        }
+        if count == 0 {
+            return Ok(());
+        }
+        let limits = match self.discard {
+            Some(limits) if !self.readonly() => limits,
+            _ => return Err(DevError::Unsupported),
+        };
+
+        let mut start = block_id;
+        // Segments are 16 bytes, of up to `max_sectors` sectors each
+        let seg_count = count
+            .div_ceil(limits.max_sectors)
+            .min(limits.max_segs as u64) as usize;
+        let mut segs = Vec::with_capacity(16 * seg_count);
+        while start < end {
+            segs.clear();
+            while start < end && segs.len() < 16 * limits.max_segs {
+                let mut seg_end = end.min(start + limits.max_sectors);
+                // Split at an aligned sector, unless the range ends first
+                if seg_end < end && seg_end / limits.alignment * limits.alignment > start {
+                    seg_end = seg_end / limits.alignment * limits.alignment;
+                }
+                segs.extend_from_slice(&start.to_le_bytes());
+                segs.extend_from_slice(&((seg_end - start) as u32).to_le_bytes());
+                segs.extend_from_slice(&0u32.to_le_bytes());
+                start = seg_end;
+            }
+            self.request(T_DISCARD, 0, &[&segs], &mut [])?;
+        }
         Ok(())
     }
+
+    fn discard_supported(&self) -> bool {
+        self.discard.is_some() && !self.readonly()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::{Buf, FakeHal, FakeTransport};
+    use alloc::{boxed::Box, rc::Rc, vec};
+    use core::cell::RefCell;
+    use virtio_drivers::transport::DeviceType as VirtIoDevType;
+
+    /// The disk of the device model, and the types of the requests it got.
+    #[derive(Default)]
+    struct Disk {
+        data: Vec<u8>,
+        requests: Vec<u32>,
+        /// Discarded segments
+        discarded: Vec<(u64, u32)>,
+    }
+
+    /// A block device of `sectors` sectors offering `features`.
+    fn device(
+        sectors: u64,
+        features: u64,
+    ) -> (VirtIoBlkDev<FakeHal, FakeTransport>, Rc<RefCell<Disk>>) {
+        let disk = Rc::new(RefCell::new(Disk {
+            data: (0..sectors as usize * SECTOR_SIZE)
+                .map(|i| (i / SECTOR_SIZE) as u8)
+                .collect(),
+            ..Disk::default()
+        }));
+        let model = disk.clone();
+        let handler = Box::new(move |_queue: u16, bufs: &mut [Buf]| {
+            let mut disk = model.borrow_mut();
+            let (header, rest) = bufs.split_first_mut().unwrap();
+            let (status, data) = rest.split_last_mut().unwrap();
+            assert!(!header.writable && status.writable && status.data.len() == 1);
+            let type_ = u32::from_le_bytes(header.data[..4].try_into().unwrap());
+            let mut offset =
+                u64::from_le_bytes(header.data[8..].try_into().unwrap()) as usize * SECTOR_SIZE;
+            disk.requests.push(type_);
+            let mut written = 1;
+            for buf in data {
+                let len = buf.data.len();
+                match type_ {
+                    T_IN => {
+                        assert!(buf.writable);
+                        buf.data.copy_from_slice(&disk.data[offset..offset + len]);
+                        written += len as u32;
+                    }
+                    T_OUT => disk.data[offset..offset + len].copy_from_slice(buf.data),
+                    T_DISCARD => {
+                        for seg in buf.data.chunks(16) {
+                            let sector = u64::from_le_bytes(seg[..8].try_into().unwrap());
+                            let count = u32::from_le_bytes(seg[8..12].try_into().unwrap());
+                            disk.discarded.push((sector, count));
+                        }
+                    }
+                    _ => unreachable!(),
+                }
+                offset += len;
+            }
+            status.data[0] = S_OK;
+            Some(written)
+        });
+        let mut transport =
+            FakeTransport::new(VirtIoDevType::Block, features | F_VERSION_1, handler);
+        let config = transport.config_bytes();
+        config[..8].copy_from_slice(&sectors.to_le_bytes());
+        // seg_max
+        config[12..16].copy_from_slice(&126u32.to_le_bytes());
+        // max_discard_sectors, max_discard_seg, discard_sector_alignment
+        config[36..40].copy_from_slice(&100u32.to_le_bytes());
+        config[40..44].copy_from_slice(&2u32.to_le_bytes());
+        config[44..48].copy_from_slice(&8u32.to_le_bytes());
+        (VirtIoBlkDev::try_new(transport).unwrap(), disk)
+    }
+
+    #[test]
+    fn reads_and_writes_reach_the_disk() {
+        let (mut dev, disk) = device(16, F_FLUSH);
+        assert_eq!(dev.num_blocks(), 16);
+        assert_eq!(dev.negotiated_features(), F_FLUSH | F_VERSION_1);
+        assert!(dev.transport.status.contains(DeviceStatus::DRIVER_OK));
+
+        let mut buf = [0; 2 * SECTOR_SIZE];
+        dev.read_block(3, &mut buf).unwrap();
+        assert!(buf[..SECTOR_SIZE].iter().all(|&b| b == 3));
+        assert!(buf[SECTOR_SIZE..].iter().all(|&b| b == 4));
+        dev.write_block(15, &[0xaa; SECTOR_SIZE]).unwrap();
+        assert!(disk.borrow().data[15 * SECTOR_SIZE..]
+            .iter()
+            .all(|&b| b == 0xaa));
+        dev.flush().unwrap();
+        assert_eq!(disk.borrow().requests, [T_IN, T_OUT, T_FLUSH]);
+
+        // Empty accesses do nothing, out-of-range ones fail
+        dev.read_block(16, &mut []).unwrap();
+        dev.write_block(0, &[]).unwrap();
+        assert!(matches!(
+            dev.read_block(15, &mut buf),
+            Err(DevError::InvalidParam)
+        ));
+        assert!(matches!(
+            dev.write_block(0, &buf[..100]),
+            Err(DevError::InvalidParam)
+        ));
+        assert!(matches!(dev.discard(0, 1), Err(DevError::Unsupported)));
+        assert_eq!(disk.borrow().requests.len(), 3);
+        assert_eq!(dev.stats().reads, 1);
+        assert_eq!(dev.stats().writes, 1);
+    }
+
+    /// Reads 64 sectors into 64 buffers, and returns them.
+    fn read_64_fragments(dev: &mut VirtIoBlkDev<FakeHal, FakeTransport>) -> Vec<Vec<u8>> {
+        let mut bufs = vec![vec![0; SECTOR_SIZE]; 64];
+        let mut slices: Vec<&mut [u8]> = bufs.iter_mut().map(|b| &mut b[..]).collect();
+        dev.read_blocks_vectored(100, &mut slices).unwrap();
+        bufs
+    }
+
+    #[test]
+    fn a_64_fragment_request_uses_an_indirect_table() {
+        let (mut dev, disk) = device(256, F_RING_INDIRECT_DESC | F_SEG_MAX);
+        let bufs = read_64_fragments(&mut dev);
+        for (i, buf) in bufs.iter().enumerate() {
+            assert!(buf.iter().all(|&b| b == 100 + i as u8));
+        }
+        // The header, the 64 fragments and the status in a single chain
+        assert_eq!(disk.borrow().requests, [T_IN]);
+        assert_eq!(dev.transport.chains, [(0, 66, true)]);
+
+        let bufs: Vec<Vec<u8>> = (0..64).map(|i| vec![i; SECTOR_SIZE]).collect();
+        let slices: Vec<&[u8]> = bufs.iter().map(|b| &b[..]).collect();
+        dev.write_blocks_vectored(0, &slices).unwrap();
+        assert_eq!(dev.transport.chains[1], (0, 66, true));
+        let data = disk.borrow().data.clone();
+        assert!((0..64).all(|i| data[i * SECTOR_SIZE..][..SECTOR_SIZE]
+            .iter()
+            .all(|&b| b == i as u8)));
+
+        // Short chains still go to the ring
+        dev.read_block(0, &mut [0; SECTOR_SIZE]).unwrap();
+        assert_eq!(dev.transport.chains[2], (0, 3, false));
+    }
+
+    #[test]
+    fn without_indirect_descriptors_requests_are_split() {
+        let (mut dev, disk) = device(256, 0);
+        let bufs = read_64_fragments(&mut dev);
+        for (i, buf) in bufs.iter().enumerate() {
+            assert!(buf.iter().all(|&b| b == 100 + i as u8));
+        }
+        // At most 14 fragments fit in the 16 descriptors with the header and
+        // the status
+        assert_eq!(disk.borrow().requests.len(), 5);
+        assert!(dev
+            .transport
+            .chains
+            .iter()
+            .all(|&(_, len, indirect)| len <= QUEUE_SIZE && !indirect));
+    }
+
+    #[test]
+    fn discard_is_split_into_aligned_segments() {
+        let (mut dev, disk) = device(1024, F_DISCARD);
+        assert!(dev.discard_supported());
+        assert!(dev.capabilities().contains(DeviceCapabilities::DISCARD));
+        dev.discard(0, 0).unwrap();
+        assert!(disk.borrow().requests.is_empty());
+
+        // Segments of at most 96 sectors that end at multiples of 8, two per
+        // request
+        dev.discard(3, 300).unwrap();
+        let disk = disk.borrow();
+        assert_eq!(disk.requests, [T_DISCARD, T_DISCARD]);
+        assert_eq!(disk.discarded, [(3, 93), (96, 96), (192, 96), (288, 15)]);
+        assert!(matches!(dev.discard(1000, 25), Err(DevError::InvalidParam)));
+    }
 }
@@ -0,0 +1,77 @@
+//! Commands of the control virtqueue of network devices.
+
+use crate::queue::VirtQueue;
+use axdriver_base::DevResult;
+use virtio_drivers::{transport::Transport, Hal};
+
+/// Status written by the device when a control command succeeded.
+pub const VIRTIO_NET_OK: u8 = 0;
+/// Status written by the device when a control command failed.
+pub const VIRTIO_NET_ERR: u8 = 1;
+
+/// The virtqueue that control commands are sent on.
+pub trait ControlVirtQueue {
+    /// Adds a descriptor chain of the `inputs`, read by the device, followed
+    /// by the `outputs`, written by it, then notifies the device and waits
+    /// until it has used the chain.
+    fn add_notify_wait_pop(&mut self, inputs: &[&[u8]], outputs: &mut [&mut [u8]]) -> DevResult;
+}
+
+/// The control virtqueue of a device of this crate, with the transport to
+/// notify the device.
+pub(crate) struct DeviceQueue<'a, H: Hal, T: Transport, const SIZE: usize> {
+    pub queue: &'a mut VirtQueue<H, SIZE>,
+    pub transport: &'a mut T,
+}
+
+impl<H: Hal, T: Transport, const SIZE: usize> ControlVirtQueue for DeviceQueue<'_, H, T, SIZE> {
+    fn add_notify_wait_pop(&mut self, inputs: &[&[u8]], outputs: &mut [&mut [u8]]) -> DevResult {
+        self.queue
+            .add_notify_wait_pop(self.transport, inputs, outputs)
+            .map(drop)
+    }
+}
+
+/// Sends the commands of the control virtqueue (VIRTIO_NET_F_CTRL_VQ) of a
+/// network device, e.g. to set the RX mode, the MAC filters or the number of
+/// queue pairs.
+///
+/// Each command is a chain of the class and command bytes, the data of the
+/// command and the status byte written by the device.
+pub struct VirtIoControlQueue<Q> {
+    queue: Q,
+}
+
+impl<Q: ControlVirtQueue> VirtIoControlQueue<Q> {
+    /// Sends commands on `queue`.
+    pub const fn new(queue: Q) -> Self {
+        Self { queue }
+    }
+
+    /// Sends the command `cmd` of class `class` with `data`, and returns the
+    /// status written by the device, [`VIRTIO_NET_OK`] or [`VIRTIO_NET_ERR`].
+    ///
+    /// The status is [`VIRTIO_NET_ERR`] if the device did not write it.
+    pub fn send_command(&mut self, class: u8, cmd: u8, data: &[u8]) -> DevResult<u8> {
+        let header = [class, cmd];
+        let mut status = [VIRTIO_NET_ERR];
+        if data.is_empty() {
+            self.queue
+                .add_notify_wait_pop(&[&header], &mut [&mut status])?;
+        } else {
+            self.queue
+                .add_notify_wait_pop(&[&header, data], &mut [&mut status])?;
+        }
+        Ok(status[0])
+    }
+
+    /// Returns a reference to the virtqueue.
+    pub const fn queue(&self) -> &Q {
+        &self.queue
+    }
+
+    /// Unwraps the virtqueue.
+    pub fn into_queue(self) -> Q {
+        self.queue
+    }
+}
@@ -0,0 +1,293 @@
+//! A fake transport and HAL, with a device model that handles the chains
+//! posted to its queues, for the tests of the drivers.
+
+extern crate alloc;
+extern crate std;
+
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use alloc::{boxed::Box, vec::Vec};
+use core::{cell::Cell, ptr::NonNull};
+
+use virtio_drivers::transport::{DeviceStatus, DeviceType, Transport};
+use virtio_drivers::{BufferDirection, Hal, PhysAddr, PAGE_SIZE};
+
+std::thread_local! {
+    /// Number of buffers shared by [`FakeHal`] and not unshared yet, on this
+    /// thread (that is, by this test).
+    static SHARED: Cell<usize> = const { Cell::new(0) };
+}
+
+/// A HAL whose DMA memory comes from the heap, and whose physical addresses
+/// are the virtual ones.
+pub struct FakeHal;
+
+impl FakeHal {
+    /// Number of buffers shared and not unshared yet by the current test.
+    pub fn shared() -> usize {
+        SHARED.get()
+    }
+}
+
+unsafe impl Hal for FakeHal {
+    fn dma_alloc(pages: usize, _direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
+        let layout = Layout::from_size_align(pages * PAGE_SIZE, PAGE_SIZE).unwrap();
+        // SAFETY: the layout has a non-zero size.
+        let vaddr = NonNull::new(unsafe { alloc_zeroed(layout) }).unwrap();
+        (vaddr.as_ptr() as PhysAddr, vaddr)
+    }
+
+    unsafe fn dma_dealloc(_paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32 {
+        let layout = Layout::from_size_align(pages * PAGE_SIZE, PAGE_SIZE).unwrap();
+        unsafe { dealloc(vaddr.as_ptr(), layout) };
+        0
+    }
+
+    unsafe fn mmio_phys_to_virt(paddr: PhysAddr, _size: usize) -> NonNull<u8> {
+        NonNull::new(paddr as *mut u8).unwrap()
+    }
+
+    unsafe fn share(buffer: NonNull<[u8]>, _direction: BufferDirection) -> PhysAddr {
+        SHARED.set(SHARED.get() + 1);
+        buffer.as_ptr() as *mut u8 as PhysAddr
+    }
+
+    unsafe fn unshare(_paddr: PhysAddr, _buffer: NonNull<[u8]>, _direction: BufferDirection) {
+        SHARED.set(SHARED.get() - 1);
+    }
+}
+
+/// A buffer of a chain, as seen by the device.
+pub struct Buf {
+    pub data: &'static mut [u8],
+    pub writable: bool,
+}
+
+/// What the device model is asked to do with a chain posted to a queue:
+/// returns the number of bytes written, or `None` to keep the chain
+/// unused.
+pub type Handler = Box<dyn FnMut(u16, &mut [Buf]) -> Option<u32>>;
+
+/// The addresses and state of a queue.
+#[derive(Default, Clone, Copy)]
+struct Queue {
+    size: u16,
+    desc: usize,
+    avail: usize,
+    used: usize,
+    /// The next available element to handle
+    next_avail: u16,
+}
+
+/// A chain walked by the device model.
+pub struct Chain {
+    pub bufs: Vec<Buf>,
+    /// Whether the chain is an indirect table
+    pub indirect: bool,
+}
+
+/// A transport of a device offering `features`, with a configuration space
+/// `config`, whose chains are handled as they are notified.
+pub struct FakeTransport {
+    pub device_type: DeviceType,
+    pub features: u64,
+    pub driver_features: u64,
+    pub status: DeviceStatus,
+    /// Whether FEATURES_OK is kept clear, rejecting the driver features
+    pub reject_features: bool,
+    /// The maximum size of each queue, 0 if it is missing
+    pub max_queue_sizes: [u32; 16],
+    pub config: Box<[u64; 64]>,
+    queues: [Queue; 16],
+    pub handler: Handler,
+    pub notifications: usize,
+    /// The chains handled, for inspection
+    pub chains: Vec<(u16, usize, bool)>,
+}
+
+impl FakeTransport {
+    pub fn new(device_type: DeviceType, features: u64, handler: Handler) -> Self {
+        Self {
+            device_type,
+            features,
+            driver_features: 0,
+            status: DeviceStatus::empty(),
+            reject_features: false,
+            max_queue_sizes: [256; 16],
+            config: Box::new([0; 64]),
+            queues: [Queue::default(); 16],
+            handler,
+            notifications: 0,
+            chains: Vec::new(),
+        }
+    }
+
+    /// The configuration space, as bytes.
+    pub fn config_bytes(&mut self) -> &mut [u8] {
+        // SAFETY: the configuration space is plain memory.
+        unsafe { core::slice::from_raw_parts_mut(self.config.as_mut_ptr().cast(), 64 * 8) }
+    }
+
+    /// Walks a descriptor chain from `head` of the table at `desc`.
+    ///
+    /// # Safety
+    ///
+    /// The descriptors must be valid, with identity-mapped addresses.
+    unsafe fn walk(desc: usize, head: u16) -> Chain {
+        let read = |table: usize, i: u16| unsafe {
+            let p = (table + 16 * i as usize) as *const u8;
+            let addr = (p as *const u64).read_volatile();
+            let len = (p.add(8) as *const u32).read_volatile();
+            let flags = (p.add(12) as *const u16).read_volatile();
+            let next = (p.add(14) as *const u16).read_volatile();
+            (addr, len, flags, next)
+        };
+        let (mut table, mut i) = (desc, head);
+        let mut indirect = false;
+        let mut bufs = Vec::new();
+        loop {
+            let (addr, len, flags, next) = read(table, i);
+            if flags & 4 != 0 {
+                assert!(!indirect, "nested indirect table");
+                indirect = true;
+                table = addr as usize;
+                i = 0;
+                continue;
+            }
+            bufs.push(Buf {
+                data: unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, len as usize) },
+                writable: flags & 2 != 0,
+            });
+            if flags & 1 == 0 {
+                break;
+            }
+            i = next;
+        }
+        Chain { bufs, indirect }
+    }
+
+    /// Handles the chains made available on queue `idx`.
+    pub fn process(&mut self, idx: u16) {
+        let q = self.queues[idx as usize];
+        if q.size == 0 {
+            return;
+        }
+        let size = q.size as usize;
+        let avail_idx = unsafe { ((q.avail + 2) as *const u16).read_volatile() };
+        let mut next = q.next_avail;
+        while next != avail_idx {
+            let head = unsafe {
+                ((q.avail + 4 + 2 * (next as usize % size)) as *const u16).read_volatile()
+            };
+            let mut chain = unsafe { Self::walk(q.desc, head) };
+            let Some(len) = (self.handler)(idx, &mut chain.bufs) else {
+                break;
+            };
+            self.chains.push((idx, chain.bufs.len(), chain.indirect));
+            unsafe {
+                let used_idx = ((q.used + 2) as *const u16).read_volatile();
+                let elem = (q.used + 4 + 8 * (used_idx as usize % size)) as *mut u32;
+                elem.write_volatile(head as u32);
+                elem.add(1).write_volatile(len);
+                ((q.used + 2) as *mut u16).write_volatile(used_idx.wrapping_add(1));
+            }
+            next = next.wrapping_add(1);
+        }
+        self.queues[idx as usize].next_avail = next;
+    }
+}
+
+impl Transport for FakeTransport {
+    fn device_type(&self) -> DeviceType {
+        self.device_type
+    }
+
+    fn read_device_features(&mut self) -> u64 {
+        self.features
+    }
+
+    fn write_driver_features(&mut self, driver_features: u64) {
+        assert_eq!(driver_features & !self.features, 0);
+        self.driver_features = driver_features;
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u32 {
+        self.max_queue_sizes[queue as usize]
+    }
+
+    fn notify(&mut self, queue: u16) {
+        self.notifications += 1;
+        self.process(queue);
+    }
+
+    fn get_status(&self) -> DeviceStatus {
+        self.status
+    }
+
+    fn set_status(&mut self, status: DeviceStatus) {
+        if status == DeviceStatus::empty() {
+            self.queues = [Queue::default(); 16];
+        }
+        self.status = if self.reject_features {
+            DeviceStatus::from_bits_truncate(status.bits() & !DeviceStatus::FEATURES_OK.bits())
+        } else {
+            status
+        };
+    }
+
+    fn set_guest_page_size(&mut self, _guest_page_size: u32) {}
+
+    fn requires_legacy_layout(&self) -> bool {
+        false
+    }
+
+    fn queue_set(
+        &mut self,
+        queue: u16,
+        size: u32,
+        descriptors: PhysAddr,
+        driver_area: PhysAddr,
+        device_area: PhysAddr,
+    ) {
+        self.queues[queue as usize] = Queue {
+            size: size as u16,
+            desc: descriptors,
+            avail: driver_area,
+            used: device_area,
+            next_avail: 0,
+        };
+    }
+
+    fn queue_unset(&mut self, queue: u16) {
+        self.queues[queue as usize] = Queue::default();
+    }
+
+    fn queue_used(&mut self, queue: u16) -> bool {
+        self.queues[queue as usize].size != 0
+    }
+
+    fn ack_interrupt(&mut self) -> bool {
+        false
+    }
+
+    fn config_space<T: 'static>(&self) -> virtio_drivers::Result<NonNull<T>> {
+        Ok(NonNull::from(&*self.config).cast())
+    }
+}
+
+impl Drop for FakeTransport {
+    /// Checks that the device was reset, or its queues never set up, before
+    /// its driver went away, so that it no longer uses the memory freed with
+    /// the driver.
+    fn drop(&mut self) {
+        if !std::thread::panicking() {
+            assert!(
+                !self.status.contains(DeviceStatus::DRIVER_OK),
+                "live device dropped without a reset"
+            );
+            assert!(
+                self.queues.iter().all(|q| q.size == 0),
+                "device dropped with queues set up"
+            );
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! Feature bits negotiated with VirtIO devices.
+
+use core::fmt;
+
+#[cfg(any(feature = "block", feature = "net", feature = "gpu"))]
+use axdriver_base::trace;
+#[cfg(any(feature = "block", feature = "net", feature = "gpu"))]
+use virtio_drivers::transport::Transport;
+
+/// Names of the device-independent feature bits (bits 24~40).
+const COMMON_FEATURES: &[(u32, &str)] = &[
+    (24, "NOTIFY_ON_EMPTY"),
+    (27, "ANY_LAYOUT"),
+    (28, "RING_INDIRECT_DESC"),
+    (29, "RING_EVENT_IDX"),
+    (32, "VERSION_1"),
+    (33, "ACCESS_PLATFORM"),
+    (34, "RING_PACKED"),
+    (35, "IN_ORDER"),
+    (36, "ORDER_PLATFORM"),
+    (37, "SR_IOV"),
+    (38, "NOTIFICATION_DATA"),
+];
+
+pub(crate) const F_RING_INDIRECT_DESC: u64 = 1 << 28;
+pub(crate) const F_RING_EVENT_IDX: u64 = 1 << 29;
+#[cfg(any(feature = "block", feature = "net", feature = "gpu"))]
+pub(crate) const F_VERSION_1: u64 = 1 << 32;
+
+/// A set of VirtIO feature bits, as returned by the `negotiated_features`
+/// methods of the drivers.
+///
+/// The [`Debug`] output names the device-independent bits, and shows the
+/// remaining (device-specific) ones in hexadecimal.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct VirtIoFeatures(pub u64);
+
+impl VirtIoFeatures {
+    /// Whether all the bits of `features` are set.
+    pub const fn contains(self, features: u64) -> bool {
+        self.0 & features == features
+    }
+
+    /// Whether indirect descriptors are used.
+    pub const fn indirect_desc(self) -> bool {
+        self.contains(F_RING_INDIRECT_DESC)
+    }
+
+    /// Whether used buffer notifications are suppressed with the event index.
+    pub const fn event_idx(self) -> bool {
+        self.contains(F_RING_EVENT_IDX)
+    }
+
+    /// Whether the packed virtqueue layout is used.
+    pub const fn ring_packed(self) -> bool {
+        self.contains(1 << 34)
+    }
+}
+
+impl From<u64> for VirtIoFeatures {
+    fn from(bits: u64) -> Self {
+        Self(bits)
+    }
+}
+
+impl fmt::Debug for VirtIoFeatures {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut rest = self.0;
+        let mut first = true;
+        f.write_str("VirtIoFeatures(")?;
+        for &(bit, name) in COMMON_FEATURES {
+            if rest & (1 << bit) != 0 {
+                rest &= !(1 << bit);
+                if !first {
+                    f.write_str(" | ")?;
+                }
+                f.write_str(name)?;
+                first = false;
+            }
+        }
+        if rest != 0 || first {
+            if !first {
+                f.write_str(" | ")?;
+            }
+            write!(f, "{rest:#x}")?;
+        }
+        f.write_str(")")
+    }
+}
+
+/// Resets the device and negotiates the features that it offers among the
+/// `supported` ones, which are returned and logged.
+///
+/// A device that negotiated VIRTIO_F_VERSION_1 must accept the features by
+/// keeping FEATURES_OK set, otherwise negotiation fails with
+/// [`DevError::Unsupported`]. The device is then to be set up, and made live
+/// by [`Transport::finish_init`].
+#[cfg(any(feature = "block", feature = "net", feature = "gpu"))]
+pub(crate) fn negotiate<T: Transport>(
+    transport: &mut T,
+    supported: u64,
+) -> axdriver_base::DevResult<u64> {
+    use axdriver_base::DevError;
+    use virtio_drivers::{transport::DeviceStatus, PAGE_SIZE};
+
+    let status = DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER;
+    transport.set_status(DeviceStatus::empty());
+    transport.set_status(status);
+    let features = transport.read_device_features() & supported;
+    transport.write_driver_features(features);
+    transport.set_status(status | DeviceStatus::FEATURES_OK);
+    if features & F_VERSION_1 != 0 && !transport.get_status().contains(DeviceStatus::FEATURES_OK) {
+        trace::warn!(
+            "{:?}: device rejected {:?}",
+            transport.device_type(),
+            VirtIoFeatures(features)
+        );
+        transport.set_status(status | DeviceStatus::FAILED);
+        return Err(DevError::Unsupported);
+    }
+    transport.set_guest_page_size(PAGE_SIZE as u32);
+    trace::debug!(
+        "{:?}: negotiated {:?}",
+        transport.device_type(),
+        VirtIoFeatures(features)
+    );
+    Ok(features)
+}
+
+#[cfg(all(test, any(feature = "block", feature = "net", feature = "gpu")))]
+mod tests {
+    use super::*;
+    use crate::fake::FakeTransport;
+    extern crate alloc;
+
+    use alloc::boxed::Box;
+    use axdriver_base::DevError;
+    use virtio_drivers::transport::{DeviceStatus, DeviceType};
+
+    const F_OTHER: u64 = 1 << 5;
+
+    fn transport(features: u64) -> FakeTransport {
+        FakeTransport::new(DeviceType::Block, features, Box::new(|_, _| None))
+    }
+
+    #[test]
+    fn offered_and_supported_features_are_negotiated() {
+        let mut fake = transport(F_VERSION_1 | F_OTHER | 1 << 7);
+        let features = negotiate(&mut fake, F_VERSION_1 | F_OTHER | 1 << 9).unwrap();
+        assert_eq!(features, F_VERSION_1 | F_OTHER);
+        assert_eq!(fake.driver_features, features);
+        assert!(fake.status.contains(DeviceStatus::FEATURES_OK));
+    }
+
+    #[test]
+    fn rejected_features_fail_the_device() {
+        let mut fake = transport(F_VERSION_1 | F_OTHER);
+        fake.reject_features = true;
+        assert!(matches!(
+            negotiate(&mut fake, F_VERSION_1 | F_OTHER),
+            Err(DevError::Unsupported)
+        ));
+        assert!(fake.status.contains(DeviceStatus::FAILED));
+
+        // Legacy devices do not report it
+        let mut fake = transport(F_OTHER);
+        fake.reject_features = true;
+        assert_eq!(negotiate(&mut fake, F_OTHER).unwrap(), F_OTHER);
+    }
+}
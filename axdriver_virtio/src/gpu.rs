@@ -1,14 +1,122 @@
 extern crate alloc;
-use crate::as_dev_err;
+use crate::features::{self, F_RING_EVENT_IDX, F_RING_INDIRECT_DESC, F_VERSION_1};
+use crate::queue::{self, Dma, VirtQueue};
 
-use axdriver_base::{BaseDriverOps, DevResult, DeviceType};
-use axdriver_display::{DisplayDriverOps, DisplayInfo, FrameBuffer};
-use virtio_drivers::{device::gpu::VirtIOGpu as InnerDev, transport::Transport, Hal};
+use alloc::vec::Vec;
+use axdriver_base::{BaseDriverOps, DevError, DevResult, DeviceType};
+use axdriver_display::{
+    CursorImage, DisplayDriverOps, DisplayInfo, DisplayMode, FrameBuffer, PixelFormat,
+};
+use virtio_drivers::{
+    transport::{DeviceStatus, Transport},
+    BufferDirection, Hal,
+};
+
+/// Size of the control and cursor queues, which have a single command in
+/// flight.
+const QUEUE_SIZE: usize = 16;
+
+const CONTROL_QUEUE: u16 = 0;
+const CURSOR_QUEUE: u16 = 1;
+
+/// Features offered by the driver, only the ring features.
+const SUPPORTED_FEATURES: u64 = F_RING_INDIRECT_DESC | F_RING_EVENT_IDX | F_VERSION_1;
+
+// Commands.
+const CMD_GET_DISPLAY_INFO: u32 = 0x100;
+const CMD_RESOURCE_CREATE_2D: u32 = 0x101;
+const CMD_RESOURCE_UNREF: u32 = 0x102;
+const CMD_SET_SCANOUT: u32 = 0x103;
+const CMD_RESOURCE_FLUSH: u32 = 0x104;
+const CMD_TRANSFER_TO_HOST_2D: u32 = 0x105;
+const CMD_RESOURCE_ATTACH_BACKING: u32 = 0x106;
+const CMD_RESOURCE_DETACH_BACKING: u32 = 0x107;
+const CMD_UPDATE_CURSOR: u32 = 0x300;
+const CMD_MOVE_CURSOR: u32 = 0x301;
+
+// Responses.
+const RESP_OK_NODATA: u32 = 0x1100;
+const RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+const RESP_ERR_OUT_OF_MEMORY: u32 = 0x1201;
+const RESP_ERR_INVALID_SCANOUT_ID: u32 = 0x1202;
+const RESP_ERR_INVALID_RESOURCE_ID: u32 = 0x1203;
+const RESP_ERR_INVALID_PARAMETER: u32 = 0x1205;
+
+/// Size of `virtio_gpu_ctrl_hdr`, before each command and response.
+const HDR_SIZE: usize = 24;
+
+/// Maximum number of scanouts in the response to GET_DISPLAY_INFO.
+const MAX_SCANOUTS: usize = 16;
+
+/// VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM.
+const FORMAT_B8G8R8A8_UNORM: u32 = 1;
+
+const RESOURCE_ID_CURSOR: u32 = 1;
+/// The framebuffer resource, and the one that replaces it on a mode switch,
+/// `RESOURCE_ID_FB ^ 1`.
+const RESOURCE_ID_FB: u32 = 2;
+
+/// Common resolutions, supported if they fit in the preferred one of the
+/// first scanout.
+const STANDARD_MODES: &[(u32, u32)] = &[
+    (640, 480),
+    (800, 600),
+    (1024, 768),
+    (1280, 720),
+    (1280, 1024),
+    (1920, 1080),
+];
+
+/// Width and height of the cursor images of virtio-gpu.
+const CURSOR_SIZE: u32 = 64;
+
+/// A rectangle of a resource or of a scanout, `virtio_gpu_rect`.
+#[derive(Clone, Copy)]
+struct Rect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Rect {
+    fn bytes(self) -> [u8; 16] {
+        let mut bytes = [0; 16];
+        for (i, v) in [self.x, self.y, self.width, self.height].iter().enumerate() {
+            bytes[4 * i..4 * i + 4].copy_from_slice(&v.to_le_bytes());
+        }
+        bytes
+    }
+}
 
 /// The VirtIO GPU device driver.
+///
+/// The display starts at the resolution of the first scanout reported by
+/// GET_DISPLAY_INFO. The supported modes are the resolutions of the enabled
+/// scanouts, and the common ones that fit in the first. The framebuffer is a
+/// 2D resource in [`PixelFormat::Bgra8888`], backed by DMA memory, which
+/// [`flush`] and [`flush_region`] transfer to the host and flush to scanout
+/// 0. [`set_mode`] replaces it with a resource of the new resolution.
+///
+/// The hardware cursor images must be 64 × 64 pixels in
+/// [`PixelFormat::Bgra8888`].
+///
+/// [`flush`]: DisplayDriverOps::flush
+/// [`flush_region`]: DisplayDriverOps::flush_region
+/// [`set_mode`]: DisplayDriverOps::set_mode
 pub struct VirtIoGpuDev<H: Hal, T: Transport> {
-    inner: InnerDev<H, T>,
+    transport: T,
+    control: VirtQueue<H, QUEUE_SIZE>,
+    cursor: VirtQueue<H, QUEUE_SIZE>,
+    fb: Dma<H>,
+    /// The resource of the framebuffer
+    fb_resource: u32,
     info: DisplayInfo,
+    modes: Vec<DisplayMode>,
+    /// The backing of the cursor resource, once created
+    cursor_image: Option<Dma<H>>,
+    cursor_pos: (u32, u32),
+    features: u64,
 }
 
 unsafe impl<H: Hal, T: Transport> Send for VirtIoGpuDev<H, T> {}
@@ -17,26 +125,310 @@ unsafe impl<H: Hal, T: Transport> Sync for VirtIoGpuDev<H, T> {}
 impl<H: Hal, T: Transport> VirtIoGpuDev<H, T> {
     /// Creates a new driver instance and initializes the device, or returns
     /// an error if any step fails.
-    pub fn try_new(transport: T) -> DevResult<Self> {
-        let mut virtio = InnerDev::new(transport).unwrap();
-
-        // get framebuffer
-        let fbuffer = virtio.setup_framebuffer().unwrap();
-        let fb_base_vaddr = fbuffer.as_mut_ptr() as usize;
-        let fb_size = fbuffer.len();
-        let (width, height) = virtio.resolution().unwrap();
+    pub fn try_new(mut transport: T) -> DevResult<Self> {
+        let features = features::negotiate(&mut transport, SUPPORTED_FEATURES)?;
+        let queues = Self::setup_queues(&mut transport, features);
+        let (mut control, cursor) = match queues {
+            Ok(queues) => queues,
+            Err(e) => {
+                transport.set_status(DeviceStatus::FAILED);
+                return Err(e);
+            }
+        };
+        transport.finish_init();
+
+        let (info, modes, fb) = match Self::setup_scanout(&mut control, &mut transport) {
+            Ok(scanout) => scanout,
+            Err(e) => {
+                queue::reset_device(&mut transport, [control.index(), cursor.index()]);
+                return Err(e);
+            }
+        };
+        Ok(Self {
+            transport,
+            control,
+            cursor,
+            fb,
+            fb_resource: RESOURCE_ID_FB,
+            info,
+            modes,
+            cursor_image: None,
+            cursor_pos: (0, 0),
+            features,
+        })
+    }
+
+    /// Sets up the control and cursor queues. The device is reset if the
+    /// cursor queue cannot be set up after the control queue.
+    #[allow(clippy::type_complexity)]
+    fn setup_queues(
+        transport: &mut T,
+        features: u64,
+    ) -> DevResult<(VirtQueue<H, QUEUE_SIZE>, VirtQueue<H, QUEUE_SIZE>)> {
+        let indirect = features & F_RING_INDIRECT_DESC != 0;
+        let event_idx = features & F_RING_EVENT_IDX != 0;
+        let control = VirtQueue::new(transport, CONTROL_QUEUE, indirect, event_idx)?;
+        match VirtQueue::new(transport, CURSOR_QUEUE, indirect, event_idx) {
+            Ok(cursor) => Ok((control, cursor)),
+            Err(e) => {
+                queue::reset_device(transport, [control.index()]);
+                Err(e)
+            }
+        }
+    }
+
+    /// Gets the scanouts and sets up the framebuffer as the first one.
+    /// Returns its information, the supported modes, and its backing.
+    fn setup_scanout(
+        control: &mut VirtQueue<H, QUEUE_SIZE>,
+        transport: &mut T,
+    ) -> DevResult<(DisplayInfo, Vec<DisplayMode>, Dma<H>)> {
+        let scanouts = get_display_info(control, transport)?;
+        let rect = scanouts.first().ok_or(DevError::NotPresent)?;
+        let (width, height) = (rect.width, rect.height);
+        let mut modes: Vec<DisplayMode> = Vec::new();
+        let standard = STANDARD_MODES
+            .iter()
+            .filter(|&&(w, h)| w <= width && h <= height)
+            .copied();
+        for (width, height) in scanouts
+            .iter()
+            .map(|rect| (rect.width, rect.height))
+            .chain(standard)
+        {
+            let mode = DisplayMode { width, height };
+            if !modes.contains(&mode) {
+                modes.push(mode);
+            }
+        }
+        let fb = create_framebuffer(control, transport, RESOURCE_ID_FB, width, height)?;
         let info = DisplayInfo {
             width,
             height,
-            fb_base_vaddr,
-            fb_size,
+            // The 2D resource is created as VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM.
+            format: PixelFormat::Bgra8888,
+            fb_base_vaddr: fb.vaddr().as_ptr() as usize,
+            fb_size: width as usize * height as usize * PixelFormat::Bgra8888.bytes_per_pixel(),
         };
+        Ok((info, modes, fb))
+    }
 
-        Ok(Self {
-            inner: virtio,
-            info,
+    /// The feature bits negotiated with the device, see
+    /// [`VirtIoFeatures`](crate::VirtIoFeatures) to decode them.
+    pub const fn negotiated_features(&self) -> u64 {
+        self.features
+    }
+
+    /// Transfers the rectangle `rect` of the framebuffer to the host, and
+    /// flushes it to the scanout.
+    fn flush_rect(&mut self, rect: Rect) -> DevResult {
+        let offset = rect.y as usize * self.info.stride()
+            + rect.x as usize * self.info.format.bytes_per_pixel();
+        transfer_to_host(
+            &mut self.control,
+            &mut self.transport,
+            self.fb_resource,
+            rect,
+            offset as u64,
+        )?;
+        let mut body = Vec::with_capacity(24);
+        body.extend_from_slice(&rect.bytes());
+        body.extend_from_slice(&self.fb_resource.to_le_bytes());
+        body.extend_from_slice(&[0; 4]);
+        command(
+            &mut self.control,
+            &mut self.transport,
+            CMD_RESOURCE_FLUSH,
+            &body,
+        )
+    }
+
+    /// Sends an UPDATE_CURSOR or MOVE_CURSOR command on the cursor queue.
+    fn cursor_command(&mut self, cmd: u32, resource_id: u32, hot: (u32, u32)) -> DevResult {
+        let (x, y) = self.cursor_pos;
+        let mut req = Vec::with_capacity(HDR_SIZE + 32);
+        req.extend_from_slice(&header(cmd));
+        for v in [0, x, y, 0, resource_id, hot.0, hot.1, 0] {
+            req.extend_from_slice(&v.to_le_bytes());
+        }
+        // The cursor queue has no responses
+        self.cursor
+            .add_notify_wait_pop(&mut self.transport, &[&req], &mut [])
+            .map(drop)
+    }
+}
+
+impl<H: Hal, T: Transport> Drop for VirtIoGpuDev<H, T> {
+    fn drop(&mut self) {
+        // Stop the device before the queue memory and the resource backings
+        // are freed
+        queue::reset_device(
+            &mut self.transport,
+            [self.control.index(), self.cursor.index()],
+        );
+    }
+}
+
+/// The `virtio_gpu_ctrl_hdr` of a command.
+fn header(cmd: u32) -> [u8; HDR_SIZE] {
+    let mut hdr = [0; HDR_SIZE];
+    hdr[..4].copy_from_slice(&cmd.to_le_bytes());
+    hdr
+}
+
+/// Sends the command `cmd` with `body` on the control queue, and checks that
+/// the response is `expected`, written to `resp` with its header.
+fn request<H: Hal, T: Transport>(
+    control: &mut VirtQueue<H, QUEUE_SIZE>,
+    transport: &mut T,
+    cmd: u32,
+    body: &[u8],
+    expected: u32,
+    resp: &mut [u8],
+) -> DevResult {
+    let hdr = header(cmd);
+    if body.is_empty() {
+        control.add_notify_wait_pop(transport, &[&hdr], &mut [&mut *resp])?;
+    } else {
+        control.add_notify_wait_pop(transport, &[&hdr, body], &mut [&mut *resp])?;
+    }
+    match u32::from_le_bytes(resp[..4].try_into().unwrap()) {
+        t if t == expected => Ok(()),
+        RESP_ERR_OUT_OF_MEMORY => Err(DevError::NoMemory),
+        RESP_ERR_INVALID_SCANOUT_ID | RESP_ERR_INVALID_RESOURCE_ID | RESP_ERR_INVALID_PARAMETER => {
+            Err(DevError::InvalidParam)
+        }
+        _ => Err(DevError::Io),
+    }
+}
+
+/// Sends a command whose response has no data.
+fn command<H: Hal, T: Transport>(
+    control: &mut VirtQueue<H, QUEUE_SIZE>,
+    transport: &mut T,
+    cmd: u32,
+    body: &[u8],
+) -> DevResult {
+    let mut resp = [0; HDR_SIZE];
+    request(control, transport, cmd, body, RESP_OK_NODATA, &mut resp)
+}
+
+/// Returns the rectangles of the enabled scanouts, from GET_DISPLAY_INFO.
+fn get_display_info<H: Hal, T: Transport>(
+    control: &mut VirtQueue<H, QUEUE_SIZE>,
+    transport: &mut T,
+) -> DevResult<Vec<Rect>> {
+    let mut resp = [0; HDR_SIZE + MAX_SCANOUTS * 24];
+    request(
+        control,
+        transport,
+        CMD_GET_DISPLAY_INFO,
+        &[],
+        RESP_OK_DISPLAY_INFO,
+        &mut resp,
+    )?;
+    let u32_at = |i: usize| u32::from_le_bytes(resp[i..i + 4].try_into().unwrap());
+    Ok((0..MAX_SCANOUTS)
+        .map(|i| HDR_SIZE + 24 * i)
+        .filter(|&pmode| u32_at(pmode + 16) != 0)
+        .map(|pmode| Rect {
+            x: u32_at(pmode),
+            y: u32_at(pmode + 4),
+            width: u32_at(pmode + 8),
+            height: u32_at(pmode + 12),
         })
+        .filter(|rect| rect.width != 0 && rect.height != 0)
+        .collect())
+}
+
+/// Creates the 2D resource `resource_id` of `width` × `height` pixels, backed
+/// by `backing`.
+fn create_resource<H: Hal, T: Transport>(
+    control: &mut VirtQueue<H, QUEUE_SIZE>,
+    transport: &mut T,
+    resource_id: u32,
+    width: u32,
+    height: u32,
+    backing: &Dma<H>,
+    len: usize,
+) -> DevResult {
+    let mut body = Vec::with_capacity(24);
+    for v in [resource_id, FORMAT_B8G8R8A8_UNORM, width, height] {
+        body.extend_from_slice(&v.to_le_bytes());
     }
+    command(control, transport, CMD_RESOURCE_CREATE_2D, &body)?;
+
+    // A single entry of the whole backing
+    body.clear();
+    body.extend_from_slice(&resource_id.to_le_bytes());
+    body.extend_from_slice(&1u32.to_le_bytes());
+    body.extend_from_slice(&(backing.paddr() as u64).to_le_bytes());
+    body.extend_from_slice(&(len as u32).to_le_bytes());
+    body.extend_from_slice(&[0; 4]);
+    command(control, transport, CMD_RESOURCE_ATTACH_BACKING, &body)
+}
+
+/// Detaches the backing of resource `resource_id`, and destroys it.
+fn destroy_resource<H: Hal, T: Transport>(
+    control: &mut VirtQueue<H, QUEUE_SIZE>,
+    transport: &mut T,
+    resource_id: u32,
+) -> DevResult {
+    let mut body = [0; 8];
+    body[..4].copy_from_slice(&resource_id.to_le_bytes());
+    command(control, transport, CMD_RESOURCE_DETACH_BACKING, &body)?;
+    command(control, transport, CMD_RESOURCE_UNREF, &body)
+}
+
+/// Creates the framebuffer resource `resource_id` of `width` × `height`
+/// pixels, and sets it as the resource of scanout 0. Returns its backing.
+///
+/// The resource is destroyed if it cannot be set as the resource of the
+/// scanout, which keeps the previous one.
+fn create_framebuffer<H: Hal, T: Transport>(
+    control: &mut VirtQueue<H, QUEUE_SIZE>,
+    transport: &mut T,
+    resource_id: u32,
+    width: u32,
+    height: u32,
+) -> DevResult<Dma<H>> {
+    let len = width as usize * height as usize * PixelFormat::Bgra8888.bytes_per_pixel();
+    let fb = Dma::new(len, BufferDirection::DriverToDevice)?;
+    create_resource(control, transport, resource_id, width, height, &fb, len)?;
+    let rect = Rect {
+        x: 0,
+        y: 0,
+        width,
+        height,
+    };
+    let mut body = Vec::with_capacity(24);
+    body.extend_from_slice(&rect.bytes());
+    // scanout_id
+    body.extend_from_slice(&0u32.to_le_bytes());
+    body.extend_from_slice(&resource_id.to_le_bytes());
+    if let Err(e) = command(control, transport, CMD_SET_SCANOUT, &body) {
+        // The backing must not be freed while attached
+        destroy_resource(control, transport, resource_id)?;
+        return Err(e);
+    }
+    Ok(fb)
+}
+
+/// Transfers the rectangle `rect` of resource `resource_id`, at `offset` in
+/// its backing, to the host.
+fn transfer_to_host<H: Hal, T: Transport>(
+    control: &mut VirtQueue<H, QUEUE_SIZE>,
+    transport: &mut T,
+    resource_id: u32,
+    rect: Rect,
+    offset: u64,
+) -> DevResult {
+    let mut body = Vec::with_capacity(32);
+    body.extend_from_slice(&rect.bytes());
+    body.extend_from_slice(&offset.to_le_bytes());
+    body.extend_from_slice(&resource_id.to_le_bytes());
+    body.extend_from_slice(&[0; 4]);
+    command(control, transport, CMD_TRANSFER_TO_HOST_2D, &body)
 }
 
 impl<H: Hal, T: Transport> BaseDriverOps for VirtIoGpuDev<H, T> {
@@ -54,10 +446,8 @@ impl<H: Hal, T: Transport> DisplayDriverOps for VirtIoGpuDev<H, T> {
         self.info
     }
 
-    fn fb(&self) -> FrameBuffer {
-        unsafe {
-            FrameBuffer::from_raw_parts_mut(self.info.fb_base_vaddr as *mut u8, self.info.fb_size)
-        }
+    fn fb(&mut self) -> FrameBuffer<'_> {
+        unsafe { FrameBuffer::from_raw_parts_mut(self.fb.vaddr().as_ptr(), self.info.fb_size) }
     }
 
     fn need_flush(&self) -> bool {
@@ -65,6 +455,357 @@ impl<H: Hal, T: Transport> DisplayDriverOps for VirtIoGpuDev<H, T> {
     }
 
     fn flush(&mut self) -> DevResult {
-        self.inner.flush().map_err(as_dev_err)
+        self.flush_rect(Rect {
+            x: 0,
+            y: 0,
+            width: self.info.width,
+            height: self.info.height,
+        })
+    }
+
+    /// Transfers and flushes the clamped rectangle only, with
+    /// TRANSFER_TO_HOST_2D and RESOURCE_FLUSH.
+    fn flush_region(&mut self, x: u32, y: u32, w: u32, h: u32) -> DevResult {
+        let (x, y, width, height) = self.info.clamp_rect(x, y, w, h)?;
+        self.flush_rect(Rect {
+            x,
+            y,
+            width,
+            height,
+        })
+    }
+
+    fn set_cursor(&mut self, image: &CursorImage, hot_x: u32, hot_y: u32) -> DevResult {
+        let len = (CURSOR_SIZE * CURSOR_SIZE) as usize * PixelFormat::Bgra8888.bytes_per_pixel();
+        if (image.width, image.height) != (CURSOR_SIZE, CURSOR_SIZE)
+            || image.format != PixelFormat::Bgra8888
+            || image.pixels.len() != len
+            || hot_x >= CURSOR_SIZE
+            || hot_y >= CURSOR_SIZE
+        {
+            return Err(DevError::InvalidParam);
+        }
+        if self.cursor_image.is_none() {
+            let backing = Dma::new(len, BufferDirection::DriverToDevice)?;
+            create_resource(
+                &mut self.control,
+                &mut self.transport,
+                RESOURCE_ID_CURSOR,
+                CURSOR_SIZE,
+                CURSOR_SIZE,
+                &backing,
+                len,
+            )?;
+            self.cursor_image = Some(backing);
+        }
+        let backing = self.cursor_image.as_ref().unwrap();
+        // SAFETY: the backing holds `len` bytes, which the device only reads
+        // on TRANSFER_TO_HOST_2D.
+        unsafe { core::slice::from_raw_parts_mut(backing.vaddr().as_ptr(), len) }
+            .copy_from_slice(image.pixels);
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: CURSOR_SIZE,
+            height: CURSOR_SIZE,
+        };
+        transfer_to_host(
+            &mut self.control,
+            &mut self.transport,
+            RESOURCE_ID_CURSOR,
+            rect,
+            0,
+        )?;
+        self.cursor_command(CMD_UPDATE_CURSOR, RESOURCE_ID_CURSOR, (hot_x, hot_y))
+    }
+
+    fn move_cursor(&mut self, x: u32, y: u32) -> DevResult {
+        let old = self.cursor_pos;
+        self.cursor_pos = (x, y);
+        let result = self.cursor_command(CMD_MOVE_CURSOR, 0, (0, 0));
+        if result.is_err() {
+            self.cursor_pos = old;
+        }
+        result
+    }
+
+    fn supported_modes(&self) -> &[DisplayMode] {
+        &self.modes
+    }
+
+    /// Creates a framebuffer resource of the new resolution, sets it as the
+    /// resource of the scanout, and destroys the previous one. The new
+    /// framebuffer is blank, and flushed.
+    fn set_mode(&mut self, mode: DisplayMode) -> DevResult {
+        if !self.modes.contains(&mode) {
+            return Err(DevError::InvalidParam);
+        }
+        if (mode.width, mode.height) == (self.info.width, self.info.height) {
+            return Ok(());
+        }
+        let resource = self.fb_resource ^ 1;
+        let fb = create_framebuffer(
+            &mut self.control,
+            &mut self.transport,
+            resource,
+            mode.width,
+            mode.height,
+        )?;
+        let old = core::mem::replace(&mut self.fb, fb);
+        let old_resource = core::mem::replace(&mut self.fb_resource, resource);
+        self.info.width = mode.width;
+        self.info.height = mode.height;
+        self.info.fb_base_vaddr = self.fb.vaddr().as_ptr() as usize;
+        self.info.fb_size = self.info.stride() * mode.height as usize;
+        if let Err(e) = destroy_resource(&mut self.control, &mut self.transport, old_resource) {
+            // The device may still access the old backing
+            core::mem::forget(old);
+            return Err(e);
+        }
+        drop(old);
+        self.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::{Buf, FakeHal, FakeTransport};
+    use alloc::{boxed::Box, rc::Rc, vec};
+    use core::cell::RefCell;
+    use virtio_drivers::transport::DeviceType as VirtIoDevType;
+
+    type Dev = VirtIoGpuDev<FakeHal, FakeTransport>;
+
+    /// The commands got by the device model, by queue, with their body as
+    /// `u32`s.
+    type Commands = Rc<RefCell<Vec<(u16, u32, Vec<u32>)>>>;
+
+    /// A GPU whose scanouts have the given resolutions.
+    fn device(scanouts: &'static [(u32, u32)]) -> (Dev, Commands) {
+        let (transport, commands) = transport(scanouts);
+        (Dev::try_new(transport).unwrap(), commands)
+    }
+
+    /// The transport of a GPU like [`device`].
+    fn transport(scanouts: &'static [(u32, u32)]) -> (FakeTransport, Commands) {
+        let commands = Commands::default();
+        let model = commands.clone();
+        let handler = Box::new(move |queue: u16, bufs: &mut [Buf]| {
+            let (readable, mut writable): (Vec<&mut Buf>, Vec<&mut Buf>) =
+                bufs.iter_mut().partition(|buf| !buf.writable);
+            let req: Vec<u8> = readable
+                .iter()
+                .flat_map(|buf| buf.data.iter().copied())
+                .collect();
+            let words: Vec<u32> = req
+                .chunks(4)
+                .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+                .collect();
+            model
+                .borrow_mut()
+                .push((queue, words[0], words[HDR_SIZE / 4..].to_vec()));
+            let [resp] = &mut writable[..] else {
+                assert_eq!(queue, CURSOR_QUEUE);
+                return Some(0);
+            };
+            let mut type_ = RESP_OK_NODATA;
+            if words[0] == CMD_GET_DISPLAY_INFO {
+                type_ = RESP_OK_DISPLAY_INFO;
+                for (i, &(width, height)) in scanouts.iter().enumerate() {
+                    let pmode = &mut resp.data[HDR_SIZE + 24 * i..][..24];
+                    pmode[8..12].copy_from_slice(&width.to_le_bytes());
+                    pmode[12..16].copy_from_slice(&height.to_le_bytes());
+                    pmode[16..20].copy_from_slice(&1u32.to_le_bytes());
+                }
+            }
+            resp.data[..4].copy_from_slice(&type_.to_le_bytes());
+            Some(resp.data.len() as u32)
+        });
+        let transport = FakeTransport::new(VirtIoDevType::GPU, F_VERSION_1, handler);
+        (transport, commands)
+    }
+
+    const fn mode(width: u32, height: u32) -> DisplayMode {
+        DisplayMode { width, height }
+    }
+
+    #[test]
+    fn the_framebuffer_is_set_up_as_the_first_scanout() {
+        let (mut dev, commands) = device(&[(64, 48), (32, 32)]);
+        let info = dev.info();
+        assert_eq!(
+            (info.width, info.height, info.fb_size),
+            (64, 48, 64 * 48 * 4)
+        );
+        assert_eq!(dev.fb().len(), info.fb_size);
+        assert_eq!(dev.supported_modes(), [mode(64, 48), mode(32, 32)]);
+        let commands = commands.borrow();
+        let types: Vec<u32> = commands.iter().map(|c| c.1).collect();
+        assert_eq!(
+            types,
+            [
+                CMD_GET_DISPLAY_INFO,
+                CMD_RESOURCE_CREATE_2D,
+                CMD_RESOURCE_ATTACH_BACKING,
+                CMD_SET_SCANOUT
+            ]
+        );
+        assert_eq!(
+            commands[1].2,
+            [RESOURCE_ID_FB, FORMAT_B8G8R8A8_UNORM, 64, 48]
+        );
+        assert_eq!(commands[2].2[4], 64 * 48 * 4);
+        assert_eq!(commands[3].2, [0, 0, 64, 48, 0, RESOURCE_ID_FB]);
+    }
+
+    #[test]
+    fn a_region_flush_transfers_the_clamped_rectangle() {
+        let (mut dev, commands) = device(&[(64, 48)]);
+        commands.borrow_mut().clear();
+        dev.flush_region(60, 10, 8, 2).unwrap();
+        // The rectangle, its offset in the framebuffer and the resource
+        let offset = 10 * 64 * 4 + 60 * 4;
+        assert_eq!(
+            *commands.borrow(),
+            [
+                (
+                    CONTROL_QUEUE,
+                    CMD_TRANSFER_TO_HOST_2D,
+                    vec![60, 10, 4, 2, offset, 0, RESOURCE_ID_FB, 0]
+                ),
+                (
+                    CONTROL_QUEUE,
+                    CMD_RESOURCE_FLUSH,
+                    vec![60, 10, 4, 2, RESOURCE_ID_FB, 0]
+                ),
+            ]
+        );
+        assert!(matches!(
+            dev.flush_region(64, 0, 1, 1),
+            Err(DevError::InvalidParam)
+        ));
+
+        commands.borrow_mut().clear();
+        dev.flush().unwrap();
+        assert_eq!(commands.borrow()[1].2, [0, 0, 64, 48, RESOURCE_ID_FB, 0]);
+    }
+
+    #[test]
+    fn the_cursor_is_updated_on_the_cursor_queue() {
+        let (mut dev, commands) = device(&[(64, 48)]);
+        commands.borrow_mut().clear();
+        let pixels = vec![0x80; 64 * 64 * 4];
+        let image = CursorImage {
+            width: 64,
+            height: 64,
+            format: PixelFormat::Bgra8888,
+            pixels: &pixels,
+        };
+        dev.move_cursor(5, 6).unwrap();
+        dev.set_cursor(&image, 1, 2).unwrap();
+        dev.set_cursor(&image, 3, 4).unwrap();
+        let commands = commands.borrow();
+        let types: Vec<(u16, u32)> = commands.iter().map(|c| (c.0, c.1)).collect();
+        assert_eq!(
+            types,
+            [
+                (CURSOR_QUEUE, CMD_MOVE_CURSOR),
+                (CONTROL_QUEUE, CMD_RESOURCE_CREATE_2D),
+                (CONTROL_QUEUE, CMD_RESOURCE_ATTACH_BACKING),
+                (CONTROL_QUEUE, CMD_TRANSFER_TO_HOST_2D),
+                (CURSOR_QUEUE, CMD_UPDATE_CURSOR),
+                (CONTROL_QUEUE, CMD_TRANSFER_TO_HOST_2D),
+                (CURSOR_QUEUE, CMD_UPDATE_CURSOR),
+            ]
+        );
+        assert_eq!(commands[6].2, [0, 5, 6, 0, RESOURCE_ID_CURSOR, 3, 4, 0]);
+    }
+
+    #[test]
+    fn common_modes_that_fit_are_supported() {
+        let (dev, _) = device(&[(1024, 768)]);
+        assert_eq!(
+            dev.supported_modes(),
+            [mode(1024, 768), mode(640, 480), mode(800, 600)]
+        );
+    }
+
+    #[test]
+    fn set_mode_replaces_the_framebuffer_resource() {
+        let (mut dev, commands) = device(&[(64, 48), (32, 32)]);
+        commands.borrow_mut().clear();
+        assert!(matches!(
+            dev.set_mode(mode(100, 100)),
+            Err(DevError::InvalidParam)
+        ));
+        dev.set_mode(mode(64, 48)).unwrap();
+        assert!(commands.borrow().is_empty());
+
+        dev.set_mode(mode(32, 32)).unwrap();
+        let info = dev.info();
+        assert_eq!(
+            (info.width, info.height, info.fb_size),
+            (32, 32, 32 * 32 * 4)
+        );
+        assert_eq!(dev.fb().len(), 32 * 32 * 4);
+        assert_eq!(info.fb_base_vaddr, dev.fb.vaddr().as_ptr() as usize);
+        let new = RESOURCE_ID_FB ^ 1;
+        assert_eq!(
+            *commands.borrow(),
+            [
+                (
+                    CONTROL_QUEUE,
+                    CMD_RESOURCE_CREATE_2D,
+                    vec![new, FORMAT_B8G8R8A8_UNORM, 32, 32]
+                ),
+                (
+                    CONTROL_QUEUE,
+                    CMD_RESOURCE_ATTACH_BACKING,
+                    vec![
+                        new,
+                        1,
+                        dev.fb.paddr() as u32,
+                        (dev.fb.paddr() as u64 >> 32) as u32,
+                        32 * 32 * 4,
+                        0
+                    ]
+                ),
+                (CONTROL_QUEUE, CMD_SET_SCANOUT, vec![0, 0, 32, 32, 0, new]),
+                (
+                    CONTROL_QUEUE,
+                    CMD_RESOURCE_DETACH_BACKING,
+                    vec![RESOURCE_ID_FB, 0]
+                ),
+                (CONTROL_QUEUE, CMD_RESOURCE_UNREF, vec![RESOURCE_ID_FB, 0]),
+                (
+                    CONTROL_QUEUE,
+                    CMD_TRANSFER_TO_HOST_2D,
+                    vec![0, 0, 32, 32, 0, 0, new, 0]
+                ),
+                (
+                    CONTROL_QUEUE,
+                    CMD_RESOURCE_FLUSH,
+                    vec![0, 0, 32, 32, new, 0]
+                ),
+            ]
+        );
+
+        // And back, on the first resource
+        dev.set_mode(mode(64, 48)).unwrap();
+        assert_eq!(dev.fb_resource, RESOURCE_ID_FB);
+        assert_eq!(dev.info().fb_size, 64 * 48 * 4);
+    }
+
+    #[test]
+    fn failed_initialization_resets_the_device() {
+        // The transport checks that the device is reset when dropped
+        let (fake, commands) = transport(&[]);
+        assert!(matches!(Dev::try_new(fake), Err(DevError::NotPresent)));
+        assert_eq!(commands.borrow()[0].1, CMD_GET_DISPLAY_INFO);
+
+        let (mut fake, _) = transport(&[(64, 48)]);
+        fake.max_queue_sizes[CURSOR_QUEUE as usize] = 0;
+        assert!(matches!(Dev::try_new(fake), Err(DevError::InvalidParam)));
     }
 }
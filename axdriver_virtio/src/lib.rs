@@ -6,6 +6,12 @@
 //! translate between physical addresses (as seen by devices) and virtual
 //! addresses (as seen by your program).
 //!
+//! The devices have their own split virtqueues, set up through the
+//! [`Transport`] of `virtio-drivers`. They use an indirect descriptor table for
+//! the chains of more than three buffers when VIRTIO_F_RING_INDIRECT_DESC is
+//! negotiated, which [`VirtIoFeatures::indirect_desc`] tells, so that a
+//! scatter-gather request takes a single descriptor of the ring.
+//!
 //! [1]: https://docs.rs/virtio-drivers/latest/virtio_drivers/
 //! [2]: https://github.com/arceos-org/axdriver_crates/tree/main/axdriver_base
 //! [3]: https://docs.rs/virtio-drivers/latest/virtio_drivers/trait.Hal.html
@@ -13,15 +19,28 @@
 #![no_std]
 #![cfg_attr(doc, feature(doc_auto_cfg))]
 
+mod features;
+#[cfg(any(feature = "block", feature = "net", feature = "gpu"))]
+mod queue;
+
+#[cfg(test)]
+mod fake;
+
 #[cfg(feature = "block")]
 mod blk;
+#[cfg(feature = "net")]
+mod ctrl;
 #[cfg(feature = "gpu")]
 mod gpu;
 #[cfg(feature = "net")]
 mod net;
 
+pub use self::features::VirtIoFeatures;
+
 #[cfg(feature = "block")]
 pub use self::blk::VirtIoBlkDev;
+#[cfg(feature = "net")]
+pub use self::ctrl::{ControlVirtQueue, VirtIoControlQueue, VIRTIO_NET_ERR, VIRTIO_NET_OK};
 #[cfg(feature = "gpu")]
 pub use self::gpu::VirtIoGpuDev;
 #[cfg(feature = "net")]
@@ -32,7 +51,7 @@ pub use virtio_drivers::transport::{mmio::MmioTransport, pci::PciTransport, Tran
 pub use virtio_drivers::{BufferDirection, Hal as VirtIoHal, PhysAddr};
 
 use self::pci::{DeviceFunction, DeviceFunctionInfo, PciRoot};
-use axdriver_base::{DevError, DeviceType};
+use axdriver_base::DeviceType;
 use virtio_drivers::transport::DeviceType as VirtIoDevType;
 
 /// Try to probe a VirtIO MMIO device from the given memory region.
@@ -77,21 +96,3 @@ const fn as_dev_type(t: VirtIoDevType) -> Option<DeviceType> {
         _ => None,
     }
 }
-
-#[allow(dead_code)]
-const fn as_dev_err(e: virtio_drivers::Error) -> DevError {
-    use virtio_drivers::Error::*;
-    match e {
-        QueueFull => DevError::BadState,
-        NotReady => DevError::Again,
-        WrongToken => DevError::BadState,
-        AlreadyUsed => DevError::AlreadyExists,
-        InvalidParam => DevError::InvalidParam,
-        DmaError => DevError::NoMemory,
-        IoError => DevError::Io,
-        Unsupported => DevError::Unsupported,
-        ConfigSpaceTooSmall => DevError::BadState,
-        ConfigSpaceMissing => DevError::BadState,
-        _ => DevError::BadState,
-    }
-}
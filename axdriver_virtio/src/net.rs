@@ -1,22 +1,158 @@
-use crate::as_dev_err;
+use crate::ctrl::{DeviceQueue, VirtIoControlQueue, VIRTIO_NET_OK};
+use crate::features::{self, F_RING_EVENT_IDX, F_RING_INDIRECT_DESC, F_VERSION_1};
+use crate::queue::{self, VirtQueue};
 use alloc::{sync::Arc, vec::Vec};
-use axdriver_base::{BaseDriverOps, DevError, DevResult, DeviceType};
-use axdriver_net::{EthernetAddress, NetBuf, NetBufBox, NetBufPool, NetBufPtr, NetDriverOps};
-use virtio_drivers::{device::net::VirtIONetRaw as InnerDev, transport::Transport, Hal};
+use axdriver_base::{trace, BaseDriverOps, DevError, DevResult, DeviceStats, DeviceType};
+use axdriver_net::{
+    EthernetAddress, LinkStatus, NetBuf, NetBufBox, NetBufPool, NetBufPtr, NetDriverOps, TxFlags,
+};
+use core::ptr::NonNull;
+use virtio_drivers::{
+    transport::{DeviceStatus, Transport},
+    Hal,
+};
 
 extern crate alloc;
 
 const NET_BUF_LEN: usize = 1526;
 
+/// The receive queue is refilled by [`VirtIoNetDev::receive`] when fewer
+/// than `QS / RX_LOW_WATERMARK_DIV` buffers are posted.
+const RX_LOW_WATERMARK_DIV: usize = 4;
+
+/// Size of the control virtqueue, which has a single command in flight.
+const CTRL_QUEUE_SIZE: usize = 16;
+
+const F_CSUM: u64 = 1 << 0;
+const F_MAC: u64 = 1 << 5;
+const F_STATUS: u64 = 1 << 16;
+const F_CTRL_VQ: u64 = 1 << 17;
+const F_CTRL_RX: u64 = 1 << 18;
+const F_MQ: u64 = 1 << 22;
+
+/// Features offered by the driver: VIRTIO_NET_F_CSUM, VIRTIO_NET_F_MAC,
+/// VIRTIO_NET_F_STATUS, VIRTIO_NET_F_CTRL_VQ, VIRTIO_NET_F_CTRL_RX,
+/// VIRTIO_NET_F_MQ and the ring features.
+const SUPPORTED_FEATURES: u64 = F_CSUM
+    | F_MAC
+    | F_STATUS
+    | F_CTRL_VQ
+    | F_CTRL_RX
+    | F_MQ
+    | F_RING_INDIRECT_DESC
+    | F_RING_EVENT_IDX
+    | F_VERSION_1;
+
+/// VIRTIO_NET_HDR_F_NEEDS_CSUM in the `flags` of the virtio-net header.
+const HDR_F_NEEDS_CSUM: u8 = 1;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+
+// Control commands.
+const CTRL_RX: u8 = 0;
+const CTRL_RX_PROMISC: u8 = 0;
+const CTRL_MAC: u8 = 1;
+const CTRL_MAC_TABLE_SET: u8 = 0;
+const CTRL_MQ: u8 = 4;
+const CTRL_MQ_VQ_PAIRS_SET: u8 = 0;
+
+/// VIRTIO_NET_S_LINK_UP in the `status` field of the configuration space.
+const S_LINK_UP: u16 = 1;
+
+/// The start of the configuration space of a network device.
+#[repr(C)]
+struct NetConfig {
+    mac: [u8; 6],
+    status: u16,
+    max_virtqueue_pairs: u16,
+}
+
+/// A receive queue and a transmit queue, with the buffers posted to them.
+struct QueuePair<H: Hal, const QS: usize> {
+    rx: VirtQueue<H, QS>,
+    /// The buffers posted to `rx`, by token
+    rx_buffers: [Option<NetBufBox>; QS],
+    /// Number of buffers posted to `rx`
+    rx_posted: usize,
+    tx: VirtQueue<H, QS>,
+    /// The buffers posted to `tx`, by token
+    tx_buffers: [Option<NetBufBox>; QS],
+    /// Number of buffers posted to `tx`
+    tx_posted: usize,
+}
+
+impl<H: Hal, const QS: usize> QueuePair<H, QS> {
+    /// A pair of the receive queue `rx` and the transmit queue `tx`.
+    fn new(rx: VirtQueue<H, QS>, tx: VirtQueue<H, QS>) -> Self {
+        Self {
+            rx,
+            rx_buffers: core::array::from_fn(|_| None),
+            rx_posted: 0,
+            tx,
+            tx_buffers: core::array::from_fn(|_| None),
+            tx_posted: 0,
+        }
+    }
+
+    /// Posts `rx_buf` to the receive queue, the device is not notified.
+    fn post_rx(&mut self, mut rx_buf: NetBufBox) -> DevResult {
+        // SAFETY: the buffer is kept in `rx_buffers` until the chain is
+        // popped, or the queue dropped.
+        let token = unsafe { self.rx.add(&[], &mut [rx_buf.raw_buf_mut()])? };
+        // `rx_buffers[token]` is expected to be `None` since it was taken
+        // away when the chain was popped.
+        if self.rx_buffers[token as usize].is_some() {
+            return Err(DevError::BadState);
+        }
+        self.rx_buffers[token as usize] = Some(rx_buf);
+        self.rx_posted += 1;
+        Ok(())
+    }
+
+    /// Takes the next received packet, with a virtio-net header of
+    /// `hdr_len` bytes.
+    fn receive(&mut self, hdr_len: usize) -> DevResult<NetBufPtr> {
+        let token = self.rx.peek_used().ok_or(DevError::Again)?;
+        let len = self.rx.pop_used(token)? as usize;
+        let mut rx_buf = self.rx_buffers[token as usize]
+            .take()
+            .ok_or(DevError::BadState)?;
+        self.rx_posted -= 1;
+        rx_buf.set_packet_len(0);
+        rx_buf.set_header_len(hdr_len);
+        rx_buf.set_packet_len(len.saturating_sub(hdr_len));
+        Ok(rx_buf.into_buf_ptr())
+    }
+}
+
 /// The VirtIO network device driver.
 ///
 /// `QS` is the VirtIO queue size.
+///
+/// The device has pairs of a receive and a transmit queue, on which each
+/// packet takes a single buffer, after its virtio-net header. There is a
+/// single pair unless the device is created by
+/// [`VirtIoNetDev::try_new_multiqueue`] and VIRTIO_NET_F_MQ is negotiated.
+/// If VIRTIO_NET_F_CTRL_VQ is negotiated, commands are sent on the control
+/// virtqueue with [`VirtIoNetDev::send_command`].
 pub struct VirtIoNetDev<H: Hal, T: Transport, const QS: usize> {
-    rx_buffers: [Option<NetBufBox>; QS],
-    tx_buffers: [Option<NetBufBox>; QS],
+    transport: T,
+    queues: Vec<QueuePair<H, QS>>,
+    /// The control virtqueue, if VIRTIO_NET_F_CTRL_VQ is negotiated
+    ctrl: Option<VirtQueue<H, CTRL_QUEUE_SIZE>>,
     free_tx_bufs: Vec<NetBufBox>,
     buf_pool: Arc<NetBufPool>,
-    inner: InnerDev<H, T, QS>,
+    mac: EthernetAddress,
+    /// Length of the virtio-net header before each packet
+    hdr_len: usize,
+    features: u64,
+    /// Configuration space, if VIRTIO_NET_F_STATUS is negotiated
+    config: Option<NonNull<NetConfig>>,
+    stats: DeviceStats,
 }
 
 unsafe impl<H: Hal, T: Transport, const QS: usize> Send for VirtIoNetDev<H, T, QS> {}
@@ -26,50 +162,349 @@ impl<H: Hal, T: Transport, const QS: usize> VirtIoNetDev<H, T, QS> {
     /// Creates a new driver instance and initializes the device, or returns
     /// an error if any step fails.
     pub fn try_new(transport: T) -> DevResult<Self> {
-        // 0. Create a new driver instance.
-        const NONE_BUF: Option<NetBufBox> = None;
-        let inner = InnerDev::new(transport).map_err(as_dev_err)?;
-        let rx_buffers = [NONE_BUF; QS];
-        let tx_buffers = [NONE_BUF; QS];
-        let buf_pool = NetBufPool::new(2 * QS, NET_BUF_LEN)?;
-        let free_tx_bufs = Vec::with_capacity(QS);
+        Self::try_new_multiqueue(transport, 1)
+    }
+
+    /// Creates a new driver instance with up to `max_pairs` queue pairs, and
+    /// initializes the device, or returns an error if any step fails.
+    ///
+    /// There are as many pairs as the device supports, at most `max_pairs`
+    /// and at least one, if VIRTIO_NET_F_MQ is negotiated; the number of
+    /// pairs is then set with VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET. Otherwise
+    /// there is a single pair.
+    pub fn try_new_multiqueue(mut transport: T, max_pairs: usize) -> DevResult<Self> {
+        // 0. Negotiate the features and set up the queues.
+        let features = features::negotiate(&mut transport, SUPPORTED_FEATURES)?;
+        let config: NonNull<NetConfig> =
+            transport.config_space().map_err(|_| DevError::BadState)?;
+        // SAFETY: the configuration space is mapped as long as the transport.
+        let (mac, max_virtqueue_pairs) = unsafe {
+            let config = config.as_ptr();
+            let mac = (&raw const (*config).mac).read_volatile();
+            let pairs = (&raw const (*config).max_virtqueue_pairs).read_volatile();
+            (mac, pairs)
+        };
+        // The control virtqueue comes after all the pairs that the device
+        // supports
+        let max_virtqueue_pairs = if features & F_MQ != 0 {
+            max_virtqueue_pairs.max(1)
+        } else {
+            1
+        };
+        let pairs = max_pairs.clamp(1, max_virtqueue_pairs as usize);
+        // QS buffers of each queue, and QS more per pair to refill the receive
+        // queues while received packets are in use
+        let buf_pool = match NetBufPool::new(3 * QS * pairs, NET_BUF_LEN) {
+            Ok(buf_pool) => buf_pool,
+            Err(e) => {
+                transport.set_status(DeviceStatus::FAILED);
+                return Err(e);
+            }
+        };
+        let queues = Self::setup_queues(&mut transport, features, pairs, max_virtqueue_pairs);
+        let (queues, ctrl) = match queues {
+            Ok(queues) => queues,
+            Err(e) => {
+                transport.set_status(DeviceStatus::FAILED);
+                return Err(e);
+            }
+        };
+        transport.finish_init();
 
         let mut dev = Self {
-            rx_buffers,
-            inner,
-            tx_buffers,
-            free_tx_bufs,
+            transport,
+            queues,
+            ctrl,
+            free_tx_bufs: Vec::with_capacity(QS * pairs),
             buf_pool,
+            mac: EthernetAddress(mac),
+            hdr_len: if features & F_VERSION_1 != 0 { 12 } else { 10 },
+            features,
+            config: (features & F_STATUS != 0).then_some(config),
+            stats: DeviceStats::default(),
         };
 
         // 1. Fill all rx buffers.
-        for (i, rx_buf_place) in dev.rx_buffers.iter_mut().enumerate() {
-            let mut rx_buf = dev.buf_pool.alloc_boxed().ok_or(DevError::NoMemory)?;
-            // Safe because the buffer lives as long as the queue.
-            let token = unsafe {
-                dev.inner
-                    .receive_begin(rx_buf.raw_buf_mut())
-                    .map_err(as_dev_err)?
-            };
-            assert_eq!(token, i as u16);
-            *rx_buf_place = Some(rx_buf);
+        for q in 0..pairs {
+            for _ in 0..QS {
+                let rx_buf = dev.buf_pool.alloc_boxed()?;
+                dev.queues[q].post_rx(rx_buf)?;
+            }
+            dev.queues[q].rx.notify(&mut dev.transport);
         }
 
         // 2. Allocate all tx buffers.
-        for _ in 0..QS {
-            let mut tx_buf = dev.buf_pool.alloc_boxed().ok_or(DevError::NoMemory)?;
-            // Fill header
-            let hdr_len = dev
-                .inner
-                .fill_buffer_header(tx_buf.raw_buf_mut())
-                .or(Err(DevError::InvalidParam))?;
-            tx_buf.set_header_len(hdr_len);
+        for _ in 0..QS * pairs {
+            let mut tx_buf = dev.buf_pool.alloc_boxed()?;
+            tx_buf.set_header_len(dev.hdr_len);
             dev.free_tx_bufs.push(tx_buf);
         }
 
-        // 3. Return the driver instance.
+        // 3. Enable the queue pairs.
+        if pairs > 1 {
+            dev.control(CTRL_MQ, CTRL_MQ_VQ_PAIRS_SET, &(pairs as u16).to_le_bytes())?;
+        }
+
+        // 4. Return the driver instance.
         Ok(dev)
     }
+
+    /// Sets up `pairs` receive and transmit queues, and the control virtqueue
+    /// after the `max_pairs` ones if VIRTIO_NET_F_CTRL_VQ is negotiated.
+    /// The device is reset if an error follows the setup of some queues.
+    #[allow(clippy::type_complexity)]
+    fn setup_queues(
+        transport: &mut T,
+        features: u64,
+        pairs: usize,
+        max_pairs: u16,
+    ) -> DevResult<(Vec<QueuePair<H, QS>>, Option<VirtQueue<H, CTRL_QUEUE_SIZE>>)> {
+        let indirect = features & F_RING_INDIRECT_DESC != 0;
+        let event_idx = features & F_RING_EVENT_IDX != 0;
+        // The receive queue `2 * n` and the transmit queue `2 * n + 1` of each
+        // pair `n`
+        let mut rings: Vec<VirtQueue<H, QS>> = Vec::with_capacity(2 * pairs);
+        let mut result = Ok(None);
+        for idx in 0..2 * pairs as u16 {
+            match VirtQueue::new(transport, idx, indirect, event_idx) {
+                Ok(ring) => rings.push(ring),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        if result.is_ok() && features & F_CTRL_VQ != 0 {
+            result = VirtQueue::new(transport, 2 * max_pairs, indirect, event_idx).map(Some);
+        }
+        let ctrl = match result {
+            Ok(ctrl) => ctrl,
+            Err(e) => {
+                queue::reset_device(transport, rings.iter().map(VirtQueue::index));
+                return Err(e);
+            }
+        };
+
+        let mut rings = rings.into_iter();
+        let mut queues = Vec::with_capacity(pairs);
+        while let (Some(rx), Some(tx)) = (rings.next(), rings.next()) {
+            queues.push(QueuePair::new(rx, tx));
+        }
+        Ok((queues, ctrl))
+    }
+
+    /// Posts up to `max` fresh buffers from the buffer pool to the receive
+    /// queues, in place of the received buffers that are not recycled yet.
+    ///
+    /// Returns the number of buffers posted, fewer than `max` if the queues
+    /// are full or the pool is exhausted. The device is notified once per
+    /// queue, unless VIRTIO_F_EVENT_IDX lets it skip the notifications that
+    /// it does not need.
+    pub fn refill_rx(&mut self, max: usize) -> usize {
+        let mut posted = 0;
+        for q in 0..self.queues.len() {
+            posted += self.refill_queue(q, max - posted);
+        }
+        posted
+    }
+
+    /// Posts up to `max` fresh buffers to the receive queue of pair `q`.
+    fn refill_queue(&mut self, q: usize, max: usize) -> usize {
+        let queues = &mut self.queues[q];
+        let mut posted = 0;
+        while posted < max && queues.rx_posted < QS {
+            let Ok(rx_buf) = self.buf_pool.alloc_boxed() else {
+                break;
+            };
+            if let Err(e) = queues.post_rx(rx_buf) {
+                trace::warn!("virtio-net: failed to refill the receive queue: {:?}", e);
+                break;
+            }
+            posted += 1;
+        }
+        if posted > 0 {
+            queues.rx.notify(&mut self.transport);
+        }
+        posted
+    }
+
+    /// Sends the command `cmd` of class `class` with `data` on the control
+    /// virtqueue, and returns the status written by the device, see
+    /// [`VirtIoControlQueue::send_command`].
+    ///
+    /// Returns [`DevError::Unsupported`] if VIRTIO_NET_F_CTRL_VQ is not
+    /// negotiated.
+    pub fn send_command(&mut self, class: u8, cmd: u8, data: &[u8]) -> DevResult<u8> {
+        let queue = self.ctrl.as_mut().ok_or(DevError::Unsupported)?;
+        VirtIoControlQueue::new(DeviceQueue {
+            queue,
+            transport: &mut self.transport,
+        })
+        .send_command(class, cmd, data)
+    }
+
+    /// Sends a command like [`VirtIoNetDev::send_command`], and fails with
+    /// [`DevError::Io`] if the device does not acknowledge it.
+    fn control(&mut self, class: u8, cmd: u8, data: &[u8]) -> DevResult {
+        match self.send_command(class, cmd, data)? {
+            VIRTIO_NET_OK => Ok(()),
+            status => {
+                trace::warn!(
+                    "virtio-net: control command {}.{} failed with status {}",
+                    class,
+                    cmd,
+                    status
+                );
+                Err(DevError::Io)
+            }
+        }
+    }
+
+    /// Transmits a packet on the transmit queue of pair `queue`, with the
+    /// offloads in `flags`, see [`NetDriverOps::transmit_with`].
+    fn post_tx(&mut self, queue: usize, tx_buf: NetBufPtr, flags: TxFlags) -> DevResult {
+        // 0. prepare tx buffer.
+        let offloads = self.tx_offloads();
+        let mut tx_buf = unsafe { NetBuf::from_buf_ptr(tx_buf) };
+        let Some(queues) = self.queues.get_mut(queue) else {
+            self.free_tx_bufs.push(tx_buf);
+            return Err(DevError::InvalidParam);
+        };
+        let len = tx_buf.packet().len();
+        let (hdr, packet) = tx_buf.raw_buf_mut()[..self.hdr_len + len].split_at_mut(self.hdr_len);
+        hdr.fill(0);
+        let result = if offloads.contains(flags) {
+            offload(hdr, packet, flags)
+        } else {
+            Err(DevError::Unsupported)
+        };
+        // 1. transmit packet.
+        // SAFETY: the buffer is kept in `tx_buffers` until the chain is
+        // popped, or the queue dropped.
+        let result =
+            result.and_then(|_| unsafe { queues.tx.add(&[tx_buf.packet_with_header()], &mut []) });
+        self.stats.record(true, len, &result);
+        match result {
+            Ok(token) => {
+                queues.tx_buffers[token as usize] = Some(tx_buf);
+                queues.tx_posted += 1;
+                queues.tx.notify(&mut self.transport);
+                Ok(())
+            }
+            Err(e) => {
+                self.free_tx_bufs.push(tx_buf);
+                Err(e)
+            }
+        }
+    }
+
+    /// The feature bits negotiated with the device, see
+    /// [`VirtIoFeatures`](crate::VirtIoFeatures) to decode them.
+    pub const fn negotiated_features(&self) -> u64 {
+        self.features
+    }
+}
+
+/// Fills the virtio-net header `hdr` of the Ethernet frame `packet` for the
+/// offloads in `flags`, which are among [`NetDriverOps::tx_offloads`].
+fn offload(hdr: &mut [u8], packet: &mut [u8], flags: TxFlags) -> DevResult {
+    if flags.is_empty() {
+        return Ok(());
+    }
+    let l4_flags = TxFlags::CSUM_TCP | TxFlags::CSUM_UDP;
+
+    let be16 = |packet: &[u8], at: usize| {
+        packet
+            .get(at..at + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .ok_or(DevError::InvalidParam)
+    };
+    let mut l3 = 14;
+    let mut ether_type = be16(packet, 12)?;
+    if ether_type == ETHERTYPE_VLAN {
+        l3 += 4;
+        ether_type = be16(packet, 16)?;
+    }
+    // The offset of the transport header, its protocol, and the checksum of
+    // the pseudo-header
+    let (l4, protocol, pseudo) = match ether_type {
+        ETHERTYPE_IPV4 => {
+            let ihl = 4 * (*packet.get(l3).ok_or(DevError::InvalidParam)? & 0xf) as usize;
+            let total_len = be16(packet, l3 + 2)? as usize;
+            if ihl < 20 || total_len < ihl || packet.len() < l3 + total_len {
+                return Err(DevError::InvalidParam);
+            }
+            let ip = &mut packet[l3..l3 + ihl];
+            if flags.contains(TxFlags::CSUM_IPV4) {
+                ip[10..12].fill(0);
+                let sum = !checksum(0, ip);
+                ip[10..12].copy_from_slice(&sum.to_be_bytes());
+            }
+            let protocol = ip[9];
+            let pseudo = checksum(checksum(0, &ip[12..20]), &[0, protocol]);
+            (
+                l3 + ihl,
+                protocol,
+                checksum(pseudo, &((total_len - ihl) as u16).to_be_bytes()),
+            )
+        }
+        ETHERTYPE_IPV6 if !flags.contains(TxFlags::CSUM_IPV4) => {
+            let payload_len = be16(packet, l3 + 4)? as usize;
+            if packet.len() < l3 + 40 + payload_len {
+                return Err(DevError::InvalidParam);
+            }
+            let protocol = packet[l3 + 6];
+            let pseudo = checksum(checksum(0, &packet[l3 + 8..l3 + 40]), &[0, protocol]);
+            (
+                l3 + 40,
+                protocol,
+                checksum(pseudo, &(payload_len as u16).to_be_bytes()),
+            )
+        }
+        _ => return Err(DevError::InvalidParam),
+    };
+
+    if !flags.intersects(l4_flags) {
+        return Ok(());
+    }
+    let csum_offset = match protocol {
+        IPPROTO_TCP if flags.contains(TxFlags::CSUM_TCP) => 16,
+        IPPROTO_UDP if flags.contains(TxFlags::CSUM_UDP) => 6,
+        _ => return Err(DevError::InvalidParam),
+    };
+    let field = packet
+        .get_mut(l4 + csum_offset..l4 + csum_offset + 2)
+        .ok_or(DevError::InvalidParam)?;
+    field.copy_from_slice(&pseudo.to_be_bytes());
+    hdr[0] = HDR_F_NEEDS_CSUM;
+    hdr[6..8].copy_from_slice(&(l4 as u16).to_le_bytes());
+    hdr[8..10].copy_from_slice(&(csum_offset as u16).to_le_bytes());
+    Ok(())
+}
+
+/// Adds `data` to the ones' complement sum `sum`, as 16-bit big-endian words.
+fn checksum(sum: u16, data: &[u8]) -> u16 {
+    let mut sum = sum as u32;
+    for word in data.chunks(2) {
+        sum += u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]) as u32;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+impl<H: Hal, T: Transport, const QS: usize> Drop for VirtIoNetDev<H, T, QS> {
+    fn drop(&mut self) {
+        // Stop the device before the queue memory and the posted buffers are
+        // freed
+        let rings = self
+            .queues
+            .iter()
+            .flat_map(|queues| [queues.rx.index(), queues.tx.index()]);
+        let ctrl = self.ctrl.as_ref().map(VirtQueue::index);
+        queue::reset_device(&mut self.transport, rings.chain(ctrl));
+    }
 }
 
 impl<H: Hal, T: Transport, const QS: usize> BaseDriverOps for VirtIoNetDev<H, T, QS> {
@@ -80,22 +515,48 @@ impl<H: Hal, T: Transport, const QS: usize> BaseDriverOps for VirtIoNetDev<H, T,
     fn device_type(&self) -> DeviceType {
         DeviceType::Net
     }
+
+    fn stats(&self) -> DeviceStats {
+        self.stats
+    }
 }
 
 impl<H: Hal, T: Transport, const QS: usize> NetDriverOps for VirtIoNetDev<H, T, QS> {
     #[inline]
     fn mac_address(&self) -> EthernetAddress {
-        EthernetAddress(self.inner.mac_address())
+        self.mac
     }
 
     #[inline]
     fn can_transmit(&self) -> bool {
-        !self.free_tx_bufs.is_empty() && self.inner.can_send()
+        !self.free_tx_bufs.is_empty() && self.queues[0].tx_posted < QS
     }
 
     #[inline]
     fn can_receive(&self) -> bool {
-        self.inner.poll_receive().is_some()
+        self.queues[0].rx.peek_used().is_some()
+    }
+
+    /// Reads VIRTIO_NET_S_LINK_UP from the configuration space.
+    ///
+    /// The link is reported as up if VIRTIO_NET_F_STATUS is not negotiated,
+    /// since the device cannot tell. The speed and the duplex are unknown,
+    /// VIRTIO_NET_F_SPEED_DUPLEX is not negotiated.
+    fn link_status(&self) -> LinkStatus {
+        let up = self.config.is_none_or(|config| {
+            // Safe because the configuration space is mapped as long as the
+            // device.
+            let status = unsafe { (&raw const (*config.as_ptr()).status).read_volatile() };
+            status & S_LINK_UP != 0
+        });
+        if up {
+            LinkStatus::Up {
+                speed_mbps: None,
+                full_duplex: None,
+            }
+        } else {
+            LinkStatus::Down
+        }
     }
 
     #[inline]
@@ -108,71 +569,105 @@ impl<H: Hal, T: Transport, const QS: usize> NetDriverOps for VirtIoNetDev<H, T,
         QS
     }
 
+    /// The buffer is posted to the receive queue with the fewest buffers. It
+    /// goes back to the pool instead if the receive queues were refilled by
+    /// [`VirtIoNetDev::refill_rx`] in the meantime.
     fn recycle_rx_buffer(&mut self, rx_buf: NetBufPtr) -> DevResult {
-        let mut rx_buf = unsafe { NetBuf::from_buf_ptr(rx_buf) };
-        // Safe because we take the ownership of `rx_buf` back to `rx_buffers`,
-        // it lives as long as the queue.
-        let new_token = unsafe {
-            self.inner
-                .receive_begin(rx_buf.raw_buf_mut())
-                .map_err(as_dev_err)?
-        };
-        // `rx_buffers[new_token]` is expected to be `None` since it was taken
-        // away at `Self::receive()` and has not been added back.
-        if self.rx_buffers[new_token as usize].is_some() {
-            return Err(DevError::BadState);
+        let rx_buf = unsafe { NetBuf::from_buf_ptr(rx_buf) };
+        let queues = self
+            .queues
+            .iter_mut()
+            .min_by_key(|queues| queues.rx_posted)
+            .ok_or(DevError::BadState)?;
+        if queues.rx_posted == QS {
+            return Ok(());
         }
-        self.rx_buffers[new_token as usize] = Some(rx_buf);
+        queues.post_rx(rx_buf)?;
+        queues.rx.notify(&mut self.transport);
         Ok(())
     }
 
     fn recycle_tx_buffers(&mut self) -> DevResult {
-        while let Some(token) = self.inner.poll_transmit() {
-            let tx_buf = self.tx_buffers[token as usize]
-                .take()
-                .ok_or(DevError::BadState)?;
-            unsafe {
-                self.inner
-                    .transmit_complete(token, tx_buf.packet_with_header())
-                    .map_err(as_dev_err)?;
+        for queues in &mut self.queues {
+            while let Some(token) = queues.tx.peek_used() {
+                queues.tx.pop_used(token)?;
+                let tx_buf = queues.tx_buffers[token as usize]
+                    .take()
+                    .ok_or(DevError::BadState)?;
+                queues.tx_posted -= 1;
+                // Recycle the buffer.
+                self.free_tx_bufs.push(tx_buf);
             }
-            // Recycle the buffer.
-            self.free_tx_bufs.push(tx_buf);
         }
         Ok(())
     }
 
     fn transmit(&mut self, tx_buf: NetBufPtr) -> DevResult {
-        // 0. prepare tx buffer.
-        let tx_buf = unsafe { NetBuf::from_buf_ptr(tx_buf) };
-        // 1. transmit packet.
-        let token = unsafe {
-            self.inner
-                .transmit_begin(tx_buf.packet_with_header())
-                .map_err(as_dev_err)?
-        };
-        self.tx_buffers[token as usize] = Some(tx_buf);
-        Ok(())
+        self.transmit_on(0, tx_buf)
     }
 
-    fn receive(&mut self) -> DevResult<NetBufPtr> {
-        if let Some(token) = self.inner.poll_receive() {
-            let mut rx_buf = self.rx_buffers[token as usize]
-                .take()
-                .ok_or(DevError::BadState)?;
-            // Safe because the buffer lives as long as the queue.
-            let (hdr_len, pkt_len) = unsafe {
-                self.inner
-                    .receive_complete(token, rx_buf.raw_buf_mut())
-                    .map_err(as_dev_err)?
-            };
-            rx_buf.set_header_len(hdr_len);
-            rx_buf.set_packet_len(pkt_len);
-
-            Ok(rx_buf.into_buf_ptr())
+    /// [`TxFlags::CSUM_IPV4`], with [`TxFlags::CSUM_TCP`] and
+    /// [`TxFlags::CSUM_UDP`] if VIRTIO_NET_F_CSUM is negotiated.
+    fn tx_offloads(&self) -> TxFlags {
+        if self.features & F_CSUM != 0 {
+            TxFlags::CSUM_IPV4 | TxFlags::CSUM_TCP | TxFlags::CSUM_UDP
         } else {
-            Err(DevError::Again)
+            TxFlags::CSUM_IPV4
+        }
+    }
+
+    /// Transmits a packet with checksum offloads.
+    ///
+    /// [`TxFlags::CSUM_TCP`] and [`TxFlags::CSUM_UDP`] are honored if
+    /// VIRTIO_NET_F_CSUM is negotiated: the virtio-net header asks the device
+    /// to compute the checksum of the TCP or UDP packet, whose checksum field
+    /// the driver overwrites with the checksum of the pseudo-header.
+    /// [`TxFlags::CSUM_IPV4`] is always honored, the driver computes the IPv4
+    /// header checksum itself since virtio-net has no offload for it.
+    /// [`TxFlags::TSO`] is not supported.
+    ///
+    /// The packet must be an Ethernet frame, with at most one VLAN tag, of an
+    /// IPv4 or IPv6 packet without extension headers, whose transport
+    /// protocol matches the flags. Otherwise [`DevError::InvalidParam`] is
+    /// returned.
+    fn transmit_with(&mut self, tx_buf: NetBufPtr, flags: TxFlags) -> DevResult {
+        self.post_tx(0, tx_buf, flags)
+    }
+
+    /// Sends VIRTIO_NET_CTRL_RX_PROMISC on the control virtqueue.
+    ///
+    /// Returns [`DevError::Unsupported`] if VIRTIO_NET_F_CTRL_RX is not
+    /// negotiated, and [`DevError::Io`] if the device rejects the command.
+    fn set_promiscuous(&mut self, on: bool) -> DevResult {
+        if self.features & F_CTRL_RX == 0 {
+            return Err(DevError::Unsupported);
         }
+        self.control(CTRL_RX, CTRL_RX_PROMISC, &[on as u8])
+    }
+
+    /// Sends VIRTIO_NET_CTRL_MAC_TABLE_SET on the control virtqueue, with the
+    /// unicast and the multicast addresses of `addrs` in their own tables.
+    ///
+    /// Returns [`DevError::Unsupported`] if VIRTIO_NET_F_CTRL_RX is not
+    /// negotiated, and [`DevError::Io`] if the device rejects the command,
+    /// e.g. if the tables are too large.
+    fn set_mac_filter(&mut self, addrs: &[EthernetAddress]) -> DevResult {
+        if self.features & F_CTRL_RX == 0 {
+            return Err(DevError::Unsupported);
+        }
+        let mut data = Vec::with_capacity(8 + 6 * addrs.len());
+        for multicast in [false, true] {
+            let table = addrs
+                .iter()
+                .filter(|addr| (addr.0[0] & 1 != 0) == multicast);
+            data.extend_from_slice(&(table.clone().count() as u32).to_le_bytes());
+            table.for_each(|addr| data.extend_from_slice(&addr.0));
+        }
+        self.control(CTRL_MAC, CTRL_MAC_TABLE_SET, &data)
+    }
+
+    fn receive(&mut self) -> DevResult<NetBufPtr> {
+        self.receive_from(0)
     }
 
     fn alloc_tx_buffer(&mut self, size: usize) -> DevResult<NetBufPtr> {
@@ -183,6 +678,7 @@ impl<H: Hal, T: Transport, const QS: usize> NetDriverOps for VirtIoNetDev<H, T,
         // 1. Check if the buffer is large enough.
         let hdr_len = net_buf.header_len();
         if hdr_len + pkt_len > net_buf.capacity() {
+            self.free_tx_bufs.push(net_buf);
             return Err(DevError::InvalidParam);
         }
         net_buf.set_packet_len(pkt_len);
@@ -190,4 +686,388 @@ impl<H: Hal, T: Transport, const QS: usize> NetDriverOps for VirtIoNetDev<H, T,
         // 2. Return the buffer.
         Ok(net_buf.into_buf_ptr())
     }
+
+    fn num_queues(&self) -> usize {
+        self.queues.len()
+    }
+
+    fn transmit_on(&mut self, queue: usize, tx_buf: NetBufPtr) -> DevResult {
+        self.post_tx(queue, tx_buf, TxFlags::empty())
+    }
+
+    /// Refills the receive queue with [`VirtIoNetDev::refill_rx`] when few
+    /// buffers are left in it.
+    fn receive_from(&mut self, queue: usize) -> DevResult<NetBufPtr> {
+        let posted = self
+            .queues
+            .get(queue)
+            .ok_or(DevError::InvalidParam)?
+            .rx_posted;
+        if posted < QS / RX_LOW_WATERMARK_DIV {
+            self.refill_queue(queue, QS - posted);
+        }
+        let result = self.queues[queue].receive(self.hdr_len);
+        let len = result.as_ref().map_or(0, |buf| buf.packet_len());
+        self.stats.record(false, len, &result);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ctrl::{VIRTIO_NET_ERR, VIRTIO_NET_OK};
+    use crate::fake::{Buf, FakeHal, FakeTransport};
+    use alloc::{boxed::Box, collections::VecDeque, rc::Rc, vec};
+    use core::cell::RefCell;
+    use virtio_drivers::transport::DeviceType as VirtIoDevType;
+
+    type Dev = VirtIoNetDev<FakeHal, FakeTransport, 8>;
+
+    const MAC: [u8; 6] = [0x52, 0x54, 0, 0x12, 0x34, 0x56];
+
+    /// The network of the device model.
+    #[derive(Default)]
+    struct Wire {
+        /// Packets to receive, with their virtio-net header
+        inbox: VecDeque<Vec<u8>>,
+        /// Packets transmitted, with their virtio-net header, by queue
+        sent: Vec<(u16, Vec<u8>)>,
+        /// Control commands: class, command and data
+        commands: Vec<(u8, u8, Vec<u8>)>,
+        /// Status of the control commands
+        ctrl_status: u8,
+    }
+
+    /// A network device offering `features`, whose control virtqueue is
+    /// `ctrl`.
+    fn device(features: u64, ctrl: u16) -> (Dev, Rc<RefCell<Wire>>) {
+        let (transport, wire) = transport(features, ctrl);
+        (Dev::try_new(transport).unwrap(), wire)
+    }
+
+    /// The transport of a network device like [`device`].
+    fn transport(features: u64, ctrl: u16) -> (FakeTransport, Rc<RefCell<Wire>>) {
+        let wire = Rc::new(RefCell::new(Wire::default()));
+        let model = wire.clone();
+        let handler = Box::new(move |queue: u16, bufs: &mut [Buf]| {
+            let mut wire = model.borrow_mut();
+            if queue == ctrl {
+                let (status, data) = bufs.split_last_mut().unwrap();
+                assert!(status.writable && data.iter().all(|buf| !buf.writable));
+                let data: Vec<u8> = data
+                    .iter()
+                    .flat_map(|buf| buf.data.iter().copied())
+                    .collect();
+                wire.commands.push((data[0], data[1], data[2..].to_vec()));
+                status.data[0] = wire.ctrl_status;
+                Some(1)
+            } else if queue.is_multiple_of(2) {
+                let packet = wire.inbox.pop_front()?;
+                assert!(bufs.len() == 1 && bufs[0].writable);
+                bufs[0].data[..packet.len()].copy_from_slice(&packet);
+                Some(packet.len() as u32)
+            } else {
+                assert!(bufs.len() == 1 && !bufs[0].writable);
+                wire.sent.push((queue, bufs[0].data.to_vec()));
+                Some(0)
+            }
+        });
+        let mut transport = FakeTransport::new(VirtIoDevType::Network, features, handler);
+        transport.config_bytes()[..6].copy_from_slice(&MAC);
+        (transport, wire)
+    }
+
+    /// Delivers `payload` to receive queue `queue`.
+    fn deliver(dev: &mut Dev, wire: &RefCell<Wire>, queue: u16, payload: &[u8]) {
+        let mut packet = vec![0; dev.hdr_len];
+        packet.extend_from_slice(payload);
+        wire.borrow_mut().inbox.push_back(packet);
+        dev.transport.process(queue);
+    }
+
+    #[test]
+    fn packets_are_sent_and_received() {
+        let (mut dev, wire) = device(F_MAC | F_VERSION_1, 2);
+        assert_eq!(dev.mac_address(), EthernetAddress(MAC));
+        assert!(!dev.can_receive());
+        assert!(matches!(dev.receive(), Err(DevError::Again)));
+
+        deliver(&mut dev, &wire, 0, b"hello");
+        assert!(dev.can_receive());
+        let rx_buf = dev.receive().unwrap();
+        assert_eq!(rx_buf.packet(), b"hello");
+        dev.recycle_rx_buffer(rx_buf).unwrap();
+
+        assert!(dev.can_transmit());
+        let mut tx_buf = dev.alloc_tx_buffer(5).unwrap();
+        tx_buf.packet_mut().copy_from_slice(b"world");
+        dev.transmit(tx_buf).unwrap();
+        dev.recycle_tx_buffers().unwrap();
+        let sent = wire.borrow().sent.clone();
+        assert_eq!(sent, [(1, b"\0\0\0\0\0\0\0\0\0\0\0\0world".to_vec())]);
+        assert_eq!(dev.stats().reads, 1);
+        assert_eq!(dev.stats().writes, 1);
+    }
+
+    #[test]
+    fn control_commands_are_sent_on_the_control_queue() {
+        let (mut dev, wire) = device(F_CTRL_VQ | F_VERSION_1, 2);
+        assert_eq!(dev.send_command(0, 1, &[1]).unwrap(), VIRTIO_NET_OK);
+        assert_eq!(dev.send_command(3, 0, &[]).unwrap(), VIRTIO_NET_OK);
+        wire.borrow_mut().ctrl_status = VIRTIO_NET_ERR;
+        assert_eq!(dev.send_command(0, 0, &[1]).unwrap(), VIRTIO_NET_ERR);
+        assert_eq!(
+            wire.borrow().commands,
+            [(0, 1, vec![1]), (3, 0, vec![]), (0, 0, vec![1])]
+        );
+        assert_eq!(dev.transport.chains.len(), 3);
+
+        let (mut dev, _) = device(F_VERSION_1, 2);
+        assert!(matches!(
+            dev.send_command(0, 1, &[1]),
+            Err(DevError::Unsupported)
+        ));
+    }
+
+    #[test]
+    fn queue_pairs_are_set_up_with_multiqueue() {
+        let (mut fake, wire) = transport(F_CTRL_VQ | F_MQ | F_VERSION_1, 8);
+        // max_virtqueue_pairs
+        fake.config_bytes()[8..10].copy_from_slice(&4u16.to_le_bytes());
+        let mut dev = Dev::try_new_multiqueue(fake, 2).unwrap();
+        assert_eq!(dev.num_queues(), 2);
+        assert_eq!(wire.borrow().commands, [(4, 0, vec![2, 0])]);
+
+        deliver(&mut dev, &wire, 2, b"to 1");
+        assert!(matches!(dev.receive(), Err(DevError::Again)));
+        let rx_buf = dev.receive_from(1).unwrap();
+        assert_eq!(rx_buf.packet(), b"to 1");
+        dev.recycle_rx_buffer(rx_buf).unwrap();
+        assert!(matches!(dev.receive_from(2), Err(DevError::InvalidParam)));
+
+        let tx_buf = dev.alloc_tx_buffer(4).unwrap();
+        dev.transmit_on(1, tx_buf).unwrap();
+        let tx_buf = dev.alloc_tx_buffer(4).unwrap();
+        assert!(matches!(
+            dev.transmit_on(2, tx_buf),
+            Err(DevError::InvalidParam)
+        ));
+        dev.recycle_tx_buffers().unwrap();
+        assert_eq!(wire.borrow().sent.len(), 1);
+        assert_eq!(wire.borrow().sent[0].0, 3);
+        assert_eq!(dev.free_tx_bufs.len(), 16);
+
+        // Without VIRTIO_NET_F_MQ there is a single pair
+        let (fake, wire) = transport(F_CTRL_VQ | F_VERSION_1, 2);
+        let dev = Dev::try_new_multiqueue(fake, 2).unwrap();
+        assert_eq!(dev.num_queues(), 1);
+        assert!(wire.borrow().commands.is_empty());
+    }
+
+    /// An Ethernet frame of an IPv4 (`ipv6 == false`) or IPv6 packet of
+    /// `protocol`, with a transport header of `l4_len` bytes and `payload`.
+    fn frame(ipv6: bool, protocol: u8, l4_len: usize, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0xff; 12];
+        let l4 = l4_len + payload.len();
+        if ipv6 {
+            frame.extend_from_slice(&[0x86, 0xdd, 0x60, 0, 0, 0]);
+            frame.extend_from_slice(&(l4 as u16).to_be_bytes());
+            frame.extend_from_slice(&[protocol, 64]);
+            frame.extend((0..32).map(|i| i as u8));
+        } else {
+            frame.extend_from_slice(&[0x08, 0x00, 0x45, 0]);
+            frame.extend_from_slice(&(20 + l4 as u16).to_be_bytes());
+            frame.extend_from_slice(&[0, 0, 0, 0, 64, protocol, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2]);
+        }
+        frame.extend((0..l4_len).map(|i| i as u8));
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// Transmits `frame` with `flags`, and returns what the device got.
+    fn transmit_frame(
+        dev: &mut Dev,
+        wire: &RefCell<Wire>,
+        frame: &[u8],
+        flags: TxFlags,
+    ) -> DevResult<Vec<u8>> {
+        let mut tx_buf = dev.alloc_tx_buffer(frame.len()).unwrap();
+        tx_buf.packet_mut().copy_from_slice(frame);
+        dev.transmit_with(tx_buf, flags)?;
+        dev.recycle_tx_buffers().unwrap();
+        Ok(wire.borrow_mut().sent.pop().unwrap().1)
+    }
+
+    /// Computes the checksum at `csum_start + csum_offset` like the device,
+    /// and returns the sum of the checksummed part and of `pseudo`, which is
+    /// 0xffff if the checksum is right.
+    fn device_checksum(sent: &mut [u8], pseudo: &[u8]) -> u16 {
+        let (hdr, frame) = sent.split_at_mut(12);
+        assert_eq!(hdr[0], HDR_F_NEEDS_CSUM);
+        let start = u16::from_le_bytes([hdr[6], hdr[7]]) as usize;
+        let offset = u16::from_le_bytes([hdr[8], hdr[9]]) as usize;
+        let sum = !checksum(0, &frame[start..]);
+        frame[start + offset..][..2].copy_from_slice(&sum.to_be_bytes());
+        checksum(checksum(0, pseudo), &frame[start..])
+    }
+
+    #[test]
+    fn checksums_are_offloaded_with_csum() {
+        let (mut dev, wire) = device(F_CSUM | F_VERSION_1, 2);
+        assert_eq!(
+            dev.tx_offloads(),
+            TxFlags::CSUM_IPV4 | TxFlags::CSUM_TCP | TxFlags::CSUM_UDP
+        );
+
+        let tcp = frame(false, IPPROTO_TCP, 20, b"ping");
+        let flags = TxFlags::CSUM_IPV4 | TxFlags::CSUM_TCP;
+        let mut sent = transmit_frame(&mut dev, &wire, &tcp, flags).unwrap();
+        assert_eq!(&sent[6..10], &[34, 0, 16, 0]);
+        // The IPv4 header checksum is filled in by the driver
+        assert_eq!(checksum(0, &sent[12 + 14..][..20]), 0xffff);
+        let pseudo = [10, 0, 0, 1, 10, 0, 0, 2, 0, IPPROTO_TCP, 0, 24];
+        assert_eq!(device_checksum(&mut sent, &pseudo), 0xffff);
+
+        let udp = frame(true, IPPROTO_UDP, 8, b"pong!");
+        let mut sent = transmit_frame(&mut dev, &wire, &udp, TxFlags::CSUM_UDP).unwrap();
+        assert_eq!(&sent[6..10], &[54, 0, 6, 0]);
+        let mut pseudo: Vec<u8> = (0..32).collect();
+        pseudo.extend_from_slice(&[0, 0, 0, 13, 0, 0, 0, IPPROTO_UDP]);
+        assert_eq!(device_checksum(&mut sent, &pseudo), 0xffff);
+
+        // Without flags the header is left empty
+        let sent = transmit_frame(&mut dev, &wire, &udp, TxFlags::empty()).unwrap();
+        assert!(sent[..12].iter().all(|&b| b == 0));
+
+        // The flags must match the packet, and there is no TSO
+        assert!(matches!(
+            transmit_frame(&mut dev, &wire, &udp, TxFlags::CSUM_TCP),
+            Err(DevError::InvalidParam)
+        ));
+        assert!(matches!(
+            transmit_frame(&mut dev, &wire, &udp, TxFlags::CSUM_IPV4),
+            Err(DevError::InvalidParam)
+        ));
+        assert!(matches!(
+            transmit_frame(&mut dev, &wire, &tcp, TxFlags::TSO),
+            Err(DevError::Unsupported)
+        ));
+        assert_eq!(dev.free_tx_bufs.len(), 8);
+    }
+
+    #[test]
+    fn checksum_offload_is_unsupported_without_csum() {
+        let (mut dev, wire) = device(F_VERSION_1, 2);
+        let udp = frame(false, IPPROTO_UDP, 8, b"ping");
+        assert!(matches!(
+            transmit_frame(&mut dev, &wire, &udp, TxFlags::CSUM_UDP),
+            Err(DevError::Unsupported)
+        ));
+        // The IPv4 header checksum is still computed by the driver
+        let sent = transmit_frame(&mut dev, &wire, &udp, TxFlags::CSUM_IPV4).unwrap();
+        assert!(sent[..12].iter().all(|&b| b == 0));
+        assert_eq!(checksum(0, &sent[12 + 14..][..20]), 0xffff);
+    }
+
+    #[test]
+    fn callers_fall_back_to_software_checksums() {
+        let (mut dev, wire) = device(F_VERSION_1, 2);
+        let flags = TxFlags::CSUM_IPV4 | TxFlags::CSUM_UDP;
+        let offloads = dev.tx_offloads();
+        assert_eq!(offloads, TxFlags::CSUM_IPV4);
+
+        // The stack computes the checksum that the device cannot
+        let mut udp = frame(false, IPPROTO_UDP, 8, b"ping");
+        let pseudo = [10, 0, 0, 1, 10, 0, 0, 2, 0, IPPROTO_UDP, 0, 12];
+        if !offloads.contains(TxFlags::CSUM_UDP) {
+            udp[40..42].fill(0);
+            let sum = !checksum(checksum(0, &pseudo), &udp[34..]);
+            udp[40..42].copy_from_slice(&sum.to_be_bytes());
+        }
+        let sent = transmit_frame(&mut dev, &wire, &udp, flags & offloads).unwrap();
+        assert!(sent[..12].iter().all(|&b| b == 0));
+        assert_eq!(checksum(0, &sent[12 + 14..][..20]), 0xffff);
+        assert_eq!(checksum(checksum(0, &pseudo), &sent[12 + 34..]), 0xffff);
+    }
+
+    #[test]
+    fn rx_mode_and_mac_filter_are_set_with_ctrl_rx() {
+        let (mut dev, wire) = device(F_CTRL_VQ | F_CTRL_RX | F_VERSION_1, 2);
+        dev.set_promiscuous(true).unwrap();
+        let unicast = EthernetAddress([0x52, 0x54, 0, 0, 0, 1]);
+        let multicast = EthernetAddress([0x01, 0, 0x5e, 0, 0, 0xfb]);
+        dev.set_mac_filter(&[multicast, unicast]).unwrap();
+        dev.set_mac_filter(&[]).unwrap();
+        let mut tables = vec![1, 0, 0, 0];
+        tables.extend_from_slice(&unicast.0);
+        tables.extend_from_slice(&[1, 0, 0, 0]);
+        tables.extend_from_slice(&multicast.0);
+        assert_eq!(
+            wire.borrow().commands,
+            [(0, 0, vec![1]), (1, 0, tables), (1, 0, vec![0; 8])]
+        );
+
+        wire.borrow_mut().ctrl_status = VIRTIO_NET_ERR;
+        assert!(matches!(dev.set_promiscuous(false), Err(DevError::Io)));
+
+        // The control virtqueue alone does not allow it
+        let (mut dev, wire) = device(F_CTRL_VQ | F_VERSION_1, 2);
+        assert!(matches!(
+            dev.set_promiscuous(true),
+            Err(DevError::Unsupported)
+        ));
+        assert!(matches!(
+            dev.set_mac_filter(&[unicast]),
+            Err(DevError::Unsupported)
+        ));
+        assert!(wire.borrow().commands.is_empty());
+    }
+
+    #[test]
+    fn link_status_is_read_with_status() {
+        let (mut fake, _) = transport(F_STATUS | F_VERSION_1, 2);
+        let mut dev = Dev::try_new(fake).unwrap();
+        assert_eq!(dev.negotiated_features(), F_STATUS | F_VERSION_1);
+        assert_eq!(dev.link_status(), LinkStatus::Down);
+        dev.transport.config_bytes()[6..8].copy_from_slice(&S_LINK_UP.to_le_bytes());
+        assert!(matches!(dev.link_status(), LinkStatus::Up { .. }));
+
+        // The link cannot be down without it
+        (fake, _) = transport(F_VERSION_1, 2);
+        let dev = Dev::try_new(fake).unwrap();
+        assert_eq!(dev.negotiated_features(), F_VERSION_1);
+        assert!(matches!(dev.link_status(), LinkStatus::Up { .. }));
+    }
+
+    #[test]
+    fn posted_buffers_are_unshared_on_drop() {
+        let (mut dev, _) = device(F_VERSION_1, 2);
+        assert_eq!(FakeHal::shared(), 8);
+        let mut tx_buf = dev.alloc_tx_buffer(5).unwrap();
+        tx_buf.packet_mut().copy_from_slice(b"world");
+        dev.transmit(tx_buf).unwrap();
+        assert_eq!(FakeHal::shared(), 9);
+
+        // The transport checks that the device is reset
+        drop(dev);
+        assert_eq!(FakeHal::shared(), 0);
+    }
+
+    #[test]
+    fn failed_initialization_resets_the_device() {
+        // The transport checks that the device is reset when dropped
+        let (mut fake, _) = transport(F_CTRL_VQ | F_VERSION_1, 2);
+        fake.max_queue_sizes[2] = 0;
+        assert!(matches!(Dev::try_new(fake), Err(DevError::InvalidParam)));
+
+        let (mut fake, _) = transport(F_MQ | F_CTRL_VQ | F_VERSION_1, 4);
+        fake.config_bytes()[8..10].copy_from_slice(&2u16.to_le_bytes());
+        fake.max_queue_sizes[3] = 0;
+        assert!(matches!(
+            Dev::try_new_multiqueue(fake, 2),
+            Err(DevError::InvalidParam)
+        ));
+        assert_eq!(FakeHal::shared(), 0);
+    }
 }
@@ -0,0 +1,541 @@
+//! Split virtqueues.
+//!
+//! `virtio-drivers` keeps its virtqueues to its own device drivers, so the
+//! drivers of this crate set up theirs through the [`Transport`] and allocate
+//! their memory through the [`Hal`].
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::mem::size_of;
+use core::ptr::NonNull;
+use core::sync::atomic::{fence, Ordering};
+
+use axdriver_base::{DevError, DevResult};
+use virtio_drivers::transport::{DeviceStatus, Transport};
+use virtio_drivers::{BufferDirection, Hal, PhysAddr, PAGE_SIZE};
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+const DESC_F_INDIRECT: u16 = 4;
+
+/// VIRTQ_USED_F_NO_NOTIFY in the flags of the used ring.
+const USED_F_NO_NOTIFY: u16 = 1;
+
+/// Chains of more descriptors than this go to an indirect table when
+/// VIRTIO_F_RING_INDIRECT_DESC is negotiated. Shorter ones, e.g. the header,
+/// data and status of a single-buffer block request, are cheaper to post to
+/// the ring directly.
+pub(crate) const INDIRECT_THRESHOLD: usize = 3;
+
+/// Maximum number of descriptors of an indirect table, which takes a page.
+pub(crate) const MAX_INDIRECT_DESCS: usize = PAGE_SIZE / size_of::<Descriptor>();
+
+/// A descriptor of the descriptor table, or of an indirect table.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// Resets the device, then unsets its `queues`.
+///
+/// The device no longer uses the memory of the queues nor the buffers posted
+/// to them, which can then be freed. Drivers call it when they are dropped,
+/// and on the errors of their initialization once a queue is set up.
+pub(crate) fn reset_device<T: Transport>(transport: &mut T, queues: impl IntoIterator<Item = u16>) {
+    transport.set_status(DeviceStatus::empty());
+    for queue in queues {
+        transport.queue_unset(queue);
+    }
+}
+
+/// Contiguous DMA memory of whole pages, from [`Hal::dma_alloc`], zeroed.
+pub(crate) struct Dma<H: Hal> {
+    paddr: PhysAddr,
+    vaddr: NonNull<u8>,
+    pages: usize,
+    _hal: PhantomData<H>,
+}
+
+impl<H: Hal> Dma<H> {
+    /// Allocates at least `size` bytes of DMA memory.
+    pub fn new(size: usize, direction: BufferDirection) -> DevResult<Self> {
+        let pages = size.div_ceil(PAGE_SIZE).max(1);
+        let (paddr, vaddr) = H::dma_alloc(pages, direction);
+        if paddr == 0 {
+            return Err(DevError::NoMemory);
+        }
+        Ok(Self {
+            paddr,
+            vaddr,
+            pages,
+            _hal: PhantomData,
+        })
+    }
+
+    /// The physical address, as seen by the device.
+    pub const fn paddr(&self) -> PhysAddr {
+        self.paddr
+    }
+
+    /// The virtual address.
+    pub const fn vaddr(&self) -> NonNull<u8> {
+        self.vaddr
+    }
+}
+
+impl<H: Hal> Drop for Dma<H> {
+    fn drop(&mut self) {
+        // SAFETY: the memory was allocated by `H::dma_alloc` with these
+        // addresses and number of pages, and is not used any longer.
+        unsafe { H::dma_dealloc(self.paddr, self.vaddr, self.pages) };
+    }
+}
+
+/// A buffer of a posted chain, shared with the device.
+struct Shared {
+    buf: NonNull<[u8]>,
+    paddr: PhysAddr,
+    direction: BufferDirection,
+}
+
+/// A chain posted to the device, until it is used.
+struct Chain<H: Hal> {
+    bufs: Vec<Shared>,
+    /// The indirect table, if the chain takes a single descriptor of the ring
+    indirect: Option<Dma<H>>,
+}
+
+/// A split virtqueue of `SIZE` descriptors.
+///
+/// A chain of buffers posted by [`VirtQueue::add`] is identified by a token,
+/// the index of its first descriptor, until [`VirtQueue::pop_used`] returns
+/// it once the device has used it.
+///
+/// The descriptor table, the available ring and the used ring are laid out in
+/// a single DMA region, in the layout that legacy devices require.
+///
+/// The queue must not be dropped while the device may still access it, i.e.
+/// its owner resets the device first.
+pub(crate) struct VirtQueue<H: Hal, const SIZE: usize> {
+    mem: Dma<H>,
+    /// Index of the queue on the device
+    idx: u16,
+    /// Head of the list of free descriptors, linked by their `next` field
+    free_head: u16,
+    num_free: usize,
+    /// The next index of the available ring, as posted to the device
+    avail_idx: u16,
+    /// `avail_idx` when the device was last notified
+    notified_idx: u16,
+    /// Index of the next used element to pop
+    last_used_idx: u16,
+    chains: [Option<Chain<H>>; SIZE],
+    indirect: bool,
+    event_idx: bool,
+}
+
+impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
+    const DESC_SIZE: usize = size_of::<Descriptor>() * SIZE;
+    /// Flags, index, ring and `used_event` of the available ring.
+    const AVAIL_SIZE: usize = size_of::<u16>() * (3 + SIZE);
+    const USED_OFFSET: usize = (Self::DESC_SIZE + Self::AVAIL_SIZE).next_multiple_of(PAGE_SIZE);
+    /// Flags, index, ring and `avail_event` of the used ring.
+    const USED_SIZE: usize = size_of::<u16>() * 3 + size_of::<u32>() * 2 * SIZE;
+
+    /// Sets up queue `idx` of the device, which must not be in use.
+    ///
+    /// `indirect` and `event_idx` tell whether VIRTIO_F_RING_INDIRECT_DESC and
+    /// VIRTIO_F_RING_EVENT_IDX are negotiated.
+    pub fn new<T: Transport>(
+        transport: &mut T,
+        idx: u16,
+        indirect: bool,
+        event_idx: bool,
+    ) -> DevResult<Self> {
+        if !SIZE.is_power_of_two() || SIZE > u16::MAX as usize {
+            return Err(DevError::InvalidParam);
+        }
+        if transport.queue_used(idx) {
+            return Err(DevError::AlreadyExists);
+        }
+        if (transport.max_queue_size(idx) as usize) < SIZE {
+            return Err(DevError::InvalidParam);
+        }
+
+        let mem = Dma::new(Self::USED_OFFSET + Self::USED_SIZE, BufferDirection::Both)?;
+        let mut queue = Self {
+            mem,
+            idx,
+            free_head: 0,
+            num_free: SIZE,
+            avail_idx: 0,
+            notified_idx: 0,
+            last_used_idx: 0,
+            chains: core::array::from_fn(|_| None),
+            indirect,
+            event_idx,
+        };
+        for i in 0..SIZE {
+            queue.write_desc(i as u16, 0, 0, 0, (i as u16).wrapping_add(1));
+        }
+        let paddr = queue.mem.paddr();
+        transport.queue_set(
+            idx,
+            SIZE as u32,
+            paddr,
+            paddr + Self::DESC_SIZE,
+            paddr + Self::USED_OFFSET,
+        );
+        Ok(queue)
+    }
+
+    /// The index of the queue on the device.
+    pub const fn index(&self) -> u16 {
+        self.idx
+    }
+
+    /// The maximum number of buffers of a chain: the queue size, or the size
+    /// of an indirect table if indirect descriptors are used.
+    pub const fn max_chain_len(&self) -> usize {
+        if self.indirect && MAX_INDIRECT_DESCS > SIZE {
+            MAX_INDIRECT_DESCS
+        } else {
+            SIZE
+        }
+    }
+
+    /// The number of descriptors of the ring that a chain of `len` buffers
+    /// takes, `None` if it is too long.
+    fn descs_for(&self, len: usize) -> Option<usize> {
+        if len == 0 || len > self.max_chain_len() {
+            None
+        } else if self.indirect && (len > INDIRECT_THRESHOLD || len > SIZE) {
+            Some(1)
+        } else {
+            Some(len)
+        }
+    }
+
+    /// Posts a chain of the `inputs`, read by the device, followed by the
+    /// `outputs`, written by it, and returns its token.
+    ///
+    /// The device is not notified, see [`VirtQueue::notify`]. Returns
+    /// [`DevError::InvalidParam`] if the chain is empty, has an empty buffer
+    /// or is longer than [`VirtQueue::max_chain_len`], and
+    /// [`DevError::BadState`] if there are not enough free descriptors.
+    ///
+    /// # Safety
+    ///
+    /// The buffers must stay valid and must not be accessed until the chain
+    /// is returned by [`VirtQueue::pop_used`], or until the queue is dropped
+    /// after the device has been reset.
+    pub unsafe fn add(&mut self, inputs: &[&[u8]], outputs: &mut [&mut [u8]]) -> DevResult<u16> {
+        let len = inputs.len() + outputs.len();
+        if inputs.iter().any(|b| b.is_empty()) || outputs.iter().any(|b| b.is_empty()) {
+            return Err(DevError::InvalidParam);
+        }
+        let descs = self.descs_for(len).ok_or(DevError::InvalidParam)?;
+        if descs > self.num_free {
+            return Err(DevError::BadState);
+        }
+        let mut indirect = if descs == 1 && self.indirect && len > 1 {
+            Some(Dma::<H>::new(
+                len * size_of::<Descriptor>(),
+                BufferDirection::DriverToDevice,
+            )?)
+        } else {
+            None
+        };
+
+        let mut bufs = Vec::with_capacity(len);
+        let inputs = inputs
+            .iter()
+            .map(|buf| (NonNull::from(*buf), BufferDirection::DriverToDevice));
+        let outputs = outputs
+            .iter_mut()
+            .map(|buf| (NonNull::from(&mut **buf), BufferDirection::DeviceToDriver));
+        for (buf, direction) in inputs.chain(outputs) {
+            // SAFETY: the caller keeps the buffer valid until it is unshared,
+            // when the chain is popped or the queue dropped.
+            let paddr = unsafe { H::share(buf, direction) };
+            bufs.push(Shared {
+                buf,
+                paddr,
+                direction,
+            });
+        }
+
+        let head = self.free_head;
+        if let Some(table) = &mut indirect {
+            let table_ptr = table.vaddr().cast::<Descriptor>().as_ptr();
+            for (i, shared) in bufs.iter().enumerate() {
+                let desc = Descriptor {
+                    addr: shared.paddr as u64,
+                    len: shared.buf.len() as u32,
+                    flags: desc_flags(shared.direction, i + 1 < len),
+                    next: (i + 1) as u16,
+                };
+                // SAFETY: the table holds `len` descriptors.
+                unsafe { table_ptr.add(i).write_volatile(desc) };
+            }
+            let next = self.read_desc(head).next;
+            self.write_desc(
+                head,
+                table.paddr() as u64,
+                (len * size_of::<Descriptor>()) as u32,
+                DESC_F_INDIRECT,
+                next,
+            );
+            self.free_head = next;
+        } else {
+            let mut idx = head;
+            for (i, shared) in bufs.iter().enumerate() {
+                let next = self.read_desc(idx).next;
+                let flags = desc_flags(shared.direction, i + 1 < len);
+                self.write_desc(
+                    idx,
+                    shared.paddr as u64,
+                    shared.buf.len() as u32,
+                    flags,
+                    next,
+                );
+                idx = next;
+            }
+            self.free_head = idx;
+        }
+        self.num_free -= descs;
+        self.chains[head as usize] = Some(Chain { bufs, indirect });
+
+        // The descriptors must be visible before the chain is made available,
+        // and the chain before the index.
+        fence(Ordering::SeqCst);
+        // SAFETY: the pointers are within the available ring.
+        unsafe {
+            self.avail_ring(self.avail_idx as usize % SIZE)
+                .write_volatile(head);
+            fence(Ordering::SeqCst);
+            self.avail_idx = self.avail_idx.wrapping_add(1);
+            self.avail_field(1).write_volatile(self.avail_idx);
+        }
+        fence(Ordering::SeqCst);
+        Ok(head)
+    }
+
+    /// Notifies the device of the chains added since the last notification,
+    /// unless it asked not to be notified.
+    pub fn notify<T: Transport>(&mut self, transport: &mut T) {
+        fence(Ordering::SeqCst);
+        let needed = if self.event_idx {
+            // SAFETY: the pointer is within the used ring.
+            let avail_event = unsafe { self.used_field(Self::USED_SIZE - 2).read_volatile() };
+            // vring_need_event() of the specification
+            self.avail_idx.wrapping_sub(avail_event).wrapping_sub(1)
+                < self.avail_idx.wrapping_sub(self.notified_idx)
+        } else {
+            // SAFETY: the pointer is within the used ring.
+            unsafe { self.used_field(0).read_volatile() & USED_F_NO_NOTIFY == 0 }
+        };
+        self.notified_idx = self.avail_idx;
+        if needed {
+            transport.notify(self.idx);
+        }
+    }
+
+    /// The token of the next chain that the device has used, if any.
+    pub fn peek_used(&self) -> Option<u16> {
+        fence(Ordering::SeqCst);
+        // SAFETY: the pointers are within the used ring.
+        unsafe {
+            if self.used_field(2).read_volatile() == self.last_used_idx {
+                return None;
+            }
+            // The element, and the buffers of its chain, are read after the
+            // index that tells they are written (virtio_rmb).
+            fence(Ordering::Acquire);
+            let elem = self.used_elem(self.last_used_idx as usize % SIZE);
+            Some(elem.read_volatile() as u16)
+        }
+    }
+
+    /// Takes back the chain `token`, which must be the next used one, and
+    /// returns the number of bytes that the device wrote to its outputs.
+    ///
+    /// Returns [`DevError::Again`] if no chain is used yet, and
+    /// [`DevError::BadState`] if the next used chain is another one.
+    pub fn pop_used(&mut self, token: u16) -> DevResult<u32> {
+        if self.peek_used().ok_or(DevError::Again)? != token {
+            return Err(DevError::BadState);
+        }
+        let chain = self.chains[token as usize]
+            .take()
+            .ok_or(DevError::BadState)?;
+        // The length is read after the barrier of `peek_used`, which saw the
+        // element as used.
+        // SAFETY: the pointers are within the used and available rings.
+        let len = unsafe {
+            let len = self
+                .used_elem(self.last_used_idx as usize % SIZE)
+                .add(1)
+                .read_volatile();
+            self.last_used_idx = self.last_used_idx.wrapping_add(1);
+            if self.event_idx {
+                // Ask for an interrupt when the next chain is used
+                self.avail_field(2 + SIZE)
+                    .write_volatile(self.last_used_idx);
+            }
+            len
+        };
+        self.recycle(token, chain);
+        Ok(len)
+    }
+
+    /// Posts a chain like [`VirtQueue::add`], notifies the device, and waits
+    /// until it has used the chain, for queues with a single request in
+    /// flight.
+    pub fn add_notify_wait_pop<T: Transport>(
+        &mut self,
+        transport: &mut T,
+        inputs: &[&[u8]],
+        outputs: &mut [&mut [u8]],
+    ) -> DevResult<u32> {
+        // SAFETY: the buffers are borrowed until the chain is popped.
+        let token = unsafe { self.add(inputs, outputs)? };
+        self.notify(transport);
+        while self.peek_used() != Some(token) {
+            core::hint::spin_loop();
+        }
+        self.pop_used(token)
+    }
+
+    /// Unshares the buffers of a chain and frees its descriptors.
+    fn recycle(&mut self, head: u16, chain: Chain<H>) {
+        for shared in &chain.bufs {
+            // SAFETY: the buffer was shared with this address and direction,
+            // and the device no longer uses it.
+            unsafe { H::unshare(shared.paddr, shared.buf, shared.direction) };
+        }
+        let descs = if chain.indirect.is_some() {
+            1
+        } else {
+            chain.bufs.len()
+        };
+        let mut last = head;
+        for i in 0..descs {
+            let desc = self.read_desc(last);
+            self.write_desc(last, 0, 0, 0, desc.next);
+            if i + 1 < descs {
+                last = desc.next;
+            }
+        }
+        self.write_desc(last, 0, 0, 0, self.free_head);
+        self.free_head = head;
+        self.num_free += descs;
+    }
+
+    fn desc_ptr(&self, idx: u16) -> *mut Descriptor {
+        assert!((idx as usize) < SIZE);
+        // SAFETY: the descriptor table holds `SIZE` descriptors.
+        unsafe {
+            self.mem
+                .vaddr()
+                .cast::<Descriptor>()
+                .as_ptr()
+                .add(idx as usize)
+        }
+    }
+
+    fn read_desc(&self, idx: u16) -> Descriptor {
+        // SAFETY: the pointer is within the descriptor table.
+        unsafe { self.desc_ptr(idx).read_volatile() }
+    }
+
+    fn write_desc(&mut self, idx: u16, addr: u64, len: u32, flags: u16, next: u16) {
+        let desc = Descriptor {
+            addr,
+            len,
+            flags,
+            next: next % SIZE as u16,
+        };
+        // SAFETY: the pointer is within the descriptor table.
+        unsafe { self.desc_ptr(idx).write_volatile(desc) };
+    }
+
+    /// The `i`-th `u16` of the available ring: flags, index, then the ring.
+    ///
+    /// # Safety
+    ///
+    /// `i` must be at most `SIZE + 2`.
+    unsafe fn avail_field(&self, i: usize) -> *mut u16 {
+        unsafe {
+            self.mem
+                .vaddr()
+                .as_ptr()
+                .add(Self::DESC_SIZE)
+                .cast::<u16>()
+                .add(i)
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `i` must be less than `SIZE`.
+    unsafe fn avail_ring(&self, i: usize) -> *mut u16 {
+        unsafe { self.avail_field(2 + i) }
+    }
+
+    /// The `u16` at `offset` of the used ring.
+    ///
+    /// # Safety
+    ///
+    /// `offset` must be even and at most `USED_SIZE - 2`.
+    unsafe fn used_field(&self, offset: usize) -> *mut u16 {
+        unsafe {
+            self.mem
+                .vaddr()
+                .as_ptr()
+                .add(Self::USED_OFFSET + offset)
+                .cast::<u16>()
+        }
+    }
+
+    /// The ID of the `i`-th element of the used ring, followed by its length.
+    ///
+    /// # Safety
+    ///
+    /// `i` must be less than `SIZE`.
+    unsafe fn used_elem(&self, i: usize) -> *mut u32 {
+        unsafe { self.used_field(4 + 8 * i).cast::<u32>() }
+    }
+}
+
+impl<H: Hal, const SIZE: usize> Drop for VirtQueue<H, SIZE> {
+    /// Unshares the buffers of the chains that the device did not use, which
+    /// it can no longer access once reset.
+    fn drop(&mut self) {
+        for chain in self.chains.iter_mut().filter_map(Option::take) {
+            for shared in &chain.bufs {
+                // SAFETY: the buffer was shared with this address and
+                // direction, and the device has been reset.
+                unsafe { H::unshare(shared.paddr, shared.buf, shared.direction) };
+            }
+        }
+    }
+}
+
+const fn desc_flags(direction: BufferDirection, next: bool) -> u16 {
+    let write = match direction {
+        BufferDirection::DriverToDevice => 0,
+        _ => DESC_F_WRITE,
+    };
+    if next {
+        write | DESC_F_NEXT
+    } else {
+        write
+    }
+}